@@ -334,6 +334,8 @@ impl AppService {
             user_id: user_id.clone(),
             // TODO: expose & proper E2EE
             device_id: DeviceId::new(),
+            refresh_token: None,
+            expires_at: None,
         };
 
         client.restore_login(session).await?;
@@ -42,8 +42,11 @@ use matrix_sdk_crypto::{
 use ruma::{
     api::client::r0::keys::claim_keys::Request as KeysClaimRequest,
     events::{
-        room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
-        AnyMessageEventContent, AnySyncMessageEvent,
+        room::{
+            encrypted::{EncryptedEventContent, EncryptedEventScheme},
+            history_visibility::HistoryVisibility,
+        },
+        AnyMessageEventContent, AnySyncMessageEvent, AnyToDeviceEvent,
     },
     DeviceId,
 };
@@ -58,6 +61,8 @@ use ruma::{
     serde::Raw,
     MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
 };
+#[cfg(feature = "encryption")]
+use serde_json::Value;
 use tracing::{info, trace, warn};
 use zeroize::Zeroizing;
 
@@ -65,7 +70,9 @@ use crate::{
     error::Result,
     rooms::{Room, RoomInfo, RoomType},
     session::Session,
-    store::{ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, Store},
+    store::{
+        ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, StateStore, Store,
+    },
 };
 
 pub type Token = String;
@@ -118,7 +125,10 @@ impl fmt::Debug for BaseClient {
 pub struct BaseClientConfig {
     #[cfg(feature = "encryption")]
     crypto_store: Option<Box<dyn CryptoStore>>,
+    state_store: Option<Box<dyn StateStore>>,
     store_path: Option<PathBuf>,
+    #[cfg(feature = "sqlite_state_store")]
+    sqlite_store_path: Option<PathBuf>,
     passphrase: Option<Zeroizing<String>>,
 }
 
@@ -144,6 +154,16 @@ impl BaseClientConfig {
         self
     }
 
+    /// Set a custom implementation of a `StateStore`.
+    ///
+    /// The state store should be opened before being set. A custom state store
+    /// takes precedence over the default state stores that would otherwise be
+    /// opened in the configured store path.
+    pub fn state_store(mut self, store: Box<dyn StateStore>) -> Self {
+        self.state_store = Some(store);
+        self
+    }
+
     /// Set the path for storage.
     ///
     /// # Arguments
@@ -160,6 +180,25 @@ impl BaseClientConfig {
         self
     }
 
+    /// Use the SQLite based state store and save it in the given path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory the SQLite database should be saved in, the
+    /// directory will be created if it doesn't exist.
+    ///
+    /// This takes precedence over the default sled based state store. If a
+    /// passphrase is set it will be used to encrypt the state store. If the
+    /// `sqlite_cryptostore` feature is enabled and no custom crypto store is
+    /// set, a SQLite based crypto store will be opened in the same directory.
+    /// Otherwise, if the `sled_cryptostore` feature is enabled, a sled based
+    /// crypto store will be opened in the `crypto` subdirectory.
+    #[cfg(feature = "sqlite_state_store")]
+    pub fn sqlite_store_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sqlite_store_path = Some(path.as_ref().into());
+        self
+    }
+
     /// Set the passphrase to encrypt the crypto store.
     ///
     /// # Argument
@@ -186,12 +225,17 @@ impl BaseClient {
     ///
     /// * `config` - An optional session if the user already has one from a
     /// previous login call.
-    pub fn new_with_config(config: BaseClientConfig) -> Result<Self> {
-        #[cfg_attr(
-            not(any(feature = "sled_state_store", feature = "sled_cryptostore")),
-            allow(unused_variables)
-        )]
-        let config = config;
+    pub fn new_with_config(mut config: BaseClientConfig) -> Result<Self> {
+        if let Some(state_store) = config.state_store.take() {
+            return Ok(Self::new_with_custom_store(config, state_store));
+        }
+
+        #[cfg(feature = "sqlite_state_store")]
+        {
+            if let Some(path) = config.sqlite_store_path.clone() {
+                return Self::new_with_sqlite_store(config, path);
+            }
+        }
 
         #[cfg(feature = "sled_state_store")]
         let stores = if let Some(path) = &config.store_path {
@@ -247,6 +291,83 @@ impl BaseClient {
         })
     }
 
+    fn new_with_custom_store(config: BaseClientConfig, state_store: Box<dyn StateStore>) -> Self {
+        info!("Using a custom state store");
+
+        let store = Store::new(state_store);
+
+        BaseClient {
+            session: store.session.clone(),
+            sync_token: store.sync_token.clone(),
+            store,
+            #[cfg(feature = "encryption")]
+            olm: Mutex::new(None).into(),
+            #[cfg(feature = "encryption")]
+            cryptostore: Mutex::new(config.crypto_store).into(),
+            #[cfg(feature = "encryption")]
+            store_path: config.store_path.into(),
+            #[cfg(feature = "sled_cryptostore")]
+            store_passphrase: config.passphrase.into(),
+        }
+    }
+
+    #[cfg(feature = "sqlite_state_store")]
+    fn new_with_sqlite_store(config: BaseClientConfig, path: PathBuf) -> Result<Self> {
+        let passphrase = config.passphrase.as_deref().map(|p| p.as_str());
+
+        if passphrase.is_some() {
+            info!("Opening an encrypted SQLite store in path {}", path.display());
+        } else {
+            info!("Opening a SQLite store in path {}", path.display());
+        }
+
+        let store = Store::open_sqlite(&path, passphrase)?;
+
+        // A sled based crypto store can't share the directory with the SQLite
+        // databases, it gets its own subdirectory.
+        #[cfg(feature = "encryption")]
+        let sled_crypto_store_path = path.join("crypto");
+
+        #[cfg(feature = "encryption")]
+        let crypto_store = if config.crypto_store.is_none() {
+            #[cfg(feature = "sqlite_cryptostore")]
+            let store: Option<Box<dyn CryptoStore>> = Some(Box::new(
+                matrix_sdk_crypto::store::SqliteCryptoStore::open_with_passphrase(
+                    &path, passphrase,
+                )
+                .map_err(OlmError::Store)?,
+            ));
+            #[cfg(all(not(feature = "sqlite_cryptostore"), feature = "sled_cryptostore"))]
+            let store: Option<Box<dyn CryptoStore>> = Some(Box::new(
+                matrix_sdk_crypto::store::SledStore::open_with_passphrase(
+                    &sled_crypto_store_path,
+                    passphrase,
+                )
+                .map_err(OlmError::Store)?,
+            ));
+            #[cfg(not(any(feature = "sqlite_cryptostore", feature = "sled_cryptostore")))]
+            let store = config.crypto_store;
+
+            store
+        } else {
+            config.crypto_store
+        };
+
+        Ok(BaseClient {
+            session: store.session.clone(),
+            sync_token: store.sync_token.clone(),
+            store,
+            #[cfg(feature = "encryption")]
+            olm: Mutex::new(None).into(),
+            #[cfg(feature = "encryption")]
+            cryptostore: Mutex::new(crypto_store).into(),
+            #[cfg(feature = "encryption")]
+            store_path: Some(sled_crypto_store_path).into(),
+            #[cfg(feature = "sled_cryptostore")]
+            store_passphrase: config.passphrase.into(),
+        })
+    }
+
     /// The current client session containing our user id, device id and access
     /// token.
     pub fn session(&self) -> &Arc<RwLock<Option<Session>>> {
@@ -280,6 +401,8 @@ impl BaseClient {
             access_token: response.access_token.clone(),
             device_id: response.device_id.clone(),
             user_id: response.user_id.clone(),
+            refresh_token: None,
+            expires_at: None,
         };
         self.restore_login(session).await
     }
@@ -413,10 +536,47 @@ impl BaseClient {
                             encrypted,
                         )) => {
                             if let Some(olm) = self.olm_machine().await {
-                                if let Ok(decrypted) =
-                                    olm.decrypt_room_event(encrypted, room_id).await
+                                match olm.decrypt_room_event(encrypted, room_id).await {
+                                    Ok(decrypted) => event = decrypted,
+                                    // Remember the event so we can try to
+                                    // decrypt it again once the room key
+                                    // arrives.
+                                    Err(MegolmError::MissingSession)
+                                    | Err(MegolmError::Withheld(_)) => {
+                                        if let EncryptedEventScheme::MegolmV1AesSha2(c) =
+                                            &encrypted.content.scheme
+                                        {
+                                            changes.add_undecryptable_event(
+                                                room_id,
+                                                &c.session_id,
+                                                &encrypted.event_id,
+                                                event.event.clone(),
+                                            );
+                                        }
+                                    }
+                                    Err(e) => {
+                                        warn!(
+                                            room_id = room_id.as_str(),
+                                            event_id = encrypted.event_id.as_str(),
+                                            error =? e,
+                                            "Failed to decrypt a room event"
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        #[cfg(feature = "encryption")]
+                        AnySyncRoomEvent::Message(message) => {
+                            if let Some(olm) = self.olm_machine().await {
+                                if let Err(e) = olm
+                                    .receive_unencrypted_verification_event(message, room_id)
+                                    .await
                                 {
-                                    event = decrypted;
+                                    warn!(
+                                        room_id = room_id.as_str(),
+                                        error =? e,
+                                        "Error handling an in-room verification event"
+                                    );
                                 }
                             }
                         }
@@ -648,6 +808,9 @@ impl BaseClient {
         &self,
         response: api::sync::sync_events::Response,
     ) -> Result<SyncResponse> {
+        #[cfg(feature = "encryption")]
+        let unused_fallback_keys = response.device_unused_fallback_key_types.clone();
+
         let api::sync::sync_events::Response {
             next_batch,
             rooms,
@@ -677,13 +840,21 @@ impl BaseClient {
                 // decrypts to-device events, but leaves room events alone.
                 // This makes sure that we have the decryption keys for the room
                 // events at hand.
-                o.receive_sync_changes(to_device, &device_lists, &device_one_time_keys_count)
-                    .await?
+                o.receive_sync_changes(
+                    to_device,
+                    &device_lists,
+                    &device_one_time_keys_count,
+                    unused_fallback_keys.as_deref(),
+                )
+                .await?
             } else {
                 to_device
             }
         };
 
+        #[cfg(feature = "encryption")]
+        let received_room_keys = Self::received_room_keys(&to_device);
+
         let mut changes = StateChanges::new(next_batch.clone());
         let mut ambiguity_cache = AmbiguityCache::new(self.store.clone());
 
@@ -734,6 +905,7 @@ impl BaseClient {
                     &mut user_ids,
                 )
                 .await?;
+            changes.add_timeline(&room_id, timeline.clone());
 
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
@@ -798,6 +970,7 @@ impl BaseClient {
                     &mut user_ids,
                 )
                 .await?;
+            changes.add_timeline(&room_id, timeline.clone());
 
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
@@ -850,6 +1023,13 @@ impl BaseClient {
         *self.sync_token.write().await = Some(next_batch.clone());
         self.apply_changes(&changes).await;
 
+        // Events from earlier syncs might have become decryptable with the room
+        // keys we just received.
+        #[cfg(feature = "encryption")]
+        let late_decrypted_events = self.retry_decryption_helper(Some(received_room_keys)).await?;
+        #[cfg(not(feature = "encryption"))]
+        let late_decrypted_events = BTreeMap::new();
+
         info!("Processed a sync response in {:?}", now.elapsed());
 
         let response = SyncResponse {
@@ -865,11 +1045,118 @@ impl BaseClient {
                 .collect(),
             ambiguity_changes: AmbiguityChanges { changes: ambiguity_cache.changes },
             notifications: changes.notifications,
+            late_decrypted_events,
         };
 
         Ok(response)
     }
 
+    /// Collect the room and session ids of the room keys that were received in
+    /// the given to-device events.
+    #[cfg(feature = "encryption")]
+    fn received_room_keys(
+        to_device: &api::sync::sync_events::ToDevice,
+    ) -> BTreeMap<RoomId, BTreeSet<String>> {
+        let mut room_keys: BTreeMap<RoomId, BTreeSet<String>> = BTreeMap::new();
+
+        for event in to_device.events.iter().filter_map(|e| e.deserialize().ok()) {
+            let (room_id, session_id) = match event {
+                AnyToDeviceEvent::RoomKey(e) => (e.content.room_id, e.content.session_id),
+                AnyToDeviceEvent::ForwardedRoomKey(e) => (e.content.room_id, e.content.session_id),
+                _ => continue,
+            };
+
+            room_keys.entry(room_id).or_default().insert(session_id);
+        }
+
+        room_keys
+    }
+
+    /// Try to decrypt the undecryptable events of the given rooms and
+    /// sessions, or of all the rooms if `sessions` is `None`.
+    ///
+    /// Events that were successfully decrypted are removed from the store.
+    #[cfg(feature = "encryption")]
+    async fn retry_decryption_helper(
+        &self,
+        sessions: Option<BTreeMap<RoomId, BTreeSet<String>>>,
+    ) -> Result<BTreeMap<RoomId, Vec<SyncRoomEvent>>> {
+        let olm = if let Some(o) = self.olm_machine().await {
+            o
+        } else {
+            return Ok(BTreeMap::new());
+        };
+
+        let rooms: Vec<(RoomId, Option<BTreeSet<String>>)> = match sessions {
+            Some(s) => s.into_iter().map(|(r, s)| (r, Some(s))).collect(),
+            None => {
+                self.store.get_rooms().into_iter().map(|r| (r.room_id().clone(), None)).collect()
+            }
+        };
+
+        let mut decrypted_events = BTreeMap::new();
+
+        for (room_id, session_ids) in rooms {
+            let undecryptable = self.store.get_undecryptable_events(&room_id).await?;
+
+            for (session_id, events) in undecryptable {
+                if session_ids.as_ref().map_or(false, |s| !s.contains(&session_id)) {
+                    continue;
+                }
+
+                let mut event_ids = Vec::new();
+
+                for raw_event in events {
+                    if let Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(
+                        encrypted,
+                    ))) = raw_event.deserialize()
+                    {
+                        if let Ok(decrypted) = olm.decrypt_room_event(&encrypted, &room_id).await {
+                            // The stored timeline still contains the encrypted
+                            // version of the event.
+                            self.store.replace_timeline_event(&room_id, &decrypted).await?;
+
+                            event_ids.push(encrypted.event_id);
+                            decrypted_events
+                                .entry(room_id.clone())
+                                .or_insert_with(Vec::new)
+                                .push(decrypted);
+                        }
+                    }
+                }
+
+                if !event_ids.is_empty() {
+                    trace!(
+                        room_id = room_id.as_str(),
+                        session_id = session_id.as_str(),
+                        count = event_ids.len(),
+                        "Decrypted previously undecryptable events"
+                    );
+
+                    self.store
+                        .remove_undecryptable_events(&room_id, &session_id, &event_ids)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(decrypted_events)
+    }
+
+    /// Try to decrypt all the events that couldn't be decrypted when they
+    /// were received.
+    ///
+    /// This is done automatically for room keys that are received in a sync
+    /// response, this method should be called if room keys were added in
+    /// another way, e.g. by importing a key export.
+    ///
+    /// Returns the successfully decrypted events, grouped by room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn retry_decryption(&self) -> Result<BTreeMap<RoomId, Vec<SyncRoomEvent>>> {
+        self.retry_decryption_helper(None).await
+    }
+
     async fn apply_changes(&self, changes: &StateChanges) {
         for (room_id, room_info) in &changes.room_infos {
             if let Some(room) = self.store.get_room(room_id) {
@@ -1079,7 +1366,10 @@ impl BaseClient {
                 };
 
                 let settings = settings.ok_or(MegolmError::EncryptionNotEnabled)?;
-                let settings = EncryptionSettings::new(settings, history_visibility);
+                let settings = EncryptionSettings {
+                    sharing_policy: o.sharing_policy(room_id),
+                    ..EncryptionSettings::new(settings, history_visibility)
+                };
 
                 Ok(o.share_group_session(room_id, members, settings).await?)
             }
@@ -1112,6 +1402,26 @@ impl BaseClient {
         }
     }
 
+    /// Encrypt a json [`Value`] content of a message event.
+    ///
+    /// This is equivalent to [`encrypt()`](#method.encrypt) but allows
+    /// custom events to be encrypted.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn encrypt_raw(
+        &self,
+        room_id: &RoomId,
+        content: Value,
+        event_type: &str,
+    ) -> Result<EncryptedEventContent> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.encrypt_raw(room_id, content, event_type).await?),
+            None => panic!("Olm machine wasn't started"),
+        }
+    }
+
     /// Invalidate the currently active outbound group session for the given
     /// room.
     ///
@@ -1362,4 +1672,39 @@ impl BaseClient {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use matrix_sdk_test::async_test;
+
+    use super::{BaseClient, BaseClientConfig};
+    use crate::store::{memory_store::MemoryStore, StateStore};
+
+    #[async_test]
+    async fn custom_state_store() {
+        let store = MemoryStore::new();
+        StateStore::save_filter(&store, "filter", "filter_id").await.unwrap();
+
+        let config = BaseClientConfig::new().state_store(Box::new(store));
+        let client = BaseClient::new_with_config(config).unwrap();
+
+        assert_eq!(
+            client.store().get_filter("filter").await.unwrap().as_deref(),
+            Some("filter_id")
+        );
+    }
+
+    #[cfg(all(
+        feature = "sqlite_state_store",
+        feature = "sled_cryptostore",
+        not(feature = "sqlite_cryptostore")
+    ))]
+    #[async_test]
+    async fn sled_crypto_store_next_to_sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = BaseClientConfig::new().sqlite_store_path(dir.path());
+        let _client = BaseClient::new_with_config(config).unwrap();
+
+        assert!(dir.path().join("matrix-sdk-state.sqlite3").is_file());
+        assert!(dir.path().join("crypto").is_dir());
+    }
+}
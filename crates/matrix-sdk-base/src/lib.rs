@@ -44,4 +44,4 @@ pub use client::{BaseClient, BaseClientConfig};
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use matrix_sdk_crypto as crypto;
 pub use rooms::{Room, RoomInfo, RoomMember, RoomType};
-pub use store::{StateChanges, StateStore, Store, StoreError};
+pub use store::{QueuedEvent, StateChanges, StateStore, Store, StoreError, TimelineChunk};
@@ -15,7 +15,7 @@
 
 //! User sessions.
 
-use ruma::{DeviceId, UserId};
+use ruma::{DeviceId, MilliSecondsSinceUnixEpoch, UserId};
 use serde::{Deserialize, Serialize};
 
 /// A user session, containing an access token and information about the
@@ -28,4 +28,23 @@ pub struct Session {
     pub user_id: UserId,
     /// The ID of the client device
     pub device_id: Box<DeviceId>,
+    /// The token that can be used to get a new access token once the current
+    /// one expires, see [MSC2918].
+    ///
+    /// [MSC2918]: https://github.com/matrix-org/matrix-doc/pull/2918
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// The point in time at which the access token expires, if it expires at
+    /// all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl Session {
+    /// Has the access token of this session expired.
+    ///
+    /// Returns false if the access token doesn't expire.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= MilliSecondsSinceUnixEpoch::now())
+    }
 }
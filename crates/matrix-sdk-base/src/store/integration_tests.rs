@@ -0,0 +1,288 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A test-suite that every `StateStore` implementation should pass.
+//!
+//! The module that invokes the macro needs to provide a `get_store()` function
+//! that returns a new and empty store.
+
+macro_rules! statestore_integration_tests {
+    () => {
+        use std::convert::TryFrom;
+
+        use matrix_sdk_test::async_test;
+        use ruma::{
+            event_id, events::AnySyncRoomEvent, room_id, serde::Raw, user_id, EventId, UserId,
+        };
+        use serde_json::json;
+        use $crate::{
+            deserialized_responses::{SyncRoomEvent, Timeline},
+            store::{QueuedEvent, StateChanges, StateStore, MAX_UNDECRYPTABLE_EVENTS},
+        };
+
+        fn user_id() -> UserId {
+            user_id!("@example:localhost")
+        }
+
+        fn timeline_event(event_id: &str) -> SyncRoomEvent {
+            let event = json!({
+                "content": { "body": "hello", "msgtype": "m.text" },
+                "event_id": event_id,
+                "origin_server_ts": 0u64,
+                "sender": user_id(),
+                "type": "m.room.message",
+            });
+
+            serde_json::from_value::<Raw<AnySyncRoomEvent>>(event).unwrap().into()
+        }
+
+        fn encrypted_event(event_id: &str) -> Raw<AnySyncRoomEvent> {
+            serde_json::from_value(json!({
+                "event_id": event_id,
+                "content": {
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "ciphertext": "AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg",
+                    "device_id": "SOMEDEVICE",
+                    "sender_key": "sender_key",
+                    "session_id": "session_id",
+                },
+                "sender": user_id(),
+                "type": "m.room.encrypted",
+                "origin_server_ts": 0u64,
+            }))
+            .unwrap()
+        }
+
+        fn event_id_of(event: &Raw<AnySyncRoomEvent>) -> String {
+            let event = event.deserialize_as::<serde_json::Value>().unwrap();
+            event["event_id"].as_str().unwrap().to_owned()
+        }
+
+        fn timeline_event_ids(events: &[(i64, SyncRoomEvent)]) -> Vec<String> {
+            events.iter().map(|(_, e)| event_id_of(&e.event)).collect()
+        }
+
+        #[async_test]
+        async fn test_timeline_gaps() {
+            let store = get_store();
+            let room_id = room_id!("!test:localhost");
+
+            let mut timeline = Timeline::new(false, Some("start".to_owned()));
+            timeline.events = vec![timeline_event("$a:localhost"), timeline_event("$b:localhost")];
+            let mut changes = StateChanges::default();
+            changes.add_timeline(&room_id, timeline);
+            store.save_changes(&changes).await.unwrap();
+
+            // A limited timeline starts a new chunk, the events in between are
+            // missing.
+            let mut timeline = Timeline::new(true, Some("gap".to_owned()));
+            timeline.events = vec![timeline_event("$e:localhost"), timeline_event("$f:localhost")];
+            let mut changes = StateChanges::default();
+            changes.add_timeline(&room_id, timeline);
+            store.save_changes(&changes).await.unwrap();
+
+            let chunks = store.get_timeline_chunks(&room_id).await.unwrap();
+            assert_eq!(chunks.len(), 2);
+            assert_eq!(chunks[0].prev_batch.as_deref(), Some("start"));
+            assert_eq!(chunks[1].prev_batch.as_deref(), Some("gap"));
+
+            let events = store.get_timeline_events(&room_id, chunks[1].id, None, 10).await.unwrap();
+            assert_eq!(timeline_event_ids(&events), ["$f:localhost", "$e:localhost"]);
+            assert_eq!(events.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1, 0]);
+
+            // A backfill that doesn't reach known events keeps the gap open.
+            store
+                .add_timeline_backfill(
+                    &room_id,
+                    chunks[1].id,
+                    &[timeline_event("$d:localhost")],
+                    Some("older"),
+                )
+                .await
+                .unwrap();
+
+            let chunks = store.get_timeline_chunks(&room_id).await.unwrap();
+            assert_eq!(chunks[1].prev_batch.as_deref(), Some("older"));
+
+            // Filling the gap stops at the first event we already know about.
+            let backfill = [timeline_event("$c:localhost"), timeline_event("$b:localhost")];
+            store
+                .add_timeline_backfill(&room_id, chunks[1].id, &backfill, Some("oldest"))
+                .await
+                .unwrap();
+
+            let chunks = store.get_timeline_chunks(&room_id).await.unwrap();
+            assert_eq!(chunks[1].prev_batch, None);
+
+            let events = store.get_timeline_events(&room_id, chunks[1].id, None, 10).await.unwrap();
+            assert_eq!(
+                timeline_event_ids(&events),
+                ["$f:localhost", "$e:localhost", "$d:localhost", "$c:localhost"]
+            );
+            assert_eq!(events.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1, 0, -1, -2]);
+
+            // Backfilled events are ordered before the ones from the sync,
+            // across the sign change of their indices.
+            let events =
+                store.get_timeline_events(&room_id, chunks[1].id, Some(0), 1).await.unwrap();
+            assert_eq!(timeline_event_ids(&events), ["$d:localhost"]);
+            let events =
+                store.get_timeline_events(&room_id, chunks[1].id, Some(-1), 10).await.unwrap();
+            assert_eq!(timeline_event_ids(&events), ["$c:localhost"]);
+
+            // New events keep being appended after the last one of the chunk.
+            let mut timeline = Timeline::new(false, None);
+            timeline.events = vec![timeline_event("$f:localhost"), timeline_event("$g:localhost")];
+            let mut changes = StateChanges::default();
+            changes.add_timeline(&room_id, timeline);
+            store.save_changes(&changes).await.unwrap();
+
+            let events = store.get_timeline_events(&room_id, chunks[1].id, None, 2).await.unwrap();
+            assert_eq!(timeline_event_ids(&events), ["$g:localhost", "$f:localhost"]);
+            assert_eq!(events[0].0, 2);
+        }
+
+        #[async_test]
+        async fn test_replace_timeline_event() {
+            let store = get_store();
+            let room_id = room_id!("!test:localhost");
+
+            let mut timeline = Timeline::new(false, Some("start".to_owned()));
+            timeline.events = vec![
+                timeline_event("$a:localhost"),
+                encrypted_event("$b:localhost").into(),
+                timeline_event("$c:localhost"),
+            ];
+            let mut changes = StateChanges::default();
+            changes.add_timeline(&room_id, timeline);
+            store.save_changes(&changes).await.unwrap();
+
+            store.replace_timeline_event(&room_id, &timeline_event("$b:localhost")).await.unwrap();
+            // Events that aren't part of the timeline don't get added.
+            store.replace_timeline_event(&room_id, &timeline_event("$d:localhost")).await.unwrap();
+
+            let chunks = store.get_timeline_chunks(&room_id).await.unwrap();
+            let events = store.get_timeline_events(&room_id, chunks[0].id, None, 10).await.unwrap();
+            assert_eq!(
+                timeline_event_ids(&events),
+                ["$c:localhost", "$b:localhost", "$a:localhost"]
+            );
+
+            let event = events[1].1.event.deserialize_as::<serde_json::Value>().unwrap();
+            assert_eq!(event["type"], "m.room.message");
+        }
+
+        #[async_test]
+        async fn test_undecryptable_events() {
+            let store = get_store();
+            let room_id = room_id!("!test:localhost");
+            let event_id = event_id!("$h29iv0s8:example.com");
+
+            assert!(store.get_undecryptable_events(&room_id).await.unwrap().is_empty());
+
+            let mut changes = StateChanges::default();
+            changes.add_undecryptable_event(
+                &room_id,
+                "session_id",
+                &event_id,
+                encrypted_event(event_id.as_str()),
+            );
+            store.save_changes(&changes).await.unwrap();
+
+            let events = store.get_undecryptable_events(&room_id).await.unwrap();
+            assert_eq!(events.get("session_id").map(|e| e.len()), Some(1));
+
+            store.remove_undecryptable_events(&room_id, "session_id", &[event_id]).await.unwrap();
+            assert!(store.get_undecryptable_events(&room_id).await.unwrap().is_empty());
+        }
+
+        #[async_test]
+        async fn test_undecryptable_events_limit() {
+            let store = get_store();
+            let room_id = room_id!("!test:localhost");
+
+            for i in 0..MAX_UNDECRYPTABLE_EVENTS + 10 {
+                let event_id = EventId::try_from(format!("${}:localhost", i)).unwrap();
+
+                let mut changes = StateChanges::default();
+                changes.add_undecryptable_event(
+                    &room_id,
+                    &format!("session_{}", i % 2),
+                    &event_id,
+                    encrypted_event(event_id.as_str()),
+                );
+                store.save_changes(&changes).await.unwrap();
+            }
+
+            let event_ids: Vec<String> = store
+                .get_undecryptable_events(&room_id)
+                .await
+                .unwrap()
+                .values()
+                .flatten()
+                .map(event_id_of)
+                .collect();
+
+            // The oldest events were forgotten to make room for the newest
+            // ones.
+            assert_eq!(event_ids.len(), MAX_UNDECRYPTABLE_EVENTS);
+            assert!(!event_ids.contains(&"$9:localhost".to_owned()));
+            assert!(event_ids.contains(&"$10:localhost".to_owned()));
+            assert!(event_ids.contains(&format!("${}:localhost", MAX_UNDECRYPTABLE_EVENTS + 9)));
+        }
+
+        #[async_test]
+        async fn test_queued_events() {
+            let store = get_store();
+            let room_id = room_id!("!test:localhost");
+
+            let event = |transaction_id: &str| QueuedEvent {
+                transaction_id: transaction_id.to_owned(),
+                event_type: "m.room.message".to_owned(),
+                content: json!({ "body": "hello", "msgtype": "m.text" }),
+                failed: false,
+            };
+
+            store.add_queued_event(&room_id, &event("txn1")).await.unwrap();
+            store.add_queued_event(&room_id, &event("txn2")).await.unwrap();
+            store.add_queued_event(&room_id, &event("txn3")).await.unwrap();
+
+            store.remove_queued_event(&room_id, "txn2").await.unwrap();
+
+            assert_eq!(
+                store.get_queued_events(&room_id).await.unwrap(),
+                vec![event("txn1"), event("txn3")]
+            );
+            assert!(store
+                .get_queued_events(&room_id!("!other:localhost"))
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(store.get_rooms_with_queued_events().await.unwrap(), vec![room_id.clone()]);
+
+            // Updated events keep their place in the queue.
+            let failed = QueuedEvent { failed: true, ..event("txn1") };
+            store.update_queued_event(&room_id, &failed).await.unwrap();
+            store.update_queued_event(&room_id, &event("txn4")).await.unwrap();
+            assert_eq!(
+                store.get_queued_events(&room_id).await.unwrap(),
+                vec![failed, event("txn3")]
+            );
+
+            store.remove_queued_event(&room_id, "txn1").await.unwrap();
+            store.remove_queued_event(&room_id, "txn3").await.unwrap();
+            assert!(store.get_rooms_with_queued_events().await.unwrap().is_empty());
+        }
+    };
+}
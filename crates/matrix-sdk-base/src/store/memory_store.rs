@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     sync::{Arc, RwLock},
 };
 
@@ -26,7 +26,7 @@ use ruma::{
         receipt::Receipt,
         room::member::{MemberEventContent, MembershipState},
         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
-        AnySyncStateEvent, EventType,
+        AnySyncRoomEvent, AnySyncStateEvent, EventType,
     },
     receipt::ReceiptType,
     serde::Raw,
@@ -34,12 +34,92 @@ use ruma::{
 };
 use tracing::info;
 
-use super::{Result, RoomInfo, StateChanges, StateStore};
+use super::{
+    timeline_event_id, QueuedEvent, Result, RoomInfo, StateChanges, StateStore, TimelineChunk,
+    MAX_UNDECRYPTABLE_EVENTS,
+};
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent, Timeline},
     media::{MediaRequest, UniqueKey},
 };
 
+/// The stored timeline of a single room.
+#[derive(Debug, Default)]
+struct RoomTimeline {
+    chunks: BTreeMap<u64, TimelineChunk>,
+    events: BTreeMap<(u64, i64), SyncRoomEvent>,
+    event_ids: BTreeMap<EventId, (u64, i64)>,
+}
+
+impl RoomTimeline {
+    fn contains(&self, event: &SyncRoomEvent) -> bool {
+        timeline_event_id(event).map_or(false, |e| self.event_ids.contains_key(&e))
+    }
+
+    fn insert(&mut self, position: (u64, i64), event: SyncRoomEvent) {
+        if let Some(event_id) = timeline_event_id(&event) {
+            self.event_ids.insert(event_id, position);
+        }
+
+        self.events.insert(position, event);
+    }
+
+    fn replace(&mut self, event: &SyncRoomEvent) {
+        let position = timeline_event_id(event).and_then(|e| self.event_ids.get(&e).copied());
+
+        if let Some(position) = position {
+            self.events.insert(position, event.clone());
+        }
+    }
+
+    fn append(&mut self, timeline: &Timeline) {
+        let events: Vec<_> = timeline.events.iter().filter(|e| !self.contains(e)).collect();
+
+        if events.is_empty() {
+            return;
+        }
+
+        let last_chunk = self.chunks.keys().next_back().copied();
+
+        let (chunk_id, start) = match last_chunk {
+            Some(id) if !timeline.limited => {
+                let last = self.events.range((id, i64::MIN)..=(id, i64::MAX)).next_back();
+                (id, last.map_or(0, |((_, index), _)| index + 1))
+            }
+            last => {
+                let id = last.map_or(0, |id| id + 1);
+                self.chunks
+                    .insert(id, TimelineChunk { id, prev_batch: timeline.prev_batch.clone() });
+                (id, 0)
+            }
+        };
+
+        for (index, event) in (start..).zip(events) {
+            self.insert((chunk_id, index), event.clone());
+        }
+    }
+
+    fn backfill(&mut self, chunk_id: u64, events: &[SyncRoomEvent], prev_batch: Option<&str>) {
+        let first = self.events.range((chunk_id, i64::MIN)..=(chunk_id, i64::MAX)).next();
+        let mut index = first.map_or(0, |((_, index), _)| *index);
+        let mut prev_batch = prev_batch.map(ToOwned::to_owned);
+
+        for event in events {
+            if self.contains(event) {
+                prev_batch = None;
+                break;
+            }
+
+            index -= 1;
+            self.insert((chunk_id, index), event.clone());
+        }
+
+        if let Some(chunk) = self.chunks.get_mut(&chunk_id) {
+            chunk.prev_batch = prev_batch;
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
     sync_token: Arc<RwLock<Option<String>>>,
@@ -65,6 +145,12 @@ pub struct MemoryStore {
     #[allow(clippy::type_complexity)]
     room_event_receipts:
         Arc<DashMap<RoomId, DashMap<String, DashMap<EventId, DashMap<UserId, Receipt>>>>>,
+    #[allow(clippy::type_complexity)]
+    undecryptable_events:
+        Arc<DashMap<RoomId, DashMap<String, DashMap<EventId, Raw<AnySyncRoomEvent>>>>>,
+    undecryptable_event_order: Arc<DashMap<RoomId, VecDeque<(String, EventId)>>>,
+    timelines: Arc<DashMap<RoomId, RoomTimeline>>,
+    queued_events: Arc<DashMap<RoomId, Vec<QueuedEvent>>>,
     media: Arc<Mutex<LruCache<String, Vec<u8>>>>,
     custom: Arc<DashMap<Vec<u8>, Vec<u8>>>,
 }
@@ -90,6 +176,10 @@ impl MemoryStore {
             presence: Default::default(),
             room_user_receipts: Default::default(),
             room_event_receipts: Default::default(),
+            undecryptable_events: Default::default(),
+            undecryptable_event_order: Default::default(),
+            timelines: Default::default(),
+            queued_events: Default::default(),
             media: Arc::new(Mutex::new(LruCache::new(100))),
             custom: DashMap::new().into(),
         }
@@ -273,6 +363,38 @@ impl MemoryStore {
             }
         }
 
+        for (room, sessions) in &changes.undecryptable_events {
+            let room_sessions =
+                self.undecryptable_events.entry(room.clone()).or_insert_with(DashMap::new);
+            let mut order = self.undecryptable_event_order.entry(room.clone()).or_default();
+
+            for (session_id, events) in sessions {
+                let session =
+                    room_sessions.entry(session_id.to_owned()).or_insert_with(DashMap::new);
+
+                for (event_id, event) in events {
+                    if session.insert(event_id.clone(), event.clone()).is_none() {
+                        order.push_back((session_id.to_owned(), event_id.clone()));
+                    }
+                }
+            }
+
+            // Forget the oldest events once the room goes over the limit.
+            let excess = order.len().saturating_sub(MAX_UNDECRYPTABLE_EVENTS);
+
+            for (session_id, event_id) in order.drain(..excess) {
+                if let Some(session) = room_sessions.get(&session_id) {
+                    session.remove(&event_id);
+                }
+            }
+
+            room_sessions.retain(|_, events| !events.is_empty());
+        }
+
+        for (room, timeline) in &changes.timeline {
+            self.timelines.entry(room.clone()).or_default().append(timeline);
+        }
+
         info!("Saved changes in {:?}", now.elapsed());
 
         Ok(())
@@ -399,6 +521,136 @@ impl MemoryStore {
             .unwrap_or_else(Vec::new))
     }
 
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>> {
+        Ok(self
+            .undecryptable_events
+            .get(room_id)
+            .map(|m| {
+                m.iter()
+                    .map(|s| {
+                        (s.key().clone(), s.value().iter().map(|e| e.value().clone()).collect())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()> {
+        if let Some(sessions) = self.undecryptable_events.get(room_id) {
+            if let Some(events) = sessions.get(session_id) {
+                for event_id in event_ids {
+                    events.remove(event_id);
+                }
+            }
+
+            sessions.remove_if(session_id, |_, events| events.is_empty());
+        }
+
+        if let Some(mut order) = self.undecryptable_event_order.get_mut(room_id) {
+            order.retain(|(s, e)| s != session_id || !event_ids.contains(e));
+        }
+
+        Ok(())
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        Ok(self
+            .timelines
+            .get(room_id)
+            .map(|t| t.chunks.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>> {
+        let before = before.unwrap_or(i64::MAX);
+
+        Ok(self
+            .timelines
+            .get(room_id)
+            .map(|t| {
+                t.events
+                    .range((chunk_id, i64::MIN)..(chunk_id, before))
+                    .rev()
+                    .take(limit)
+                    .map(|((_, index), event)| (*index, event.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()> {
+        if let Some(mut timeline) = self.timelines.get_mut(room_id) {
+            timeline.backfill(chunk_id, events, prev_batch);
+        }
+
+        Ok(())
+    }
+
+    async fn replace_timeline_event(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<()> {
+        if let Some(mut timeline) = self.timelines.get_mut(room_id) {
+            timeline.replace(event);
+        }
+
+        Ok(())
+    }
+
+    async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.queued_events.entry(room_id.clone()).or_default().push(event.clone());
+
+        Ok(())
+    }
+
+    async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>> {
+        Ok(self.queued_events.get(room_id).map(|e| e.clone()).unwrap_or_default())
+    }
+
+    async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        if let Some(mut events) = self.queued_events.get_mut(room_id) {
+            if let Some(e) = events.iter_mut().find(|e| e.transaction_id == event.transaction_id) {
+                *e = event.clone();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        if let Some(mut events) = self.queued_events.get_mut(room_id) {
+            events.retain(|e| e.transaction_id != transaction_id);
+        }
+
+        Ok(())
+    }
+
+    async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>> {
+        Ok(self
+            .queued_events
+            .iter()
+            .filter(|e| !e.value().is_empty())
+            .map(|e| e.key().clone())
+            .collect())
+    }
+
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         Ok(self.custom.get(key).map(|e| e.value().clone()))
     }
@@ -562,6 +814,70 @@ impl StateStore for MemoryStore {
         self.get_event_room_receipt_events(room_id, receipt_type, event_id).await
     }
 
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>> {
+        self.get_undecryptable_events(room_id).await
+    }
+
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()> {
+        self.remove_undecryptable_events(room_id, session_id, event_ids).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.get_timeline_chunks(room_id).await
+    }
+
+    async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>> {
+        self.get_timeline_events(room_id, chunk_id, before, limit).await
+    }
+
+    async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()> {
+        self.add_timeline_backfill(room_id, chunk_id, events, prev_batch).await
+    }
+
+    async fn replace_timeline_event(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<()> {
+        self.replace_timeline_event(room_id, event).await
+    }
+
+    async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.add_queued_event(room_id, event).await
+    }
+
+    async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>> {
+        self.get_queued_events(room_id).await
+    }
+
+    async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.update_queued_event(room_id, event).await
+    }
+
+    async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.remove_queued_event(room_id, transaction_id).await
+    }
+
+    async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>> {
+        self.get_rooms_with_queued_events().await
+    }
+
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.get_custom_value(key).await
     }
@@ -589,20 +905,19 @@ impl StateStore for MemoryStore {
 
 #[cfg(test)]
 mod test {
-    use matrix_sdk_test::async_test;
     use ruma::{
-        api::client::r0::media::get_content_thumbnail::Method, event_id, mxc_uri,
-        receipt::ReceiptType, room_id, uint, user_id, UserId,
+        api::client::r0::media::get_content_thumbnail::Method, mxc_uri, receipt::ReceiptType, uint,
     };
-    use serde_json::json;
 
-    use super::{MemoryStore, StateChanges};
+    use super::MemoryStore;
     use crate::media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType};
 
-    fn user_id() -> UserId {
-        user_id!("@example:localhost")
+    fn get_store() -> MemoryStore {
+        MemoryStore::new()
     }
 
+    statestore_integration_tests!();
+
     #[async_test]
     async fn test_receipts_saving() {
         let store = MemoryStore::new();
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "sled_state_store")]
+#[cfg(any(feature = "sled_state_store", feature = "sqlite_state_store"))]
 use std::path::Path;
 use std::{
     collections::{BTreeMap, BTreeSet},
@@ -29,31 +29,42 @@ use ruma::{
         receipt::{Receipt, ReceiptEventContent},
         room::member::MemberEventContent,
         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
-        AnySyncStateEvent, EventContent, EventType,
+        AnySyncRoomEvent, AnySyncStateEvent, EventContent, EventType,
     },
     receipt::ReceiptType,
     serde::Raw,
     EventId, MxcUri, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 #[cfg(feature = "sled_state_store")]
 use sled::Db;
 
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    deserialized_responses::{MemberEvent, StrippedMemberEvent, SyncRoomEvent, Timeline},
     media::MediaRequest,
     rooms::{RoomInfo, RoomType},
     Room, Session,
 };
 
 pub(crate) mod ambiguity_map;
-mod memory_store;
+#[cfg(test)]
+#[macro_use]
+mod integration_tests;
+pub(crate) mod memory_store;
 #[cfg(feature = "sled_state_store")]
 mod sled_store;
+#[cfg(feature = "sqlite_state_store")]
+mod sqlite_store;
+#[cfg(any(feature = "sled_state_store", feature = "sqlite_state_store"))]
+mod store_key;
 
 #[cfg(not(feature = "sled_state_store"))]
 use self::memory_store::MemoryStore;
 #[cfg(feature = "sled_state_store")]
 use self::sled_store::SledStore;
+#[cfg(feature = "sqlite_state_store")]
+use self::sqlite_store::SqliteStore;
 
 /// State store specific error type.
 #[derive(Debug, thiserror::Error)]
@@ -62,6 +73,13 @@ pub enum StoreError {
     #[cfg(feature = "sled_state_store")]
     #[error(transparent)]
     Sled(#[from] sled::Error),
+    /// An error happened in the underlying SQLite database.
+    #[cfg(feature = "sqlite_state_store")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    /// An IO error happened while opening the store.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     /// An error happened while serializing or deserializing some data.
     #[error(transparent)]
     Json(#[from] serde_json::Error),
@@ -76,14 +94,71 @@ pub enum StoreError {
     /// An unencrypted store was tried to be unlocked with a passphrase.
     #[error("The store is not encrypted but was tried to be opened with a passphrase")]
     UnencryptedStore,
+    /// An encrypted store was tried to be encrypted again.
+    #[error("The store is already encrypted with a passphrase")]
+    EncryptedStore,
     /// The store failed to encrypt or decrypt some data.
     #[error("Error encrypting or decrypting data from the store: {0}")]
     Encryption(String),
+    /// A key of the store couldn't be decoded.
+    #[error("The store contains a malformed key")]
+    InvalidKey,
 }
 
+/// The maximum number of undecryptable events that are remembered per room.
+///
+/// Once a room goes over this limit, the oldest remembered events are
+/// forgotten to make room for the new ones.
+pub(crate) const MAX_UNDECRYPTABLE_EVENTS: usize = 1000;
+
 /// A `StateStore` specific result type.
 pub type Result<T, E = StoreError> = std::result::Result<T, E>;
 
+/// A chunk of the stored timeline of a room.
+///
+/// The events inside of a chunk are known to be contiguous. A new chunk is
+/// started every time a sync response contains a limited timeline, since the
+/// events between the stored ones and the ones of the sync response are
+/// missing.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimelineChunk {
+    /// The id of the chunk, newer chunks have bigger ids.
+    pub id: u64,
+    /// The token that can be used to fetch the events that are missing before
+    /// this chunk from the server.
+    ///
+    /// This is `None` if no events are missing, either because the chunk
+    /// continues the previous chunk or because it starts at the beginning of
+    /// the room.
+    pub prev_batch: Option<String>,
+}
+
+/// A message event that is waiting to be sent to a room.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct QueuedEvent {
+    /// The transaction id that is used to send the event.
+    pub transaction_id: String,
+    /// The type of the event.
+    pub event_type: String,
+    /// The plaintext content of the event.
+    pub content: JsonValue,
+    /// Did sending the event fail with an error that retrying won't fix.
+    /// Failed events hold back the rest of the queue until they are retried
+    /// or removed.
+    #[serde(default)]
+    pub failed: bool,
+}
+
+/// Get the event id of a timeline event, used to avoid storing an event twice.
+pub(crate) fn timeline_event_id(event: &SyncRoomEvent) -> Option<EventId> {
+    #[derive(Deserialize)]
+    struct EventIdDeHelper {
+        event_id: EventId,
+    }
+
+    event.event.deserialize_as::<EventIdDeHelper>().ok().map(|e| e.event_id)
+}
+
 /// An abstract state store trait that can be used to implement different stores
 /// for the SDK.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -279,6 +354,152 @@ pub trait StateStore: AsyncTraitDeps {
     /// * `value` - The value to insert
     async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
+    /// Get the events of a room that we weren't able to decrypt.
+    ///
+    /// The events are grouped by the ID of the Megolm session that was used to
+    /// encrypt them. Only the newest 1000 events are remembered per room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the undecryptable events
+    /// should be fetched.
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>>;
+
+    /// Remove events from the set of undecryptable events of a room, e.g.
+    /// because they have been successfully decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the events belong to.
+    ///
+    /// * `session_id` - The id of the Megolm session that was used to encrypt
+    /// the events.
+    ///
+    /// * `event_ids` - The ids of the events that should be removed.
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()>;
+
+    /// Get the chunks of the stored timeline of a room, ordered from the
+    /// oldest to the newest chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the timeline chunks should
+    /// be fetched.
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>>;
+
+    /// Get events out of a chunk of the stored timeline of a room.
+    ///
+    /// The events are returned together with their index inside of the chunk,
+    /// ordered from the newest to the oldest event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the events belong to.
+    ///
+    /// * `chunk_id` - The id of the chunk the events belong to.
+    ///
+    /// * `before` - Only return events that are older than the event with the
+    /// given index, if one is given.
+    ///
+    /// * `limit` - The maximal number of events that should be returned.
+    async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>>;
+
+    /// Prepend events that were fetched from the server to a chunk of the
+    /// stored timeline of a room, filling the gap before the chunk.
+    ///
+    /// Events are added until an event is found that is already stored, in that
+    /// case the gap is closed and the chunk continues the chunk that contains
+    /// the found event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the events belong to.
+    ///
+    /// * `chunk_id` - The id of the chunk the events should be prepended to.
+    ///
+    /// * `events` - The events, ordered from the newest to the oldest event.
+    ///
+    /// * `prev_batch` - The token that can be used to fetch the events before
+    /// the given ones, `None` if the beginning of the room was reached.
+    async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()>;
+
+    /// Replace an event of the stored timeline of a room, e.g. because it got
+    /// decrypted after it was stored.
+    ///
+    /// The stored event is found using the event id of the given event,
+    /// nothing happens if the timeline doesn't contain the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event belongs to.
+    ///
+    /// * `event` - The event that should replace the stored one.
+    async fn replace_timeline_event(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<()>;
+
+    /// Add an event to the end of the send queue of a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event should be sent to.
+    ///
+    /// * `event` - The event that should be sent.
+    async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()>;
+
+    /// Get the events in the send queue of a room, in the order they were
+    /// added.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the queued events should be
+    /// fetched.
+    async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>>;
+
+    /// Replace an event in the send queue of a room with a new version of it,
+    /// the event keeps its place in the queue.
+    ///
+    /// Does nothing if there's no event with the same transaction id in the
+    /// queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event belongs to.
+    ///
+    /// * `event` - The new version of the event.
+    async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()>;
+
+    /// Remove an event from the send queue of a room, e.g. because it was
+    /// sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event belongs to.
+    ///
+    /// * `transaction_id` - The transaction id of the event.
+    async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()>;
+
+    /// Get the ids of the rooms that have events in their send queue.
+    async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>>;
+
     /// Add a media file's content in the media store.
     ///
     /// # Arguments
@@ -325,7 +546,7 @@ pub struct Store {
 }
 
 impl Store {
-    fn new(inner: Box<dyn StateStore>) -> Self {
+    pub(crate) fn new(inner: Box<dyn StateStore>) -> Self {
         Self {
             inner: inner.into(),
             session: Default::default(),
@@ -380,6 +601,21 @@ impl Store {
         Ok((Self::new(Box::new(inner.clone())), inner.inner))
     }
 
+    /// Open the SQLite based store.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the store should reside in.
+    ///
+    /// * `passphrase` - A passphrase that should be used to encrypt the state
+    /// store.
+    #[cfg(feature = "sqlite_state_store")]
+    pub fn open_sqlite(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        let inner = SqliteStore::open_with_path(path, passphrase)?;
+
+        Ok(Self::new(Box::new(inner)))
+    }
+
     #[cfg(feature = "sled_state_store")]
     pub(crate) fn open_temporary() -> Result<(Self, Db)> {
         let inner = SledStore::open()?;
@@ -479,6 +715,15 @@ pub struct StateChanges {
     pub ambiguity_maps: BTreeMap<RoomId, BTreeMap<String, BTreeSet<UserId>>>,
     /// A map of `RoomId` to a vector of `Notification`s
     pub notifications: BTreeMap<RoomId, Vec<Notification>>,
+
+    /// A mapping of `RoomId` to a map of Megolm session ids to the events,
+    /// keyed by their event id, that we couldn't decrypt.
+    pub undecryptable_events:
+        BTreeMap<RoomId, BTreeMap<String, BTreeMap<EventId, Raw<AnySyncRoomEvent>>>>,
+
+    /// A map of `RoomId` to the `Timeline` that was received for the room and
+    /// should be appended to the stored timeline.
+    pub timeline: BTreeMap<RoomId, Timeline>,
 }
 
 impl StateChanges {
@@ -558,6 +803,29 @@ impl StateChanges {
         self.notifications.entry(room_id.to_owned()).or_insert_with(Vec::new).push(notification);
     }
 
+    /// Update the `StateChanges` struct with the given room with a new
+    /// event that couldn't be decrypted.
+    pub fn add_undecryptable_event(
+        &mut self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_id: &EventId,
+        raw_event: Raw<AnySyncRoomEvent>,
+    ) {
+        self.undecryptable_events
+            .entry(room_id.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .entry(session_id.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(event_id.to_owned(), raw_event);
+    }
+
+    /// Update the `StateChanges` struct with the given room with a new
+    /// `Timeline`.
+    pub fn add_timeline(&mut self, room_id: &RoomId, timeline: Timeline) {
+        self.timeline.insert(room_id.to_owned(), timeline);
+    }
+
     /// Update the `StateChanges` struct with the given room with a new
     /// `Receipts`.
     pub fn add_receipts(&mut self, room_id: &RoomId, event: ReceiptEventContent) {
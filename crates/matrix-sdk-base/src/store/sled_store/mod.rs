@@ -12,10 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod store_key;
-
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     convert::{TryFrom, TryInto},
     path::{Path, PathBuf},
     sync::Arc,
@@ -32,7 +30,8 @@ use ruma::{
         presence::PresenceEvent,
         receipt::Receipt,
         room::member::{MemberEventContent, MembershipState},
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncStateEvent, EventType,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncRoomEvent, AnySyncStateEvent,
+        EventType,
     },
     receipt::ReceiptType,
     serde::Raw,
@@ -45,19 +44,16 @@ use sled::{
 };
 use tracing::info;
 
-use self::store_key::{EncryptedEvent, StoreKey};
-use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
+use super::{
+    store_key::{self, DatabaseType, EncryptedEvent, StoreKey},
+    timeline_event_id, QueuedEvent, Result, RoomInfo, StateChanges, StateStore, StoreError,
+    TimelineChunk, MAX_UNDECRYPTABLE_EVENTS,
+};
 use crate::{
-    deserialized_responses::MemberEvent,
+    deserialized_responses::{MemberEvent, SyncRoomEvent, Timeline},
     media::{MediaRequest, UniqueKey},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum DatabaseType {
-    Unencrypted,
-    Encrypted(store_key::EncryptedStoreKey),
-}
-
 #[derive(Debug, thiserror::Error)]
 pub enum SerializationError {
     #[error(transparent)]
@@ -154,6 +150,33 @@ impl EncodeKey for EventType {
     }
 }
 
+/// Encode the key of a timeline chunk, the key is also the prefix of the keys
+/// of all the events in the chunk.
+///
+/// Integers are encoded in big endian so the chunks of a room are sorted by
+/// their id.
+fn encode_chunk_key(room_id: &RoomId, chunk_id: u64) -> Vec<u8> {
+    [room_id.encode().as_slice(), &chunk_id.to_be_bytes()].concat()
+}
+
+/// Encode the key of an event in a timeline chunk.
+///
+/// The sign bit of the index is flipped so the events of a chunk are sorted by
+/// their index, negative indices included.
+fn encode_timeline_key(room_id: &RoomId, chunk_id: u64, index: i64) -> Vec<u8> {
+    [encode_chunk_key(room_id, chunk_id).as_slice(), &((index as u64) ^ (1 << 63)).to_be_bytes()]
+        .concat()
+}
+
+/// Get the index of an event out of a key that was encoded using
+/// `encode_timeline_key()`.
+fn decode_timeline_index(key: &[u8]) -> i64 {
+    let index: [u8; 8] =
+        key[key.len() - 8..].try_into().expect("Timeline keys end with the event index");
+
+    (u64::from_be_bytes(index) ^ (1 << 63)) as i64
+}
+
 /// Get the value at `position` in encoded `key`.
 ///
 /// The key must have been encoded with the `EncodeKey` trait. `position`
@@ -188,6 +211,12 @@ pub struct SledStore {
     presence: Tree,
     room_user_receipts: Tree,
     room_event_receipts: Tree,
+    undecryptable_events: Tree,
+    undecryptable_event_order: Tree,
+    timeline_chunks: Tree,
+    timeline_events: Tree,
+    timeline_event_ids: Tree,
+    queued_events: Tree,
     media: Tree,
     custom: Tree,
 }
@@ -225,6 +254,15 @@ impl SledStore {
         let room_user_receipts = db.open_tree("room_user_receipts")?;
         let room_event_receipts = db.open_tree("room_event_receipts")?;
 
+        let undecryptable_events = db.open_tree("undecryptable_events")?;
+        let undecryptable_event_order = db.open_tree("undecryptable_event_order")?;
+
+        let timeline_chunks = db.open_tree("timeline_chunks")?;
+        let timeline_events = db.open_tree("timeline_events")?;
+        let timeline_event_ids = db.open_tree("timeline_event_ids")?;
+
+        let queued_events = db.open_tree("queued_events")?;
+
         let media = db.open_tree("media")?;
 
         let custom = db.open_tree("custom")?;
@@ -249,6 +287,12 @@ impl SledStore {
             stripped_room_state,
             room_user_receipts,
             room_event_receipts,
+            undecryptable_events,
+            undecryptable_event_order,
+            timeline_chunks,
+            timeline_events,
+            timeline_event_ids,
+            queued_events,
             media,
             custom,
         })
@@ -294,6 +338,131 @@ impl SledStore {
         SledStore::open_helper(db, Some(path), None)
     }
 
+    /// Change the passphrase that protects this store.
+    ///
+    /// Only the store key is re-encrypted with the new passphrase, the stored
+    /// events are left untouched, so this is a single atomic write.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        let store_key: Option<DatabaseType> = self
+            .inner
+            .get("store_key".encode())?
+            .map(|k| serde_json::from_slice(&k).map_err(StoreError::Json))
+            .transpose()?;
+
+        let store_key = if let Some(DatabaseType::Encrypted(k)) = store_key {
+            StoreKey::import(old_passphrase, k).map_err(|_| StoreError::StoreLocked)?
+        } else {
+            return Err(StoreError::UnencryptedStore);
+        };
+
+        let encrypted_key = DatabaseType::Encrypted(
+            store_key.export(new_passphrase).map_err::<StoreError, _>(|e| e.into())?,
+        );
+        self.inner.insert("store_key".encode(), serde_json::to_vec(&encrypted_key)?)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Encrypt an existing, unencrypted, sled based state store at the given
+    /// path with the given passphrase.
+    ///
+    /// A new store key is created and every encryptable entry of the store is
+    /// encrypted using it, the store key is then encrypted with the passphrase
+    /// and stored alongside the entries in a single transaction.
+    ///
+    /// The store must not be opened while this runs, afterwards it needs to be
+    /// opened using [`SledStore::open_with_passphrase()`] with the same
+    /// passphrase.
+    pub async fn encrypt_store(path: impl AsRef<Path>, passphrase: &str) -> Result<()> {
+        let path = path.as_ref().join("matrix-sdk-state");
+        let db = Config::new().temporary(false).path(&path).open()?;
+
+        let store_key: Option<DatabaseType> = db
+            .get("store_key".encode())?
+            .map(|k| serde_json::from_slice(&k).map_err(StoreError::Json))
+            .transpose()?;
+
+        if let Some(DatabaseType::Encrypted(_)) = store_key {
+            return Err(StoreError::EncryptedStore);
+        }
+
+        let key = StoreKey::new().map_err::<StoreError, _>(|e| e.into())?;
+        let store = SledStore::open_helper(db, Some(path), None)?;
+
+        let mut trees = store.encrypted_trees();
+        let mut encrypted_entries = Vec::with_capacity(trees.len());
+
+        for tree in &trees {
+            let mut entries = Vec::new();
+
+            for entry in tree.iter() {
+                let (entry_key, value) = entry?;
+                let value: serde_json::Value = serde_json::from_slice(&value)?;
+                let encrypted = key.encrypt(&value).map_err::<StoreError, _>(|e| e.into())?;
+
+                entries.push((entry_key, serde_json::to_vec(&encrypted)?));
+            }
+
+            encrypted_entries.push(entries);
+        }
+
+        let encrypted_key = serde_json::to_vec(&DatabaseType::Encrypted(
+            key.export(passphrase).map_err::<StoreError, _>(|e| e.into())?,
+        ))?;
+
+        trees.push(&store.inner);
+
+        let ret: Result<(), TransactionError<SerializationError>> =
+            trees.as_slice().transaction(|trees| {
+                let (inner, trees) = trees.split_last().expect("The database tree is missing");
+
+                for (tree, entries) in trees.iter().zip(&encrypted_entries) {
+                    for (entry_key, value) in entries {
+                        tree.insert(entry_key.clone(), value.as_slice())?;
+                    }
+                }
+
+                inner.insert("store_key".encode(), encrypted_key.as_slice())?;
+
+                Ok(())
+            });
+
+        ret?;
+        store.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// The trees whose values go through `serialize_event()` and are thus
+    /// encrypted if the store has a store key.
+    fn encrypted_trees(&self) -> Vec<&Tree> {
+        vec![
+            &self.account_data,
+            &self.members,
+            &self.profiles,
+            &self.display_names,
+            &self.room_info,
+            &self.room_state,
+            &self.room_account_data,
+            &self.stripped_room_info,
+            &self.stripped_room_state,
+            &self.stripped_members,
+            &self.presence,
+            &self.room_user_receipts,
+            &self.room_event_receipts,
+            &self.undecryptable_events,
+            &self.timeline_chunks,
+            &self.timeline_events,
+            &self.timeline_event_ids,
+            &self.queued_events,
+        ]
+    }
+
     fn serialize_event(&self, event: &impl Serialize) -> Result<Vec<u8>, SerializationError> {
         if let Some(key) = &*self.store_key {
             let encrypted = key.encrypt(event)?;
@@ -560,6 +729,51 @@ impl SledStore {
 
         ret?;
 
+        let mut undecryptable_events = sled::Batch::default();
+        let mut undecryptable_event_order = sled::Batch::default();
+
+        for (room, sessions) in &changes.undecryptable_events {
+            // The order tree maps a room and an increasing id to the key of
+            // the event, so the oldest events of a room come first.
+            let mut order = self
+                .undecryptable_event_order
+                .scan_prefix(room.encode())
+                .map(|e| e.map(|(k, v)| (k.to_vec(), v.to_vec())))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for (session_id, events) in sessions {
+                for (event_id, event) in events {
+                    let key = (room.as_str(), session_id.as_str(), event_id.as_str()).encode();
+
+                    if !self.undecryptable_events.contains_key(&key)? {
+                        let order_key =
+                            [room.encode().as_slice(), &self.inner.generate_id()?.to_be_bytes()]
+                                .concat();
+
+                        undecryptable_event_order.insert(order_key.as_slice(), key.as_slice());
+                        order.push((order_key, key.clone()));
+                    }
+
+                    undecryptable_events.insert(key, self.serialize_event(&event)?);
+                }
+            }
+
+            // Forget the oldest events once the room goes over the limit.
+            let excess = order.len().saturating_sub(MAX_UNDECRYPTABLE_EVENTS);
+
+            for (order_key, key) in order.drain(..excess) {
+                undecryptable_event_order.remove(order_key);
+                undecryptable_events.remove(key);
+            }
+        }
+
+        self.undecryptable_events.apply_batch(undecryptable_events)?;
+        self.undecryptable_event_order.apply_batch(undecryptable_event_order)?;
+
+        for (room, timeline) in &changes.timeline {
+            self.append_timeline(room, timeline)?;
+        }
+
         self.inner.flush_async().await?;
 
         info!("Saved changes in {:?}", now.elapsed());
@@ -567,6 +781,269 @@ impl SledStore {
         Ok(())
     }
 
+    fn timeline_contains(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<bool> {
+        Ok(match timeline_event_id(event) {
+            Some(event_id) => self
+                .timeline_event_ids
+                .contains_key((room_id.as_str(), event_id.as_str()).encode())?,
+            None => false,
+        })
+    }
+
+    fn get_timeline_chunk(&self, room_id: &RoomId, chunk_id: u64) -> Result<Option<TimelineChunk>> {
+        Ok(self
+            .timeline_chunks
+            .get(encode_chunk_key(room_id, chunk_id))?
+            .map(|c| self.deserialize_event(&c))
+            .transpose()?)
+    }
+
+    fn add_timeline_event(
+        &self,
+        events: &mut sled::Batch,
+        event_ids: &mut sled::Batch,
+        room_id: &RoomId,
+        (chunk_id, index): (u64, i64),
+        event: &SyncRoomEvent,
+    ) -> Result<()> {
+        if let Some(event_id) = timeline_event_id(event) {
+            event_ids.insert(
+                (room_id.as_str(), event_id.as_str()).encode(),
+                self.serialize_event(&(chunk_id, index))?,
+            );
+        }
+
+        events.insert(encode_timeline_key(room_id, chunk_id, index), self.serialize_event(event)?);
+
+        Ok(())
+    }
+
+    fn append_timeline(&self, room_id: &RoomId, timeline: &Timeline) -> Result<()> {
+        let mut new_events = Vec::new();
+
+        for event in &timeline.events {
+            if !self.timeline_contains(room_id, event)? {
+                new_events.push(event);
+            }
+        }
+
+        if new_events.is_empty() {
+            return Ok(());
+        }
+
+        let last_chunk: Option<TimelineChunk> = self
+            .timeline_chunks
+            .scan_prefix(room_id.encode())
+            .next_back()
+            .transpose()?
+            .map(|(_, c)| self.deserialize_event(&c))
+            .transpose()?;
+
+        let (chunk_id, start) = match last_chunk {
+            Some(chunk) if !timeline.limited => {
+                let last_event = self
+                    .timeline_events
+                    .scan_prefix(encode_chunk_key(room_id, chunk.id))
+                    .next_back()
+                    .transpose()?;
+
+                (chunk.id, last_event.map_or(0, |(key, _)| decode_timeline_index(&key) + 1))
+            }
+            last_chunk => {
+                let chunk = TimelineChunk {
+                    id: last_chunk.map_or(0, |c| c.id + 1),
+                    prev_batch: timeline.prev_batch.clone(),
+                };
+
+                self.timeline_chunks
+                    .insert(encode_chunk_key(room_id, chunk.id), self.serialize_event(&chunk)?)?;
+
+                (chunk.id, 0)
+            }
+        };
+
+        let mut events = sled::Batch::default();
+        let mut event_ids = sled::Batch::default();
+
+        for (index, event) in (start..).zip(new_events) {
+            self.add_timeline_event(
+                &mut events,
+                &mut event_ids,
+                room_id,
+                (chunk_id, index),
+                event,
+            )?;
+        }
+
+        self.timeline_events.apply_batch(events)?;
+        self.timeline_event_ids.apply_batch(event_ids)?;
+
+        Ok(())
+    }
+
+    pub async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.timeline_chunks
+            .scan_prefix(room_id.encode())
+            .map(|c| Ok(self.deserialize_event(&c?.1)?))
+            .collect()
+    }
+
+    pub async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>> {
+        let start = encode_timeline_key(room_id, chunk_id, i64::MIN);
+        let end = encode_timeline_key(room_id, chunk_id, before.unwrap_or(i64::MAX));
+
+        self.timeline_events
+            .range(start..end)
+            .rev()
+            .take(limit)
+            .map(|e| {
+                let (key, event) = e?;
+                Ok((decode_timeline_index(&key), self.deserialize_event(&event)?))
+            })
+            .collect()
+    }
+
+    pub async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()> {
+        let mut chunk = match self.get_timeline_chunk(room_id, chunk_id)? {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let first_event = self
+            .timeline_events
+            .scan_prefix(encode_chunk_key(room_id, chunk_id))
+            .next()
+            .transpose()?;
+        let mut index = first_event.map_or(0, |(key, _)| decode_timeline_index(&key));
+
+        chunk.prev_batch = prev_batch.map(ToOwned::to_owned);
+
+        let mut new_events = sled::Batch::default();
+        let mut event_ids = sled::Batch::default();
+
+        for event in events {
+            if self.timeline_contains(room_id, event)? {
+                // We reached events we already know about, the gap is closed.
+                chunk.prev_batch = None;
+                break;
+            }
+
+            index -= 1;
+            self.add_timeline_event(
+                &mut new_events,
+                &mut event_ids,
+                room_id,
+                (chunk_id, index),
+                event,
+            )?;
+        }
+
+        self.timeline_events.apply_batch(new_events)?;
+        self.timeline_event_ids.apply_batch(event_ids)?;
+        self.timeline_chunks
+            .insert(encode_chunk_key(room_id, chunk_id), self.serialize_event(&chunk)?)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn replace_timeline_event(
+        &self,
+        room_id: &RoomId,
+        event: &SyncRoomEvent,
+    ) -> Result<()> {
+        let event_id = match timeline_event_id(event) {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if let Some(position) =
+            self.timeline_event_ids.get((room_id.as_str(), event_id.as_str()).encode())?
+        {
+            let (chunk_id, index): (u64, i64) = self.deserialize_event(&position)?;
+
+            self.timeline_events.insert(
+                encode_timeline_key(room_id, chunk_id, index),
+                self.serialize_event(event)?,
+            )?;
+            self.inner.flush_async().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        // Sled generates monotonic ids, so the events of a room are sorted in
+        // the order they were added.
+        let key = [room_id.encode().as_slice(), &self.inner.generate_id()?.to_be_bytes()].concat();
+
+        self.queued_events.insert(key, self.serialize_event(event)?)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>> {
+        self.queued_events
+            .scan_prefix(room_id.encode())
+            .map(|e| Ok(self.deserialize_event(&e?.1)?))
+            .collect()
+    }
+
+    pub async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        for entry in self.queued_events.scan_prefix(room_id.encode()) {
+            let (key, value) = entry?;
+            let queued: QueuedEvent = self.deserialize_event(&value)?;
+
+            if queued.transaction_id == event.transaction_id {
+                self.queued_events.insert(key, self.serialize_event(event)?)?;
+            }
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        for entry in self.queued_events.scan_prefix(room_id.encode()) {
+            let (key, value) = entry?;
+            let event: QueuedEvent = self.deserialize_event(&value)?;
+
+            if event.transaction_id == transaction_id {
+                self.queued_events.remove(key)?;
+            }
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>> {
+        let mut room_ids = BTreeSet::new();
+
+        for entry in self.queued_events.iter() {
+            let (key, _) = entry?;
+            let room_id = decode_key_value(&key, 0).ok_or(StoreError::InvalidKey)?;
+            room_ids.insert(RoomId::try_from(room_id)?);
+        }
+
+        Ok(room_ids.into_iter().collect())
+    }
+
     pub async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
         Ok(self.presence.get(user_id.encode())?.map(|e| self.deserialize_event(&e)).transpose()?)
     }
@@ -746,6 +1223,53 @@ impl SledStore {
             .collect()
     }
 
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>> {
+        let mut events = BTreeMap::new();
+
+        for entry in self.undecryptable_events.scan_prefix(room_id.encode()) {
+            let (key, value) = entry?;
+            let session_id = decode_key_value(&key, 1).ok_or(StoreError::InvalidKey)?;
+
+            events.entry(session_id).or_insert_with(Vec::new).push(self.deserialize_event(&value)?);
+        }
+
+        Ok(events)
+    }
+
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()> {
+        let keys: BTreeSet<Vec<u8>> =
+            event_ids.iter().map(|e| (room_id.as_str(), session_id, e.as_str()).encode()).collect();
+
+        let mut batch = sled::Batch::default();
+        let mut order = sled::Batch::default();
+
+        for key in &keys {
+            batch.remove(key.as_slice());
+        }
+
+        for entry in self.undecryptable_event_order.scan_prefix(room_id.encode()) {
+            let (order_key, key) = entry?;
+
+            if keys.contains(&*key) {
+                order.remove(order_key);
+            }
+        }
+
+        self.undecryptable_events.apply_batch(batch)?;
+        self.undecryptable_event_order.apply_batch(order)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         self.media.insert(
             (request.media_type.unique_key().as_str(), request.format.unique_key().as_str())
@@ -914,6 +1438,70 @@ impl StateStore for SledStore {
         self.get_event_room_receipt_events(room_id, receipt_type, event_id).await
     }
 
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>> {
+        self.get_undecryptable_events(room_id).await
+    }
+
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()> {
+        self.remove_undecryptable_events(room_id, session_id, event_ids).await
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.get_timeline_chunks(room_id).await
+    }
+
+    async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>> {
+        self.get_timeline_events(room_id, chunk_id, before, limit).await
+    }
+
+    async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()> {
+        self.add_timeline_backfill(room_id, chunk_id, events, prev_batch).await
+    }
+
+    async fn replace_timeline_event(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<()> {
+        self.replace_timeline_event(room_id, event).await
+    }
+
+    async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.add_queued_event(room_id, event).await
+    }
+
+    async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>> {
+        self.get_queued_events(room_id).await
+    }
+
+    async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.update_queued_event(room_id, event).await
+    }
+
+    async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.remove_queued_event(room_id, transaction_id).await
+    }
+
+    async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>> {
+        self.get_rooms_with_queued_events().await
+    }
+
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.get_custom_value(key).await
     }
@@ -941,12 +1529,8 @@ impl StateStore for SledStore {
 
 #[cfg(test)]
 mod test {
-    use std::convert::TryFrom;
-
-    use matrix_sdk_test::async_test;
     use ruma::{
         api::client::r0::media::get_content_thumbnail::Method,
-        event_id,
         events::{
             room::{
                 member::{MemberEventContent, MembershipState},
@@ -956,23 +1540,21 @@ mod test {
         },
         mxc_uri,
         receipt::ReceiptType,
-        room_id,
-        serde::Raw,
-        uint, user_id, EventId, MilliSecondsSinceUnixEpoch, UserId,
+        uint, MilliSecondsSinceUnixEpoch,
     };
-    use serde_json::json;
 
-    use super::{Result, SledStore, StateChanges};
+    use super::{Result, SledStore};
     use crate::{
         deserialized_responses::MemberEvent,
         media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
-        StateStore,
     };
 
-    fn user_id() -> UserId {
-        user_id!("@example:localhost")
+    fn get_store() -> SledStore {
+        SledStore::open().unwrap()
     }
 
+    statestore_integration_tests!();
+
     fn power_level_event() -> Raw<AnySyncStateEvent> {
         let content = PowerLevelsEventContent::default();
 
@@ -1179,6 +1761,62 @@ mod test {
         assert!(store.get_media_content(&request_thumbnail).await.unwrap().is_none());
     }
 
+    #[async_test]
+    async fn test_change_passphrase() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_passphrase(dir.path(), "old_passphrase")?;
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), membership_event());
+        store.save_changes(&changes).await?;
+
+        assert!(store.change_passphrase("wrong_passphrase", "new_passphrase").await.is_err());
+        store.change_passphrase("old_passphrase", "new_passphrase").await?;
+
+        drop(store);
+
+        assert!(SledStore::open_with_passphrase(dir.path(), "old_passphrase").is_err());
+
+        let store = SledStore::open_with_passphrase(dir.path(), "new_passphrase")?;
+        assert!(store.get_member_event(&room_id, &user_id).await?.is_some());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_encrypt_store() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_path(dir.path())?;
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), membership_event());
+        store.save_changes(&changes).await?;
+
+        assert!(store.change_passphrase("old_passphrase", "new_passphrase").await.is_err());
+
+        drop(store);
+
+        SledStore::encrypt_store(dir.path(), "secret_passphrase").await?;
+        assert!(SledStore::encrypt_store(dir.path(), "secret_passphrase").await.is_err());
+
+        let store = SledStore::open_with_passphrase(dir.path(), "secret_passphrase")?;
+        assert!(store.get_member_event(&room_id, &user_id).await?.is_some());
+
+        Ok(())
+    }
+
     #[async_test]
     async fn test_custom_storage() -> Result<()> {
         let key = "my_key";
@@ -1193,4 +1831,30 @@ mod test {
 
         Ok(())
     }
+
+    #[async_test]
+    async fn test_queued_events_survive_restart() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledStore::open_with_passphrase(dir.path(), "passphrase")?;
+        let room_id = room_id!("!test:localhost");
+
+        let event = QueuedEvent {
+            transaction_id: "txn1".to_owned(),
+            event_type: "m.room.message".to_owned(),
+            content: json!({ "body": "hello", "msgtype": "m.text" }),
+            failed: false,
+        };
+
+        store.add_queued_event(&room_id, &event).await?;
+        let event = QueuedEvent { failed: true, ..event };
+        store.update_queued_event(&room_id, &event).await?;
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), "passphrase")?;
+        assert_eq!(store.get_queued_events(&room_id).await?, vec![event]);
+        assert_eq!(store.get_rooms_with_queued_events().await?, vec![room_id]);
+
+        Ok(())
+    }
 }
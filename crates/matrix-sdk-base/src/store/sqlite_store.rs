@@ -0,0 +1,1392 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use matrix_sdk_common::async_trait;
+use ruma::{
+    events::{
+        presence::PresenceEvent, receipt::Receipt, room::member::MemberEventContent,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncRoomEvent, AnySyncStateEvent,
+        EventType,
+    },
+    receipt::ReceiptType,
+    serde::Raw,
+    EventId, MxcUri, RoomId, UserId,
+};
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{info, trace};
+
+use super::{
+    store_key::{DatabaseType, EncryptedEvent, StoreKey},
+    timeline_event_id, QueuedEvent, Result, RoomInfo, StateChanges, StateStore, StoreError,
+    TimelineChunk, MAX_UNDECRYPTABLE_EVENTS,
+};
+use crate::{
+    deserialized_responses::{MemberEvent, SyncRoomEvent, Timeline},
+    media::{MediaRequest, UniqueKey},
+};
+
+const DATABASE_NAME: &str = "matrix-sdk-state.sqlite3";
+
+/// The schema migrations of the store, the migration at index `n` upgrades the
+/// database from version `n` to version `n + 1`.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE kv (
+        key TEXT PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL
+    );
+
+    CREATE TABLE account_data (
+        event_type TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE presence (
+        user_id TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE members (
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        membership TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE INDEX members_membership ON members (room_id, membership);
+
+    CREATE TABLE profiles (
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, user_id)
+    );
+
+    CREATE TABLE display_names (
+        room_id TEXT NOT NULL,
+        display_name TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, display_name)
+    );
+
+    CREATE TABLE room_infos (
+        room_id TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE room_state (
+        room_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        state_key TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, event_type, state_key)
+    );
+
+    CREATE TABLE room_account_data (
+        room_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, event_type)
+    );
+
+    CREATE TABLE stripped_room_infos (
+        room_id TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE stripped_members (
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, user_id)
+    );
+
+    CREATE TABLE stripped_room_state (
+        room_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        state_key TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, event_type, state_key)
+    );
+
+    CREATE TABLE receipts (
+        room_id TEXT NOT NULL,
+        receipt_type TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, receipt_type, user_id)
+    );
+    CREATE INDEX receipts_event_id ON receipts (room_id, receipt_type, event_id);
+
+    CREATE TABLE media (
+        uri TEXT NOT NULL,
+        format TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (uri, format)
+    );
+
+    CREATE TABLE custom (
+        key BLOB PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL
+    );
+"#,
+    r#"
+    CREATE TABLE undecryptable_events (
+        room_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        event_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, session_id, event_id)
+    );
+"#,
+    r#"
+    CREATE TABLE timeline_chunks (
+        room_id TEXT NOT NULL,
+        chunk_id INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, chunk_id)
+    );
+
+    CREATE TABLE timeline_events (
+        room_id TEXT NOT NULL,
+        chunk_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        event_id TEXT,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, chunk_id, position)
+    );
+    CREATE UNIQUE INDEX timeline_events_event_id ON timeline_events (room_id, event_id);
+"#,
+    r#"
+    CREATE TABLE queued_events (
+        position INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL,
+        transaction_id TEXT NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE UNIQUE INDEX queued_events_transaction_id ON queued_events (room_id, transaction_id);
+"#,
+];
+
+const DATABASE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Get a value out of the key/value table, values in this table are never
+/// encrypted.
+fn get_kv_value<T: DeserializeOwned>(connection: &Connection, key: &str) -> Result<Option<T>> {
+    Ok(connection
+        .query_row("SELECT value FROM kv WHERE key = ?1", params![key], |row| {
+            row.get::<_, Vec<u8>>(0)
+        })
+        .optional()?
+        .map(|v| serde_json::from_slice(&v))
+        .transpose()?)
+}
+
+fn set_kv_value(connection: &Connection, key: &str, value: &impl Serialize) -> Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)",
+        params![key, serde_json::to_vec(value)?],
+    )?;
+
+    Ok(())
+}
+
+/// A SQLite based state store.
+#[derive(Clone)]
+pub struct SqliteStore {
+    path: Option<PathBuf>,
+    connection: Arc<Mutex<Connection>>,
+    store_key: Arc<Option<StoreKey>>,
+}
+
+impl std::fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            f.debug_struct("SqliteStore").field("path", &path).finish()
+        } else {
+            f.debug_struct("SqliteStore").field("path", &"memory store").finish()
+        }
+    }
+}
+
+impl SqliteStore {
+    fn open_helper(
+        mut connection: Connection,
+        path: Option<PathBuf>,
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        Self::upgrade_database(&mut connection)?;
+
+        let database_type: Option<DatabaseType> = get_kv_value(&connection, "store_key")?;
+
+        let store_key = match (database_type, passphrase) {
+            (Some(DatabaseType::Encrypted(key)), Some(passphrase)) => {
+                Some(StoreKey::import(passphrase, key).map_err(|_| StoreError::StoreLocked)?)
+            }
+            (Some(DatabaseType::Encrypted(_)), None) => return Err(StoreError::StoreLocked),
+            (Some(DatabaseType::Unencrypted), Some(_)) => return Err(StoreError::UnencryptedStore),
+            (Some(DatabaseType::Unencrypted), None) => None,
+            (None, Some(passphrase)) => {
+                let key = StoreKey::new().map_err::<StoreError, _>(|e| e.into())?;
+                let encrypted_key = DatabaseType::Encrypted(
+                    key.export(passphrase).map_err::<StoreError, _>(|e| e.into())?,
+                );
+                set_kv_value(&connection, "store_key", &encrypted_key)?;
+
+                Some(key)
+            }
+            (None, None) => {
+                set_kv_value(&connection, "store_key", &DatabaseType::Unencrypted)?;
+                None
+            }
+        };
+
+        Ok(Self { path, connection: Mutex::new(connection).into(), store_key: store_key.into() })
+    }
+
+    /// Open a temporary, in-memory only, SQLite store.
+    #[cfg(test)]
+    pub fn open() -> Result<Self> {
+        let connection = Connection::open_in_memory()?;
+
+        SqliteStore::open_helper(connection, None, None)
+    }
+
+    /// Open the SQLite store inside of the given directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory the database file should reside in, it will
+    /// be created if it doesn't exist.
+    ///
+    /// * `passphrase` - The passphrase that should be used to encrypt the
+    /// store, if one is given.
+    pub fn open_with_path(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        std::fs::create_dir_all(path.as_ref())?;
+        let path = path.as_ref().join(DATABASE_NAME);
+        let connection = Connection::open(&path)?;
+
+        SqliteStore::open_helper(connection, Some(path), passphrase)
+    }
+
+    fn upgrade_database(connection: &mut Connection) -> Result<()> {
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        if version < DATABASE_VERSION {
+            trace!(
+                version = version,
+                new_version = DATABASE_VERSION,
+                "Upgrading the SQLite state store"
+            );
+
+            let transaction = connection.transaction()?;
+
+            for migration in &MIGRATIONS[version as usize..] {
+                transaction.execute_batch(migration)?;
+            }
+
+            transaction.pragma_update(None, "user_version", &DATABASE_VERSION)?;
+            transaction.commit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Lock the database connection.
+    ///
+    /// The returned guard must not be held across an `.await` point.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+
+    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
+        if let Some(key) = &*self.store_key {
+            let encrypted = key.encrypt(value).map_err::<StoreError, _>(|e| e.into())?;
+            Ok(serde_json::to_vec(&encrypted)?)
+        } else {
+            Ok(serde_json::to_vec(value)?)
+        }
+    }
+
+    fn deserialize_value<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T> {
+        if let Some(key) = &*self.store_key {
+            let encrypted: EncryptedEvent = serde_json::from_slice(value)?;
+            key.decrypt(encrypted).map_err(|e| e.into())
+        } else {
+            Ok(serde_json::from_slice(value)?)
+        }
+    }
+
+    /// Get a single, possibly encrypted, value using the given query.
+    fn query_value<T: DeserializeOwned>(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<Option<T>> {
+        self.connection()
+            .query_row(sql, params, |row| row.get::<_, Vec<u8>>(0))
+            .optional()?
+            .map(|v| self.deserialize_value(&v))
+            .transpose()
+    }
+
+    /// Get all the, possibly encrypted, values the given query returns.
+    fn query_values<T: DeserializeOwned>(&self, sql: &str, params: impl Params) -> Result<Vec<T>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(sql)?;
+        let values = statement.query_map(params, |row| row.get::<_, Vec<u8>>(0))?;
+
+        values.map(|v| self.deserialize_value(&v?)).collect()
+    }
+
+    /// Get all the user ids the given query returns.
+    fn query_user_ids(&self, sql: &str, params: impl Params) -> Result<Vec<UserId>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(sql)?;
+        let user_ids = statement.query_map(params, |row| row.get::<_, String>(0))?;
+
+        user_ids.map(|u| -> Result<_> { Ok(UserId::try_from(u?)?) }).collect()
+    }
+
+    fn timeline_contains(
+        &self,
+        connection: &Connection,
+        room_id: &RoomId,
+        event: &SyncRoomEvent,
+    ) -> Result<bool> {
+        Ok(match timeline_event_id(event) {
+            Some(event_id) => connection
+                .query_row(
+                    "SELECT 1 FROM timeline_events WHERE room_id = ?1 AND event_id = ?2",
+                    params![room_id.as_str(), event_id.as_str()],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some(),
+            None => false,
+        })
+    }
+
+    fn add_timeline_event(
+        &self,
+        connection: &Connection,
+        room_id: &RoomId,
+        (chunk_id, position): (u64, i64),
+        event: &SyncRoomEvent,
+    ) -> Result<()> {
+        connection.execute(
+            "INSERT OR REPLACE INTO timeline_events (room_id, chunk_id, position, event_id, data)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id.as_str(),
+                chunk_id as i64,
+                position,
+                timeline_event_id(event).as_ref().map(|e| e.as_str()),
+                self.serialize_value(event)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn save_timeline_chunk(
+        &self,
+        connection: &Connection,
+        room_id: &RoomId,
+        chunk: &TimelineChunk,
+    ) -> Result<()> {
+        connection.execute(
+            "INSERT OR REPLACE INTO timeline_chunks (room_id, chunk_id, data) VALUES (?1, ?2, ?3)",
+            params![room_id.as_str(), chunk.id as i64, self.serialize_value(chunk)?],
+        )?;
+
+        Ok(())
+    }
+
+    fn append_timeline(
+        &self,
+        connection: &Connection,
+        room_id: &RoomId,
+        timeline: &Timeline,
+    ) -> Result<()> {
+        let mut new_events = Vec::new();
+
+        for event in &timeline.events {
+            if !self.timeline_contains(connection, room_id, event)? {
+                new_events.push(event);
+            }
+        }
+
+        if new_events.is_empty() {
+            return Ok(());
+        }
+
+        let last_chunk: Option<TimelineChunk> = connection
+            .query_row(
+                "SELECT data FROM timeline_chunks WHERE room_id = ?1
+                 ORDER BY chunk_id DESC LIMIT 1",
+                params![room_id.as_str()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .map(|c| self.deserialize_value(&c))
+            .transpose()?;
+
+        let (chunk_id, start) = match last_chunk {
+            Some(chunk) if !timeline.limited => {
+                let last_position: Option<i64> = connection.query_row(
+                    "SELECT MAX(position) FROM timeline_events WHERE room_id = ?1 AND chunk_id = ?2",
+                    params![room_id.as_str(), chunk.id as i64],
+                    |row| row.get(0),
+                )?;
+
+                (chunk.id, last_position.map_or(0, |p| p + 1))
+            }
+            last_chunk => {
+                let chunk = TimelineChunk {
+                    id: last_chunk.map_or(0, |c| c.id + 1),
+                    prev_batch: timeline.prev_batch.clone(),
+                };
+                self.save_timeline_chunk(connection, room_id, &chunk)?;
+
+                (chunk.id, 0)
+            }
+        };
+
+        for (position, event) in (start..).zip(new_events) {
+            self.add_timeline_event(connection, room_id, (chunk_id, position), event)?;
+        }
+
+        Ok(())
+    }
+
+    fn save_changes_helper(&self, changes: &StateChanges) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        if let Some(s) = &changes.sync_token {
+            set_kv_value(&transaction, "sync_token", s)?;
+        }
+
+        for (room, events) in &changes.members {
+            let profile_changes = changes.profiles.get(room);
+
+            for event in events.values() {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO members (room_id, user_id, membership, data)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        room.as_str(),
+                        event.state_key.as_str(),
+                        event.content.membership.as_ref(),
+                        self.serialize_value(event)?
+                    ],
+                )?;
+
+                if let Some(profile) = profile_changes.and_then(|p| p.get(&event.state_key)) {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO profiles (room_id, user_id, data)
+                         VALUES (?1, ?2, ?3)",
+                        params![
+                            room.as_str(),
+                            event.state_key.as_str(),
+                            self.serialize_value(profile)?
+                        ],
+                    )?;
+                }
+            }
+        }
+
+        for (room_id, ambiguity_maps) in &changes.ambiguity_maps {
+            for (display_name, map) in ambiguity_maps {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO display_names (room_id, display_name, data)
+                     VALUES (?1, ?2, ?3)",
+                    params![room_id.as_str(), display_name, self.serialize_value(map)?],
+                )?;
+            }
+        }
+
+        for (event_type, event) in &changes.account_data {
+            transaction.execute(
+                "INSERT OR REPLACE INTO account_data (event_type, data) VALUES (?1, ?2)",
+                params![event_type, self.serialize_value(event)?],
+            )?;
+        }
+
+        for (room, events) in &changes.room_account_data {
+            for (event_type, event) in events {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO room_account_data (room_id, event_type, data)
+                     VALUES (?1, ?2, ?3)",
+                    params![room.as_str(), event_type, self.serialize_value(event)?],
+                )?;
+            }
+        }
+
+        for (room, event_types) in &changes.state {
+            for (event_type, events) in event_types {
+                for (state_key, event) in events {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO room_state (room_id, event_type, state_key, data)
+                         VALUES (?1, ?2, ?3, ?4)",
+                        params![room.as_str(), event_type, state_key, self.serialize_value(event)?],
+                    )?;
+                }
+            }
+        }
+
+        for (room_id, room_info) in &changes.room_infos {
+            transaction.execute(
+                "INSERT OR REPLACE INTO room_infos (room_id, data) VALUES (?1, ?2)",
+                params![room_id.as_str(), self.serialize_value(room_info)?],
+            )?;
+        }
+
+        for (sender, event) in &changes.presence {
+            transaction.execute(
+                "INSERT OR REPLACE INTO presence (user_id, data) VALUES (?1, ?2)",
+                params![sender.as_str(), self.serialize_value(event)?],
+            )?;
+        }
+
+        for (room_id, info) in &changes.invited_room_info {
+            transaction.execute(
+                "INSERT OR REPLACE INTO stripped_room_infos (room_id, data) VALUES (?1, ?2)",
+                params![room_id.as_str(), self.serialize_value(info)?],
+            )?;
+        }
+
+        for (room, events) in &changes.stripped_members {
+            for event in events.values() {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO stripped_members (room_id, user_id, data)
+                     VALUES (?1, ?2, ?3)",
+                    params![room.as_str(), event.state_key.as_str(), self.serialize_value(event)?],
+                )?;
+            }
+        }
+
+        for (room, event_types) in &changes.stripped_state {
+            for (event_type, events) in event_types {
+                for (state_key, event) in events {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO stripped_room_state
+                         (room_id, event_type, state_key, data) VALUES (?1, ?2, ?3, ?4)",
+                        params![room.as_str(), event_type, state_key, self.serialize_value(event)?],
+                    )?;
+                }
+            }
+        }
+
+        for (room, content) in &changes.receipts {
+            for (event_id, receipts) in &content.0 {
+                for (receipt_type, receipts) in receipts {
+                    for (user_id, receipt) in receipts {
+                        // A user can only have a single receipt of a given type
+                        // in a room, so this replaces any older receipt.
+                        transaction.execute(
+                            "INSERT OR REPLACE INTO receipts
+                             (room_id, receipt_type, user_id, event_id, data)
+                             VALUES (?1, ?2, ?3, ?4, ?5)",
+                            params![
+                                room.as_str(),
+                                receipt_type.as_ref(),
+                                user_id.as_str(),
+                                event_id.as_str(),
+                                self.serialize_value(receipt)?
+                            ],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        for (room, sessions) in &changes.undecryptable_events {
+            for (session_id, events) in sessions {
+                for (event_id, event) in events {
+                    // Updating the event in place keeps its rowid, which is
+                    // used to find the oldest events below.
+                    transaction.execute(
+                        "INSERT INTO undecryptable_events (room_id, session_id, event_id, data)
+                         VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT (room_id, session_id, event_id)
+                         DO UPDATE SET data = excluded.data",
+                        params![
+                            room.as_str(),
+                            session_id,
+                            event_id.as_str(),
+                            self.serialize_value(event)?
+                        ],
+                    )?;
+                }
+            }
+
+            // Forget the oldest events once the room goes over the limit.
+            transaction.execute(
+                "DELETE FROM undecryptable_events WHERE rowid IN (
+                     SELECT rowid FROM undecryptable_events WHERE room_id = ?1
+                     ORDER BY rowid DESC LIMIT -1 OFFSET ?2
+                 )",
+                params![room.as_str(), MAX_UNDECRYPTABLE_EVENTS as i64],
+            )?;
+        }
+
+        for (room, timeline) in &changes.timeline {
+            self.append_timeline(&transaction, room, timeline)?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StateStore for SqliteStore {
+    async fn save_filter(&self, filter_name: &str, filter_id: &str) -> Result<()> {
+        set_kv_value(&self.connection(), &format!("filter:{}", filter_name), &filter_id)
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let now = Instant::now();
+
+        self.save_changes_helper(changes)?;
+
+        info!("Saved changes in {:?}", now.elapsed());
+
+        Ok(())
+    }
+
+    async fn get_filter(&self, filter_name: &str) -> Result<Option<String>> {
+        get_kv_value(&self.connection(), &format!("filter:{}", filter_name))
+    }
+
+    async fn get_sync_token(&self) -> Result<Option<String>> {
+        get_kv_value(&self.connection(), "sync_token")
+    }
+
+    async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
+        self.query_value("SELECT data FROM presence WHERE user_id = ?1", params![user_id.as_str()])
+    }
+
+    async fn get_state_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+        state_key: &str,
+    ) -> Result<Option<Raw<AnySyncStateEvent>>> {
+        self.query_value(
+            "SELECT data FROM room_state WHERE room_id = ?1 AND event_type = ?2 AND state_key = ?3",
+            params![room_id.as_str(), event_type.as_str(), state_key],
+        )
+    }
+
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.query_values(
+            "SELECT data FROM room_state WHERE room_id = ?1 AND event_type = ?2",
+            params![room_id.as_str(), event_type.as_str()],
+        )
+    }
+
+    async fn get_profile(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<MemberEventContent>> {
+        self.query_value(
+            "SELECT data FROM profiles WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.as_str(), user_id.as_str()],
+        )
+    }
+
+    async fn get_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<MemberEvent>> {
+        self.query_value(
+            "SELECT data FROM members WHERE room_id = ?1 AND user_id = ?2",
+            params![room_id.as_str(), state_key.as_str()],
+        )
+    }
+
+    async fn get_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.query_user_ids(
+            "SELECT user_id FROM members WHERE room_id = ?1",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn get_invited_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.query_user_ids(
+            "SELECT user_id FROM members WHERE room_id = ?1 AND membership = 'invite'",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn get_joined_user_ids(&self, room_id: &RoomId) -> Result<Vec<UserId>> {
+        self.query_user_ids(
+            "SELECT user_id FROM members WHERE room_id = ?1 AND membership = 'join'",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn get_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.query_values("SELECT data FROM room_infos", params![])
+    }
+
+    async fn get_stripped_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        self.query_values("SELECT data FROM stripped_room_infos", params![])
+    }
+
+    async fn get_users_with_display_name(
+        &self,
+        room_id: &RoomId,
+        display_name: &str,
+    ) -> Result<BTreeSet<UserId>> {
+        Ok(self
+            .query_value(
+                "SELECT data FROM display_names WHERE room_id = ?1 AND display_name = ?2",
+                params![room_id.as_str(), display_name],
+            )?
+            .unwrap_or_default())
+    }
+
+    async fn get_account_data_event(
+        &self,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyGlobalAccountDataEvent>>> {
+        self.query_value(
+            "SELECT data FROM account_data WHERE event_type = ?1",
+            params![event_type.as_str()],
+        )
+    }
+
+    async fn get_room_account_data_event(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Option<Raw<AnyRoomAccountDataEvent>>> {
+        self.query_value(
+            "SELECT data FROM room_account_data WHERE room_id = ?1 AND event_type = ?2",
+            params![room_id.as_str(), event_type.as_str()],
+        )
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        user_id: &UserId,
+    ) -> Result<Option<(EventId, Receipt)>> {
+        let receipt = self
+            .connection()
+            .query_row(
+                "SELECT event_id, data FROM receipts
+                 WHERE room_id = ?1 AND receipt_type = ?2 AND user_id = ?3",
+                params![room_id.as_str(), receipt_type.as_ref(), user_id.as_str()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )
+            .optional()?;
+
+        receipt
+            .map(|(event_id, receipt)| -> Result<_> {
+                Ok((EventId::try_from(event_id)?, self.deserialize_value(&receipt)?))
+            })
+            .transpose()
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        event_id: &EventId,
+    ) -> Result<Vec<(UserId, Receipt)>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT user_id, data FROM receipts
+             WHERE room_id = ?1 AND receipt_type = ?2 AND event_id = ?3",
+        )?;
+        let receipts = statement.query_map(
+            params![room_id.as_str(), receipt_type.as_ref(), event_id.as_str()],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+        )?;
+
+        receipts
+            .map(|r| -> Result<_> {
+                let (user_id, receipt) = r?;
+                Ok((UserId::try_from(user_id)?, self.deserialize_value(&receipt)?))
+            })
+            .collect()
+    }
+
+    async fn get_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<String, Vec<Raw<AnySyncRoomEvent>>>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT session_id, data FROM undecryptable_events WHERE room_id = ?1")?;
+        let rows = statement.query_map(params![room_id.as_str()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+
+        let mut events = BTreeMap::new();
+
+        for row in rows {
+            let (session_id, event) = row?;
+            events.entry(session_id).or_insert_with(Vec::new).push(self.deserialize_value(&event)?);
+        }
+
+        Ok(events)
+    }
+
+    async fn remove_undecryptable_events(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        event_ids: &[EventId],
+    ) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        for event_id in event_ids {
+            transaction.execute(
+                "DELETE FROM undecryptable_events
+                 WHERE room_id = ?1 AND session_id = ?2 AND event_id = ?3",
+                params![room_id.as_str(), session_id, event_id.as_str()],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn get_timeline_chunks(&self, room_id: &RoomId) -> Result<Vec<TimelineChunk>> {
+        self.query_values(
+            "SELECT data FROM timeline_chunks WHERE room_id = ?1 ORDER BY chunk_id ASC",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn get_timeline_events(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, SyncRoomEvent)>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT position, data FROM timeline_events
+             WHERE room_id = ?1 AND chunk_id = ?2 AND position < ?3
+             ORDER BY position DESC LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![room_id.as_str(), chunk_id as i64, before.unwrap_or(i64::MAX), limit as i64],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)),
+        )?;
+
+        rows.map(|r| -> Result<_> {
+            let (position, event) = r?;
+            Ok((position, self.deserialize_value(&event)?))
+        })
+        .collect()
+    }
+
+    async fn add_timeline_backfill(
+        &self,
+        room_id: &RoomId,
+        chunk_id: u64,
+        events: &[SyncRoomEvent],
+        prev_batch: Option<&str>,
+    ) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        let chunk: Option<TimelineChunk> = transaction
+            .query_row(
+                "SELECT data FROM timeline_chunks WHERE room_id = ?1 AND chunk_id = ?2",
+                params![room_id.as_str(), chunk_id as i64],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .map(|c| self.deserialize_value(&c))
+            .transpose()?;
+
+        let mut chunk = match chunk {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let first_position: Option<i64> = transaction.query_row(
+            "SELECT MIN(position) FROM timeline_events WHERE room_id = ?1 AND chunk_id = ?2",
+            params![room_id.as_str(), chunk_id as i64],
+            |row| row.get(0),
+        )?;
+        let mut position = first_position.unwrap_or(0);
+
+        chunk.prev_batch = prev_batch.map(ToOwned::to_owned);
+
+        for event in events {
+            if self.timeline_contains(&transaction, room_id, event)? {
+                // We reached events we already know about, the gap is closed.
+                chunk.prev_batch = None;
+                break;
+            }
+
+            position -= 1;
+            self.add_timeline_event(&transaction, room_id, (chunk_id, position), event)?;
+        }
+
+        self.save_timeline_chunk(&transaction, room_id, &chunk)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn replace_timeline_event(&self, room_id: &RoomId, event: &SyncRoomEvent) -> Result<()> {
+        if let Some(event_id) = timeline_event_id(event) {
+            self.connection().execute(
+                "UPDATE timeline_events SET data = ?3 WHERE room_id = ?1 AND event_id = ?2",
+                params![room_id.as_str(), event_id.as_str(), self.serialize_value(event)?],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    async fn add_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO queued_events (room_id, transaction_id, data) VALUES (?1, ?2, ?3)",
+            params![room_id.as_str(), event.transaction_id, self.serialize_value(event)?],
+        )?;
+
+        Ok(())
+    }
+
+    async fn get_queued_events(&self, room_id: &RoomId) -> Result<Vec<QueuedEvent>> {
+        self.query_values(
+            "SELECT data FROM queued_events WHERE room_id = ?1 ORDER BY position ASC",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn update_queued_event(&self, room_id: &RoomId, event: &QueuedEvent) -> Result<()> {
+        self.connection().execute(
+            "UPDATE queued_events SET data = ?3 WHERE room_id = ?1 AND transaction_id = ?2",
+            params![room_id.as_str(), event.transaction_id, self.serialize_value(event)?],
+        )?;
+
+        Ok(())
+    }
+
+    async fn remove_queued_event(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.connection().execute(
+            "DELETE FROM queued_events WHERE room_id = ?1 AND transaction_id = ?2",
+            params![room_id.as_str(), transaction_id],
+        )?;
+
+        Ok(())
+    }
+
+    async fn get_rooms_with_queued_events(&self) -> Result<Vec<RoomId>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT DISTINCT room_id FROM queued_events")?;
+        let room_ids = statement.query_map(params![], |row| row.get::<_, String>(0))?;
+
+        room_ids.map(|r| -> Result<_> { Ok(RoomId::try_from(r?)?) }).collect()
+    }
+
+    async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .connection()
+            .query_row("SELECT value FROM custom WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        let old_value = transaction
+            .query_row("SELECT value FROM custom WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO custom (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        transaction.commit()?;
+
+        Ok(old_value)
+    }
+
+    async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO media (uri, format, data) VALUES (?1, ?2, ?3)",
+            params![request.media_type.unique_key(), request.format.unique_key(), data],
+        )?;
+
+        Ok(())
+    }
+
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .connection()
+            .query_row(
+                "SELECT data FROM media WHERE uri = ?1 AND format = ?2",
+                params![request.media_type.unique_key(), request.format.unique_key()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        self.connection().execute(
+            "DELETE FROM media WHERE uri = ?1 AND format = ?2",
+            params![request.media_type.unique_key(), request.format.unique_key()],
+        )?;
+
+        Ok(())
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        self.connection().execute("DELETE FROM media WHERE uri = ?1", params![uri.as_str()])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{
+        api::client::r0::media::get_content_thumbnail::Method,
+        events::{
+            room::{
+                member::{MemberEventContent, MembershipState},
+                power_levels::PowerLevelsEventContent,
+            },
+            AnySyncStateEvent, EventType, Unsigned,
+        },
+        mxc_uri,
+        receipt::ReceiptType,
+        uint, MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::{Result, SqliteStore, StoreError};
+    use crate::{
+        deserialized_responses::MemberEvent,
+        media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
+    };
+
+    fn get_store() -> SqliteStore {
+        SqliteStore::open().unwrap()
+    }
+
+    statestore_integration_tests!();
+
+    fn power_level_event() -> Raw<AnySyncStateEvent> {
+        let content = PowerLevelsEventContent::default();
+
+        let event = json!({
+            "event_id": EventId::try_from("$h29iv0s8:example.com").unwrap(),
+            "content": content,
+            "sender": user_id(),
+            "type": "m.room.power_levels",
+            "origin_server_ts": 0u64,
+            "state_key": "",
+            "unsigned": Unsigned::default(),
+        });
+
+        serde_json::from_value(event).unwrap()
+    }
+
+    fn membership_event() -> MemberEvent {
+        MemberEvent {
+            event_id: EventId::try_from("$h29iv0s8:example.com").unwrap(),
+            content: MemberEventContent::new(MembershipState::Join),
+            sender: user_id(),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            state_key: user_id(),
+            prev_content: None,
+            unsigned: Unsigned::default(),
+        }
+    }
+
+    #[async_test]
+    async fn test_member_saving() {
+        let store = SqliteStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        assert!(store.get_member_event(&room_id, &user_id).await.unwrap().is_none());
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), membership_event());
+
+        store.save_changes(&changes).await.unwrap();
+        assert!(store.get_member_event(&room_id, &user_id).await.unwrap().is_some());
+
+        let members = store.get_user_ids(&room_id).await.unwrap();
+        assert!(!members.is_empty())
+    }
+
+    #[async_test]
+    async fn test_power_level_saving() {
+        let store = SqliteStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+
+        let raw_event = power_level_event();
+        let event = raw_event.deserialize().unwrap();
+
+        assert!(store
+            .get_state_event(&room_id, EventType::RoomPowerLevels, "")
+            .await
+            .unwrap()
+            .is_none());
+        let mut changes = StateChanges::default();
+        changes.add_state_event(&room_id, event, raw_event);
+
+        store.save_changes(&changes).await.unwrap();
+        assert!(store
+            .get_state_event(&room_id, EventType::RoomPowerLevels, "")
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[async_test]
+    async fn test_receipts_saving() {
+        let store = SqliteStore::open().unwrap();
+
+        let room_id = room_id!("!test:localhost");
+
+        let first_event_id = event_id!("$1435641916114394fHBLK:matrix.org");
+        let second_event_id = event_id!("$fHBLK1435641916114394:matrix.org");
+
+        let first_receipt_event = serde_json::from_value(json!({
+            first_event_id.clone(): {
+                "m.read": {
+                    user_id(): {
+                        "ts": 1436451550453u64
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        let second_receipt_event = serde_json::from_value(json!({
+            second_event_id.clone(): {
+                "m.read": {
+                    user_id(): {
+                        "ts": 1436451551453u64
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(store
+            .get_user_room_receipt_event(&room_id, ReceiptType::Read, &user_id())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_event_room_receipt_events(&room_id, ReceiptType::Read, &first_event_id)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .get_event_room_receipt_events(&room_id, ReceiptType::Read, &second_event_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut changes = StateChanges::default();
+        changes.add_receipts(&room_id, first_receipt_event);
+
+        store.save_changes(&changes).await.unwrap();
+        assert!(store
+            .get_user_room_receipt_event(&room_id, ReceiptType::Read, &user_id())
+            .await
+            .unwrap()
+            .is_some(),);
+        assert_eq!(
+            store
+                .get_event_room_receipt_events(&room_id, ReceiptType::Read, &first_event_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(store
+            .get_event_room_receipt_events(&room_id, ReceiptType::Read, &second_event_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut changes = StateChanges::default();
+        changes.add_receipts(&room_id, second_receipt_event);
+
+        store.save_changes(&changes).await.unwrap();
+        assert!(store
+            .get_user_room_receipt_event(&room_id, ReceiptType::Read, &user_id())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store
+            .get_event_room_receipt_events(&room_id, ReceiptType::Read, &first_event_id)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .get_event_room_receipt_events(&room_id, ReceiptType::Read, &second_event_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[async_test]
+    async fn test_media_content() {
+        let store = SqliteStore::open().unwrap();
+
+        let uri = mxc_uri!("mxc://localhost/media");
+        let content: Vec<u8> = "somebinarydata".into();
+
+        let request_file =
+            MediaRequest { media_type: MediaType::Uri(uri.clone()), format: MediaFormat::File };
+
+        let request_thumbnail = MediaRequest {
+            media_type: MediaType::Uri(uri.clone()),
+            format: MediaFormat::Thumbnail(MediaThumbnailSize {
+                method: Method::Crop,
+                width: uint!(100),
+                height: uint!(100),
+            }),
+        };
+
+        assert!(store.get_media_content(&request_file).await.unwrap().is_none());
+        assert!(store.get_media_content(&request_thumbnail).await.unwrap().is_none());
+
+        store.add_media_content(&request_file, content.clone()).await.unwrap();
+        assert!(store.get_media_content(&request_file).await.unwrap().is_some());
+
+        store.remove_media_content(&request_file).await.unwrap();
+        assert!(store.get_media_content(&request_file).await.unwrap().is_none());
+
+        store.add_media_content(&request_file, content.clone()).await.unwrap();
+        assert!(store.get_media_content(&request_file).await.unwrap().is_some());
+
+        store.add_media_content(&request_thumbnail, content.clone()).await.unwrap();
+        assert!(store.get_media_content(&request_thumbnail).await.unwrap().is_some());
+
+        store.remove_media_content_for_uri(&uri).await.unwrap();
+        assert!(store.get_media_content(&request_file).await.unwrap().is_none());
+        assert!(store.get_media_content(&request_thumbnail).await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn test_encrypted_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id();
+
+        let store = SqliteStore::open_with_path(dir.path(), Some("secret")).unwrap();
+
+        let mut changes = StateChanges::default();
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), membership_event());
+        store.save_changes(&changes).await.unwrap();
+        drop(store);
+
+        assert!(matches!(
+            SqliteStore::open_with_path(dir.path(), Some("wrong")),
+            Err(StoreError::StoreLocked)
+        ));
+        assert!(matches!(
+            SqliteStore::open_with_path(dir.path(), None),
+            Err(StoreError::StoreLocked)
+        ));
+
+        let store = SqliteStore::open_with_path(dir.path(), Some("secret")).unwrap();
+        assert!(store.get_member_event(&room_id, &user_id).await.unwrap().is_some());
+        assert_eq!(store.get_joined_user_ids(&room_id).await.unwrap(), vec![user_id]);
+    }
+
+    #[async_test]
+    async fn test_custom_storage() -> Result<()> {
+        let key = "my_key";
+        let value = &[0, 1, 2, 3];
+        let store = SqliteStore::open()?;
+
+        store.set_custom_value(key.as_bytes(), value.to_vec()).await?;
+
+        let read = store.get_custom_value(key.as_bytes()).await?;
+
+        assert_eq!(Some(value.as_ref()), read.as_deref());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_queued_events_survive_restart() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open_with_path(dir.path(), Some("passphrase"))?;
+        let room_id = room_id!("!test:localhost");
+
+        let event = QueuedEvent {
+            transaction_id: "txn1".to_owned(),
+            event_type: "m.room.message".to_owned(),
+            content: json!({ "body": "hello", "msgtype": "m.text" }),
+            failed: false,
+        };
+
+        store.add_queued_event(&room_id, &event).await?;
+        let event = QueuedEvent { failed: true, ..event };
+        store.update_queued_event(&room_id, &event).await?;
+
+        drop(store);
+
+        let store = SqliteStore::open_with_path(dir.path(), Some("passphrase"))?;
+        assert_eq!(store.get_queued_events(&room_id).await?, vec![event]);
+        assert_eq!(store.get_rooms_with_queued_events().await?, vec![room_id]);
+
+        Ok(())
+    }
+}
@@ -108,6 +108,14 @@ pub struct EncryptedStoreKey {
     pub ciphertext_info: CipherTextInfo,
 }
 
+/// The type of a database, stored inside of the database itself so we know if
+/// a passphrase is needed to open it.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DatabaseType {
+    Unencrypted,
+    Encrypted(EncryptedStoreKey),
+}
+
 /// A store key that can be used to encrypt entries in the store.
 #[derive(Debug, Zeroize, PartialEq)]
 pub struct StoreKey {
@@ -52,6 +52,33 @@ pub enum VerificationState {
     UnknownDevice,
 }
 
+/// The way a room key was obtained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RoomKeyOrigin {
+    /// The room key was created by us or sent to us by the device that created
+    /// it using an `m.room_key` event.
+    Direct,
+    /// The room key was forwarded to us by another device using an
+    /// `m.forwarded_room_key` event.
+    Forwarded,
+    /// The room key was imported from a key export.
+    Imported,
+    /// The room key was downloaded from the server-side key backup.
+    Backup,
+}
+
+impl RoomKeyOrigin {
+    /// Was the room key sent to us directly by the device that created it.
+    ///
+    /// Only room keys that were directly received are guaranteed to belong to
+    /// the sender they claim to belong to, applications might want to display
+    /// a warning for events that were decrypted using room keys of any other
+    /// origin.
+    pub fn is_direct(&self) -> bool {
+        matches!(self, RoomKeyOrigin::Direct)
+    }
+}
+
 /// The algorithm specific information of a decrypted event.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum AlgorithmInfo {
@@ -67,6 +94,14 @@ pub enum AlgorithmInfo {
         /// Chain of curve25519 keys through which this session was forwarded,
         /// via m.forwarded_room_key events.
         forwarding_curve25519_key_chain: Vec<String>,
+        /// The way we obtained the megolm key that was used to decrypt this
+        /// event, `None` if the event was decrypted before this was recorded.
+        #[serde(default)]
+        room_key_origin: Option<RoomKeyOrigin>,
+        /// The time we received the megolm key that was used to decrypt this
+        /// event, if known.
+        #[serde(default)]
+        room_key_received_time: Option<MilliSecondsSinceUnixEpoch>,
     },
 }
 
@@ -85,6 +120,16 @@ pub struct EncryptionInfo {
     /// is the state of the device at the time of decryption. It may change in
     /// the future if a device gets verified or deleted.
     pub verification_state: VerificationState,
+    /// Was the room key for this event re-requested after we recovered from a
+    /// wedged Olm session with the sender.
+    ///
+    /// If this is set, earlier attempts to decrypt the event most likely
+    /// failed and the application might want to replace the undecryptable
+    /// event it displayed with this one. Every event that we failed to
+    /// decrypt is marked the first time it gets decrypted with the
+    /// re-requested room key.
+    #[serde(default)]
+    pub recovered_room_key: bool,
 }
 
 /// A customized version of a room event coming from a sync that holds optional
@@ -128,6 +173,10 @@ pub struct SyncResponse {
     pub ambiguity_changes: AmbiguityChanges,
     /// New notifications per room.
     pub notifications: BTreeMap<RoomId, Vec<Notification>>,
+    /// Events from earlier syncs that couldn't be decrypted at the time but
+    /// could be decrypted now since the room keys arrived, per room.
+    #[serde(default)]
+    pub late_decrypted_events: BTreeMap<RoomId, Vec<SyncRoomEvent>>,
 }
 
 impl SyncResponse {
@@ -0,0 +1,51 @@
+//! A helper to notify listeners about updates, e.g. state transitions or
+//! changes to a list of items.
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+/// A list of listeners that want to be notified about updates.
+///
+/// Every listener gets its own stream of updates, listeners that dropped their
+/// stream are removed the next time an update is sent out.
+#[derive(Debug)]
+pub struct Observers<T> {
+    senders: Vec<UnboundedSender<T>>,
+}
+
+impl<T> Default for Observers<T> {
+    fn default() -> Self {
+        Self { senders: Vec::new() }
+    }
+}
+
+impl<T: Clone> Observers<T> {
+    /// Create a new, empty list of listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a new listener.
+    ///
+    /// The returned stream first yields the given items, e.g. the current
+    /// state, afterwards it yields every update that is sent out.
+    pub fn subscribe(&mut self, current: impl IntoIterator<Item = T>) -> UnboundedReceiver<T> {
+        let (sender, receiver) = unbounded();
+
+        if current.into_iter().all(|item| sender.unbounded_send(item).is_ok()) {
+            self.senders.push(sender);
+        }
+
+        receiver
+    }
+
+    /// Send an update to all the listeners.
+    pub fn notify(&mut self, update: T) {
+        self.senders.retain(|s| s.unbounded_send(update.clone()).is_ok());
+    }
+
+    /// Remove all the listeners, their streams end after yielding the updates
+    /// they already received.
+    pub fn clear(&mut self) {
+        self.senders.clear();
+    }
+}
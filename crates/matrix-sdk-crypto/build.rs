@@ -0,0 +1,66 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generates the table of translated SAS emoji descriptions from the
+//! `sas-emoji.json` file of the spec.
+
+use std::{collections::BTreeMap, env, fmt::Write, fs, path::Path};
+
+use serde_json::Value;
+
+const SAS_EMOJI_JSON: &str = "src/verification/sas/sas-emoji.json";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SAS_EMOJI_JSON);
+
+    let json = fs::read_to_string(SAS_EMOJI_JSON).expect("Can't read sas-emoji.json");
+    let emoji: Vec<Value> = serde_json::from_str(&json).expect("Can't parse sas-emoji.json");
+    assert_eq!(emoji.len(), 64, "sas-emoji.json needs to contain exactly 64 emoji");
+
+    let mut translations: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+    for (index, entry) in emoji.iter().enumerate() {
+        assert_eq!(entry["number"].as_u64(), Some(index as u64), "The emoji aren't ordered");
+
+        let translated = entry["translated_descriptions"]
+            .as_object()
+            .expect("The emoji are missing their translated descriptions");
+
+        for (language, description) in translated {
+            // Some translations are incomplete, those get skipped below.
+            if let Some(description) = description.as_str() {
+                let descriptions = translations.entry(language).or_default();
+                descriptions.resize(index, "");
+                descriptions.push(description);
+            }
+        }
+    }
+
+    let mut table = String::from("&[\n");
+
+    for (language, descriptions) in translations {
+        if descriptions.len() != 64 || descriptions.iter().any(|d| d.is_empty()) {
+            println!("cargo:warning=Skipping the incomplete {} SAS emoji translation", language);
+            continue;
+        }
+
+        writeln!(table, "    ({:?}, {:?}),", language, descriptions).unwrap();
+    }
+
+    table.push(']');
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR isn't set");
+    fs::write(Path::new(&out_dir).join("sas_emoji_translations.rs"), table)
+        .expect("Can't write the SAS emoji translations");
+}
@@ -0,0 +1,116 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use olm_rs::pk::OlmPkEncryption;
+use ruma::api::client::r0::backup::{KeyBackupData, KeyBackupDataInit, SessionDataInit};
+use zeroize::Zeroizing;
+
+use super::recovery::DecodeError;
+use crate::{olm::InboundGroupSession, utilities::decode};
+
+const KEY_SIZE: usize = 32;
+
+/// The public part of a server-side room key backup key.
+///
+/// This key is used to encrypt room keys before they are uploaded to the
+/// server, the matching [`RecoveryKey`](super::RecoveryKey) is needed to
+/// decrypt them again.
+#[derive(Clone)]
+pub struct MegolmV1BackupKey {
+    key: Arc<str>,
+    version: Arc<Mutex<Option<String>>>,
+}
+
+impl std::fmt::Debug for MegolmV1BackupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MegolmV1BackupKey")
+            .field("key", &self.key)
+            .field("version", &self.backup_version())
+            .finish()
+    }
+}
+
+impl MegolmV1BackupKey {
+    pub(super) fn new(key: &str, version: Option<String>) -> Self {
+        Self { key: key.into(), version: Mutex::new(version).into() }
+    }
+
+    /// Try to create a new `MegolmV1BackupKey` from a base64 encoded string.
+    ///
+    /// This is the format that is used in the `auth_data` of a backup version
+    /// on the server.
+    pub fn from_base64(public_key: &str) -> Result<Self, DecodeError> {
+        let key = decode(public_key)?;
+
+        if key.len() != KEY_SIZE {
+            Err(DecodeError::Length(KEY_SIZE, key.len()))
+        } else {
+            Ok(Self::new(public_key, None))
+        }
+    }
+
+    /// Convert the backup key to a base64 encoded string.
+    pub fn to_base64(&self) -> String {
+        self.key.to_string()
+    }
+
+    /// Get the backup version that this key is used with, if any.
+    pub fn backup_version(&self) -> Option<String> {
+        self.version.lock().unwrap().clone()
+    }
+
+    /// Set the backup version that this key should be used with.
+    ///
+    /// Room keys will only be backed up once the backup key has a version.
+    pub fn set_version(&self, version: String) {
+        *self.version.lock().unwrap() = Some(version);
+    }
+
+    /// Encrypt the given inbound group session so it can be uploaded to the
+    /// server.
+    pub async fn encrypt(&self, session: InboundGroupSession) -> KeyBackupData {
+        let pk = OlmPkEncryption::new(&self.key);
+
+        // The forwarding chains don't mean much, we only care whether we received
+        // the session directly from the creator of the session or not.
+        let forwarded_count = (session.forwarding_key_chain().len() as u32).into();
+        let first_message_index = session.first_known_index().into();
+
+        let key = session.to_backup().await;
+        let key =
+            Zeroizing::new(serde_json::to_string(&key).expect("Can't serialize exported room key"));
+
+        let message = pk.encrypt(&key);
+
+        let session_data = SessionDataInit {
+            ephemeral: message.ephemeral_key,
+            ciphertext: message.ciphertext,
+            mac: message.mac,
+        }
+        .into();
+
+        KeyBackupDataInit {
+            first_message_index,
+            forwarded_count,
+            // We don't check the trust of the device that sent us the room key
+            // here, that would require us to fetch the device out of the store
+            // for every session we back up.
+            is_verified: false,
+            session_data,
+        }
+        .into()
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::convert::TryInto;
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead},
+    Aes256Gcm,
+};
+use getrandom::getrandom;
+use olm_rs::{
+    errors::OlmPkDecryptionError,
+    pk::{OlmPkDecryption, PkMessage},
+};
+use ruma::api::client::r0::backup::KeyBackupData;
+use serde::{Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use super::MegolmV1BackupKey;
+use crate::{
+    olm::BackedUpRoomKey,
+    utilities::{decode, decode_url_safe, encode, encode_url_safe},
+};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const PREFIX: [u8; 2] = [0x8b, 0x01];
+const PREFIX_PARITY: u8 = PREFIX[0] ^ PREFIX[1];
+
+/// The private part of a server-side room key backup key.
+///
+/// The recovery key can be used to decrypt room keys that were backed up to
+/// the server, the public part of it, the [`MegolmV1BackupKey`], is used to
+/// encrypt the room keys.
+#[derive(Clone, Zeroize)]
+#[zeroize(drop)]
+pub struct RecoveryKey {
+    inner: [u8; KEY_SIZE],
+}
+
+impl std::fmt::Debug for RecoveryKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecoveryKey").finish_non_exhaustive()
+    }
+}
+
+/// Error type describing why a recovery key couldn't be decoded.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// The decoded recovery key has an invalid prefix.
+    #[error("The decoded recovery key has an invalid prefix: expected {0:?}, got {1:?}")]
+    Prefix([u8; 2], [u8; 2]),
+    /// The parity byte of the recovery key didn't match.
+    #[error("The parity byte of the recovery key doesn't match: expected {0:?}, got {1:?}")]
+    Parity(u8, u8),
+    /// The recovery key has an invalid length.
+    #[error("The decoded recovery key has an invalid length: expected {0}, got {1}")]
+    Length(usize, usize),
+    /// The recovery key isn't valid base58.
+    #[error(transparent)]
+    Base58(#[from] bs58::decode::Error),
+    /// The recovery key isn't valid base64.
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+}
+
+/// Error type describing why a pickled recovery key couldn't be restored.
+#[derive(Debug, Error)]
+pub enum UnpicklingError {
+    /// The pickle couldn't be deserialized.
+    #[error(transparent)]
+    Json(#[from] JsonError),
+    /// The pickle contains invalid base64.
+    #[error(transparent)]
+    Decode(#[from] base64::DecodeError),
+    /// The pickle couldn't be decrypted.
+    #[error("Couldn't decrypt the pickled recovery key")]
+    Decryption,
+    /// The decrypted recovery key has an invalid length.
+    #[error("The pickled recovery key has an invalid length")]
+    Length,
+}
+
+/// Error type describing why a backed up room key couldn't be decrypted.
+#[derive(Debug, Error)]
+pub enum BackupDecryptionError {
+    /// The ciphertext couldn't be decrypted.
+    #[error(transparent)]
+    Decryption(#[from] OlmPkDecryptionError),
+    /// The decrypted room key couldn't be deserialized.
+    #[error(transparent)]
+    Json(#[from] JsonError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InnerPickle {
+    version: u8,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// A pickled version of a `RecoveryKey`.
+///
+/// The recovery key is encrypted using the pickle key of the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PickledRecoveryKey(String);
+
+impl PickledRecoveryKey {
+    /// Get the string representation of the pickle.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for PickledRecoveryKey {
+    fn from(pickle: String) -> Self {
+        Self(pickle)
+    }
+}
+
+impl RecoveryKey {
+    /// Create a new random recovery key.
+    pub fn new() -> Result<Self, getrandom::Error> {
+        let mut inner = [0u8; KEY_SIZE];
+        getrandom(&mut inner)?;
+
+        Ok(Self { inner })
+    }
+
+    pub(crate) fn from_slice(key: &[u8]) -> Result<Self, DecodeError> {
+        if key.len() != KEY_SIZE {
+            Err(DecodeError::Length(KEY_SIZE, key.len()))
+        } else {
+            let mut inner = [0u8; KEY_SIZE];
+            inner.copy_from_slice(key);
+
+            Ok(Self { inner })
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.inner
+    }
+
+    fn parity_byte(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(PREFIX_PARITY, |acc, x| acc ^ x)
+    }
+
+    /// Try to create a `RecoveryKey` from a base64 export of it.
+    pub fn from_base64(key: &str) -> Result<Self, DecodeError> {
+        let decoded = Zeroizing::new(decode(key)?);
+        Self::from_slice(&decoded)
+    }
+
+    /// Export the `RecoveryKey` as a base64 encoded string.
+    pub fn to_base64(&self) -> String {
+        encode(self.inner)
+    }
+
+    /// Try to create a `RecoveryKey` from a base58 export of it.
+    ///
+    /// Whitespace is ignored, the base58 export is commonly split into groups
+    /// of four characters so it's easier to write down.
+    pub fn from_base58(value: &str) -> Result<Self, DecodeError> {
+        // Remove any whitespace we might have.
+        let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let decoded =
+            Zeroizing::new(bs58::decode(value).with_alphabet(bs58::Alphabet::BITCOIN).into_vec()?);
+        let expected_length = PREFIX.len() + KEY_SIZE + 1;
+
+        if decoded.len() != expected_length {
+            return Err(DecodeError::Length(expected_length, decoded.len()));
+        }
+
+        let (prefix, rest) = decoded.split_at(PREFIX.len());
+        let (key, parity) = rest.split_at(KEY_SIZE);
+        let prefix: [u8; 2] = prefix.try_into().expect("The prefix has the correct length");
+        let parity = parity[0];
+
+        if prefix != PREFIX {
+            return Err(DecodeError::Prefix(PREFIX, prefix));
+        }
+
+        let expected_parity = Self::parity_byte(key);
+
+        if expected_parity != parity {
+            return Err(DecodeError::Parity(expected_parity, parity));
+        }
+
+        Self::from_slice(key)
+    }
+
+    /// Export the `RecoveryKey` as a base58 encoded string.
+    ///
+    /// The string is split into groups of four characters, separated by a
+    /// space, as recommended by the spec.
+    pub fn to_base58(&self) -> String {
+        let bytes = Zeroizing::new(
+            [PREFIX.as_ref(), self.inner.as_ref(), [Self::parity_byte(&self.inner)].as_ref()]
+                .concat(),
+        );
+
+        let encoded = Zeroizing::new(
+            bs58::encode(bytes.as_slice()).with_alphabet(bs58::Alphabet::BITCOIN).into_string(),
+        );
+
+        encoded
+            .chars()
+            .collect::<Vec<char>>()
+            .chunks(4)
+            .map(|c| c.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn pk_decryption(&self) -> OlmPkDecryption {
+        OlmPkDecryption::from_bytes(&self.inner)
+            .expect("Can't create a PkDecryption object from our recovery key")
+    }
+
+    /// Get the public part of the recovery key.
+    pub fn megolm_v1_public_key(&self) -> MegolmV1BackupKey {
+        let pk = self.pk_decryption();
+        MegolmV1BackupKey::new(pk.public_key(), None)
+    }
+
+    /// Try to decrypt a message that was encrypted using the public part of
+    /// this recovery key.
+    ///
+    /// # Arguments
+    ///
+    /// * `ephemeral_key` - The ephemeral curve25519 key that was used to
+    /// encrypt the message.
+    ///
+    /// * `mac` - The MAC of the encrypted message.
+    ///
+    /// * `ciphertext` - The encrypted message.
+    pub fn decrypt_v1(
+        &self,
+        ephemeral_key: String,
+        mac: String,
+        ciphertext: String,
+    ) -> Result<String, OlmPkDecryptionError> {
+        let message = PkMessage::new(ephemeral_key, mac, ciphertext);
+        self.pk_decryption().decrypt(message)
+    }
+
+    /// Decrypt a room key that was backed up using the
+    /// `m.megolm_backup.v1.curve25519-aes-sha2` algorithm.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_backup_data` - The encrypted session data of a single room key
+    /// that we got from the server.
+    pub fn decrypt_room_key(
+        &self,
+        key_backup_data: &KeyBackupData,
+    ) -> Result<BackedUpRoomKey, BackupDecryptionError> {
+        let session_data = &key_backup_data.session_data;
+
+        let plaintext = Zeroizing::new(self.decrypt_v1(
+            session_data.ephemeral.clone(),
+            session_data.mac.clone(),
+            session_data.ciphertext.clone(),
+        )?);
+
+        Ok(serde_json::from_str(&plaintext)?)
+    }
+
+    /// Store the recovery key in an encrypted form.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The 32 byte key that should be used to encrypt the
+    /// recovery key.
+    pub fn pickle(&self, pickle_key: &[u8]) -> PickledRecoveryKey {
+        let key = GenericArray::from_slice(pickle_key);
+        let cipher = Aes256Gcm::new(key);
+
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        getrandom(&mut nonce).expect("Can't generate nonce to pickle the recovery key");
+        let nonce = GenericArray::from_slice(nonce.as_slice());
+
+        let ciphertext =
+            cipher.encrypt(nonce, self.inner.as_ref()).expect("Can't encrypt recovery key");
+
+        let ciphertext = encode_url_safe(ciphertext);
+
+        let pickle =
+            InnerPickle { version: 1, nonce: encode_url_safe(nonce.as_slice()), ciphertext };
+
+        PickledRecoveryKey(
+            serde_json::to_string(&pickle).expect("Can't encode pickled recovery key"),
+        )
+    }
+
+    /// Restore a recovery key from a previously pickled version.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle` - The pickled version of the recovery key.
+    ///
+    /// * `pickle_key` - The key that was used to pickle the recovery key.
+    pub fn from_pickle(
+        pickle: PickledRecoveryKey,
+        pickle_key: &[u8],
+    ) -> Result<Self, UnpicklingError> {
+        let pickled: InnerPickle = serde_json::from_str(pickle.as_str())?;
+
+        let key = GenericArray::from_slice(pickle_key);
+        let cipher = Aes256Gcm::new(key);
+
+        let nonce = decode_url_safe(pickled.nonce)?;
+        let nonce = GenericArray::from_slice(&nonce);
+        let ciphertext = &decode_url_safe(pickled.ciphertext)?;
+
+        let decrypted = Zeroizing::new(
+            cipher
+                .decrypt(nonce, ciphertext.as_slice())
+                .map_err(|_| UnpicklingError::Decryption)?,
+        );
+
+        Self::from_slice(&decrypted).map_err(|_| UnpicklingError::Length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DecodeError, RecoveryKey};
+
+    const TEST_KEY: [u8; 32] = [
+        0x77, 0x07, 0x6D, 0x0A, 0x73, 0x18, 0xA5, 0x7D, 0x3C, 0x16, 0xC1, 0x72, 0x51, 0xB2, 0x66,
+        0x45, 0xDF, 0x4C, 0x2F, 0x87, 0xEB, 0xC0, 0x99, 0x2A, 0xB1, 0x77, 0xFB, 0xA5, 0x1D, 0xB9,
+        0x2C, 0x2A,
+    ];
+
+    #[test]
+    fn base58_encoding() {
+        let key = RecoveryKey { inner: TEST_KEY };
+        let encoded = key.to_base58();
+
+        assert_eq!(encoded, "EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d");
+
+        let decoded = RecoveryKey::from_base58(&encoded).unwrap();
+        assert_eq!(key.inner, decoded.inner);
+    }
+
+    #[test]
+    fn base58_decoding_errors() {
+        let key = RecoveryKey::new().unwrap();
+        let mut encoded = key.to_base58().replace(' ', "");
+
+        // Flip the last character so the parity byte doesn't match anymore.
+        let last = if encoded.ends_with('a') { 'b' } else { 'a' };
+        encoded.pop();
+        encoded.push(last);
+
+        assert!(matches!(
+            RecoveryKey::from_base58(&encoded),
+            Err(DecodeError::Parity(..)) | Err(DecodeError::Length(..))
+        ));
+        assert!(matches!(RecoveryKey::from_base58("0OIl"), Err(DecodeError::Base58(_))));
+    }
+
+    #[test]
+    fn base64_encoding() {
+        let key = RecoveryKey::new().unwrap();
+        let decoded = RecoveryKey::from_base64(&key.to_base64()).unwrap();
+
+        assert_eq!(key.inner, decoded.inner);
+    }
+
+    #[test]
+    fn pickling() {
+        let pickle_key = [0u8; 32];
+        let key = RecoveryKey::new().unwrap();
+
+        let pickle = key.pickle(&pickle_key);
+        let unpickled = RecoveryKey::from_pickle(pickle, &pickle_key).unwrap();
+
+        assert_eq!(key.inner, unpickled.inner);
+    }
+}
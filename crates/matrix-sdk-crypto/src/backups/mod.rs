@@ -0,0 +1,502 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server-side backup support for room keys
+//!
+//! This module implements support for server-side backups of room keys using
+//! the `m.megolm_backup.v1.curve25519-aes-sha2` algorithm.
+//!
+//! The [`BackupMachine`] keeps track of which room keys have been uploaded to
+//! the currently active backup version, requests to upload room keys will be
+//! returned by the [`OlmMachine::outgoing_requests()`] method once a backup
+//! has been enabled.
+//!
+//! [`OlmMachine::outgoing_requests()`]: crate::OlmMachine::outgoing_requests
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    sync::Arc,
+};
+
+use matrix_sdk_common::{locks::RwLock, uuid::Uuid};
+use ruma::{
+    api::client::r0::backup::{BackupAlgorithm, RoomKeyBackup},
+    serde::CanonicalJsonValue,
+    DeviceKeyAlgorithm, DeviceKeyId, RoomId,
+};
+use serde_json::json;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    olm::InboundGroupSession,
+    requests::KeysBackupRequest,
+    store::{BackupKeys, Changes, CryptoStoreError, RoomKeyCounts, Store},
+    OutgoingRequest,
+};
+
+mod keys;
+
+pub use keys::{
+    BackupDecryptionError, DecodeError, MegolmV1BackupKey, PickledRecoveryKey, RecoveryKey,
+    UnpicklingError,
+};
+
+/// The number of room keys that will be uploaded to the server in a single
+/// backup request.
+const BACKUP_BATCH_SIZE: usize = 100;
+
+/// A state machine that handles backing up room keys.
+///
+/// The machine can be activated using the [`BackupMachine::enable_backup()`]
+/// method, after that room keys will be periodically uploaded to the server.
+#[derive(Debug, Clone)]
+pub struct BackupMachine {
+    store: Store,
+    backup_key: Arc<RwLock<Option<MegolmV1BackupKey>>>,
+    pending_backup: Arc<RwLock<Option<PendingBackup>>>,
+}
+
+#[derive(Debug, Clone)]
+struct PendingBackup {
+    request_id: Uuid,
+    request: KeysBackupRequest,
+    sessions: BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl From<PendingBackup> for OutgoingRequest {
+    fn from(b: PendingBackup) -> Self {
+        OutgoingRequest { request_id: b.request_id, request: Arc::new(b.request.into()) }
+    }
+}
+
+impl BackupMachine {
+    pub(crate) fn new(store: Store, backup_key: Option<MegolmV1BackupKey>) -> Self {
+        Self {
+            store,
+            backup_key: RwLock::new(backup_key).into(),
+            pending_backup: RwLock::new(None).into(),
+        }
+    }
+
+    /// Get the backup algorithm of the given backup key, this can be used to
+    /// create a new backup version on the server.
+    ///
+    /// The `auth_data` of the algorithm is signed by our own device and, if we
+    /// have the private master key, by our cross signing identity, this lets
+    /// our other devices trust the backup.
+    pub async fn backup_algorithm(&self, backup_key: &MegolmV1BackupKey) -> BackupAlgorithm {
+        let public_key = backup_key.to_base64();
+        let auth_data: CanonicalJsonValue = json!({ "public_key": public_key })
+            .try_into()
+            .expect("Can't canonicalize the backup auth data");
+        let message = auth_data.to_string();
+
+        let account = self.store.account();
+        let mut user_signatures = BTreeMap::new();
+        user_signatures.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, account.device_id()),
+            account.sign(&message).await,
+        );
+
+        let identity = self.store.private_identity();
+
+        if let Some((key_id, signature)) =
+            identity.lock().await.sign_with_master_key(&message).await
+        {
+            user_signatures.insert(key_id, signature);
+        }
+
+        let mut signatures = BTreeMap::new();
+        signatures.insert(self.store.user_id().to_owned(), user_signatures);
+
+        BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, signatures }
+    }
+
+    /// Are we able to back up room keys to the server?
+    pub async fn enabled(&self) -> bool {
+        self.backup_key.read().await.as_ref().map(|b| b.backup_version().is_some()).unwrap_or(false)
+    }
+
+    /// Activate the given backup key to be used to encrypt and backup room
+    /// keys.
+    ///
+    /// The backup key needs to have a backup version set, room keys will only
+    /// be uploaded to the backup version the key belongs to.
+    ///
+    /// Activating a new backup key will reset the backup state of all room
+    /// keys, the room keys will be uploaded to the new backup version.
+    pub async fn enable_backup(&self, key: MegolmV1BackupKey) -> Result<(), CryptoStoreError> {
+        let version = if let Some(version) = key.backup_version() {
+            version
+        } else {
+            warn!("Tried to enable a backup key that doesn't have a backup version");
+            return Ok(());
+        };
+
+        let stored_version = self.store.load_backup_keys().await?.backup_version;
+
+        if stored_version.as_ref() != Some(&version) {
+            // The sessions were backed up to a different backup version, they
+            // need to be uploaded again.
+            self.store.reset_backup_state().await?;
+
+            let changes = Changes { backup_version: Some(version.clone()), ..Default::default() };
+            self.store.save_changes(changes).await?;
+        }
+
+        *self.pending_backup.write().await = None;
+        *self.backup_key.write().await = Some(key);
+
+        info!(version = version.as_str(), "Activated a backup");
+
+        Ok(())
+    }
+
+    /// Disable and reset our backup state.
+    ///
+    /// This will remove any pending backup request and remove the marker that
+    /// marks room keys as backed up.
+    pub async fn disable_backup(&self) -> Result<(), CryptoStoreError> {
+        debug!("Disabling key backup and resetting backup state for room keys");
+
+        self.backup_key.write().await.take();
+        self.pending_backup.write().await.take();
+
+        self.store.reset_backup_state().await?;
+
+        debug!("Done disabling backup");
+
+        Ok(())
+    }
+
+    /// Store the recovery key in the crypto store.
+    ///
+    /// This is useful if the client wants to support gossiping of the backup
+    /// key or to restore the backup once the client restarts.
+    pub async fn save_recovery_key(
+        &self,
+        recovery_key: Option<RecoveryKey>,
+        version: Option<String>,
+    ) -> Result<(), CryptoStoreError> {
+        let changes = Changes { recovery_key, backup_version: version, ..Default::default() };
+        self.store.save_changes(changes).await
+    }
+
+    /// Get the backup keys we have saved in our crypto store.
+    pub async fn get_backup_keys(&self) -> Result<BackupKeys, CryptoStoreError> {
+        self.store.load_backup_keys().await
+    }
+
+    /// Get the number of backed up room keys and the total number of room
+    /// keys.
+    pub async fn room_key_counts(&self) -> Result<RoomKeyCounts, CryptoStoreError> {
+        self.store.inbound_group_session_counts().await
+    }
+
+    /// Get a request that will back up a batch of room keys to the server.
+    ///
+    /// Returns `None` if backups aren't enabled or if all the room keys have
+    /// already been backed up. The response of the request needs to be passed
+    /// back to the `OlmMachine` using the `mark_request_as_sent()` method.
+    pub async fn backup(&self) -> Result<Option<OutgoingRequest>, CryptoStoreError> {
+        let mut request = self.pending_backup.write().await;
+
+        if let Some(r) = &*request {
+            trace!("Backing up, returning an existing request");
+
+            Ok(Some(r.clone().into()))
+        } else {
+            trace!("Backing up, creating a new request");
+
+            let new_request = self.backup_helper().await?;
+            *request = new_request.clone();
+
+            Ok(new_request.map(|r| r.into()))
+        }
+    }
+
+    /// Decrypt and import room keys that were downloaded from a server-side
+    /// backup.
+    ///
+    /// The imported room keys will be marked as backed up so they don't get
+    /// uploaded again.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key that should be used to decrypt the
+    /// room keys.
+    ///
+    /// * `room_keys` - The encrypted room keys, grouped by room, as they were
+    /// downloaded from the server.
+    ///
+    /// * `progress_listener` - Closure that will be called with the index of
+    /// the currently processed room key and the total number of room keys.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// backup.
+    pub async fn import_backed_up_room_keys(
+        &self,
+        recovery_key: &RecoveryKey,
+        room_keys: BTreeMap<RoomId, RoomKeyBackup>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<(usize, usize), CryptoStoreError> {
+        let mut decrypted_keys = Vec::new();
+
+        for (room_id, room_keys) in room_keys {
+            for (session_id, key_backup_data) in room_keys.sessions {
+                match recovery_key.decrypt_room_key(&key_backup_data) {
+                    Ok(key) => {
+                        decrypted_keys.push(key.into_exported_room_key(room_id.clone(), session_id))
+                    }
+                    Err(e) => {
+                        warn!(
+                            room_id = room_id.as_str(),
+                            session_id = session_id.as_str(),
+                            error =? e,
+                            "Couldn't decrypt a backed up room key"
+                        );
+                    }
+                }
+            }
+        }
+
+        self.store.import_room_keys(decrypted_keys, true, progress_listener).await
+    }
+
+    pub(crate) async fn mark_request_as_sent(
+        &self,
+        request_id: Uuid,
+    ) -> Result<(), CryptoStoreError> {
+        let mut request = self.pending_backup.write().await;
+
+        if let Some(r) = &*request {
+            if r.request_id == request_id {
+                let mut sessions = Vec::new();
+
+                for (room_id, sender_keys) in &r.sessions {
+                    for (sender_key, session_ids) in sender_keys {
+                        for session_id in session_ids {
+                            if let Some(session) = self
+                                .store
+                                .get_inbound_group_session(room_id, sender_key, session_id)
+                                .await?
+                            {
+                                session.mark_as_backed_up();
+                                sessions.push(session);
+                            }
+                        }
+                    }
+                }
+
+                trace!(request_id =? r.request_id, keys =? r.sessions, "Marking room keys as backed up");
+
+                let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+                self.store.save_changes(changes).await?;
+
+                *request = None;
+            } else {
+                warn!(
+                    expected = r.request_id.to_string().as_str(),
+                    got = request_id.to_string().as_str(),
+                    "Tried to mark a pending backup as sent but the request id didn't match"
+                );
+            }
+        } else {
+            warn!(
+                request_id = request_id.to_string().as_str(),
+                "Tried to mark a pending backup as sent but there isn't a backup pending"
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn backup_helper(&self) -> Result<Option<PendingBackup>, CryptoStoreError> {
+        let backup_key = if let Some(k) = &*self.backup_key.read().await {
+            k.clone()
+        } else {
+            trace!("No backup key is set, not backing up room keys");
+            return Ok(None);
+        };
+
+        let version = if let Some(v) = backup_key.backup_version() {
+            v
+        } else {
+            warn!("Trying to back up room keys but the backup key is missing a version");
+            return Ok(None);
+        };
+
+        let sessions = self.store.inbound_group_sessions_for_backup(BACKUP_BATCH_SIZE).await?;
+
+        if sessions.is_empty() {
+            trace!("No room keys need to be backed up");
+            return Ok(None);
+        }
+
+        let key_count = sessions.len();
+        let (rooms, sessions) = Self::backup_keys(sessions, &backup_key).await;
+
+        info!(
+            key_count = key_count,
+            version = version.as_str(),
+            "Created a room key backup request"
+        );
+
+        Ok(Some(PendingBackup {
+            request_id: Uuid::new_v4(),
+            request: KeysBackupRequest { version, rooms },
+            sessions,
+        }))
+    }
+
+    async fn backup_keys(
+        sessions: Vec<InboundGroupSession>,
+        backup_key: &MegolmV1BackupKey,
+    ) -> (BTreeMap<RoomId, RoomKeyBackup>, BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>>)
+    {
+        let mut backup: BTreeMap<RoomId, RoomKeyBackup> = BTreeMap::new();
+        let mut session_record: BTreeMap<RoomId, BTreeMap<String, BTreeSet<String>>> =
+            BTreeMap::new();
+
+        for session in sessions {
+            let room_id = session.room_id().to_owned();
+            let session_id = session.session_id().to_owned();
+            let sender_key = session.sender_key().to_owned();
+            let session = backup_key.encrypt(session).await;
+
+            session_record
+                .entry(room_id.clone())
+                .or_default()
+                .entry(sender_key)
+                .or_default()
+                .insert(session_id.clone());
+
+            backup
+                .entry(room_id)
+                .or_insert_with(|| RoomKeyBackup::new(BTreeMap::new()))
+                .sessions
+                .insert(session_id, session);
+        }
+
+        (backup, session_record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matches::assert_matches;
+    use ruma::{room_id, user_id, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId};
+
+    use super::RecoveryKey;
+    use crate::{olm::Utility, OlmMachine, OutgoingRequests};
+
+    #[tokio::test]
+    async fn backup_algorithm_is_signed() {
+        let user_id = user_id!("@alice:example.org");
+        let device_id: DeviceIdBox = "ALICEDEVICE".into();
+
+        let machine = OlmMachine::new(&user_id, &device_id);
+        let backup_key = RecoveryKey::new().unwrap().megolm_v1_public_key();
+
+        let algorithm = machine.backup_machine().backup_algorithm(&backup_key).await;
+        let mut auth_data = serde_json::to_value(&algorithm).unwrap()["auth_data"].take();
+
+        assert_eq!(auth_data["public_key"], backup_key.to_base64());
+        assert_eq!(auth_data["signatures"][user_id.as_str()].as_object().unwrap().len(), 1);
+
+        Utility::new()
+            .verify_json(
+                &user_id,
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, &device_id),
+                machine.identity_keys().ed25519(),
+                &mut auth_data,
+            )
+            .expect("The backup isn't signed by our device");
+
+        // Once we have a cross signing identity the backup is signed by our
+        // master key as well.
+        machine.bootstrap_cross_signing(false).await.unwrap();
+
+        let algorithm = machine.backup_machine().backup_algorithm(&backup_key).await;
+        let auth_data = serde_json::to_value(&algorithm).unwrap()["auth_data"].take();
+        assert_eq!(auth_data["signatures"][user_id.as_str()].as_object().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let user_id = user_id!("@alice:example.org");
+        let device_id: DeviceIdBox = "ALICEDEVICE".into();
+        let room_id = room_id!("!test:localhost");
+
+        let machine = OlmMachine::new(&user_id, &device_id);
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+
+        let backup_machine = machine.backup_machine();
+        assert!(!backup_machine.enabled().await);
+        assert!(machine.backup_machine().backup().await.unwrap().is_none());
+
+        let recovery_key = RecoveryKey::new().unwrap();
+        let backup_key = recovery_key.megolm_v1_public_key();
+        backup_key.set_version("1".to_owned());
+
+        backup_machine.enable_backup(backup_key).await.unwrap();
+        assert!(backup_machine.enabled().await);
+
+        let counts = backup_machine.room_key_counts().await.unwrap();
+        assert_eq!(counts.total, 1);
+        assert_eq!(counts.backed_up, 0);
+
+        let request = backup_machine.backup().await.unwrap().expect("Created a backup request");
+
+        let rooms = if let OutgoingRequests::KeysBackup(r) = request.request() {
+            assert_eq!(r.version, "1");
+            r.rooms.clone()
+        } else {
+            panic!("Invalid request type {:?}", request.request())
+        };
+
+        assert!(rooms.contains_key(&room_id));
+
+        // The same request is returned until the request is marked as sent.
+        let repeated = backup_machine.backup().await.unwrap().unwrap();
+        assert_eq!(request.request_id(), repeated.request_id());
+
+        backup_machine.mark_request_as_sent(*request.request_id()).await.unwrap();
+
+        let counts = backup_machine.room_key_counts().await.unwrap();
+        assert_eq!(counts.backed_up, 1);
+        assert!(backup_machine.backup().await.unwrap().is_none());
+
+        let other_machine = OlmMachine::new(&user_id, &device_id);
+        let (imported, total) = other_machine
+            .backup_machine()
+            .import_backed_up_room_keys(&recovery_key, rooms, |_, _| {})
+            .await
+            .unwrap();
+
+        assert_eq!((imported, total), (1, 1));
+
+        let counts = other_machine.backup_machine().room_key_counts().await.unwrap();
+        assert_eq!(counts.backed_up, 1);
+
+        backup_machine.disable_backup().await.unwrap();
+        assert!(!backup_machine.enabled().await);
+
+        let counts = backup_machine.room_key_counts().await.unwrap();
+        assert_eq!(counts.backed_up, 0);
+        assert_matches!(backup_machine.backup().await, Ok(None));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for dehydrated devices as defined in [MSC2697].
+//!
+//! A dehydrated device is a device that is stored, in an encrypted form, on
+//! the server. Other users will send room keys to the dehydrated device while
+//! all our real devices are offline. Once we log in again the device can be
+//! rehydrated, the to-device events it received can be decrypted and the room
+//! keys imported into our new device.
+//!
+//! [MSC2697]: https://github.com/matrix-org/matrix-doc/pull/2697
+
+use std::collections::BTreeMap;
+
+use getrandom::getrandom;
+use olm_rs::{errors::OlmAccountError, PicklingMode};
+use ruma::{
+    api::client::r0::sync::sync_events::{DeviceLists, ToDevice},
+    assign,
+    encryption::{DeviceKeys, OneTimeKey},
+    events::AnyToDeviceEvent,
+    serde::Raw,
+    DeviceId, DeviceIdBox, DeviceKeyId,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::{
+    olm::{PickledAccount, ReadOnlyAccount},
+    store::{Changes, CryptoStoreError},
+    utilities::encode,
+    OlmError, OlmMachine,
+};
+
+/// The algorithm that is used to store the account of a dehydrated device.
+pub const DEHYDRATION_ALGORITHM: &str = "org.matrix.msc2697.v1.olm.libolm_pickle";
+
+/// Error type describing why a dehydrated device couldn't be rehydrated.
+#[derive(Debug, Error)]
+pub enum DehydrationError {
+    /// The dehydrated device uses an algorithm we don't support.
+    #[error("The dehydrated device uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    /// The pickled account couldn't be decrypted or restored.
+    #[error(transparent)]
+    Pickle(#[from] OlmAccountError),
+    /// The to-device events couldn't be processed.
+    #[error(transparent)]
+    Olm(#[from] OlmError),
+    /// The store returned an error.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The device data of a dehydrated device, as it is stored on the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DehydratedDeviceData {
+    /// The algorithm that was used to store the account.
+    pub algorithm: String,
+    /// The encrypted pickle of the account.
+    pub account: String,
+}
+
+/// A request to upload a new dehydrated device and its public keys.
+#[derive(Debug, Clone)]
+pub struct DehydratedDeviceRequest {
+    /// The device ID of the dehydrated device.
+    pub device_id: DeviceIdBox,
+    /// The display name of the dehydrated device.
+    pub initial_device_display_name: String,
+    /// The encrypted account of the dehydrated device.
+    pub device_data: DehydratedDeviceData,
+    /// The signed device keys of the dehydrated device.
+    pub device_keys: DeviceKeys,
+    /// The signed one-time keys of the dehydrated device.
+    pub one_time_keys: BTreeMap<DeviceKeyId, OneTimeKey>,
+}
+
+/// Struct collecting methods to create and rehydrate dehydrated devices.
+#[derive(Debug)]
+pub struct DehydratedDevices {
+    pub(crate) inner: OlmMachine,
+}
+
+impl DehydratedDevices {
+    /// Create a new dehydrated device.
+    ///
+    /// The device will get a random device ID and a fresh set of identity and
+    /// one-time keys.
+    pub fn create(&self) -> DehydratedDevice {
+        let device_id = Self::random_device_id();
+        let account = ReadOnlyAccount::new(self.inner.user_id(), &device_id);
+
+        debug!(device_id = device_id.as_str(), "Created a new dehydrated device");
+
+        DehydratedDevice { account }
+    }
+
+    fn random_device_id() -> DeviceIdBox {
+        let mut bytes = [0u8; 8];
+        getrandom(&mut bytes).expect("Can't generate randomness");
+
+        encode(bytes).chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().into()
+    }
+
+    /// Rehydrate a dehydrated device that was fetched from the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that was used to encrypt the device data.
+    ///
+    /// * `device_id` - The device ID of the dehydrated device.
+    ///
+    /// * `device_data` - The device data of the dehydrated device.
+    pub async fn rehydrate(
+        &self,
+        pickle_key: &[u8],
+        device_id: &DeviceId,
+        device_data: DehydratedDeviceData,
+    ) -> Result<RehydratedDevice, DehydrationError> {
+        if device_data.algorithm != DEHYDRATION_ALGORITHM {
+            return Err(DehydrationError::UnsupportedAlgorithm(device_data.algorithm));
+        }
+
+        let pickle = PickledAccount {
+            user_id: self.inner.user_id().to_owned(),
+            device_id: device_id.to_owned(),
+            pickle: device_data.account.into(),
+            shared: true,
+            uploaded_signed_key_count: 0,
+        };
+
+        let account = ReadOnlyAccount::from_pickle(
+            pickle,
+            PicklingMode::Encrypted { key: pickle_key.to_vec() },
+        )?;
+
+        info!(device_id = device_id.as_str(), "Rehydrated a dehydrated device");
+
+        Ok(RehydratedDevice {
+            rehydrated: OlmMachine::from_account(account),
+            original: self.inner.clone(),
+        })
+    }
+}
+
+/// A dehydrated device that was freshly created and needs to be uploaded to
+/// the server.
+#[derive(Debug)]
+pub struct DehydratedDevice {
+    account: ReadOnlyAccount,
+}
+
+impl DehydratedDevice {
+    /// Get the device ID of the dehydrated device.
+    pub fn device_id(&self) -> &DeviceId {
+        self.account.device_id()
+    }
+
+    /// Create the request that uploads the dehydrated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_device_display_name` - The display name the dehydrated device
+    /// should have.
+    ///
+    /// * `pickle_key` - The key that should be used to encrypt the device
+    /// data, the same key needs to be used to rehydrate the device.
+    pub async fn keys_for_upload(
+        &self,
+        initial_device_display_name: String,
+        pickle_key: &[u8],
+    ) -> DehydratedDeviceRequest {
+        let (device_keys, one_time_keys, _) = self
+            .account
+            .keys_for_upload()
+            .await
+            .expect("A new account always needs to upload its keys");
+
+        let device_keys = device_keys.expect("A new account always needs to upload device keys");
+        let one_time_keys = one_time_keys.unwrap_or_default();
+
+        // The one-time keys will be uploaded together with the device, mark them
+        // as published before we pickle the account.
+        self.account.mark_keys_as_published().await;
+
+        let pickle =
+            self.account.pickle(PicklingMode::Encrypted { key: pickle_key.to_vec() }).await;
+
+        DehydratedDeviceRequest {
+            device_id: self.account.device_id().to_owned(),
+            initial_device_display_name,
+            device_data: DehydratedDeviceData {
+                algorithm: DEHYDRATION_ALGORITHM.to_owned(),
+                account: pickle.pickle.as_str().to_owned(),
+            },
+            device_keys,
+            one_time_keys,
+        }
+    }
+}
+
+/// A dehydrated device that was rehydrated, the to-device events that were
+/// sent to the dehydrated device can be passed to it to import the room keys
+/// they contain.
+#[derive(Debug)]
+pub struct RehydratedDevice {
+    rehydrated: OlmMachine,
+    original: OlmMachine,
+}
+
+impl RehydratedDevice {
+    /// Get the device ID of the rehydrated device.
+    pub fn device_id(&self) -> &DeviceId {
+        self.rehydrated.device_id()
+    }
+
+    /// Decrypt the to-device events that were sent to the dehydrated device
+    /// and import the room keys they contain into our own device.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - A batch of to-device events that the server stored for
+    /// the dehydrated device.
+    ///
+    /// Returns the number of room keys that were imported.
+    pub async fn receive_events(
+        &self,
+        events: Vec<Raw<AnyToDeviceEvent>>,
+    ) -> Result<usize, DehydrationError> {
+        let to_device = assign!(ToDevice::new(), { events });
+
+        self.rehydrated
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new(), None)
+            .await?;
+
+        let mut sessions = Vec::new();
+
+        for session in self.rehydrated.store().get_inbound_group_sessions().await? {
+            let existing = self
+                .original
+                .store()
+                .get_inbound_group_session(
+                    session.room_id(),
+                    session.sender_key(),
+                    session.session_id(),
+                )
+                .await?;
+
+            // Only import the session if we don't have it or if it's a better
+            // version of the same session.
+            let import = existing
+                .map(|e| e.first_known_index() > session.first_known_index())
+                .unwrap_or(true);
+
+            if import {
+                sessions.push(session);
+            }
+        }
+
+        let imported = sessions.len();
+
+        if imported > 0 {
+            info!(count = imported, "Importing room keys from a rehydrated device");
+
+            let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+            self.original.store().save_changes(changes).await?;
+        }
+
+        Ok(imported)
+    }
+}
+
+impl OlmMachine {
+    /// Get the object that allows us to create and rehydrate dehydrated
+    /// devices.
+    pub fn dehydrated_devices(&self) -> DehydratedDevices {
+        DehydratedDevices { inner: self.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, convert::TryFrom};
+
+    use matrix_sdk_common::uuid::Uuid;
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        api::client::r0::keys::claim_keys, events::AnyToDeviceEvent, room_id, serde::Raw, user_id,
+    };
+    use serde_json::json;
+
+    use crate::{EncryptionSettings, OlmMachine, ReadOnlyDevice};
+
+    const PICKLE_KEY: &[u8] = &[0u8; 32];
+
+    #[async_test]
+    async fn dehydrate_and_rehydrate() {
+        let alice = OlmMachine::new(&user_id!("@alice:example.org"), "ALICEDEVICE".into());
+        let bob = OlmMachine::new(&user_id!("@bob:example.org"), "BOBDEVICE".into());
+
+        let dehydrated = bob.dehydrated_devices().create();
+        let request = dehydrated.keys_for_upload("Dehydrated device".to_owned(), PICKLE_KEY).await;
+        assert_eq!(request.device_id.as_ref(), dehydrated.device_id());
+        assert!(!request.one_time_keys.is_empty());
+
+        // Alice learns about the dehydrated device and creates an Olm session
+        // with it.
+        let device = ReadOnlyDevice::try_from(&request.device_keys).unwrap();
+        alice.store().save_devices(&[device]).await.unwrap();
+
+        let (key_id, one_time_key) = request.one_time_keys.iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(key_id.clone(), one_time_key.clone());
+        let mut device_keys = BTreeMap::new();
+        device_keys.insert(request.device_id.clone(), keys);
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob.user_id().clone(), device_keys);
+
+        let response = claim_keys::Response::new(one_time_keys);
+        alice.mark_request_as_sent(&Uuid::new_v4(), &response).await.unwrap();
+
+        // Alice sends a room key to the dehydrated device.
+        let room_id = room_id!("!test:example.org");
+        let requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let events: Vec<Raw<AnyToDeviceEvent>> = requests
+            .iter()
+            .flat_map(|r| r.messages.values().flat_map(|m| m.values()))
+            .map(|content| {
+                serde_json::from_value(json!({
+                    "sender": alice.user_id(),
+                    "type": "m.room.encrypted",
+                    "content": content,
+                }))
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(events.len(), 1);
+
+        // Bob rehydrates the device and imports the room key.
+        let rehydrated = bob
+            .dehydrated_devices()
+            .rehydrate(PICKLE_KEY, &request.device_id, request.device_data.clone())
+            .await
+            .unwrap();
+        assert_eq!(rehydrated.device_id(), dehydrated.device_id());
+
+        assert_eq!(rehydrated.receive_events(events).await.unwrap(), 1);
+        assert_eq!(bob.store().get_inbound_group_sessions().await.unwrap().len(), 1);
+        assert_eq!(rehydrated.receive_events(Vec::new()).await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn rehydrating_with_a_wrong_key_fails() {
+        let bob = OlmMachine::new(&user_id!("@bob:example.org"), "BOBDEVICE".into());
+
+        let dehydrated = bob.dehydrated_devices().create();
+        let request = dehydrated.keys_for_upload("Dehydrated device".to_owned(), PICKLE_KEY).await;
+
+        assert!(bob
+            .dehydrated_devices()
+            .rehydrate(&[1u8; 32], &request.device_id, request.device_data)
+            .await
+            .is_err());
+    }
+}
@@ -17,7 +17,7 @@ use ruma::{identifiers::Error as IdentifierError, DeviceId, RoomId, UserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
-use super::store::CryptoStoreError;
+use super::{olm::WithheldCode, store::CryptoStoreError};
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -82,6 +82,11 @@ pub enum MegolmError {
     #[error("decryption failed because the session to decrypt the message is missing")]
     MissingSession,
 
+    /// Decryption failed because the sender refused to share the session
+    /// needed to decrypt the event with us.
+    #[error("decryption failed because the session to decrypt the message was withheld: {0}")]
+    Withheld(WithheldCode),
+
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
     OlmGroupSession(#[from] OlmGroupSessionError),
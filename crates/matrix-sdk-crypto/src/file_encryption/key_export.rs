@@ -12,7 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::{
+    collections::BTreeSet,
+    io::{
+        BufRead, BufReader, Cursor, Error as IoError, ErrorKind, Read, Seek, SeekFrom, Take, Write,
+    },
+};
 
 use aes::{
     cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
@@ -22,12 +27,15 @@ use byteorder::{BigEndian, ReadBytesExt};
 use getrandom::getrandom;
 use hmac::{Hmac, Mac, NewMac};
 use pbkdf2::pbkdf2;
+use ruma::{MilliSecondsSinceUnixEpoch, RoomId};
+use serde::{de::Error as _, Deserialize};
 use serde_json::Error as SerdeError;
 use sha2::{Sha256, Sha512};
 use thiserror::Error;
 
 use crate::{
-    olm::ExportedRoomKey,
+    olm::{ExportedRoomKey, InboundGroupSession},
+    store::CryptoStoreError,
     utilities::{decode, encode, DecodeError},
 };
 
@@ -36,6 +44,12 @@ const IV_SIZE: usize = 16;
 const MAC_SIZE: usize = 32;
 const KEY_SIZE: usize = 32;
 const VERSION: u8 = 1;
+/// The size of the unencrypted header of the payload, that is the version, the
+/// salt, the IV and the number of PBKDF2 rounds.
+const PAYLOAD_HEADER_SIZE: usize = 1 + SALT_SIZE + IV_SIZE + 4;
+/// The number of payload bytes a streamed key export puts on a single line, 96
+/// bytes turn into 128 base64 characters without any padding.
+const LINE_BYTES: usize = 96;
 
 const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
 const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
@@ -64,6 +78,9 @@ pub enum KeyExportError {
     /// The key export doesn't all the required fields.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// The exported room keys couldn't be loaded from or saved to the store.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
 }
 
 /// Try to decrypt a reader into a list of exported room keys.
@@ -147,6 +164,555 @@ pub fn encrypt_key_export(
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
+/// A filter selecting which room keys should be exported.
+///
+/// An empty filter matches every room key, every added condition narrows the
+/// selection down further.
+///
+/// # Examples
+/// ```no_run
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportFilter};
+/// # use ruma::{room_id, user_id, MilliSecondsSinceUnixEpoch};
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+/// # block_on(async {
+/// # let last_week = MilliSecondsSinceUnixEpoch::now();
+/// let filter = KeyExportFilter::new()
+///     .room(room_id!("!test:localhost"))
+///     .created_after(last_week);
+///
+/// let exported_keys = machine.export_keys_with_filter(&filter).await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KeyExportFilter {
+    room_ids: BTreeSet<RoomId>,
+    sender_keys: BTreeSet<String>,
+    created_after: Option<MilliSecondsSinceUnixEpoch>,
+    created_before: Option<MilliSecondsSinceUnixEpoch>,
+    min_first_known_index: Option<u32>,
+}
+
+impl KeyExportFilter {
+    /// Create a new filter that matches every room key.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only export room keys that are used in the given room.
+    ///
+    /// Can be called multiple times, room keys from any of the given rooms
+    /// will be exported.
+    pub fn room(mut self, room_id: RoomId) -> Self {
+        self.room_ids.insert(room_id);
+        self
+    }
+
+    /// Only export room keys that were sent by a device with the given
+    /// Curve25519 key.
+    ///
+    /// Can be called multiple times, room keys from any of the given devices
+    /// will be exported.
+    pub fn sender_key(mut self, sender_key: impl Into<String>) -> Self {
+        self.sender_keys.insert(sender_key.into());
+        self
+    }
+
+    /// Only export room keys that were created at or after the given time.
+    ///
+    /// The creation time is only known for room keys that were directly sent
+    /// to us, imported and forwarded room keys won't match.
+    pub fn created_after(mut self, time: MilliSecondsSinceUnixEpoch) -> Self {
+        self.created_after = Some(time);
+        self
+    }
+
+    /// Only export room keys that were created before the given time.
+    ///
+    /// The creation time is only known for room keys that were directly sent
+    /// to us, imported and forwarded room keys won't match.
+    pub fn created_before(mut self, time: MilliSecondsSinceUnixEpoch) -> Self {
+        self.created_before = Some(time);
+        self
+    }
+
+    /// Only export room keys with a first known message index that is at least
+    /// the given index.
+    pub fn min_first_known_index(mut self, index: u32) -> Self {
+        self.min_first_known_index = Some(index);
+        self
+    }
+
+    /// Get the rooms this filter is restricted to, an empty set means that
+    /// room keys from all rooms match.
+    pub fn room_ids(&self) -> &BTreeSet<RoomId> {
+        &self.room_ids
+    }
+
+    /// Check if the given session matches this filter.
+    pub fn matches(&self, session: &InboundGroupSession) -> bool {
+        let in_time_window = match (self.created_after, self.created_before) {
+            (None, None) => true,
+            (after, before) => session.creation_time().map_or(false, |time| {
+                after.map_or(true, |after| time >= after)
+                    && before.map_or(true, |before| time < before)
+            }),
+        };
+
+        (self.room_ids.is_empty() || self.room_ids.contains(session.room_id()))
+            && (self.sender_keys.is_empty() || self.sender_keys.contains(session.sender_key()))
+            && self.min_first_known_index.map_or(true, |i| session.first_known_index() >= i)
+            && in_time_window
+    }
+}
+
+/// A writer that encrypts room keys into a key export one room key at a time.
+///
+/// Unlike [`encrypt_key_export()`] the writer never holds more than a single
+/// serialized room key in memory, which makes it suitable for very large key
+/// exports. The produced key export can be decrypted using
+/// [`decrypt_key_export()`] or a [`KeyExportReader`].
+///
+/// # Examples
+/// ```no_run
+/// # use std::{fs::File, io::BufWriter};
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportWriter};
+/// # use ruma::user_id;
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+/// # block_on(async {
+/// let file = BufWriter::new(File::create("/home/example/e2e-keys.txt").unwrap());
+/// let mut writer = KeyExportWriter::new(file, "1234", 100_000).unwrap();
+///
+/// machine.export_keys_stream(|_| true, &mut writer, |_, _| {}).await.unwrap();
+/// writer.finish().unwrap();
+/// # });
+/// ```
+pub struct KeyExportWriter<W: Write> {
+    inner: W,
+    aes: Aes256Ctr,
+    hmac: Hmac<Sha256>,
+    buffer: Vec<u8>,
+    exported_keys: usize,
+}
+
+impl<W: Write> std::fmt::Debug for KeyExportWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExportWriter")
+            .field("exported_keys", &self.exported_keys)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> KeyExportWriter<W> {
+    /// Create a new key export writer.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The writer that will receive the encrypted key export.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// exported room keys.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation, see [`encrypt_key_export()`] for the details.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    pub fn new(mut inner: W, passphrase: &str, rounds: u32) -> Result<Self, KeyExportError> {
+        let mut salt = [0u8; SALT_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+        let mut derived_keys = [0u8; KEY_SIZE * 2];
+
+        getrandom(&mut salt).expect("Can't generate randomness");
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        let mut iv = u128::from_be_bytes(iv);
+        iv &= !(1 << 63);
+        let iv = iv.to_be_bytes();
+
+        pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, &mut derived_keys);
+        let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+
+        let aes = Aes256::new(GenericArray::from_slice(key));
+        let aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        let mut hmac = Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create HMAC object");
+
+        let mut buffer = Vec::with_capacity(LINE_BYTES * 2);
+        buffer.extend(&VERSION.to_be_bytes());
+        buffer.extend(&salt);
+        buffer.extend(&iv);
+        buffer.extend(&rounds.to_be_bytes());
+        hmac.update(&buffer);
+
+        inner.write_all(HEADER.as_bytes())?;
+        inner.write_all(b"\n")?;
+
+        let mut writer = Self { inner, aes, hmac, buffer, exported_keys: 0 };
+        writer.write_encrypted(b"[".to_vec())?;
+
+        Ok(writer)
+    }
+
+    /// Get the number of room keys that were written to the key export so far.
+    pub fn exported_keys(&self) -> usize {
+        self.exported_keys
+    }
+
+    /// Encrypt the given room key and append it to the key export.
+    pub fn write_key(&mut self, key: &ExportedRoomKey) -> Result<(), KeyExportError> {
+        let mut plaintext = if self.exported_keys == 0 { Vec::new() } else { vec![b','] };
+        serde_json::to_writer(&mut plaintext, key)?;
+
+        self.write_encrypted(plaintext)?;
+        self.exported_keys += 1;
+
+        Ok(())
+    }
+
+    /// Finish the key export.
+    ///
+    /// This writes out the MAC of the encrypted payload and the footer of the
+    /// key export, the key export is incomplete and can't be decrypted if this
+    /// isn't called.
+    ///
+    /// Returns the inner writer after it has been flushed.
+    pub fn finish(mut self) -> Result<W, KeyExportError> {
+        self.write_encrypted(b"]".to_vec())?;
+
+        let mac = self.hmac.clone().finalize();
+        self.buffer.extend(mac.into_bytes());
+        self.write_lines(true)?;
+
+        self.inner.write_all(FOOTER.as_bytes())?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+
+    fn write_encrypted(&mut self, mut plaintext: Vec<u8>) -> Result<(), IoError> {
+        self.aes.apply_keystream(&mut plaintext);
+        self.hmac.update(&plaintext);
+        self.buffer.extend(plaintext);
+
+        self.write_lines(false)
+    }
+
+    fn write_lines(&mut self, all: bool) -> Result<(), IoError> {
+        let end = if all {
+            self.buffer.len()
+        } else {
+            self.buffer.len() - self.buffer.len() % LINE_BYTES
+        };
+
+        for line in self.buffer[..end].chunks(LINE_BYTES) {
+            self.inner.write_all(encode(line).as_bytes())?;
+            self.inner.write_all(b"\n")?;
+        }
+
+        self.buffer.drain(..end);
+
+        Ok(())
+    }
+}
+
+/// A reader that decodes the base64 encoded payload of an armored key export.
+struct PayloadReader<R: BufRead> {
+    inner: R,
+    line: String,
+    encoded: Vec<u8>,
+    decoded: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: BufRead> PayloadReader<R> {
+    fn new(mut inner: R) -> Result<Self, KeyExportError> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            if inner.read_line(&mut line)? == 0 {
+                return Err(KeyExportError::InvalidHeaders);
+            }
+
+            let trimmed = line.trim();
+
+            if trimmed.starts_with(HEADER) {
+                break;
+            } else if !trimmed.is_empty() {
+                return Err(KeyExportError::InvalidHeaders);
+            }
+        }
+
+        Ok(Self {
+            inner,
+            line,
+            encoded: Vec::new(),
+            decoded: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn fill_decoded(&mut self) -> Result<(), IoError> {
+        self.decoded.clear();
+        self.position = 0;
+
+        while self.decoded.is_empty() && !self.finished {
+            self.line.clear();
+
+            if self.inner.read_line(&mut self.line)? == 0 {
+                return Err(IoError::new(
+                    ErrorKind::UnexpectedEof,
+                    "The key export is missing its footer",
+                ));
+            }
+
+            let line = self.line.trim();
+
+            // Only decode whole groups of four base64 characters, the rest is
+            // kept around until we get the next line.
+            let end = if line.starts_with(FOOTER) {
+                self.finished = true;
+                self.encoded.len()
+            } else {
+                self.encoded.extend(line.as_bytes());
+                self.encoded.len() - self.encoded.len() % 4
+            };
+
+            self.decoded = decode(&self.encoded[..end])
+                .map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+            self.encoded.drain(..end);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for PayloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.position >= self.decoded.len() {
+            self.fill_decoded()?;
+        }
+
+        let available = &self.decoded[self.position..];
+        let len = available.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+/// A reader that decrypts the ciphertext of a key export as it's being read.
+struct DecryptingReader<R: Read> {
+    inner: R,
+    aes: Aes256Ctr,
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let read = self.inner.read(buf)?;
+        self.aes.apply_keystream(&mut buf[..read]);
+
+        Ok(read)
+    }
+}
+
+type PlaintextReader<R> = BufReader<DecryptingReader<Take<PayloadReader<BufReader<R>>>>>;
+
+/// A reader that decrypts a key export one room key at a time.
+///
+/// Unlike [`decrypt_key_export()`] the reader never holds the whole decrypted
+/// key export in memory, the room keys are decrypted and deserialized as the
+/// reader is iterated over. This makes it suitable for very large key exports.
+///
+/// The MAC of the key export is checked before any room key is returned, this
+/// requires the input to be read twice, hence the `Seek` requirement.
+///
+/// # Examples
+/// ```no_run
+/// # use std::fs::File;
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportReader};
+/// # use ruma::user_id;
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+/// # block_on(async {
+/// let file = File::open("/home/example/e2e-keys.txt").unwrap();
+/// let reader = KeyExportReader::new(file, "1234").unwrap();
+///
+/// machine.import_keys_stream(reader, |_| {}).await.unwrap();
+/// # });
+/// ```
+pub struct KeyExportReader<R: Read + Seek> {
+    reader: PlaintextReader<R>,
+    first: bool,
+    done: bool,
+}
+
+impl<R: Read + Seek> std::fmt::Debug for KeyExportReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExportReader").field("done", &self.done).finish_non_exhaustive()
+    }
+}
+
+impl<R: Read + Seek> KeyExportReader<R> {
+    /// Open the given key export for reading.
+    ///
+    /// This reads through the whole input once to verify the MAC of the key
+    /// export, the room keys are only decrypted once the reader is iterated
+    /// over.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The key export, starting at the current position of the
+    /// input.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the exported
+    /// keys.
+    pub fn new(mut input: R, passphrase: &str) -> Result<Self, KeyExportError> {
+        let start = input.stream_position()?;
+        let mut payload = PayloadReader::new(BufReader::new(input))?;
+
+        let mut header = [0u8; PAYLOAD_HEADER_SIZE];
+        payload.read_exact(&mut header)?;
+
+        let version = header[0];
+        let salt = &header[1..SALT_SIZE + 1];
+        let iv = &header[SALT_SIZE + 1..SALT_SIZE + IV_SIZE + 1];
+        let rounds = (&header[SALT_SIZE + IV_SIZE + 1..]).read_u32::<BigEndian>()?;
+
+        if version != VERSION {
+            return Err(KeyExportError::UnsupportedVersion);
+        }
+
+        let mut derived_keys = [0u8; KEY_SIZE * 2];
+        pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, rounds, &mut derived_keys);
+        let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(hmac_key).expect("Can't create an HMAC object");
+        hmac.update(&header);
+
+        // The MAC is at the end of the payload, hold back the last couple of
+        // bytes until we know that we reached the end.
+        let mut buffer = [0u8; 8192];
+        let mut pending = Vec::with_capacity(buffer.len() + MAC_SIZE);
+        let mut ciphertext_length = 0;
+
+        loop {
+            let read = payload.read(&mut buffer)?;
+
+            if read == 0 {
+                break;
+            }
+
+            pending.extend_from_slice(&buffer[..read]);
+
+            if pending.len() > MAC_SIZE {
+                let end = pending.len() - MAC_SIZE;
+
+                hmac.update(&pending[..end]);
+                ciphertext_length += end as u64;
+                pending.drain(..end);
+            }
+        }
+
+        hmac.verify(&pending).map_err(|_| KeyExportError::InvalidMac)?;
+
+        let mut input = payload.into_inner();
+        input.seek(SeekFrom::Start(start))?;
+
+        let mut payload = PayloadReader::new(input)?;
+        payload.read_exact(&mut [0u8; PAYLOAD_HEADER_SIZE])?;
+
+        let aes = Aes256::new(GenericArray::from_slice(key));
+        let aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(iv));
+        let mut reader =
+            BufReader::new(DecryptingReader { inner: payload.take(ciphertext_length), aes });
+
+        if peek_non_whitespace(&mut reader)? != Some(b'[') {
+            return Err(invalid_json("the key export doesn't contain a list of room keys"));
+        }
+
+        reader.consume(1);
+
+        Ok(Self { reader, first: true, done: false })
+    }
+
+    fn read_key(&mut self) -> Result<Option<ExportedRoomKey>, KeyExportError> {
+        match (peek_non_whitespace(&mut self.reader)?, self.first) {
+            (Some(b']'), _) => {
+                self.reader.consume(1);
+                return Ok(None);
+            }
+            (Some(b','), false) => self.reader.consume(1),
+            (Some(_), true) => (),
+            _ => return Err(invalid_json("malformed list of room keys")),
+        }
+
+        self.first = false;
+
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut self.reader);
+
+        Ok(Some(ExportedRoomKey::deserialize(&mut deserializer)?))
+    }
+}
+
+impl<R: Read + Seek> Iterator for KeyExportReader<R> {
+    type Item = Result<ExportedRoomKey, KeyExportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let key = self.read_key().transpose();
+
+        if !matches!(key, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        key
+    }
+}
+
+fn peek_non_whitespace(reader: &mut impl BufRead) -> Result<Option<u8>, IoError> {
+    loop {
+        let buffer = reader.fill_buf()?;
+
+        match buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(position) => {
+                let byte = buffer[position];
+                reader.consume(position);
+
+                return Ok(Some(byte));
+            }
+            None if buffer.is_empty() => return Ok(None),
+            None => {
+                let len = buffer.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+fn invalid_json(message: &str) -> KeyExportError {
+    KeyExportError::Json(SerdeError::custom(message))
+}
+
 fn encrypt_helper(plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
@@ -240,9 +806,12 @@ mod test {
     use indoc::indoc;
     use matrix_sdk_test::async_test;
     use proptest::prelude::*;
-    use ruma::room_id;
+    use ruma::{room_id, uint, MilliSecondsSinceUnixEpoch};
 
-    use super::{decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export};
+    use super::{
+        decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export,
+        KeyExportError, KeyExportFilter, KeyExportReader, KeyExportWriter,
+    };
     use crate::machine::test::get_prepared_machine;
 
     const PASSPHRASE: &str = "1234";
@@ -320,4 +889,78 @@ mod test {
         let imported = decrypt_key_export(reader, PASSPHRASE).expect("Can't decrypt key export");
         assert!(!imported.is_empty())
     }
+
+    #[test]
+    fn test_real_streaming_decrypt() {
+        let reader = KeyExportReader::new(Cursor::new(TEST_EXPORT), PASSPHRASE)
+            .expect("Can't open the key export");
+        let imported: Vec<_> = reader.collect::<Result<_, _>>().expect("Can't decrypt key export");
+
+        assert_eq!(imported, decrypt_key_export(Cursor::new(TEST_EXPORT), PASSPHRASE).unwrap());
+    }
+
+    #[test]
+    fn test_streaming_decrypt_invalid_passphrase() {
+        let result = KeyExportReader::new(Cursor::new(TEST_EXPORT), "wrong passphrase");
+        assert!(matches!(result, Err(KeyExportError::InvalidMac)));
+    }
+
+    #[async_test]
+    async fn test_streaming_session_export() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+
+        let mut writer = KeyExportWriter::new(Vec::new(), PASSPHRASE, 1).unwrap();
+        let exported = machine
+            .export_keys_stream(|s| s.room_id() == &room_id, &mut writer, |_, _| {})
+            .await
+            .unwrap();
+        let encrypted = writer.finish().unwrap();
+
+        assert_eq!(exported, 1);
+        assert_eq!(export, decrypt_key_export(Cursor::new(&encrypted), PASSPHRASE).unwrap());
+
+        let reader = KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        let streamed: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(export, streamed);
+
+        let reader = KeyExportReader::new(Cursor::new(&encrypted), PASSPHRASE).unwrap();
+        assert_eq!(machine.import_keys_stream(reader, |_| {}).await.unwrap(), (0, 1));
+    }
+
+    #[async_test]
+    async fn test_export_with_filter() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+        let other_room_id = room_id!("!test2:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        machine.create_outbound_group_session_with_defaults(&other_room_id).await.unwrap();
+
+        let all = machine.export_keys_with_filter(&KeyExportFilter::new()).await.unwrap();
+        assert_eq!(all.len(), 2);
+
+        let filter = KeyExportFilter::new().room(room_id.clone());
+        let export = machine.export_keys_with_filter(&filter).await.unwrap();
+        assert_eq!(export.len(), 1);
+        assert_eq!(export[0].room_id, room_id);
+
+        let filter = KeyExportFilter::new().sender_key(export[0].sender_key.clone());
+        assert_eq!(machine.export_keys_with_filter(&filter).await.unwrap().len(), 2);
+
+        let filter = KeyExportFilter::new().sender_key("unknown sender key");
+        assert!(machine.export_keys_with_filter(&filter).await.unwrap().is_empty());
+
+        let filter = KeyExportFilter::new().created_after(MilliSecondsSinceUnixEpoch(uint!(0)));
+        assert_eq!(machine.export_keys_with_filter(&filter).await.unwrap().len(), 2);
+
+        let filter = KeyExportFilter::new().created_before(MilliSecondsSinceUnixEpoch(uint!(0)));
+        assert!(machine.export_keys_with_filter(&filter).await.unwrap().is_empty());
+
+        let filter = KeyExportFilter::new().min_first_known_index(1);
+        assert!(machine.export_keys_with_filter(&filter).await.unwrap().is_empty());
+    }
 }
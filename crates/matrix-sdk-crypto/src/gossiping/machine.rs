@@ -20,7 +20,11 @@
 // If we don't trust the device store an object that remembers the request and
 // let the users introspect that object.
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    iter,
+    sync::Arc,
+};
 
 use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use matrix_sdk_common::uuid::Uuid;
@@ -36,22 +40,44 @@ use ruma::{
                 ToDeviceSendEventContent as SecretSendEventContent,
             },
         },
-        AnyToDeviceEvent, AnyToDeviceEventContent,
+        AnyToDeviceEvent, AnyToDeviceEventContent, EventType,
     },
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, UserId,
+    to_device::DeviceIdOrAllDevices,
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, EventId, RoomId, UserId,
 };
 use tracing::{debug, info, trace, warn};
 
 use super::{GossipRequest, KeyForwardDecision, RequestEvent, RequestInfo, SecretInfo, WaitQueue};
 use crate::{
     error::{OlmError, OlmResult},
-    olm::{InboundGroupSession, Session, ShareState},
+    olm::{
+        InboundGroupSession, RoomKeyWithheldContent, Session, ShareState, WithheldCode,
+        WITHHELD_EVENT_TYPE,
+    },
     requests::{OutgoingRequest, ToDeviceRequest},
     session_manager::GroupSessionCache,
     store::{Changes, CryptoStoreError, SecretImportError, Store},
     Device,
 };
 
+/// The maximal number of undecryptable Megolm sessions we remember per sender,
+/// the oldest session is forgotten once this limit is reached.
+const MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER: usize = 100;
+
+/// The maximal number of undecryptable events we remember per Megolm session,
+/// the oldest event is forgotten once this limit is reached.
+const MAX_UNDECRYPTABLE_EVENTS_PER_SESSION: usize = 100;
+
+/// A Megolm session we failed to decrypt events with because we never
+/// received it.
+#[derive(Debug)]
+struct UndecryptableSession {
+    room_id: RoomId,
+    session_id: String,
+    /// The events we failed to decrypt, oldest first.
+    event_ids: VecDeque<EventId>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct GossipMachine {
     user_id: Arc<UserId>,
@@ -62,6 +88,14 @@ pub(crate) struct GossipMachine {
     incoming_key_requests: Arc<DashMap<RequestInfo, RequestEvent>>,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    /// Megolm sessions we failed to decrypt events with because we never
+    /// received them, grouped by the Curve25519 key of the sender, oldest
+    /// first.
+    undecryptable_sessions: Arc<DashMap<String, VecDeque<UndecryptableSession>>>,
+    /// Megolm sessions we re-requested after we recovered from a wedged Olm
+    /// session with the sender, together with the events that we failed to
+    /// decrypt with them. Events are removed once they get decrypted.
+    rerequested_sessions: Arc<DashMap<(RoomId, String, String), BTreeSet<EventId>>>,
 }
 
 impl GossipMachine {
@@ -81,6 +115,8 @@ impl GossipMachine {
             incoming_key_requests: Default::default(),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            undecryptable_sessions: Default::default(),
+            rerequested_sessions: Default::default(),
         }
     }
 
@@ -381,6 +417,10 @@ impl GossipMachine {
                         );
                     }
 
+                    if let Some(code) = e.withheld_code() {
+                        self.withhold_session(&session, &device, code);
+                    }
+
                     Ok(None)
                 }
                 Ok(message_index) => {
@@ -466,6 +506,37 @@ impl GossipMachine {
         Ok(used_session)
     }
 
+    /// Send a `m.room_key.withheld` notice to the given device, letting it know
+    /// why we refused to share the session with it.
+    fn withhold_session(&self, session: &InboundGroupSession, device: &Device, code: WithheldCode) {
+        let content = RoomKeyWithheldContent::new(
+            session.room_id(),
+            session.session_id(),
+            session.sender_key(),
+            code,
+        );
+
+        let messages = iter::once((
+            device.user_id().to_owned(),
+            iter::once((
+                DeviceIdOrAllDevices::DeviceId(device.device_id().to_owned()),
+                content.to_raw(),
+            ))
+            .collect(),
+        ))
+        .collect();
+
+        let request = ToDeviceRequest {
+            event_type: EventType::from(WITHHELD_EVENT_TYPE),
+            txn_id: Uuid::new_v4(),
+            messages,
+        };
+
+        let request =
+            OutgoingRequest { request_id: request.txn_id, request: Arc::new(request.into()) };
+        self.outgoing_requests.insert(request.request_id, request);
+    }
+
     /// Check if it's ok to share a session with the given device.
     ///
     /// The logic for this is currently as follows:
@@ -677,6 +748,149 @@ impl GossipMachine {
         Ok(())
     }
 
+    /// Remember that we couldn't decrypt an event because the Megolm session
+    /// with the given session id is missing.
+    ///
+    /// The room key will be requested again once we recover from a wedged Olm
+    /// session with the sender, see [`rerequest_undecryptable_sessions()`].
+    /// Only up to 100 sessions per sender and 100 events per session are
+    /// remembered, the oldest ones are forgotten first.
+    ///
+    /// [`rerequest_undecryptable_sessions()`]: #method.rerequest_undecryptable_sessions
+    pub fn mark_session_as_undecryptable(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        event_id: &EventId,
+    ) {
+        let mut sessions =
+            self.undecryptable_sessions.entry(sender_key.to_owned()).or_insert_with(VecDeque::new);
+
+        let position =
+            sessions.iter().position(|s| &s.room_id == room_id && s.session_id == session_id);
+
+        let session = if let Some(position) = position {
+            &mut sessions[position]
+        } else {
+            if sessions.len() >= MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER {
+                if let Some(oldest) = sessions.pop_front() {
+                    debug!(
+                        room_id = oldest.room_id.as_str(),
+                        sender_key,
+                        session_id = oldest.session_id.as_str(),
+                        "Too many undecryptable sessions, forgetting the oldest one"
+                    );
+                }
+            }
+
+            sessions.push_back(UndecryptableSession {
+                room_id: room_id.to_owned(),
+                session_id: session_id.to_owned(),
+                event_ids: VecDeque::new(),
+            });
+
+            sessions.back_mut().expect("We just pushed a session")
+        };
+
+        if !session.event_ids.contains(event_id) {
+            if session.event_ids.len() >= MAX_UNDECRYPTABLE_EVENTS_PER_SESSION {
+                session.event_ids.pop_front();
+            }
+
+            session.event_ids.push_back(event_id.to_owned());
+        }
+    }
+
+    /// Re-request all the room keys we're missing from the sender with the
+    /// given Curve25519 key.
+    ///
+    /// This should be called after a wedged Olm session with the sender has
+    /// been replaced, the room keys might have been lost because the sender
+    /// encrypted them for the wedged session. Key requests that were already
+    /// sent out are cancelled and sent out again.
+    pub async fn rerequest_undecryptable_sessions(
+        &self,
+        sender_key: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let sessions = if let Some((_, s)) = self.undecryptable_sessions.remove(sender_key) {
+            s
+        } else {
+            return Ok(());
+        };
+
+        for UndecryptableSession { room_id, session_id, event_ids } in sessions {
+            // The room key might have arrived in the meantime.
+            if self
+                .store
+                .get_inbound_group_session(&room_id, sender_key, &session_id)
+                .await?
+                .is_some()
+            {
+                continue;
+            }
+
+            let key_info = RequestedKeyInfo::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                room_id.clone(),
+                sender_key.to_owned(),
+                session_id.clone(),
+            )
+            .into();
+
+            if let Some(request) = self.store.get_secret_request_by_info(&key_info).await? {
+                if request.sent_out {
+                    let cancel = request.to_cancellation(self.device_id());
+                    self.outgoing_requests.insert(cancel.request_id, cancel);
+                }
+
+                self.delete_key_info(&request).await?;
+            }
+
+            info!(
+                room_id = room_id.as_str(),
+                sender_key,
+                session_id = session_id.as_str(),
+                "Re-requesting a room key after recovering from a wedged Olm session"
+            );
+
+            self.create_outgoing_key_request(&room_id, sender_key, &session_id).await?;
+            self.rerequested_sessions
+                .entry((room_id, sender_key.to_owned(), session_id))
+                .or_insert_with(BTreeSet::new)
+                .extend(event_ids);
+        }
+
+        Ok(())
+    }
+
+    /// Did we fail to decrypt the given event because its room key was lost
+    /// in a wedged Olm session, the room key was re-requested after we
+    /// recovered from the wedged Olm session with the sender.
+    ///
+    /// The event is forgotten afterwards, this should only be called once the
+    /// event has been decrypted. The session is forgotten once all of its
+    /// undecryptable events have been decrypted.
+    pub fn take_recovered_event(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+        event_id: &EventId,
+    ) -> bool {
+        let key = (room_id.to_owned(), sender_key.to_owned(), session_id.to_owned());
+
+        let recovered = if let Some(mut event_ids) = self.rerequested_sessions.get_mut(&key) {
+            event_ids.remove(event_id)
+        } else {
+            return false;
+        };
+
+        self.rerequested_sessions.remove_if(&key, |_, event_ids| event_ids.is_empty());
+
+        recovered
+    }
+
     /// Save an outgoing key info.
     async fn save_outgoing_key_info(&self, info: GossipRequest) -> Result<(), CryptoStoreError> {
         let mut changes = Changes::default();
@@ -897,29 +1111,39 @@ impl GossipMachine {
 
 #[cfg(test)]
 mod test {
-    use std::{convert::TryInto, sync::Arc};
+    use std::{
+        convert::{TryFrom, TryInto},
+        sync::Arc,
+    };
 
     use dashmap::DashMap;
     use matches::assert_matches;
     use matrix_sdk_common::locks::Mutex;
     use matrix_sdk_test::async_test;
     use ruma::{
+        event_id,
         events::{
             forwarded_room_key::ToDeviceForwardedRoomKeyEventContent,
             room::encrypted::ToDeviceEncryptedEventContent,
             room_key_request::ToDeviceRoomKeyRequestEventContent,
             secret::request::{RequestAction, SecretName, ToDeviceRequestEventContent},
-            AnyToDeviceEvent, ToDeviceEvent,
+            AnyToDeviceEvent, EventType, ToDeviceEvent,
         },
         room_id,
         to_device::DeviceIdOrAllDevices,
-        user_id, DeviceIdBox, DeviceKeyAlgorithm, RoomId, UserId,
+        user_id, DeviceIdBox, DeviceKeyAlgorithm, EventId, RoomId, UserId,
     };
 
-    use super::{GossipMachine, KeyForwardDecision};
+    use super::{
+        GossipMachine, KeyForwardDecision, MAX_UNDECRYPTABLE_EVENTS_PER_SESSION,
+        MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER,
+    };
     use crate::{
         identities::{LocalTrust, ReadOnlyDevice},
-        olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
+        olm::{
+            Account, PrivateCrossSigningIdentity, ReadOnlyAccount, RoomKeyWithheldContent,
+            WithheldCode, WITHHELD_EVENT_TYPE,
+        },
         session_manager::GroupSessionCache,
         store::{Changes, CryptoStore, MemoryStore, Store},
         verification::VerificationMachine,
@@ -1082,6 +1306,142 @@ mod test {
         assert!(machine.outgoing_to_device_requests().await.unwrap().is_empty());
     }
 
+    #[async_test]
+    async fn rerequest_undecryptable_sessions() {
+        let machine = get_machine().await;
+        let account = account();
+        let second_account = alice_2_account();
+        let alice_device = ReadOnlyDevice::from_account(&second_account).await;
+
+        // We need a trusted device, otherwise we won't request keys
+        alice_device.set_trust_state(LocalTrust::Verified);
+        machine.store.save_devices(&[alice_device]).await.unwrap();
+
+        let (_, session) =
+            account.create_group_session_pair_with_defaults(&room_id()).await.unwrap();
+
+        machine
+            .create_outgoing_key_request(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        let first_event_id = event_id!("$first");
+        let second_event_id = event_id!("$second");
+
+        for event_id in &[&first_event_id, &second_event_id] {
+            machine.mark_session_as_undecryptable(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+                event_id,
+            );
+        }
+
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        let old_request_id = requests[0].request_id;
+        machine.mark_outgoing_request_as_sent(old_request_id).await.unwrap();
+        assert!(machine.outgoing_to_device_requests().await.unwrap().is_empty());
+
+        // Undecryptable sessions from other senders are left alone.
+        machine.rerequest_undecryptable_sessions("other_sender_key").await.unwrap();
+        assert!(machine.outgoing_to_device_requests().await.unwrap().is_empty());
+
+        machine.rerequest_undecryptable_sessions(&session.sender_key).await.unwrap();
+        assert!(machine.rerequested_sessions.contains_key(&(
+            session.room_id().to_owned(),
+            session.sender_key.clone(),
+            session.session_id().to_owned()
+        )));
+
+        // The old request gets cancelled and a new one is sent out.
+        let requests = machine.outgoing_to_device_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.request_id != old_request_id));
+
+        for request in requests {
+            machine.mark_outgoing_request_as_sent(request.request_id).await.unwrap();
+        }
+
+        // The session is only re-requested once.
+        machine.rerequest_undecryptable_sessions(&session.sender_key).await.unwrap();
+        assert!(machine.outgoing_to_device_requests().await.unwrap().is_empty());
+
+        // Every event that we failed to decrypt is reported as recovered
+        // once, events we never failed to decrypt aren't.
+        for event_id in &[&first_event_id, &second_event_id] {
+            assert!(machine.take_recovered_event(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+                event_id,
+            ));
+            assert!(!machine.take_recovered_event(
+                session.room_id(),
+                &session.sender_key,
+                session.session_id(),
+                event_id,
+            ));
+        }
+
+        assert!(!machine.take_recovered_event(
+            session.room_id(),
+            &session.sender_key,
+            session.session_id(),
+            &event_id!("$third"),
+        ));
+
+        // The session is forgotten once all of its events were recovered.
+        assert!(machine.rerequested_sessions.is_empty());
+    }
+
+    #[async_test]
+    async fn undecryptable_sessions_are_bounded() {
+        let machine = get_machine().await;
+        let event_id = event_id!("$event");
+
+        for i in 0..MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER + 10 {
+            machine.mark_session_as_undecryptable(
+                &room_id(),
+                "sender_key",
+                &format!("session_id_{}", i),
+                &event_id,
+            );
+        }
+
+        // Other senders have their own limit.
+        machine.mark_session_as_undecryptable(&room_id(), "other_key", "session_id", &event_id);
+        assert_eq!(machine.undecryptable_sessions.get("other_key").unwrap().len(), 1);
+
+        // The oldest sessions were forgotten.
+        let sessions = machine.undecryptable_sessions.get("sender_key").unwrap();
+        assert_eq!(sessions.len(), MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER);
+        assert_eq!(sessions.front().unwrap().session_id, "session_id_10");
+        assert_eq!(
+            sessions.back().unwrap().session_id,
+            format!("session_id_{}", MAX_UNDECRYPTABLE_SESSIONS_PER_SENDER + 9)
+        );
+        drop(sessions);
+
+        for i in 0..MAX_UNDECRYPTABLE_EVENTS_PER_SESSION + 10 {
+            machine.mark_session_as_undecryptable(
+                &room_id(),
+                "other_key",
+                "session_id",
+                &EventId::try_from(format!("$event_{}", i)).unwrap(),
+            );
+        }
+
+        // The oldest events were forgotten.
+        let sessions = machine.undecryptable_sessions.get("other_key").unwrap();
+        let event_ids = &sessions.front().unwrap().event_ids;
+        assert_eq!(event_ids.len(), MAX_UNDECRYPTABLE_EVENTS_PER_SESSION);
+        assert_eq!(event_ids.front().unwrap().as_str(), "$event_10");
+    }
+
     #[async_test]
     async fn receive_forwarded_key() {
         let machine = get_machine().await;
@@ -1437,6 +1797,90 @@ mod test {
         assert_eq!(session.session_id(), group_session.session_id())
     }
 
+    #[async_test]
+    async fn key_share_withheld() {
+        let alice_machine = get_machine().await;
+        let alice_account = Account { inner: account(), store: alice_machine.store.clone() };
+
+        let bob_machine = bob_machine();
+        let bob_account = bob_account();
+
+        let second_account = alice_2_account();
+        let alice_device = ReadOnlyDevice::from_account(&second_account).await;
+        alice_device.set_trust_state(LocalTrust::Verified);
+        alice_machine.store.save_devices(&[alice_device]).await.unwrap();
+
+        let (alice_session, bob_session) = alice_account.create_session_for(&bob_account).await;
+
+        let alice_device = ReadOnlyDevice::from_account(&alice_account).await;
+        let bob_device = ReadOnlyDevice::from_account(&bob_account).await;
+
+        alice_machine.store.save_sessions(&[alice_session]).await.unwrap();
+        alice_machine.store.save_devices(&[bob_device]).await.unwrap();
+        bob_machine.store.save_sessions(&[bob_session]).await.unwrap();
+        bob_machine.store.save_devices(&[alice_device]).await.unwrap();
+
+        let (group_session, inbound_group_session) =
+            bob_account.create_group_session_pair_with_defaults(&room_id()).await.unwrap();
+
+        bob_machine.store.save_inbound_group_sessions(&[inbound_group_session]).await.unwrap();
+
+        alice_machine
+            .create_outgoing_key_request(
+                &room_id(),
+                bob_account.identity_keys.curve25519(),
+                group_session.session_id(),
+            )
+            .await
+            .unwrap();
+
+        let requests = alice_machine.outgoing_to_device_requests().await.unwrap();
+        let content = requests[0]
+            .request
+            .to_device()
+            .unwrap()
+            .messages
+            .get(&alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::AllDevices)
+            .unwrap();
+        let content: ToDeviceRoomKeyRequestEventContent = content.deserialize_as().unwrap();
+
+        let event = ToDeviceEvent { sender: alice_id(), content };
+
+        // Bob doesn't have an outbound session for the room key, so it's not
+        // his to withhold and he stays silent.
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+        assert!(bob_machine.outgoing_to_device_requests().await.unwrap().is_empty());
+
+        // Bob never shared the session with alice, so he'll refuse the request.
+        bob_machine.outbound_group_sessions.insert(group_session.clone());
+
+        bob_machine.receive_incoming_key_request(&event);
+        bob_machine.collect_incoming_key_requests().await.unwrap();
+
+        // Bob lets alice know why he didn't share the key.
+        let requests = bob_machine.outgoing_to_device_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let request = requests[0].request.to_device().unwrap();
+        assert_eq!(request.event_type, EventType::from(WITHHELD_EVENT_TYPE));
+
+        let content: RoomKeyWithheldContent = request
+            .messages
+            .get(&alice_id())
+            .unwrap()
+            .get(&DeviceIdOrAllDevices::DeviceId(alice_device_id()))
+            .unwrap()
+            .deserialize_as()
+            .unwrap();
+
+        assert_eq!(content.code, WithheldCode::Unauthorised);
+        assert_eq!(content.room_id, Some(room_id()));
+        assert_eq!(content.session_id.as_deref(), Some(group_session.session_id()));
+    }
+
     #[async_test]
     async fn secret_share_cycle() {
         let alice_machine = get_machine().await;
@@ -39,6 +39,7 @@ use thiserror::Error;
 use tracing::error;
 
 use crate::{
+    olm::WithheldCode,
     requests::{OutgoingRequest, ToDeviceRequest},
     Device,
 };
@@ -63,6 +64,24 @@ pub enum KeyForwardDecision {
     ChangedSenderKey,
 }
 
+impl KeyForwardDecision {
+    /// The code of the `m.room_key.withheld` notice that tells the requesting
+    /// device why we refused to share the key.
+    ///
+    /// Returns `None` if no notice should be sent. Without an outbound
+    /// session the room key wasn't created by us, so it's not up to us to
+    /// withhold it.
+    pub(crate) fn withheld_code(&self) -> Option<WithheldCode> {
+        match self {
+            KeyForwardDecision::MissingOutboundSession => None,
+            KeyForwardDecision::OutboundSessionNotShared | KeyForwardDecision::ChangedSenderKey => {
+                Some(WithheldCode::Unauthorised)
+            }
+            KeyForwardDecision::UntrustedDevice => Some(WithheldCode::Unverified),
+        }
+    }
+}
+
 /// A struct describing an outgoing key request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GossipRequest {
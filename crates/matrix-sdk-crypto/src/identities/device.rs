@@ -30,7 +30,7 @@ use ruma::{
     events::{
         forwarded_room_key::ToDeviceForwardedRoomKeyEventContent,
         key::verification::VerificationMethod, room::encrypted::ToDeviceEncryptedEventContent,
-        AnyToDeviceEventContent,
+        AnyToDeviceEventContent, EventContent,
     },
     DeviceId, DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, UserId,
 };
@@ -204,6 +204,53 @@ impl Device {
         self.inner.is_cross_signing_trusted(&self.own_identity, &self.device_owner_identity)
     }
 
+    /// Is this device signed by the cross signing identity of its owner.
+    ///
+    /// Unlike [`is_cross_signing_trusted()`] this doesn't require the identity
+    /// of the owner to be verified, it returns false if the owner didn't set
+    /// up cross signing.
+    ///
+    /// [`is_cross_signing_trusted()`]: #method.is_cross_signing_trusted
+    pub fn is_cross_signed_by_owner(&self) -> bool {
+        self.device_owner_identity.as_ref().map_or(false, |identity| match identity {
+            ReadOnlyUserIdentities::Own(i) => i.is_device_signed(&self.inner).is_ok(),
+            ReadOnlyUserIdentities::Other(i) => i.is_device_signed(&self.inner).is_ok(),
+        })
+    }
+
+    /// Is this device signed by the pinned cross signing identity of its
+    /// owner.
+    ///
+    /// This is the check the [`TrustOnFirstUse`] sharing policy uses. Devices
+    /// of owners that didn't set up cross signing are accepted, devices of
+    /// owners whose master key changed since we pinned it are not.
+    ///
+    /// [`TrustOnFirstUse`]: crate::SharingPolicy::TrustOnFirstUse
+    pub(crate) fn is_signed_by_pinned_identity(&self) -> bool {
+        match &self.device_owner_identity {
+            None => true,
+            Some(ReadOnlyUserIdentities::Own(i)) => i.is_device_signed(&self.inner).is_ok(),
+            Some(ReadOnlyUserIdentities::Other(i)) => {
+                !i.has_changed() && i.is_device_signed(&self.inner).is_ok()
+            }
+        }
+    }
+
+    /// Has the identity of the owner of this device changed without the change
+    /// being acknowledged or the new identity being verified.
+    pub(crate) fn has_unacknowledged_identity_change(&self) -> bool {
+        match &self.device_owner_identity {
+            Some(ReadOnlyUserIdentities::Other(identity)) => {
+                identity.has_changed()
+                    && !self
+                        .own_identity
+                        .as_ref()
+                        .map_or(false, |o| o.is_identity_signed(identity).is_ok())
+            }
+            _ => false,
+        }
+    }
+
     /// Manually verify this device.
     ///
     /// This method will attempt to sign the device using our private cross
@@ -262,6 +309,22 @@ impl Device {
         self.inner.encrypt(self.verification_machine.store.inner(), content).await
     }
 
+    /// Encrypt the given raw content, of the given event type, for this
+    /// `Device`.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event that should be encrypted.
+    ///
+    /// * `content` - The JSON content of the event that should be encrypted.
+    pub(crate) async fn encrypt_raw(
+        &self,
+        event_type: &str,
+        content: Value,
+    ) -> OlmResult<(Session, ToDeviceEncryptedEventContent)> {
+        self.inner.encrypt_raw(self.verification_machine.store.inner(), event_type, content).await
+    }
+
     /// Encrypt the given inbound group session as a forwarded room key for this
     /// device.
     pub async fn encrypt_session(
@@ -488,6 +551,18 @@ impl ReadOnlyDevice {
         &self,
         store: &dyn CryptoStore,
         content: AnyToDeviceEventContent,
+    ) -> OlmResult<(Session, ToDeviceEncryptedEventContent)> {
+        let event_type = content.event_type().to_owned();
+        let content = serde_json::to_value(content)?;
+
+        self.encrypt_raw(store, &event_type, content).await
+    }
+
+    pub(crate) async fn encrypt_raw(
+        &self,
+        store: &dyn CryptoStore,
+        event_type: &str,
+        content: Value,
     ) -> OlmResult<(Session, ToDeviceEncryptedEventContent)> {
         let sender_key = if let Some(k) = self.get_key(DeviceKeyAlgorithm::Curve25519) {
             k
@@ -520,7 +595,7 @@ impl ReadOnlyDevice {
             return Err(OlmError::MissingSession);
         };
 
-        let message = session.encrypt(self, content).await?;
+        let message = session.encrypt_raw(self, event_type, content).await?;
 
         Ok((session, message))
     }
@@ -15,11 +15,11 @@
 use std::{
     collections::{BTreeMap, HashSet},
     convert::TryFrom,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
-use futures::future::join_all;
-use matrix_sdk_common::executor::spawn;
+use futures::{future::join_all, Stream};
+use matrix_sdk_common::{executor::spawn, observable::Observers};
 use ruma::{
     api::client::r0::keys::get_keys::Response as KeysQueryResponse, encryption::DeviceKeys,
     DeviceId, DeviceIdBox, UserId,
@@ -29,8 +29,8 @@ use tracing::{info, trace, warn};
 use crate::{
     error::OlmResult,
     identities::{
-        MasterPubkey, ReadOnlyDevice, ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities,
-        ReadOnlyUserIdentity, SelfSigningPubkey, UserSigningPubkey,
+        IdentityChange, MasterPubkey, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
+        ReadOnlyUserIdentities, ReadOnlyUserIdentity, SelfSigningPubkey, UserSigningPubkey,
     },
     olm::PrivateCrossSigningIdentity,
     requests::KeysQueryRequest,
@@ -48,13 +48,38 @@ pub(crate) struct IdentityManager {
     user_id: Arc<UserId>,
     device_id: Arc<DeviceId>,
     store: Store,
+    /// The listeners that want to be notified about identity changes of other
+    /// users.
+    identity_change_listeners: Arc<Mutex<Observers<IdentityChange>>>,
 }
 
 impl IdentityManager {
     const MAX_KEY_QUERY_USERS: usize = 250;
 
     pub fn new(user_id: Arc<UserId>, device_id: Arc<DeviceId>, store: Store) -> Self {
-        IdentityManager { user_id, device_id, store }
+        IdentityManager { user_id, device_id, store, identity_change_listeners: Default::default() }
+    }
+
+    /// Get a stream of identity changes of other users.
+    ///
+    /// An item is yielded every time the master key of another user changes to
+    /// a key that differs from the pinned one.
+    pub fn identity_changes(&self) -> impl Stream<Item = IdentityChange> {
+        self.identity_change_listeners.lock().unwrap().subscribe(None)
+    }
+
+    /// Notify all the listeners about the given identity changes, listeners
+    /// that went away are removed.
+    fn notify_identity_changes(&self, identity_changes: Vec<IdentityChange>) {
+        if identity_changes.is_empty() {
+            return;
+        }
+
+        let mut listeners = self.identity_change_listeners.lock().unwrap();
+
+        for change in identity_changes {
+            listeners.notify(change);
+        }
     }
 
     fn user_id(&self) -> &UserId {
@@ -76,7 +101,7 @@ impl IdentityManager {
     ) -> OlmResult<(DeviceChanges, IdentityChanges)> {
         let changed_devices =
             self.handle_devices_from_key_query(response.device_keys.clone()).await?;
-        let (changed_identities, cross_signing_identity) =
+        let (changed_identities, cross_signing_identity, identity_changes) =
             self.handle_cross_singing_keys(response).await?;
 
         let changes = Changes {
@@ -94,6 +119,8 @@ impl IdentityManager {
             self.store.update_tracked_user(user_id, false).await?;
         }
 
+        self.notify_identity_changes(identity_changes);
+
         Ok((changed_devices, changed_identities))
     }
 
@@ -234,12 +261,17 @@ impl IdentityManager {
     ///
     /// Returns a list of identities that changed. Changed here means either
     /// they are new, one of their properties has changed or they got deleted.
+    ///
+    /// The master key changes of other users that differ from the pinned
+    /// master key are returned as well.
     async fn handle_cross_singing_keys(
         &self,
         response: &KeysQueryResponse,
-    ) -> StoreResult<(IdentityChanges, Option<PrivateCrossSigningIdentity>)> {
+    ) -> StoreResult<(IdentityChanges, Option<PrivateCrossSigningIdentity>, Vec<IdentityChange>)>
+    {
         let mut changes = IdentityChanges::default();
         let mut changed_identity = None;
+        let mut identity_changes = Vec::new();
 
         for (user_id, master_key) in &response.master_keys {
             let master_key = MasterPubkey::from(master_key);
@@ -272,7 +304,26 @@ impl IdentityManager {
                         identity.update(master_key, self_signing, user_signing).map(|_| (i, false))
                     }
                     ReadOnlyUserIdentities::Other(ref mut identity) => {
-                        identity.update(master_key, self_signing).map(|_| (i, false))
+                        let previous_master_key = identity.master_key().clone();
+                        let result = identity.update(master_key, self_signing);
+
+                        if result.is_ok()
+                            && identity.master_key() != &previous_master_key
+                            && identity.has_changed()
+                        {
+                            warn!(
+                                user_id = user_id.as_str(),
+                                "The master key of a user identity has changed"
+                            );
+
+                            identity_changes.push(IdentityChange {
+                                user_id: user_id.to_owned(),
+                                pinned_master_key: identity.pinned_master_key(),
+                                master_key: identity.master_key().clone(),
+                            });
+                        }
+
+                        result.map(|_| (i, false))
                     }
                 }
             } else if user_id == self.user_id() {
@@ -341,7 +392,7 @@ impl IdentityManager {
             }
         }
 
-        Ok((changes, changed_identity))
+        Ok((changes, changed_identity, identity_changes))
     }
 
     /// Get a key query request if one is needed.
@@ -416,18 +467,20 @@ impl IdentityManager {
 pub(crate) mod test {
     use std::sync::Arc;
 
+    use futures::StreamExt;
     use matrix_sdk_common::locks::Mutex;
     use matrix_sdk_test::async_test;
     use ruma::{
         api::{client::r0::keys::get_keys::Response as KeyQueryResponse, IncomingResponse},
+        encryption::CrossSigningKey,
         user_id, DeviceIdBox, UserId,
     };
     use serde_json::json;
 
     use crate::{
-        identities::IdentityManager,
+        identities::{IdentityManager, LocalTrust, ReadOnlyUserIdentity},
         machine::test::response_from_file,
-        olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
+        olm::{PrivateCrossSigningIdentity, ReadOnlyAccount, SharingPolicy, WithheldCode},
         store::{CryptoStore, MemoryStore, Store},
         verification::VerificationMachine,
     };
@@ -668,4 +721,102 @@ pub(crate) mod test {
 
         assert!(identity.is_device_signed(&device).is_ok())
     }
+
+    #[async_test]
+    async fn test_manager_identity_change() {
+        let manager = manager();
+        let other_user = other_user_id();
+        let mut identity_changes = manager.identity_changes();
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        let identity = manager.store.get_user_identity(&other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert!(!identity.has_changed());
+
+        let private_identity = PrivateCrossSigningIdentity::new(other_user.clone()).await;
+        let new_identity = ReadOnlyUserIdentity::from_private(&private_identity).await;
+        let master_key: &CrossSigningKey = new_identity.master_key().as_ref();
+        let self_signing_key: &CrossSigningKey = new_identity.self_signing_key().as_ref();
+
+        let data = response_from_file(&json!({
+            "device_keys": {},
+            "failures": {},
+            "master_keys": { "@example2:localhost": master_key },
+            "self_signing_keys": { "@example2:localhost": self_signing_key },
+        }));
+        let response = KeyQueryResponse::try_from_http_response(data).unwrap();
+
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        let change = identity_changes.next().await.unwrap();
+        assert_eq!(change.user_id, other_user);
+        assert_eq!(&change.pinned_master_key, identity.master_key());
+        assert_eq!(&change.master_key, new_identity.master_key());
+
+        let identity = manager.store.get_user_identity(&other_user).await.unwrap().unwrap();
+        let identity = identity.other().unwrap();
+        assert!(identity.has_changed());
+
+        identity.pin_current_master_key();
+        assert!(!identity.has_changed());
+    }
+
+    #[async_test]
+    async fn test_sharing_policies_with_pinned_identities() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+
+        let device =
+            manager.store.get_device(&other_user, "SKISMLNIMH".into()).await.unwrap().unwrap();
+
+        // The device is signed by the identity we saw first.
+        assert_eq!(SharingPolicy::TrustOnFirstUse.withheld_code(&device), None);
+        assert_eq!(
+            SharingPolicy::CrossSignedOnly.withheld_code(&device),
+            Some(WithheldCode::Unverified)
+        );
+
+        // Trusting the device locally doesn't make it trusted by cross signing.
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+        let device =
+            manager.store.get_device(&other_user, "SKISMLNIMH".into()).await.unwrap().unwrap();
+        assert!(device.verified());
+        assert_eq!(
+            SharingPolicy::CrossSignedOnly.withheld_code(&device),
+            Some(WithheldCode::Unverified)
+        );
+
+        let private_identity = PrivateCrossSigningIdentity::new(other_user.clone()).await;
+        let new_identity = ReadOnlyUserIdentity::from_private(&private_identity).await;
+        let master_key: &CrossSigningKey = new_identity.master_key().as_ref();
+        let self_signing_key: &CrossSigningKey = new_identity.self_signing_key().as_ref();
+
+        let data = response_from_file(&json!({
+            "device_keys": {},
+            "failures": {},
+            "master_keys": { "@example2:localhost": master_key },
+            "self_signing_keys": { "@example2:localhost": self_signing_key },
+        }));
+        let response = KeyQueryResponse::try_from_http_response(data).unwrap();
+        manager.receive_keys_query_response(&response).await.unwrap();
+
+        // The identity changed, not even the locally trusted device is trusted
+        // until the change is acknowledged.
+        let device =
+            manager.store.get_device(&other_user, "SKISMLNIMH".into()).await.unwrap().unwrap();
+        assert_eq!(
+            SharingPolicy::TrustOnFirstUse.withheld_code(&device),
+            Some(WithheldCode::Unverified)
+        );
+
+        let identity = manager.store.get_identity(&other_user).await.unwrap().unwrap();
+        identity.other().unwrap().acknowledge_identity_change().await.unwrap();
+
+        let device =
+            manager.store.get_device(&other_user, "SKISMLNIMH".into()).await.unwrap().unwrap();
+        assert_eq!(SharingPolicy::TrustOnFirstUse.withheld_code(&device), None);
+    }
 }
@@ -18,7 +18,7 @@ use std::{
     ops::Deref,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, RwLock,
     },
 };
 
@@ -214,6 +214,24 @@ impl UserIdentity {
             .unwrap_or(false)
     }
 
+    /// Acknowledge that the master key of this user identity has changed.
+    ///
+    /// This pins the current master key of the identity, room keys will be
+    /// shared with the devices of the user again. Verifying the new identity
+    /// has the same effect.
+    ///
+    /// See [`ReadOnlyUserIdentity::has_changed()`] for more info.
+    pub async fn acknowledge_identity_change(&self) -> Result<(), CryptoStoreError> {
+        self.inner.pin_current_master_key();
+
+        let changes = Changes {
+            identities: IdentityChanges { changed: vec![self.inner.clone().into()], new: vec![] },
+            ..Default::default()
+        };
+
+        self.verification_machine.store.save_changes(changes).await
+    }
+
     /// Manually verify this user.
     ///
     /// This method will attempt to sign the user identity using our private
@@ -699,6 +717,27 @@ pub struct ReadOnlyUserIdentity {
     user_id: Arc<UserId>,
     pub(crate) master_key: MasterPubkey,
     self_signing_key: SelfSigningPubkey,
+    /// The master key we trust for this user, the first master key we saw
+    /// unless a change of the identity was acknowledged. `None` for identities
+    /// that were stored before we started pinning master keys, the current
+    /// master key is considered to be pinned in that case.
+    #[serde(default)]
+    pinned_master_key: Arc<RwLock<Option<MasterPubkey>>>,
+}
+
+/// A change of the master key of a user identity belonging to another user.
+///
+/// Room keys won't be shared with the devices of the user until the change is
+/// acknowledged using [`UserIdentity::acknowledge_identity_change()`] or the
+/// new identity is verified.
+#[derive(Debug, Clone)]
+pub struct IdentityChange {
+    /// The user whose identity changed.
+    pub user_id: UserId,
+    /// The master key that we trusted for the user before the change.
+    pub pinned_master_key: MasterPubkey,
+    /// The new master key of the user.
+    pub master_key: MasterPubkey,
 }
 
 impl ReadOnlyUserIdentity {
@@ -718,7 +757,12 @@ impl ReadOnlyUserIdentity {
     ) -> Result<Self, SignatureError> {
         master_key.verify_subkey(&self_signing_key)?;
 
-        Ok(Self { user_id: Arc::new(master_key.0.user_id.clone()), master_key, self_signing_key })
+        Ok(Self {
+            user_id: Arc::new(master_key.0.user_id.clone()),
+            pinned_master_key: Arc::new(RwLock::new(Some(master_key.clone()))),
+            master_key,
+            self_signing_key,
+        })
     }
 
     #[cfg(test)]
@@ -727,7 +771,12 @@ impl ReadOnlyUserIdentity {
         let self_signing_key =
             identity.self_signing_key.lock().await.as_ref().unwrap().public_key.clone();
 
-        Self { user_id: Arc::new(identity.user_id().clone()), master_key, self_signing_key }
+        Self {
+            user_id: Arc::new(identity.user_id().clone()),
+            pinned_master_key: Arc::new(RwLock::new(Some(master_key.clone()))),
+            master_key,
+            self_signing_key,
+        }
     }
 
     /// Get the user id of this identity.
@@ -745,8 +794,39 @@ impl ReadOnlyUserIdentity {
         &self.self_signing_key
     }
 
+    /// Get the master key we trust for this user.
+    ///
+    /// This is the first master key we saw for the user, or the master key
+    /// that was current when a change of the identity was last acknowledged.
+    pub fn pinned_master_key(&self) -> MasterPubkey {
+        self.pinned_master_key.read().unwrap().as_ref().unwrap_or(&self.master_key).clone()
+    }
+
+    /// Has the master key of this identity changed since we pinned it.
+    ///
+    /// This means that the user reset their cross signing keys, or that
+    /// someone is trying to impersonate the user. Room keys aren't shared with
+    /// the devices of the user until the change is acknowledged or the new
+    /// identity is verified.
+    pub fn has_changed(&self) -> bool {
+        self.pinned_master_key
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |pinned| pinned != &self.master_key)
+    }
+
+    /// Pin the current master key of the identity, acknowledging any change of
+    /// the identity.
+    pub(crate) fn pin_current_master_key(&self) {
+        *self.pinned_master_key.write().unwrap() = Some(self.master_key.clone());
+    }
+
     /// Update the identity with a new master key and self signing key.
     ///
+    /// The pinned master key isn't changed, if the master key differs from
+    /// the pinned one the identity will be marked as changed.
+    ///
     /// # Arguments
     ///
     /// * `master_key` - The new master key of the user identity.
@@ -761,6 +841,7 @@ impl ReadOnlyUserIdentity {
     ) -> Result<(), SignatureError> {
         master_key.verify_subkey(&self_signing_key)?;
 
+        self.pinned_master_key.write().unwrap().get_or_insert_with(|| self.master_key.clone());
         self.master_key = master_key;
         self.self_signing_key = self_signing_key;
 
@@ -1009,6 +1090,27 @@ pub(crate) mod test {
         get_other_identity();
     }
 
+    #[async_test]
+    async fn other_identity_pinning() {
+        let mut identity = get_other_identity();
+        let pinned_master_key = identity.master_key().clone();
+        assert!(!identity.has_changed());
+
+        let private_identity = PrivateCrossSigningIdentity::new(identity.user_id().clone()).await;
+        let new_identity = ReadOnlyUserIdentity::from_private(&private_identity).await;
+
+        identity
+            .update(new_identity.master_key().clone(), new_identity.self_signing_key().clone())
+            .unwrap();
+
+        assert!(identity.has_changed());
+        assert_eq!(identity.pinned_master_key(), pinned_master_key);
+
+        identity.pin_current_master_key();
+        assert!(!identity.has_changed());
+        assert_eq!(&identity.pinned_master_key(), new_identity.master_key());
+    }
+
     #[test]
     fn own_identity_check_signatures() {
         let response = own_key_query();
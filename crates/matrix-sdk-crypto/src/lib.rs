@@ -25,6 +25,8 @@
     unused_qualifications
 )]
 
+pub mod backups;
+pub mod dehydrated_devices;
 mod error;
 mod file_encryption;
 mod gossiping;
@@ -32,6 +34,7 @@ mod identities;
 mod machine;
 pub mod olm;
 mod requests;
+pub mod secret_storage;
 mod session_manager;
 pub mod store;
 mod utilities;
@@ -40,24 +43,28 @@ mod verification;
 pub use error::{MegolmError, OlmError, SignatureError};
 pub use file_encryption::{
     decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, EncryptionInfo, KeyExportError,
+    DecryptorError, EncryptionInfo, KeyExportError, KeyExportFilter, KeyExportReader,
+    KeyExportWriter,
 };
 pub use identities::{
-    Device, LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
-    ReadOnlyUserIdentities, ReadOnlyUserIdentity, UserDevices, UserIdentities, UserIdentity,
+    Device, IdentityChange, LocalTrust, MasterPubkey, OwnUserIdentity, ReadOnlyDevice,
+    ReadOnlyOwnUserIdentity, ReadOnlyUserIdentities, ReadOnlyUserIdentity, UserDevices,
+    UserIdentities, UserIdentity,
 };
 pub use machine::OlmMachine;
 #[cfg(feature = "qrcode")]
 #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
 pub use matrix_qrcode;
 pub(crate) use olm::ReadOnlyAccount;
-pub use olm::{CrossSigningStatus, EncryptionSettings};
+pub use olm::{CrossSigningStatus, EncryptionSettings, SharingPolicy, WithheldCode};
 pub use requests::{
-    IncomingResponse, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
+    IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest, UploadSigningKeysRequest,
 };
 pub use store::{CrossSigningKeyExport, CryptoStoreError, SecretImportError};
 #[cfg(feature = "qrcode")]
 #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
 pub use verification::QrVerification;
-pub use verification::{AcceptSettings, CancelInfo, Sas, Verification, VerificationRequest};
+pub use verification::{
+    AcceptSettings, CancelInfo, Sas, Verification, VerificationRequest, VerificationState,
+};
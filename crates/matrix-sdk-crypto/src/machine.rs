@@ -16,11 +16,13 @@
 use std::path::Path;
 use std::{
     collections::{BTreeMap, HashSet},
+    io::Write,
     mem,
     sync::Arc,
 };
 
 use dashmap::DashMap;
+use futures::Stream;
 use matrix_sdk_common::{
     deserialized_responses::{AlgorithmInfo, EncryptionInfo, SyncRoomEvent, VerificationState},
     locks::Mutex,
@@ -38,14 +40,20 @@ use ruma::{
     },
     assign,
     events::{
-        room::encrypted::{
-            EncryptedEventContent, EncryptedEventScheme, SyncEncryptedEvent, ToDeviceEncryptedEvent,
+        room::{
+            encrypted::{
+                EncryptedEventContent, EncryptedEventScheme, SyncEncryptedEvent,
+                ToDeviceEncryptedEvent,
+            },
+            message::MessageType,
         },
         room_key::ToDeviceRoomKeyEvent,
         secret::request::SecretName,
-        AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, EventContent,
+        AnyMessageEventContent, AnyRoomEvent, AnySyncMessageEvent, AnyToDeviceEvent, EventContent,
     },
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, UInt, UserId,
+    serde::Raw,
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, EventId, RoomId, UInt,
+    UserId,
 };
 use serde_json::Value;
 use tracing::{debug, error, info, trace, warn};
@@ -53,19 +61,22 @@ use tracing::{debug, error, info, trace, warn};
 #[cfg(feature = "sled_cryptostore")]
 use crate::store::sled::SledStore;
 use crate::{
+    backups::{BackupMachine, MegolmV1BackupKey},
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
+    file_encryption::{KeyExportError, KeyExportFilter, KeyExportWriter},
     gossiping::GossipMachine,
-    identities::{user::UserIdentities, Device, IdentityManager, UserDevices},
+    identities::{user::UserIdentities, Device, IdentityChange, IdentityManager, UserDevices},
     olm::{
         Account, CrossSigningStatus, EncryptionSettings, ExportedRoomKey, GroupSessionKey,
         IdentityKeys, InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, SessionType,
+        ReadOnlyAccount, RoomKeyWithheldContent, RoomKeyWithheldEvent, SessionType, SharingPolicy,
+        WithheldCode, WITHHELD_EVENT_TYPE,
     },
     requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
     session_manager::{GroupSessionManager, SessionManager},
     store::{
         Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
-        SecretImportError, Store,
+        SecretImportError, SharingPolicies, Store,
     },
     verification::{Verification, VerificationMachine, VerificationRequest},
     CrossSigningKeyExport, ToDeviceRequest,
@@ -103,6 +114,8 @@ pub struct OlmMachine {
     /// State machine handling public user identities and devices, keeping track
     /// of when a key query needs to be done and handling one.
     identity_manager: IdentityManager,
+    /// A state machine that handles creating room key backups.
+    backup_machine: BackupMachine,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -137,6 +150,26 @@ impl OlmMachine {
             store,
             account,
             PrivateCrossSigningIdentity::empty(user_id.to_owned()),
+            None,
+            SharingPolicies::default(),
+        )
+    }
+
+    /// Create a new machine from an existing account, using an in-memory
+    /// store.
+    pub(crate) fn from_account(account: ReadOnlyAccount) -> Self {
+        let store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let user_id = account.user_id().to_owned();
+        let device_id = account.device_id().to_owned();
+
+        OlmMachine::new_helper(
+            &user_id,
+            device_id,
+            store,
+            account,
+            PrivateCrossSigningIdentity::empty(user_id.clone()),
+            None,
+            SharingPolicies::default(),
         )
     }
 
@@ -146,6 +179,8 @@ impl OlmMachine {
         store: Box<dyn CryptoStore>,
         account: ReadOnlyAccount,
         user_identity: PrivateCrossSigningIdentity,
+        backup_key: Option<MegolmV1BackupKey>,
+        sharing_policies: SharingPolicies,
     ) -> Self {
         let user_id = Arc::new(user_id.clone());
         let user_identity = Arc::new(Mutex::new(user_identity));
@@ -160,7 +195,8 @@ impl OlmMachine {
 
         let account = Account { inner: account, store: store.clone() };
 
-        let group_session_manager = GroupSessionManager::new(account.clone(), store.clone());
+        let group_session_manager =
+            GroupSessionManager::new(account.clone(), store.clone(), sharing_policies);
 
         let key_request_machine = GossipMachine::new(
             user_id.clone(),
@@ -178,6 +214,7 @@ impl OlmMachine {
         );
         let identity_manager =
             IdentityManager::new(user_id.clone(), device_id.clone(), store.clone());
+        let backup_machine = BackupMachine::new(store.clone(), backup_key);
 
         OlmMachine {
             user_id,
@@ -190,6 +227,7 @@ impl OlmMachine {
             verification_machine,
             key_request_machine,
             identity_manager,
+            backup_machine,
         }
     }
 
@@ -251,7 +289,31 @@ impl OlmMachine {
             }
         };
 
-        Ok(OlmMachine::new_helper(&user_id, device_id, store, account, identity))
+        let backup_keys = store.load_backup_keys().await?;
+
+        let backup_key = if let (Some(recovery_key), Some(version)) =
+            (backup_keys.recovery_key, backup_keys.backup_version)
+        {
+            debug!(version = version.as_str(), "Restored the backup key");
+            let backup_key = recovery_key.megolm_v1_public_key();
+            backup_key.set_version(version);
+
+            Some(backup_key)
+        } else {
+            None
+        };
+
+        let sharing_policies = store.load_sharing_policies().await?;
+
+        Ok(OlmMachine::new_helper(
+            &user_id,
+            device_id,
+            store,
+            account,
+            identity,
+            backup_key,
+            sharing_policies,
+        ))
     }
 
     /// Create a new machine with the default crypto store.
@@ -291,6 +353,12 @@ impl OlmMachine {
         self.account.identity_keys()
     }
 
+    /// Get the backup machine, which is responsible for server-side backups of
+    /// our room keys.
+    pub fn backup_machine(&self) -> &BackupMachine {
+        &self.backup_machine
+    }
+
     /// Get the display name of our own device
     pub async fn display_name(&self) -> StoreResult<Option<String>> {
         self.store.device_display_name().await
@@ -331,6 +399,10 @@ impl OlmMachine {
         requests.append(&mut self.verification_machine.outgoing_messages());
         requests.append(&mut self.key_request_machine.outgoing_to_device_requests().await?);
 
+        if let Some(r) = self.backup_machine.backup().await? {
+            requests.push(r);
+        }
+
         Ok(requests)
     }
 
@@ -370,6 +442,9 @@ impl OlmMachine {
             IncomingResponse::RoomMessage(_) => {
                 self.verification_machine.mark_request_as_sent(request_id);
             }
+            IncomingResponse::KeysBackup(_) => {
+                self.backup_machine.mark_request_as_sent(*request_id).await?;
+            }
         };
 
         Ok(())
@@ -428,7 +503,7 @@ impl OlmMachine {
         }
     }
 
-    /// Should device or one-time keys be uploaded to the server.
+    /// Should device, one-time or fallback keys be uploaded to the server.
     ///
     /// This needs to be checked periodically, ideally after every sync request.
     ///
@@ -463,6 +538,11 @@ impl OlmMachine {
         &self.account
     }
 
+    /// Get the store of the machine.
+    pub(crate) fn store(&self) -> &Store {
+        &self.store
+    }
+
     /// Receive a successful keys upload response.
     ///
     /// # Arguments
@@ -510,6 +590,31 @@ impl OlmMachine {
         self.session_manager.get_missing_sessions(users).await
     }
 
+    /// Encrypt a custom to-device event for the given devices using Olm.
+    ///
+    /// Olm sessions with the devices need to be established before this is
+    /// called, see [`OlmMachine::get_missing_sessions()`]. Devices that we
+    /// don't have an Olm session with are skipped.
+    ///
+    /// Returns a [`ToDeviceRequest`] that needs to be sent out, or `None` if
+    /// the event couldn't be encrypted for any of the devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The devices that should receive the event.
+    ///
+    /// * `event_type` - The type of the event, e.g. `org.example.call`.
+    ///
+    /// * `content` - The JSON content of the event.
+    pub async fn encrypt_to_device_event(
+        &self,
+        devices: &[Device],
+        event_type: &str,
+        content: Value,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        self.session_manager.encrypt_to_device_event(devices, event_type, content).await
+    }
+
     /// Receive a successful key claim response and create new Olm sessions with
     /// the claimed keys.
     ///
@@ -546,8 +651,8 @@ impl OlmMachine {
     /// [`receive_keys_upload_response`]: #method.receive_keys_upload_response
     /// [`OlmMachine`]: struct.OlmMachine.html
     async fn keys_for_upload(&self) -> Option<upload_keys::Request> {
-        let (device_keys, one_time_keys) = self.account.keys_for_upload().await?;
-        Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys }))
+        let (device_keys, one_time_keys, fallback_keys) = self.account.keys_for_upload().await?;
+        Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys, fallback_keys }))
     }
 
     /// Decrypt a to-device event.
@@ -727,6 +832,62 @@ impl OlmMachine {
         self.group_session_manager.share_group_session(room_id, users, encryption_settings).await
     }
 
+    /// Get the sharing policy that should be used for the given room.
+    ///
+    /// This is the policy that was set for the room using
+    /// [`set_room_sharing_policy()`], or the global policy if the room
+    /// doesn't have a policy of its own.
+    ///
+    /// [`set_room_sharing_policy()`]: #method.set_room_sharing_policy
+    pub fn sharing_policy(&self, room_id: &RoomId) -> SharingPolicy {
+        self.group_session_manager.sharing_policy(room_id)
+    }
+
+    /// Set the global sharing policy, used for all rooms that don't have a
+    /// policy of their own.
+    ///
+    /// The policy is saved in the store. It needs to be put into the
+    /// [`EncryptionSettings`] that are passed to [`share_group_session()`],
+    /// the [`sharing_policy()`] method returns the policy that applies to a
+    /// room.
+    ///
+    /// [`share_group_session()`]: #method.share_group_session
+    /// [`sharing_policy()`]: #method.sharing_policy
+    pub async fn set_sharing_policy(&self, policy: SharingPolicy) -> StoreResult<()> {
+        self.group_session_manager.set_sharing_policy(policy).await
+    }
+
+    /// Set the sharing policy of a single room.
+    ///
+    /// The policy is saved in the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the policy should be used for.
+    ///
+    /// * `policy` - The sharing policy of the room, `None` if the global
+    /// policy should be used again.
+    pub async fn set_room_sharing_policy(
+        &self,
+        room_id: &RoomId,
+        policy: Option<SharingPolicy>,
+    ) -> StoreResult<()> {
+        self.group_session_manager.set_room_sharing_policy(room_id, policy).await
+    }
+
+    /// Get the devices the currently active group session of the given room
+    /// was withheld from, together with the reason why.
+    ///
+    /// This includes blacklisted devices, devices that were excluded by the
+    /// sharing policy of the room and devices we couldn't establish an Olm
+    /// session with.
+    pub async fn get_withheld_devices(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<BTreeMap<UserId, BTreeMap<DeviceIdBox, WithheldCode>>> {
+        self.group_session_manager.withheld_devices(room_id).await
+    }
+
     /// Receive and properly handle a decrypted to-device event.
     ///
     /// # Arguments
@@ -761,7 +922,10 @@ impl OlmMachine {
                 None,
             )),
             _ => {
-                warn!(event_type =? event.event_type(), "Received an unexpected encrypted to-device event");
+                debug!(
+                    event_type =? event.event_type(),
+                    "Received an encrypted to-device event that isn't handled by the crypto layer"
+                );
                 Ok((Some(event), None))
             }
         }
@@ -850,12 +1014,16 @@ impl OlmMachine {
     /// * `one_time_keys_count` - The current one-time keys counts that the sync
     /// response returned.
     ///
+    /// * `unused_fallback_keys` - The key algorithms of our fallback keys that
+    /// the server still has, None if the server doesn't support fallback keys.
+    ///
     /// [`decrypt_room_event`]: #method.decrypt_room_event
     pub async fn receive_sync_changes(
         &self,
         to_device_events: ToDevice,
         changed_devices: &DeviceLists,
         one_time_keys_counts: &BTreeMap<DeviceKeyAlgorithm, UInt>,
+        unused_fallback_keys: Option<&[DeviceKeyAlgorithm]>,
     ) -> OlmResult<ToDevice> {
         // Remove verification objects that have expired or are done.
         let mut events = self.verification_machine.garbage_collect();
@@ -867,6 +1035,10 @@ impl OlmMachine {
 
         self.update_one_time_key_count(one_time_keys_counts);
 
+        if let Some(unused_fallback_keys) = unused_fallback_keys {
+            self.account.update_unused_fallback_keys(unused_fallback_keys).await;
+        }
+
         for user_id in &changed_devices.changed {
             if let Err(e) = self.identity_manager.mark_user_as_changed(user_id).await {
                 error!("Error marking a tracked user as changed {:?}", e);
@@ -940,6 +1112,11 @@ impl OlmMachine {
 
                     raw_event = decrypted.event;
                 }
+                e if e.event_type() == WITHHELD_EVENT_TYPE => {
+                    if let Some(info) = self.receive_withheld_event(&raw_event).await? {
+                        changes.withheld_session_info.push(info);
+                    }
+                }
                 e => self.handle_to_device_event(&e).await,
             }
 
@@ -958,6 +1135,72 @@ impl OlmMachine {
         Ok(to_device)
     }
 
+    /// Parse a `m.room_key.withheld` event.
+    ///
+    /// Returns the content of the event if it's about a specific room key and
+    /// should be stored.
+    ///
+    /// Notices are only accepted if the Curve25519 key they claim to come
+    /// from belongs to a device of the sender of the event, and if we don't
+    /// already have the room key from that Curve25519 key. Otherwise anyone
+    /// could mark a room key of another user as withheld.
+    async fn receive_withheld_event(
+        &self,
+        raw_event: &Raw<AnyToDeviceEvent>,
+    ) -> StoreResult<Option<RoomKeyWithheldContent>> {
+        let event = match raw_event.deserialize_as::<RoomKeyWithheldEvent>() {
+            Ok(e) => e,
+            Err(e) => {
+                warn!(error =? e, "Received an invalid room key withheld notice");
+                return Ok(None);
+            }
+        };
+
+        let content = event.content;
+
+        info!(
+            sender = event.sender.as_str(),
+            sender_key = content.sender_key.as_str(),
+            room_id =? content.room_id,
+            session_id =? content.session_id,
+            code = content.code.as_str(),
+            "Received a room key withheld notice"
+        );
+
+        let (room_id, session_id) = match (&content.room_id, &content.session_id) {
+            (Some(r), Some(s)) => (r, s),
+            _ => return Ok(None),
+        };
+
+        if self.store.get_device_from_curve_key(&event.sender, &content.sender_key).await?.is_none()
+        {
+            warn!(
+                sender = event.sender.as_str(),
+                sender_key = content.sender_key.as_str(),
+                "Received a room key withheld notice for a sender key that doesn't belong \
+                to the sender, ignoring",
+            );
+            return Ok(None);
+        }
+
+        if self
+            .store
+            .get_inbound_group_session(room_id, &content.sender_key, session_id)
+            .await?
+            .is_some()
+        {
+            debug!(
+                sender = event.sender.as_str(),
+                room_id = room_id.as_str(),
+                session_id = session_id.as_str(),
+                "Received a room key withheld notice for a room key we already have, ignoring",
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(content))
+    }
+
     /// Request a room key from our devices.
     ///
     /// This method will return a request cancellation and a new key request if
@@ -995,6 +1238,7 @@ impl OlmMachine {
         session: &InboundGroupSession,
         sender: &UserId,
         device_id: &DeviceId,
+        event_id: &EventId,
     ) -> StoreResult<EncryptionInfo> {
         let verification_state = if let Some(device) =
             self.get_device(sender, device_id).await?.filter(|d| {
@@ -1015,6 +1259,12 @@ impl OlmMachine {
 
         let sender = sender.clone();
         let device_id = device_id.to_owned();
+        let recovered_room_key = self.key_request_machine.take_recovered_event(
+            session.room_id(),
+            session.sender_key(),
+            session.session_id(),
+            event_id,
+        );
 
         Ok(EncryptionInfo {
             sender,
@@ -1023,11 +1273,56 @@ impl OlmMachine {
                 curve25519_key: session.sender_key().to_owned(),
                 sender_claimed_keys: session.signing_keys().to_owned(),
                 forwarding_curve25519_key_chain: session.forwarding_key_chain().to_vec(),
+                room_key_origin: Some(session.origin()),
+                room_key_received_time: session.received_time(),
             },
             verification_state,
+            recovered_room_key,
         })
     }
 
+    /// Receive an unencrypted verification event from a room timeline.
+    ///
+    /// In-room verification flows, e.g. the verification of another user in a
+    /// DM, exchange their events as room messages. Encrypted verification
+    /// events are handled when they get decrypted using
+    /// [`OlmMachine::decrypt_room_event()`], events that were sent in an
+    /// unencrypted room need to be passed to this method instead.
+    ///
+    /// Events that aren't verification events are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The message event from the room timeline.
+    ///
+    /// * `room_id` - The ID of the room where the event was sent to.
+    pub async fn receive_unencrypted_verification_event(
+        &self,
+        event: &AnySyncMessageEvent,
+        room_id: &RoomId,
+    ) -> StoreResult<()> {
+        let is_verification_event = match event {
+            AnySyncMessageEvent::KeyVerificationReady(_)
+            | AnySyncMessageEvent::KeyVerificationStart(_)
+            | AnySyncMessageEvent::KeyVerificationCancel(_)
+            | AnySyncMessageEvent::KeyVerificationAccept(_)
+            | AnySyncMessageEvent::KeyVerificationKey(_)
+            | AnySyncMessageEvent::KeyVerificationMac(_)
+            | AnySyncMessageEvent::KeyVerificationDone(_) => true,
+            AnySyncMessageEvent::RoomMessage(m) => {
+                matches!(m.content.msgtype, MessageType::VerificationRequest(_))
+            }
+            _ => false,
+        };
+
+        if is_verification_event {
+            let event = event.clone().into_full_event(room_id.to_owned());
+            self.verification_machine.receive_any_event(&event).await?;
+        }
+
+        Ok(())
+    }
+
     /// Decrypt an event from a room timeline.
     ///
     /// # Arguments
@@ -1049,14 +1344,30 @@ impl OlmMachine {
             .store
             .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
             .await?;
-        // TODO check if the Olm session is wedged and re-request the key.
         let session = if let Some(s) = session {
             s
         } else {
             self.key_request_machine
                 .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
                 .await?;
-            return Err(MegolmError::MissingSession);
+            // The room key might have been encrypted for a wedged Olm session,
+            // remember the session so we can request it again once the Olm
+            // session gets replaced.
+            self.key_request_machine.mark_session_as_undecryptable(
+                room_id,
+                &content.sender_key,
+                &content.session_id,
+                &event.event_id,
+            );
+
+            // If the sender told us why we didn't receive the room key, let
+            // the caller know as well.
+            return match self.store.get_withheld_info(room_id, &content.session_id).await? {
+                Some(info) if info.sender_key == content.sender_key => {
+                    Err(MegolmError::Withheld(info.code))
+                }
+                _ => Err(MegolmError::MissingSession),
+            };
         };
 
         // TODO check the message index.
@@ -1073,8 +1384,17 @@ impl OlmMachine {
             }
         }
 
-        let encryption_info =
-            self.get_encryption_info(&session, &event.sender, &content.device_id).await?;
+        let encryption_info = self
+            .get_encryption_info(&session, &event.sender, &content.device_id, &event.event_id)
+            .await?;
+
+        if encryption_info.recovered_room_key {
+            info!(
+                room_id = room_id.as_str(),
+                session_id = session.session_id(),
+                "Decrypted an event using a room key that was lost in a wedged Olm session"
+            );
+        }
 
         Ok(SyncRoomEvent { encryption_info: Some(encryption_info), event: decrypted_event })
     }
@@ -1141,6 +1461,20 @@ impl OlmMachine {
         self.store.get_identity(user_id).await
     }
 
+    /// Get a stream of identity changes of other users.
+    ///
+    /// The first master key we see for a user is pinned, an item is yielded
+    /// when a keys query reveals that the master key of a user changed to a
+    /// different one. Room keys won't be shared with the devices of such a user
+    /// until the change is acknowledged using
+    /// [`UserIdentity::acknowledge_identity_change()`] or the new identity is
+    /// verified.
+    ///
+    /// [`UserIdentity::acknowledge_identity_change()`]: crate::UserIdentity::acknowledge_identity_change
+    pub fn identity_changes(&self) -> impl Stream<Item = IdentityChange> {
+        self.identity_manager.identity_changes()
+    }
+
     /// Get a map holding all the devices of an user.
     ///
     /// # Arguments
@@ -1199,58 +1533,7 @@ impl OlmMachine {
         exported_keys: Vec<ExportedRoomKey>,
         progress_listener: impl Fn(usize, usize),
     ) -> StoreResult<(usize, usize)> {
-        struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, u32>,
-        }
-
-        impl ShallowSessions {
-            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
-                self.inner
-                    .get(&session.room_id)
-                    .map(|existing| existing <= &session.first_known_index())
-                    .unwrap_or(false)
-            }
-        }
-
-        let mut sessions = Vec::new();
-
-        let existing_sessions = ShallowSessions {
-            inner: self
-                .store
-                .get_inbound_group_sessions()
-                .await?
-                .into_iter()
-                .map(|s| {
-                    let index = s.first_known_index();
-                    (s.room_id, index)
-                })
-                .collect(),
-        };
-
-        let total_sessions = exported_keys.len();
-
-        for (i, key) in exported_keys.into_iter().enumerate() {
-            let session = InboundGroupSession::from_export(key)?;
-
-            // Only import the session if we didn't have this session or if it's
-            // a better version of the same session, that is the first known
-            // index is lower.
-            if !existing_sessions.has_better_session(&session) {
-                sessions.push(session)
-            }
-
-            progress_listener(i, total_sessions)
-        }
-
-        let num_sessions = sessions.len();
-
-        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
-
-        self.store.save_changes(changes).await?;
-
-        info!("Successfully imported {} inbound group sessions", num_sessions);
-
-        Ok((num_sessions, total_sessions))
+        self.store.import_room_keys(exported_keys, false, progress_listener).await
     }
 
     /// Export the keys that match the given predicate.
@@ -1303,6 +1586,197 @@ impl OlmMachine {
         Ok(exported)
     }
 
+    /// Import room keys one at a time from the given iterator.
+    ///
+    /// This is the streaming counterpart of [`OlmMachine::import_keys()`], the
+    /// room keys are saved to the store in batches while they are being
+    /// consumed, so the whole key export never needs to be in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - An iterator over previously exported keys, usually a
+    /// [`KeyExportReader`](crate::KeyExportReader). If we already have a better
+    /// version of a key the key will *not* be imported.
+    ///
+    /// * `progress_listener` - Closure that will be called with the index of
+    /// the currently processed room key.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// key export.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::fs::File;
+    /// # use matrix_sdk_crypto::{OlmMachine, KeyExportReader};
+    /// # use ruma::user_id;
+    /// # use futures::executor::block_on;
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+    /// # block_on(async {
+    /// let file = File::open("/home/example/e2e-keys.txt").unwrap();
+    /// let reader = KeyExportReader::new(file, "1234").unwrap();
+    ///
+    /// let (imported, total) = machine
+    ///     .import_keys_stream(reader, |i| println!("Imported room key number {}", i + 1))
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn import_keys_stream(
+        &self,
+        exported_keys: impl IntoIterator<Item = Result<ExportedRoomKey, KeyExportError>>,
+        progress_listener: impl Fn(usize),
+    ) -> Result<(usize, usize), KeyExportError> {
+        self.store.import_room_keys_stream(exported_keys, false, progress_listener).await
+    }
+
+    /// Export the keys that match the given predicate into the given key
+    /// export writer.
+    ///
+    /// This is the streaming counterpart of [`OlmMachine::export_keys()`], the
+    /// room keys are loaded from the store in batches and exported and
+    /// encrypted one at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - A closure that will be called for every known
+    /// `InboundGroupSession`, see [`OlmMachine::export_keys()`] for the
+    /// details.
+    ///
+    /// * `writer` - The writer that will encrypt the exported room keys. The
+    /// [`KeyExportWriter::finish()`] method needs to be called once all the
+    /// room keys have been exported.
+    ///
+    /// * `progress_listener` - Closure that will be called with the index of
+    /// the currently processed room key and the total number of room keys in
+    /// the store.
+    ///
+    /// Returns the number of room keys that were exported.
+    pub async fn export_keys_stream<W: Write>(
+        &self,
+        mut predicate: impl FnMut(&InboundGroupSession) -> bool,
+        writer: &mut KeyExportWriter<W>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<usize, KeyExportError> {
+        const BATCH_SIZE: usize = 100;
+
+        let total = self.store.inbound_group_session_counts().await?.total;
+        let mut index = 0;
+        let mut exported = 0;
+        let mut last_session = None;
+
+        loop {
+            let sessions = self
+                .store
+                .get_inbound_group_sessions_batch(last_session.as_ref(), BATCH_SIZE)
+                .await?;
+
+            for session in &sessions {
+                if predicate(session) {
+                    writer.write_key(&session.export().await)?;
+                    exported += 1;
+                }
+
+                progress_listener(index, total);
+                index += 1;
+            }
+
+            last_session = sessions.into_iter().last();
+
+            if last_session.is_none() {
+                break;
+            }
+        }
+
+        Ok(exported)
+    }
+
+    /// Export the keys that match the given filter.
+    ///
+    /// Unlike [`OlmMachine::export_keys()`] this doesn't need to load every
+    /// room key from the store if the filter is restricted to some rooms.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The filter deciding which room keys should be exported.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk_crypto::{OlmMachine, KeyExportFilter, encrypt_key_export};
+    /// # use ruma::{user_id, room_id};
+    /// # use futures::executor::block_on;
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+    /// # block_on(async {
+    /// let filter = KeyExportFilter::new().room(room_id!("!test:localhost"));
+    /// let exported_keys = machine.export_keys_with_filter(&filter).await.unwrap();
+    /// let encrypted_export = encrypt_key_export(&exported_keys, "1234", 1);
+    /// # });
+    /// ```
+    pub async fn export_keys_with_filter(
+        &self,
+        filter: &KeyExportFilter,
+    ) -> StoreResult<Vec<ExportedRoomKey>> {
+        let mut exported = Vec::new();
+
+        for session in self.filtered_inbound_group_sessions(filter).await? {
+            exported.push(session.export().await);
+        }
+
+        Ok(exported)
+    }
+
+    /// Export the keys that match the given filter into the given key export
+    /// writer.
+    ///
+    /// This is the streaming counterpart of
+    /// [`OlmMachine::export_keys_with_filter()`], see
+    /// [`OlmMachine::export_keys_stream()`] for a description of the
+    /// arguments.
+    ///
+    /// Returns the number of room keys that were exported.
+    pub async fn export_keys_stream_with_filter<W: Write>(
+        &self,
+        filter: &KeyExportFilter,
+        writer: &mut KeyExportWriter<W>,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<usize, KeyExportError> {
+        if filter.room_ids().is_empty() {
+            return self.export_keys_stream(|s| filter.matches(s), writer, progress_listener).await;
+        }
+
+        let sessions = self.filtered_inbound_group_sessions(filter).await?;
+        let total = sessions.len();
+
+        for (i, session) in sessions.into_iter().enumerate() {
+            writer.write_key(&session.export().await)?;
+            progress_listener(i, total);
+        }
+
+        Ok(total)
+    }
+
+    async fn filtered_inbound_group_sessions(
+        &self,
+        filter: &KeyExportFilter,
+    ) -> StoreResult<Vec<InboundGroupSession>> {
+        let sessions = if filter.room_ids().is_empty() {
+            self.store.get_inbound_group_sessions().await?
+        } else {
+            let mut sessions = Vec::new();
+
+            for room_id in filter.room_ids() {
+                sessions.extend(self.store.get_inbound_group_sessions_for_room(room_id).await?);
+            }
+
+            sessions
+        };
+
+        Ok(sessions.into_iter().filter(|s| filter.matches(s)).collect())
+    }
+
     /// Get the status of the private cross signing keys.
     ///
     /// This can be used to check which private cross signing keys we have
@@ -1358,7 +1832,10 @@ pub(crate) mod test {
     use matrix_sdk_test::test_json;
     use ruma::{
         api::{
-            client::r0::keys::{claim_keys, get_keys, upload_keys},
+            client::r0::{
+                keys::{claim_keys, get_keys, upload_keys},
+                sync::sync_events::ToDevice,
+            },
             IncomingResponse,
         },
         encryption::OneTimeKey,
@@ -1372,16 +1849,19 @@ pub(crate) mod test {
             AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
             AnyToDeviceEventContent, SyncMessageEvent, ToDeviceEvent, Unsigned,
         },
-        room_id, uint, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId,
-        MilliSecondsSinceUnixEpoch, UserId,
+        room_id,
+        serde::Raw,
+        uint, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
+        UserId,
     };
-    use serde_json::json;
+    use serde_json::{json, value::to_raw_value};
 
     use crate::{
         machine::OlmMachine,
-        olm::Utility,
+        olm::{RoomKeyWithheldContent, Utility, WithheldCode, WITHHELD_EVENT_TYPE},
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, LocalTrust, MegolmError, ReadOnlyDevice, SharingPolicy,
+        ToDeviceRequest,
     };
 
     /// These keys need to be periodically uploaded to the server.
@@ -1535,6 +2015,88 @@ pub(crate) mod test {
         assert!(!machine.should_upload_keys().await);
     }
 
+    #[tokio::test]
+    async fn fallback_key_rotation() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(50));
+
+        let request = machine.keys_for_upload().await.unwrap();
+        assert_eq!(request.fallback_keys.map(|k| k.len()), Some(1));
+
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(!machine.should_upload_keys().await);
+
+        // The server still has our fallback key.
+        let unused = [DeviceKeyAlgorithm::SignedCurve25519];
+        machine
+            .receive_sync_changes(
+                ToDevice::new(),
+                &Default::default(),
+                &Default::default(),
+                Some(&unused[..]),
+            )
+            .await
+            .unwrap();
+        assert!(!machine.should_upload_keys().await);
+
+        // Our fallback key got used, a new one needs to be uploaded.
+        machine
+            .receive_sync_changes(
+                ToDevice::new(),
+                &Default::default(),
+                &Default::default(),
+                Some(&[][..]),
+            )
+            .await
+            .unwrap();
+        assert!(machine.should_upload_keys().await);
+
+        let request = machine.keys_for_upload().await.unwrap();
+        assert!(request.device_keys.is_none());
+        assert_eq!(request.fallback_keys.map(|k| k.len()), Some(1));
+
+        machine.receive_keys_upload_response(&response).await.unwrap();
+        assert!(!machine.should_upload_keys().await);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_key_signing() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        machine.account.inner.update_uploaded_key_count(0);
+
+        let identity_keys = machine.account.identity_keys();
+        let ed25519_key = identity_keys.ed25519();
+
+        let mut request = machine.keys_for_upload().await.unwrap();
+        let fallback_key = request.fallback_keys.as_mut().unwrap().values_mut().next().unwrap();
+
+        // The uploaded object is the one that gets signed, including the
+        // fallback flag.
+        let mut fallback_key = json!(fallback_key);
+        assert_eq!(fallback_key["fallback"], true);
+
+        let utility = Utility::new();
+        let ret = utility.verify_json(
+            &machine.user_id,
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
+            ed25519_key,
+            &mut fallback_key,
+        );
+        assert!(ret.is_ok());
+
+        let mut one_time_key = json!(request.one_time_keys.unwrap().values().next().unwrap());
+        assert!(one_time_key.get("fallback").is_none());
+
+        let ret = utility.verify_json(
+            &machine.user_id,
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, machine.device_id()),
+            ed25519_key,
+            &mut one_time_key,
+        );
+        assert!(ret.is_ok());
+    }
+
     #[tokio::test]
     async fn generate_one_time_keys() {
         let machine = OlmMachine::new(&user_id(), &alice_device_id());
@@ -1800,6 +2362,50 @@ pub(crate) mod test {
         assert!(session.unwrap().is_some());
     }
 
+    #[tokio::test]
+    async fn test_custom_to_device_encryption() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+
+        let content = json!({ "call_id": "1234", "version": 1 });
+
+        let request = alice
+            .encrypt_to_device_event(&[bob_device], "org.example.call", content.clone())
+            .await
+            .unwrap()
+            .expect("The event should be encrypted for Bob's device");
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(vec![Arc::new(request)]),
+        };
+
+        let decrypted = bob.decrypt_to_device_event(&event).await.unwrap();
+        let decrypted: serde_json::Value = decrypted.event.deserialize_as().unwrap();
+
+        assert_eq!(decrypted["type"], "org.example.call");
+        assert_eq!(decrypted["sender"], alice.user_id().as_str());
+        assert_eq!(decrypted["content"], content);
+
+        let unknown_device = ReadOnlyDevice::from_account(&crate::olm::ReadOnlyAccount::new(
+            &user_id!("@carol:example.org"),
+            "CAROLDEVICE".into(),
+        ))
+        .await;
+        alice.store.save_devices(&[unknown_device.clone()]).await.unwrap();
+        let unknown_device = alice
+            .get_device(unknown_device.user_id(), unknown_device.device_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(alice
+            .encrypt_to_device_event(&[unknown_device], "org.example.call", content)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
     #[tokio::test]
     async fn test_megolm_encryption() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -1860,6 +2466,128 @@ pub(crate) mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_withheld_room_key() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .get_device(bob.user_id(), bob.device_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .set_local_trust(LocalTrust::BlackListed)
+            .await
+            .unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        let request = &to_device_requests[0];
+        assert_eq!(request.event_type.as_ref(), WITHHELD_EVENT_TYPE);
+
+        let content = request.messages.values().next().unwrap().values().next().unwrap();
+        let withheld_content: RoomKeyWithheldContent = content.deserialize_as().unwrap();
+
+        // A notice claiming to come from alice's device but sent by someone
+        // else is ignored.
+        let forged_event = json!({
+            "sender": "@mallory:example.org",
+            "type": WITHHELD_EVENT_TYPE,
+            "content": content,
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(Raw::from_json(to_raw_value(&forged_event).unwrap()));
+
+        bob.receive_sync_changes(to_device, &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+
+        assert!(bob
+            .store
+            .get_withheld_info(&room_id, withheld_content.session_id.as_deref().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": WITHHELD_EVENT_TYPE,
+            "content": content,
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(Raw::from_json(to_raw_value(&event).unwrap()));
+
+        bob.receive_sync_changes(to_device, &Default::default(), &Default::default(), None)
+            .await
+            .unwrap();
+
+        let encrypted_content = alice
+            .encrypt(
+                &room_id,
+                AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Secret")),
+            )
+            .await
+            .unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let error = bob.decrypt_room_event(&event, &room_id).await.unwrap_err();
+        assert!(matches!(error, MegolmError::Withheld(WithheldCode::Blacklisted)));
+    }
+
+    #[tokio::test]
+    async fn test_sharing_policy() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        assert_eq!(alice.sharing_policy(&room_id), SharingPolicy::AllDevices);
+
+        alice.set_sharing_policy(SharingPolicy::TrustOnFirstUse).await.unwrap();
+        alice
+            .set_room_sharing_policy(&room_id, Some(SharingPolicy::CrossSignedOnly))
+            .await
+            .unwrap();
+        assert_eq!(alice.sharing_policy(&room_id), SharingPolicy::CrossSignedOnly);
+
+        let settings = EncryptionSettings {
+            sharing_policy: alice.sharing_policy(&room_id),
+            ..Default::default()
+        };
+
+        let to_device_requests = alice
+            .share_group_session(&room_id, [bob.user_id().clone()].iter(), settings)
+            .await
+            .unwrap();
+
+        assert_eq!(to_device_requests.len(), 1);
+        assert_eq!(to_device_requests[0].event_type.as_ref(), WITHHELD_EVENT_TYPE);
+
+        let withheld = alice.get_withheld_devices(&room_id).await.unwrap();
+        assert_eq!(
+            withheld.get(bob.user_id()).and_then(|d| d.get(bob.device_id())),
+            Some(&WithheldCode::Unverified)
+        );
+
+        alice.set_room_sharing_policy(&room_id, None).await.unwrap();
+        assert_eq!(alice.sharing_policy(&room_id), SharingPolicy::TrustOnFirstUse);
+    }
+
     #[tokio::test]
     #[cfg(feature = "sled_cryptostore")]
     async fn test_machine_with_default_store() {
@@ -1898,6 +2626,37 @@ pub(crate) mod test {
         assert_eq!(ed25519_key, machine.identity_keys().ed25519());
     }
 
+    #[tokio::test]
+    async fn unencrypted_in_room_verification_request() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        let room_id = room_id!("!test:example.org");
+        let flow_id = "$143273582443PhrSn:example.org";
+
+        let event: AnySyncMessageEvent = serde_json::from_value(json!({
+            "content": {
+                "body": "Alice is requesting to verify your device",
+                "msgtype": "m.key.verification.request",
+                "from_device": "ALICEDEVICE",
+                "methods": ["m.sas.v1"],
+                "to": user_id(),
+            },
+            "event_id": flow_id,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "sender": alice_id(),
+            "type": "m.room.message",
+        }))
+        .unwrap();
+
+        machine.receive_unencrypted_verification_event(&event, &room_id).await.unwrap();
+
+        let request = machine
+            .get_verification_request(&alice_id(), flow_id)
+            .expect("The in-room verification request should be known");
+
+        assert_eq!(request.room_id(), Some(&room_id));
+        assert!(!request.we_started());
+    }
+
     #[tokio::test]
     async fn interactive_verification() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
@@ -210,6 +210,25 @@ impl Account {
         }
     }
 
+    /// Check the list of unused fallback key types the server gave us and
+    /// generate a new fallback key if ours got used up.
+    ///
+    /// # Arguments
+    ///
+    /// * `unused_fallback_keys` - The key algorithms of the fallback keys the
+    /// server still has for us.
+    pub async fn update_unused_fallback_keys(&self, unused_fallback_keys: &[DeviceKeyAlgorithm]) {
+        // A fallback key that we didn't publish yet can't be used up, the
+        // server just doesn't know about it yet.
+        if self.inner.shared()
+            && !self.inner.has_unpublished_fallback_key()
+            && !unused_fallback_keys.contains(&DeviceKeyAlgorithm::SignedCurve25519)
+        {
+            debug!("The server doesn't have an unused fallback key of ours, generating a new one");
+            self.inner.generate_fallback_key_helper().await;
+        }
+    }
+
     pub async fn receive_keys_upload_response(
         &self,
         response: &upload_keys::Response,
@@ -440,6 +459,8 @@ pub struct ReadOnlyAccount {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: Arc<AtomicU64>,
+    /// Do we have a fallback key that wasn't yet uploaded to the server.
+    unpublished_fallback_key: Arc<AtomicBool>,
 }
 
 /// A typed representation of a base64 encoded string containing the account
@@ -476,6 +497,10 @@ pub struct PickledAccount {
     pub shared: bool,
     /// The number of uploaded one-time keys we have on the server.
     pub uploaded_signed_key_count: u64,
+    /// Does the account have a fallback key that wasn't yet uploaded to the
+    /// server. The fallback key itself is part of the Olm account pickle.
+    #[serde(default)]
+    pub unpublished_fallback_key: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -500,6 +525,10 @@ impl ReadOnlyAccount {
         let account = OlmAccount::new();
         let identity_keys = account.parsed_identity_keys();
 
+        // Every new account gets a fallback key, it will be uploaded together
+        // with the device keys.
+        account.generate_fallback_key();
+
         Self {
             user_id: Arc::new(user_id.to_owned()),
             device_id: device_id.to_owned().into(),
@@ -507,6 +536,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
             uploaded_signed_key_count: Arc::new(AtomicU64::new(0)),
+            unpublished_fallback_key: Arc::new(AtomicBool::new(true)),
         }
     }
 
@@ -564,6 +594,33 @@ impl ReadOnlyAccount {
         self.inner.lock().await.generate_one_time_keys(count);
     }
 
+    /// Generate a new fallback key, replacing the current one.
+    ///
+    /// The previous fallback key is kept around, so sessions that other
+    /// devices created using it can still be established.
+    pub(crate) async fn generate_fallback_key_helper(&self) {
+        self.inner.lock().await.generate_fallback_key();
+        self.unpublished_fallback_key.store(true, Ordering::SeqCst);
+    }
+
+    /// Does the account have a fallback key that needs to be uploaded.
+    pub(crate) fn has_unpublished_fallback_key(&self) -> bool {
+        self.unpublished_fallback_key.load(Ordering::SeqCst)
+    }
+
+    /// Get the public part of the current fallback key, a map from the key id
+    /// to the Curve25519 key.
+    ///
+    /// This is empty if the account never generated a fallback key.
+    pub(crate) async fn fallback_key(&self) -> BTreeMap<String, String> {
+        let fallback_key = self.inner.lock().await.fallback_key();
+
+        serde_json::from_str::<BTreeMap<String, BTreeMap<String, String>>>(&fallback_key)
+            .ok()
+            .and_then(|mut k| k.remove("curve25519"))
+            .unwrap_or_default()
+    }
+
     /// Get the maximum number of one-time keys the account can hold.
     pub(crate) async fn max_one_time_keys(&self) -> usize {
         self.inner.lock().await.max_number_of_one_time_keys()
@@ -590,7 +647,7 @@ impl ReadOnlyAccount {
 
     /// Should account or one-time keys be uploaded to the server.
     pub(crate) async fn should_upload_keys(&self) -> bool {
-        if !self.shared() {
+        if !self.shared() || self.has_unpublished_fallback_key() {
             return true;
         }
 
@@ -609,12 +666,17 @@ impl ReadOnlyAccount {
         key_count > 0
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
+    /// Get a tuple of device, one-time and fallback keys that need to be
+    /// uploaded.
     ///
     /// Returns None if no keys need to be uploaded.
     pub(crate) async fn keys_for_upload(
         &self,
-    ) -> Option<(Option<DeviceKeys>, Option<BTreeMap<DeviceKeyId, OneTimeKey>>)> {
+    ) -> Option<(
+        Option<DeviceKeys>,
+        Option<BTreeMap<DeviceKeyId, OneTimeKey>>,
+        Option<BTreeMap<DeviceKeyId, OneTimeKey>>,
+    )> {
         if !self.should_upload_keys().await {
             return None;
         }
@@ -622,13 +684,16 @@ impl ReadOnlyAccount {
         let device_keys = if !self.shared() { Some(self.device_keys().await) } else { None };
 
         let one_time_keys = self.signed_one_time_keys().await.ok();
+        let fallback_keys = self.signed_fallback_key().await;
 
-        Some((device_keys, one_time_keys))
+        Some((device_keys, one_time_keys, fallback_keys))
     }
 
-    /// Mark the current set of one-time keys as being published.
+    /// Mark the current set of one-time keys and the fallback key as being
+    /// published.
     pub(crate) async fn mark_keys_as_published(&self) {
         self.inner.lock().await.mark_keys_as_published();
+        self.unpublished_fallback_key.store(false, Ordering::SeqCst);
     }
 
     /// Sign the given string using the accounts signing key.
@@ -654,6 +719,7 @@ impl ReadOnlyAccount {
             pickle,
             shared: self.shared(),
             uploaded_signed_key_count: self.uploaded_key_count(),
+            unpublished_fallback_key: self.has_unpublished_fallback_key(),
         }
     }
 
@@ -680,6 +746,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(pickle.shared)),
             uploaded_signed_key_count: Arc::new(AtomicU64::new(pickle.uploaded_signed_key_count)),
+            unpublished_fallback_key: Arc::new(AtomicBool::new(pickle.unpublished_fallback_key)),
         })
     }
 
@@ -791,12 +858,50 @@ impl ReadOnlyAccount {
         &self,
     ) -> Result<BTreeMap<DeviceKeyId, OneTimeKey>, ()> {
         let one_time_keys = self.one_time_keys().await;
+
+        Ok(self.sign_keys(one_time_keys.curve25519().iter(), false).await)
+    }
+
+    /// Sign and prepare the fallback key to be uploaded.
+    ///
+    /// Returns None if the current fallback key was already uploaded.
+    pub(crate) async fn signed_fallback_key(&self) -> Option<BTreeMap<DeviceKeyId, OneTimeKey>> {
+        if !self.has_unpublished_fallback_key() {
+            return None;
+        }
+
+        let fallback_key = self.fallback_key().await;
+
+        if fallback_key.is_empty() {
+            None
+        } else {
+            Some(self.sign_keys(fallback_key.iter(), true).await)
+        }
+    }
+
+    /// Sign the given Curve25519 keys, turning them into signed keys that can
+    /// be uploaded to the server.
+    ///
+    /// Fallback keys are marked as such before they get signed, MSC2732
+    /// requires the `fallback` flag to be part of the signed object.
+    async fn sign_keys(
+        &self,
+        keys: impl Iterator<Item = (&String, &String)>,
+        fallback: bool,
+    ) -> BTreeMap<DeviceKeyId, OneTimeKey> {
         let mut one_time_key_map = BTreeMap::new();
 
-        for (key_id, key) in one_time_keys.curve25519().iter() {
-            let key_json = json!({
-                "key": key,
-            });
+        for (key_id, key) in keys {
+            let key_json = if fallback {
+                json!({
+                    "key": key,
+                    "fallback": true,
+                })
+            } else {
+                json!({
+                    "key": key,
+                })
+            };
 
             let signature = self.sign_json(key_json).await;
 
@@ -810,7 +915,11 @@ impl ReadOnlyAccount {
             let mut signatures = BTreeMap::new();
             signatures.insert((*self.user_id).clone(), signature_map);
 
-            let signed_key = SignedKey::new(key.to_owned(), signatures);
+            let signed_key = if fallback {
+                SignedKey::new_fallback(key.to_owned(), signatures)
+            } else {
+                SignedKey::new(key.to_owned(), signatures)
+            };
 
             one_time_key_map.insert(
                 DeviceKeyId::from_parts(
@@ -821,7 +930,7 @@ impl ReadOnlyAccount {
             );
         }
 
-        Ok(one_time_key_map)
+        one_time_key_map
     }
 
     /// Generate, sign and prepare one-time keys to be uploaded.
@@ -12,9 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, convert::TryFrom, fmt, mem, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use matrix_sdk_common::locks::Mutex;
+use matrix_sdk_common::{deserialized_responses::RoomKeyOrigin, locks::Mutex};
 pub use olm_rs::{
     account::IdentityKeys,
     session::{OlmMessage, PreKeyMessage},
@@ -33,19 +41,15 @@ use ruma::{
         AnySyncRoomEvent,
     },
     serde::Raw,
-    DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId,
+    DeviceKeyAlgorithm, EventEncryptionAlgorithm, MilliSecondsSinceUnixEpoch, RoomId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use zeroize::Zeroizing;
 
-use super::{ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
+use super::{BackedUpRoomKey, ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
 use crate::error::{EventError, MegolmResult};
 
-// TODO add creation times to the inbound group sessions so we can export
-// sessions that were created between some time period, this should only be set
-// for non-imported sessions.
-
 /// Inbound group session.
 ///
 /// Inbound group sessions are used to exchange room messages between a group of
@@ -61,7 +65,10 @@ pub struct InboundGroupSession {
     pub(crate) room_id: Arc<RoomId>,
     forwarding_chains: Arc<Vec<String>>,
     imported: bool,
-    backed_up: bool,
+    backed_up: Arc<AtomicBool>,
+    creation_time: Option<MilliSecondsSinceUnixEpoch>,
+    received_time: Option<MilliSecondsSinceUnixEpoch>,
+    origin: RoomKeyOrigin,
 }
 
 impl InboundGroupSession {
@@ -105,7 +112,10 @@ impl InboundGroupSession {
             room_id: room_id.clone().into(),
             forwarding_chains: Vec::new().into(),
             imported: false,
-            backed_up: false,
+            backed_up: AtomicBool::new(false).into(),
+            creation_time: Some(MilliSecondsSinceUnixEpoch::now()),
+            received_time: Some(MilliSecondsSinceUnixEpoch::now()),
+            origin: RoomKeyOrigin::Direct,
         })
     }
 
@@ -123,6 +133,20 @@ impl InboundGroupSession {
         Self::try_from(exported_session.into())
     }
 
+    /// Create a InboundGroupSession from a room key that was downloaded from
+    /// the server-side key backup.
+    ///
+    /// The session will be marked as already backed up.
+    pub(crate) fn from_backup(
+        exported_session: ExportedRoomKey,
+    ) -> Result<Self, OlmGroupSessionError> {
+        let mut session = Self::try_from(exported_session)?;
+        session.origin = RoomKeyOrigin::Backup;
+        session.mark_as_backed_up();
+
+        Ok(session)
+    }
+
     /// Create a new inbound group session from a forwarded room key content.
     ///
     /// # Arguments
@@ -157,7 +181,10 @@ impl InboundGroupSession {
             room_id: content.room_id.clone().into(),
             forwarding_chains: forwarding_chains.into(),
             imported: true,
-            backed_up: false,
+            backed_up: AtomicBool::new(false).into(),
+            creation_time: None,
+            received_time: Some(MilliSecondsSinceUnixEpoch::now()),
+            origin: RoomKeyOrigin::Forwarded,
         })
     }
 
@@ -177,11 +204,36 @@ impl InboundGroupSession {
             room_id: (&*self.room_id).clone(),
             forwarding_chains: self.forwarding_key_chain().to_vec(),
             imported: self.imported,
-            backed_up: self.backed_up,
+            backed_up: self.backed_up(),
             history_visibility: self.history_visibility.as_ref().clone(),
+            creation_time: self.creation_time,
+            received_time: self.received_time,
+            origin: Some(self.origin),
         }
     }
 
+    /// Has the session been backed up to the server.
+    pub fn backed_up(&self) -> bool {
+        self.backed_up.load(Ordering::SeqCst)
+    }
+
+    /// Mark the session as backed up.
+    pub fn mark_as_backed_up(&self) {
+        self.backed_up.store(true, Ordering::SeqCst)
+    }
+
+    /// Reset the backup state of the inbound group session, the session will
+    /// be included in the next room key backup.
+    pub fn reset_backup_state(&self) {
+        self.backed_up.store(false, Ordering::SeqCst)
+    }
+
+    /// Export this session in the form that is used for server-side room key
+    /// backups.
+    pub async fn to_backup(&self) -> BackedUpRoomKey {
+        self.export().await.into()
+    }
+
     /// Export this session at the first known message index.
     ///
     /// If only a limited part of this session should be exported use
@@ -247,6 +299,16 @@ impl InboundGroupSession {
         let first_known_index = session.first_known_index();
         let session_id = session.session_id();
 
+        // Sessions that were pickled before the origin was recorded don't have
+        // one, make a best guess using the info we have.
+        let origin = pickle.origin.unwrap_or(if !pickle.forwarding_chains.is_empty() {
+            RoomKeyOrigin::Forwarded
+        } else if pickle.imported {
+            RoomKeyOrigin::Imported
+        } else {
+            RoomKeyOrigin::Direct
+        });
+
         Ok(InboundGroupSession {
             inner: Mutex::new(session).into(),
             session_id: session_id.into(),
@@ -256,8 +318,11 @@ impl InboundGroupSession {
             signing_keys: pickle.signing_key.into(),
             room_id: pickle.room_id.into(),
             forwarding_chains: pickle.forwarding_chains.into(),
-            backed_up: pickle.backed_up,
+            backed_up: AtomicBool::from(pickle.backed_up).into(),
             imported: pickle.imported,
+            creation_time: pickle.creation_time,
+            received_time: pickle.received_time,
+            origin,
         })
     }
 
@@ -276,6 +341,25 @@ impl InboundGroupSession {
         self.first_known_index
     }
 
+    /// Get the time this session was created.
+    ///
+    /// This is only known for sessions that were directly sent to us by the
+    /// sender, imported and forwarded sessions return `None`.
+    pub fn creation_time(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.creation_time
+    }
+
+    /// Get the time we received this session, `None` if the session was
+    /// stored before the receive time was recorded.
+    pub fn received_time(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.received_time
+    }
+
+    /// Get the way we obtained this session.
+    pub fn origin(&self) -> RoomKeyOrigin {
+        self.origin
+    }
+
     /// Decrypt the given ciphertext.
     ///
     /// Returns the decrypted plaintext or an `OlmGroupSessionError` if
@@ -385,6 +469,18 @@ pub struct PickledInboundGroupSession {
     pub backed_up: bool,
     /// History visibility of the room when the session was created.
     pub history_visibility: Option<HistoryVisibility>,
+    /// The time the session was created, only known for sessions that were
+    /// directly sent to us by the sender.
+    #[serde(default)]
+    pub creation_time: Option<MilliSecondsSinceUnixEpoch>,
+    /// The time we received the session, `None` for sessions that were stored
+    /// before this was recorded.
+    #[serde(default)]
+    pub received_time: Option<MilliSecondsSinceUnixEpoch>,
+    /// The way we obtained the session, `None` for sessions that were stored
+    /// before this was recorded.
+    #[serde(default)]
+    pub origin: Option<RoomKeyOrigin>,
 }
 
 /// The typed representation of a base64 encoded string of the GroupSession
@@ -422,7 +518,10 @@ impl TryFrom<ExportedRoomKey> for InboundGroupSession {
             room_id: Arc::new(key.room_id),
             forwarding_chains: Arc::new(key.forwarding_curve25519_key_chain),
             imported: true,
-            backed_up: false,
+            backed_up: AtomicBool::new(false).into(),
+            creation_time: None,
+            received_time: Some(MilliSecondsSinceUnixEpoch::now()),
+            origin: RoomKeyOrigin::Imported,
         })
     }
 }
@@ -25,11 +25,15 @@ use zeroize::Zeroize;
 
 mod inbound;
 mod outbound;
+mod withheld;
 
 pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
 pub use outbound::{
     EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo, ShareState,
+    SharingPolicy,
 };
+pub use withheld::{RoomKeyWithheldContent, WithheldCode};
+pub(crate) use withheld::{RoomKeyWithheldEvent, WITHHELD_EVENT_TYPE};
 
 /// The private session key of a group session.
 /// Can be used to create a new inbound group session.
@@ -71,6 +75,64 @@ pub struct ExportedRoomKey {
     pub forwarding_curve25519_key_chain: Vec<String>,
 }
 
+/// A backed up version of an `InboundGroupSession`
+///
+/// This can be used to backup the `InboundGroupSession` to the server using
+/// server-side room key backups. The room id and session id aren't part of the
+/// backed up key, they are used as the keys of the backup instead.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct BackedUpRoomKey {
+    /// The encryption algorithm that the session uses.
+    pub algorithm: EventEncryptionAlgorithm,
+
+    /// The Curve25519 key of the device which initiated the session originally.
+    pub sender_key: String,
+
+    /// The key for the session.
+    pub session_key: ExportedGroupSessionKey,
+
+    /// The Ed25519 key of the device which initiated the session originally.
+    pub sender_claimed_keys: BTreeMap<DeviceKeyAlgorithm, String>,
+
+    /// Chain of Curve25519 keys through which this session was forwarded, via
+    /// m.forwarded_room_key events.
+    pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+impl BackedUpRoomKey {
+    /// Convert the backed up room key into an exported room key, which can be
+    /// imported using the `OlmMachine`.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the session.
+    pub fn into_exported_room_key(self, room_id: RoomId, session_id: String) -> ExportedRoomKey {
+        ExportedRoomKey {
+            algorithm: self.algorithm,
+            room_id,
+            sender_key: self.sender_key,
+            session_id,
+            session_key: self.session_key,
+            sender_claimed_keys: self.sender_claimed_keys,
+            forwarding_curve25519_key_chain: self.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
+impl From<ExportedRoomKey> for BackedUpRoomKey {
+    fn from(k: ExportedRoomKey) -> Self {
+        Self {
+            algorithm: k.algorithm,
+            sender_key: k.sender_key,
+            session_key: k.session_key,
+            sender_claimed_keys: k.sender_claimed_keys,
+            forwarding_curve25519_key_chain: k.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
 impl TryInto<ToDeviceForwardedRoomKeyEventContent> for ExportedRoomKey {
     type Error = ();
 
@@ -51,7 +51,7 @@ use tracing::{debug, error, trace};
 
 use super::{
     super::{deserialize_instant, serialize_instant},
-    GroupSessionKey,
+    GroupSessionKey, RoomKeyWithheldContent, WithheldCode,
 };
 use crate::{Device, ToDeviceRequest};
 
@@ -65,6 +65,64 @@ pub enum ShareState {
     Shared(u32),
 }
 
+/// Policy deciding which devices of the room members should receive a room
+/// key.
+///
+/// Devices that are excluded by the policy receive a `m.unverified` withheld
+/// notice instead of the room key. Blacklisted devices never receive a room
+/// key, no matter which policy is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SharingPolicy {
+    /// Share the room key with all devices that aren't blacklisted.
+    AllDevices,
+    /// Only share the room key with devices that are verified using cross
+    /// signing.
+    ///
+    /// Devices that were only manually marked as trusted don't receive the
+    /// room key.
+    CrossSignedOnly,
+    /// Trust the cross signing identity of other users the first time we see
+    /// it.
+    ///
+    /// The room key is shared with verified devices and with devices that
+    /// are signed by the pinned cross signing identity of their owner. If the
+    /// identity of the owner changed, the devices don't receive the room key
+    /// until the change is acknowledged or the new identity is verified.
+    /// Devices of users that didn't set up cross signing receive the room key
+    /// as well, since there is no identity that could vouch for them.
+    TrustOnFirstUse,
+}
+
+impl Default for SharingPolicy {
+    fn default() -> Self {
+        SharingPolicy::AllDevices
+    }
+}
+
+impl SharingPolicy {
+    /// Check if the room key should be withheld from the given device under
+    /// this policy.
+    ///
+    /// Returns the code of the withheld notice that the device should receive
+    /// instead of the room key, `None` if the device should get the room key.
+    pub(crate) fn withheld_code(&self, device: &Device) -> Option<WithheldCode> {
+        let allowed = match self {
+            SharingPolicy::AllDevices => true,
+            SharingPolicy::CrossSignedOnly => device.is_cross_signing_trusted(),
+            SharingPolicy::TrustOnFirstUse => {
+                !device.has_unacknowledged_identity_change()
+                    && (device.verified() || device.is_signed_by_pinned_identity())
+            }
+        };
+
+        if allowed {
+            None
+        } else {
+            Some(WithheldCode::Unverified)
+        }
+    }
+}
+
 /// Settings for an encrypted room.
 ///
 /// This determines the algorithm and rotation periods of a group session.
@@ -78,6 +136,9 @@ pub struct EncryptionSettings {
     pub rotation_period_msgs: u64,
     /// The history visibility of the room when the session was created.
     pub history_visibility: HistoryVisibility,
+    /// The policy deciding which devices should receive the room key.
+    #[serde(default)]
+    pub sharing_policy: SharingPolicy,
 }
 
 impl Default for EncryptionSettings {
@@ -87,6 +148,7 @@ impl Default for EncryptionSettings {
             rotation_period: ROTATION_PERIOD,
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
+            sharing_policy: SharingPolicy::default(),
         }
     }
 }
@@ -105,6 +167,7 @@ impl EncryptionSettings {
             rotation_period,
             rotation_period_msgs,
             history_visibility,
+            sharing_policy: SharingPolicy::default(),
         }
     }
 }
@@ -128,6 +191,7 @@ pub struct OutboundGroupSession {
     settings: Arc<EncryptionSettings>,
     pub(crate) shared_with_set: Arc<DashMap<UserId, DashMap<DeviceIdBox, ShareInfo>>>,
     to_share_with_set: Arc<DashMap<Uuid, (Arc<ToDeviceRequest>, ShareInfoSet)>>,
+    withheld_set: Arc<DashMap<UserId, DashMap<DeviceIdBox, WithheldCode>>>,
 }
 
 /// A a map of userid/device it to a `ShareInfo`.
@@ -183,6 +247,7 @@ impl OutboundGroupSession {
             settings: Arc::new(settings),
             shared_with_set: Arc::new(DashMap::new()),
             to_share_with_set: Arc::new(DashMap::new()),
+            withheld_set: Arc::new(DashMap::new()),
         }
     }
 
@@ -195,6 +260,64 @@ impl OutboundGroupSession {
         self.to_share_with_set.insert(request_id, (request, share_infos));
     }
 
+    /// Has a withheld notice with the given code already been created for the
+    /// given device.
+    pub(crate) fn is_withheld_from(&self, device: &Device, code: &WithheldCode) -> bool {
+        self.withheld_set
+            .get(device.user_id())
+            .and_then(|d| d.get(device.device_id()).map(|c| c.value() == code))
+            .unwrap_or(false)
+    }
+
+    /// Remember that we withheld this session from the given device, so we
+    /// don't send out the same withheld notice multiple times.
+    pub(crate) fn mark_withheld_from(&self, device: &Device, code: WithheldCode) {
+        self.withheld_set
+            .entry(device.user_id().to_owned())
+            .or_insert_with(DashMap::new)
+            .insert(device.device_id().into(), code);
+    }
+
+    /// Get the devices this session was withheld from, together with the
+    /// reason why it was withheld.
+    ///
+    /// Devices that received the session after all, e.g. because they got
+    /// verified in the meantime, aren't included.
+    pub fn withheld_devices(&self) -> BTreeMap<UserId, BTreeMap<DeviceIdBox, WithheldCode>> {
+        self.withheld_set
+            .iter()
+            .filter_map(|u| {
+                let devices: BTreeMap<DeviceIdBox, WithheldCode> = u
+                    .value()
+                    .iter()
+                    .filter(|d| {
+                        !self
+                            .shared_with_set
+                            .get(u.key())
+                            .map_or(false, |s| s.contains_key(d.key()))
+                    })
+                    .map(|d| (d.key().clone(), d.value().clone()))
+                    .collect();
+
+                if devices.is_empty() {
+                    None
+                } else {
+                    Some((u.key().clone(), devices))
+                }
+            })
+            .collect()
+    }
+
+    /// Create the content of a `m.room_key.withheld` event for this session.
+    pub(crate) fn withheld_content(&self, code: WithheldCode) -> RoomKeyWithheldContent {
+        RoomKeyWithheldContent::new(
+            &self.room_id,
+            &self.session_id,
+            self.account_identity_keys.curve25519(),
+            code,
+        )
+    }
+
     /// This should be called if an the user wishes to rotate this session.
     pub fn invalidate_session(&self) {
         self.invalidated.store(true, Ordering::Relaxed)
@@ -498,6 +621,13 @@ impl OutboundGroupSession {
                     .collect(),
             ),
             to_share_with_set: Arc::new(pickle.requests.into_iter().collect()),
+            withheld_set: Arc::new(
+                pickle
+                    .withheld_set
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into_iter().collect()))
+                    .collect(),
+            ),
         })
     }
 
@@ -536,6 +666,16 @@ impl OutboundGroupSession {
                 .iter()
                 .map(|r| (*r.key(), r.value().clone()))
                 .collect(),
+            withheld_set: self
+                .withheld_set
+                .iter()
+                .map(|u| {
+                    (
+                        u.key().clone(),
+                        u.value().iter().map(|d| (d.key().clone(), d.value().clone())).collect(),
+                    )
+                })
+                .collect(),
         }
     }
 }
@@ -586,6 +726,9 @@ pub struct PickledOutboundGroupSession {
     pub shared_with_set: BTreeMap<UserId, BTreeMap<DeviceIdBox, ShareInfo>>,
     /// Requests that need to be sent out to share the session.
     pub requests: BTreeMap<Uuid, (Arc<ToDeviceRequest>, ShareInfoSet)>,
+    /// The set of devices the session has been withheld from.
+    #[serde(default)]
+    pub withheld_set: BTreeMap<UserId, BTreeMap<DeviceIdBox, WithheldCode>>,
 }
 
 #[cfg(test)]
@@ -0,0 +1,202 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use ruma::{events::AnyToDeviceEventContent, serde::Raw, EventEncryptionAlgorithm, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+
+/// The event type of the `m.room_key.withheld` to-device event.
+pub(crate) const WITHHELD_EVENT_TYPE: &str = "m.room_key.withheld";
+
+/// The reason why a room key was withheld from a device, as defined in
+/// [MSC2399].
+///
+/// [MSC2399]: https://github.com/matrix-org/matrix-doc/pull/2399
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum WithheldCode {
+    /// The device was blacklisted by the sender.
+    Blacklisted,
+    /// The device isn't verified and the sender only shares room keys with
+    /// verified devices.
+    Unverified,
+    /// The device isn't authorised to receive the room key, e.g. because the
+    /// user wasn't in the room when the message was sent.
+    Unauthorised,
+    /// The sender doesn't have the room key or isn't willing to share it.
+    Unavailable,
+    /// The sender couldn't establish an Olm session with the device.
+    NoOlm,
+    /// A code that isn't known to us.
+    Custom(String),
+}
+
+impl WithheldCode {
+    /// Get the string representation of the code, e.g. `m.blacklisted`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+            WithheldCode::Custom(c) => c,
+        }
+    }
+
+    /// Get a human readable description of the code, used as the `reason` of
+    /// the withheld notices we send out.
+    fn description(&self) -> Option<&'static str> {
+        match self {
+            WithheldCode::Blacklisted => Some("The sender has blocked you."),
+            WithheldCode::Unverified => {
+                Some("The sender has disabled encrypting to unverified devices.")
+            }
+            WithheldCode::Unauthorised => Some("You are not authorised to read the message."),
+            WithheldCode::Unavailable => Some("The requested key was not found."),
+            WithheldCode::NoOlm => Some("Unable to establish a secure channel."),
+            WithheldCode::Custom(_) => None,
+        }
+    }
+}
+
+impl From<String> for WithheldCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "m.blacklisted" => WithheldCode::Blacklisted,
+            "m.unverified" => WithheldCode::Unverified,
+            "m.unauthorised" => WithheldCode::Unauthorised,
+            "m.unavailable" => WithheldCode::Unavailable,
+            "m.no_olm" => WithheldCode::NoOlm,
+            _ => WithheldCode::Custom(code),
+        }
+    }
+}
+
+impl From<WithheldCode> for String {
+    fn from(code: WithheldCode) -> Self {
+        match code {
+            WithheldCode::Custom(c) => c,
+            c => c.as_str().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The content of a `m.room_key.withheld` to-device event.
+///
+/// This event is sent to devices that won't receive a room key, it lets them
+/// know why they won't be able to decrypt the messages that were encrypted
+/// using the room key.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RoomKeyWithheldContent {
+    /// The encryption algorithm of the withheld room key.
+    pub algorithm: EventEncryptionAlgorithm,
+
+    /// The room the withheld room key belongs to.
+    ///
+    /// Not required if the code is `m.no_olm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<RoomId>,
+
+    /// The ID of the withheld room key.
+    ///
+    /// Not required if the code is `m.no_olm`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+
+    /// The Curve25519 key of the device that withheld the room key.
+    pub sender_key: String,
+
+    /// The machine readable reason why the room key was withheld.
+    pub code: WithheldCode,
+
+    /// A human readable reason why the room key was withheld.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl RoomKeyWithheldContent {
+    /// Create a new withheld notice for the given room key.
+    pub(crate) fn new(
+        room_id: &RoomId,
+        session_id: &str,
+        sender_key: &str,
+        code: WithheldCode,
+    ) -> Self {
+        Self {
+            algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id: Some(room_id.to_owned()),
+            session_id: Some(session_id.to_owned()),
+            sender_key: sender_key.to_owned(),
+            reason: code.description().map(ToOwned::to_owned),
+            code,
+        }
+    }
+
+    /// Convert the content into a raw to-device event content so it can be put
+    /// into a `ToDeviceRequest`.
+    pub(crate) fn to_raw(&self) -> Raw<AnyToDeviceEventContent> {
+        Raw::from_json(to_raw_value(self).expect("Failed to serialize a withheld notice"))
+    }
+}
+
+/// A `m.room_key.withheld` to-device event we received.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RoomKeyWithheldEvent {
+    /// The user that sent us the event.
+    pub sender: UserId,
+    /// The content of the event.
+    pub content: RoomKeyWithheldContent,
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::room_id;
+    use serde_json::json;
+
+    use super::{RoomKeyWithheldContent, WithheldCode};
+
+    #[test]
+    fn withheld_serialization() {
+        let content = RoomKeyWithheldContent::new(
+            &room_id!("!test:localhost"),
+            "session_id",
+            "sender_key",
+            WithheldCode::Blacklisted,
+        );
+
+        let json = serde_json::to_value(&content).unwrap();
+        assert_eq!(json["code"], json!("m.blacklisted"));
+        assert_eq!(json["algorithm"], json!("m.megolm.v1.aes-sha2"));
+
+        let custom = json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "sender_key": "sender_key",
+            "code": "org.example.custom",
+        });
+
+        let content: RoomKeyWithheldContent = serde_json::from_value(custom).unwrap();
+        assert_eq!(content.code, WithheldCode::Custom("org.example.custom".to_owned()));
+        assert!(content.room_id.is_none());
+        assert_eq!(serde_json::to_value(&content.code).unwrap(), json!("org.example.custom"));
+    }
+}
@@ -26,10 +26,14 @@ mod utility;
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
 pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
 pub use group_sessions::{
-    EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession,
+    InboundGroupSessionPickle, OutboundGroupSession, PickledInboundGroupSession,
+    PickledOutboundGroupSession, RoomKeyWithheldContent, ShareInfo, SharingPolicy, WithheldCode,
 };
-pub(crate) use group_sessions::{GroupSessionKey, ShareState};
+pub(crate) use group_sessions::{
+    GroupSessionKey, RoomKeyWithheldEvent, ShareState, WITHHELD_EVENT_TYPE,
+};
+pub use matrix_sdk_common::deserialized_responses::RoomKeyOrigin;
 use matrix_sdk_common::instant::{Duration, Instant};
 pub use olm_rs::{account::IdentityKeys, PicklingMode};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -76,7 +80,7 @@ pub(crate) mod test {
     use serde_json::json;
 
     use crate::{
-        olm::{InboundGroupSession, ReadOnlyAccount, Session},
+        olm::{InboundGroupSession, PicklingMode, ReadOnlyAccount, RoomKeyOrigin, Session},
         MegolmError,
     };
 
@@ -153,6 +157,62 @@ pub(crate) mod test {
         assert!(one_time_keys.curve25519().is_empty());
     }
 
+    #[tokio::test]
+    async fn fallback_key_creation() {
+        let account = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+
+        // New accounts come with a fallback key that needs to be uploaded.
+        assert!(account.has_unpublished_fallback_key());
+        let fallback_key = account.fallback_key().await;
+        assert_eq!(fallback_key.len(), 1);
+        assert_eq!(account.signed_fallback_key().await.unwrap().len(), 1);
+
+        account.mark_keys_as_published().await;
+        assert!(!account.has_unpublished_fallback_key());
+        assert!(account.signed_fallback_key().await.is_none());
+
+        account.generate_fallback_key_helper().await;
+        assert!(account.has_unpublished_fallback_key());
+        assert_ne!(account.fallback_key().await, fallback_key);
+
+        let pickle = account.pickle(PicklingMode::Unencrypted).await;
+        let unpickled = ReadOnlyAccount::from_pickle(pickle, PicklingMode::Unencrypted).unwrap();
+        assert!(unpickled.has_unpublished_fallback_key());
+        assert_eq!(unpickled.fallback_key().await, account.fallback_key().await);
+    }
+
+    #[tokio::test]
+    async fn session_creation_with_fallback_key() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let alice_keys = alice.identity_keys();
+        let bob_keys = bob.identity_keys();
+
+        let fallback_key = alice.fallback_key().await.values().next().unwrap().to_owned();
+        let fallback_key = SignedKey::new(fallback_key, BTreeMap::new());
+        alice.mark_keys_as_published().await;
+
+        // Unlike one-time keys, the fallback key can be used multiple times.
+        for _ in 0..2 {
+            let mut bob_session = bob
+                .create_outbound_session_helper(alice_keys.curve25519(), &fallback_key)
+                .await
+                .unwrap();
+
+            let message = bob_session.encrypt_helper("Hello world").await;
+
+            let prekey_message = match message {
+                OlmMessage::PreKey(m) => m,
+                OlmMessage::Message(_) => panic!("Incorrect message type"),
+            };
+
+            let alice_session =
+                alice.create_inbound_session(bob_keys.curve25519(), prekey_message).await.unwrap();
+
+            assert_eq!(bob_session.session_id(), alice_session.session_id());
+        }
+    }
+
     #[tokio::test]
     async fn session_creation() {
         let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
@@ -309,4 +369,39 @@ pub(crate) mod test {
 
         assert_eq!(inbound.session_id(), imported.session_id());
     }
+
+    #[tokio::test]
+    async fn group_session_origin() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let room_id = room_id!("!test:localhost");
+
+        let (_, inbound) = alice.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        assert_eq!(inbound.origin(), RoomKeyOrigin::Direct);
+        assert!(inbound.received_time().is_some());
+
+        let imported = InboundGroupSession::from_export(inbound.export().await).unwrap();
+        assert_eq!(imported.origin(), RoomKeyOrigin::Imported);
+        assert!(!imported.backed_up());
+        assert!(imported.creation_time().is_none());
+        assert!(imported.received_time().is_some());
+
+        let from_backup = InboundGroupSession::from_backup(inbound.export().await).unwrap();
+        assert_eq!(from_backup.origin(), RoomKeyOrigin::Backup);
+        assert!(from_backup.backed_up());
+        assert!(from_backup.received_time().is_some());
+
+        let pickle = from_backup.pickle(PicklingMode::Unencrypted).await;
+        let unpickled =
+            InboundGroupSession::from_pickle(pickle, PicklingMode::Unencrypted).unwrap();
+        assert_eq!(unpickled.origin(), RoomKeyOrigin::Backup);
+        assert!(unpickled.received_time().is_some());
+        assert_eq!(unpickled.received_time(), from_backup.received_time());
+
+        // Sessions that were stored before the origin was recorded.
+        let mut pickle = imported.pickle(PicklingMode::Unencrypted).await;
+        pickle.origin = None;
+        let unpickled =
+            InboundGroupSession::from_pickle(pickle, PicklingMode::Unencrypted).unwrap();
+        assert_eq!(unpickled.origin(), RoomKeyOrigin::Imported);
+    }
 }
@@ -31,7 +31,7 @@ use ruma::{
     DeviceId, DeviceKeyAlgorithm, UserId,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 
 use super::{deserialize_instant, serialize_instant, IdentityKeys};
 use crate::{
@@ -110,13 +110,38 @@ impl Session {
         &mut self,
         recipient_device: &ReadOnlyDevice,
         content: AnyToDeviceEventContent,
+    ) -> OlmResult<ToDeviceEncryptedEventContent> {
+        let event_type = content.event_type().to_owned();
+        let content = serde_json::to_value(content)?;
+
+        self.encrypt_raw(recipient_device, &event_type, content).await
+    }
+
+    /// Encrypt the given raw event content, of the given event type, as an
+    /// m.room.encrypted event content.
+    ///
+    /// This is useful to encrypt custom to-device events which aren't part of
+    /// the [`AnyToDeviceEventContent`] enum.
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient_device` - The device for which this message is going to be
+    ///   encrypted, this needs to be the device that was used to create this
+    ///   session with.
+    ///
+    /// * `event_type` - The type of the event, e.g. `org.example.call`.
+    ///
+    /// * `content` - The JSON content of the event.
+    pub async fn encrypt_raw(
+        &mut self,
+        recipient_device: &ReadOnlyDevice,
+        event_type: &str,
+        content: Value,
     ) -> OlmResult<ToDeviceEncryptedEventContent> {
         let recipient_signing_key = recipient_device
             .get_key(DeviceKeyAlgorithm::Ed25519)
             .ok_or(EventError::MissingSigningKey)?;
 
-        let event_type = content.event_type();
-
         let payload = json!({
             "sender": self.user_id.as_str(),
             "sender_device": self.device_id.as_ref(),
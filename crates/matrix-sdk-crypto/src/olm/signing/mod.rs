@@ -28,7 +28,7 @@ use ruma::{
     api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
     encryption::{DeviceKeys, KeyUsage},
     events::secret::request::SecretName,
-    UserId,
+    DeviceKeyAlgorithm, DeviceKeyId, UserId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Error as JsonError;
@@ -146,6 +146,25 @@ impl PrivateCrossSigningIdentity {
         self.master_key.lock().await.is_some()
     }
 
+    /// Sign the given message with our master key.
+    ///
+    /// Returns the id of the master key and the signature, `None` if we don't
+    /// have the master key.
+    pub(crate) async fn sign_with_master_key(
+        &self,
+        message: &str,
+    ) -> Option<(DeviceKeyId, String)> {
+        let master_key = self.master_key.lock().await;
+        let master_key = master_key.as_ref()?;
+
+        let key_id = DeviceKeyId::from_parts(
+            DeviceKeyAlgorithm::Ed25519,
+            master_key.inner.public_key().as_str().into(),
+        );
+
+        Some((key_id, master_key.inner.sign(message).await.to_string()))
+    }
+
     /// Get the status of our private cross signing keys, i.e. if we have the
     /// master key and the subkeys.
     pub async fn status(&self) -> CrossSigningStatus {
@@ -17,6 +17,7 @@ use std::{collections::BTreeMap, iter, sync::Arc, time::Duration};
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{
     api::client::r0::{
+        backup::{add_backup_keys::Response as KeysBackupResponse, RoomKeyBackup},
         keys::{
             claim_keys::{Request as KeysClaimRequest, Response as KeysClaimResponse},
             get_keys::Response as KeysQueryResponse,
@@ -175,6 +176,16 @@ impl KeysQueryRequest {
     }
 }
 
+/// A request that will back up a batch of room keys to the server.
+#[derive(Clone, Debug)]
+pub struct KeysBackupRequest {
+    /// The backup version that these room keys should be part of.
+    pub version: String,
+    /// The map from room id to a backed up room key that we're going to upload
+    /// to the server.
+    pub rooms: BTreeMap<RoomId, RoomKeyBackup>,
+}
+
 /// Enum over the different outgoing requests we can have.
 #[derive(Debug)]
 pub enum OutgoingRequests {
@@ -197,6 +208,8 @@ pub enum OutgoingRequests {
     /// A room message request, usually for sending in-room interactive
     /// verification events.
     RoomMessage(RoomMessageRequest),
+    /// A request that will back up a batch of room keys to the server.
+    KeysBackup(KeysBackupRequest),
 }
 
 #[cfg(test)]
@@ -239,6 +252,12 @@ impl From<RoomMessageRequest> for OutgoingRequests {
     }
 }
 
+impl From<KeysBackupRequest> for OutgoingRequests {
+    fn from(r: KeysBackupRequest) -> Self {
+        OutgoingRequests::KeysBackup(r)
+    }
+}
+
 impl From<SignatureUploadRequest> for OutgoingRequests {
     fn from(request: SignatureUploadRequest) -> Self {
         OutgoingRequests::SignatureUpload(request)
@@ -278,6 +297,8 @@ pub enum IncomingResponse<'a> {
     SignatureUpload(&'a SignatureUploadResponse),
     /// A room message response, usually for interactive verifications.
     RoomMessage(&'a RoomMessageResponse),
+    /// Response for the server-side room key backup request.
+    KeysBackup(&'a KeysBackupResponse),
 }
 
 impl<'a> From<&'a KeysUploadResponse> for IncomingResponse<'a> {
@@ -304,6 +325,12 @@ impl<'a> From<&'a RoomMessageResponse> for IncomingResponse<'a> {
     }
 }
 
+impl<'a> From<&'a KeysBackupResponse> for IncomingResponse<'a> {
+    fn from(response: &'a KeysBackupResponse) -> Self {
+        IncomingResponse::KeysBackup(response)
+    }
+}
+
 impl<'a> From<&'a KeysClaimResponse> for IncomingResponse<'a> {
     fn from(response: &'a KeysClaimResponse) -> Self {
         IncomingResponse::KeysClaim(response)
@@ -0,0 +1,603 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Secret storage support
+//!
+//! This module implements the `m.secret_storage.v1.aes-hmac-sha2` algorithm
+//! that is used to store secrets, e.g. the private cross signing keys, in the
+//! global account data of the user.
+//!
+//! A [`SecretStorageKey`] can be created from a passphrase or randomly, in
+//! which case the user will need to write down the base58 encoded recovery
+//! key, the key is then used to encrypt and decrypt individual secrets.
+
+use std::collections::BTreeMap;
+
+use aes::{
+    cipher::{generic_array::GenericArray, FromBlockCipher, NewBlockCipher, StreamCipher},
+    Aes256, Aes256Ctr,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use pbkdf2::pbkdf2;
+use ruma::events::secret::request::SecretName;
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    backups::{DecodeError as RecoveryKeyDecodeError, RecoveryKey},
+    utilities::{decode, encode, DecodeError},
+};
+
+/// The algorithm name of the only supported secret storage algorithm.
+pub const SECRET_STORAGE_ALGORITHM: &str = "m.secret_storage.v1.aes-hmac-sha2";
+/// The event type of the account data event that points to the default secret
+/// storage key.
+pub const DEFAULT_KEY_EVENT_TYPE: &str = "m.secret_storage.default_key";
+
+const PBKDF2_ALGORITHM: &str = "m.pbkdf2";
+const PBKDF2_ROUNDS: u32 = 500_000;
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const SALT_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 24;
+
+/// Error type describing why a secret storage operation failed.
+#[derive(Debug, Error)]
+pub enum SecretStorageError {
+    /// The secret storage key uses an algorithm we don't support.
+    #[error("The secret storage key uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    /// The secret storage key can't be derived from a passphrase since the key
+    /// description is missing the passphrase info.
+    #[error("The secret storage key wasn't created from a passphrase")]
+    MissingPassphraseInfo,
+    /// The given passphrase or recovery key doesn't match the stored key
+    /// description.
+    #[error("The passphrase or recovery key doesn't match the secret storage key")]
+    KeyMismatch,
+    /// The account data doesn't contain a default secret storage key or its
+    /// description.
+    #[error("No default secret storage key was found")]
+    MissingDefaultKey,
+    /// The secret isn't encrypted with the given secret storage key.
+    #[error("The secret isn't encrypted using the secret storage key {0}")]
+    MissingSecret(String),
+    /// The MAC of the encrypted secret didn't match.
+    #[error("The MAC of the encrypted secret is invalid")]
+    InvalidMac,
+    /// The IV of the encrypted secret or of the key description doesn't have
+    /// the expected length.
+    #[error("The IV has an invalid length of {0} bytes")]
+    InvalidIvLength(usize),
+    /// The encrypted secret contains invalid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// The recovery key couldn't be decoded.
+    #[error(transparent)]
+    RecoveryKey(#[from] RecoveryKeyDecodeError),
+    /// The decrypted secret isn't valid UTF-8.
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+/// Info describing how a secret storage key was derived from a passphrase.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PassphraseInfo {
+    /// The algorithm that was used to derive the key, only `m.pbkdf2` is
+    /// supported.
+    pub algorithm: String,
+    /// The salt used in the key derivation.
+    pub salt: String,
+    /// The number of iterations of the key derivation.
+    pub iterations: u32,
+    /// The number of bits that should be generated for the key, defaults to
+    /// 256.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u32>,
+}
+
+/// The content of a `m.secret_storage.key.[key_id]` account data event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SecretStorageKeyDescription {
+    /// The algorithm the key uses.
+    pub algorithm: String,
+    /// The human readable name of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Info on how to derive the key from a passphrase, if the key was
+    /// created from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseInfo>,
+    /// The IV that was used to create the MAC to check the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iv: Option<String>,
+    /// The MAC of an encrypted empty message, used to check that a key
+    /// matches this description.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+/// The content of a `m.secret_storage.default_key` account data event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DefaultKeyContent {
+    /// The ID of the default secret storage key.
+    pub key: String,
+}
+
+/// A secret encrypted using the `m.secret_storage.v1.aes-hmac-sha2`
+/// algorithm.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AesHmacSha2EncryptedData {
+    /// The IV that was used to encrypt the secret.
+    pub iv: String,
+    /// The encrypted secret.
+    pub ciphertext: String,
+    /// The MAC of the ciphertext.
+    pub mac: String,
+}
+
+/// The content of an account data event that holds an encrypted secret, e.g.
+/// `m.cross_signing.master`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SecretEventContent {
+    /// The secret encrypted by the various secret storage keys, keyed by the
+    /// key ID.
+    pub encrypted: BTreeMap<String, AesHmacSha2EncryptedData>,
+}
+
+#[derive(Zeroize)]
+#[zeroize(drop)]
+struct DerivedKeys {
+    aes_key: [u8; KEY_SIZE],
+    mac_key: [u8; KEY_SIZE],
+}
+
+/// A key that is used to encrypt secrets that should be stored in the global
+/// account data of the user.
+#[derive(Clone)]
+pub struct SecretStorageKey {
+    key_id: String,
+    key: RecoveryKey,
+    passphrase: Option<PassphraseInfo>,
+}
+
+impl std::fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStorageKey")
+            .field("key_id", &self.key_id)
+            .field("passphrase", &self.passphrase)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecretStorageKey {
+    /// Create a new random secret storage key.
+    ///
+    /// The key can be exported using the [`SecretStorageKey::to_base58()`]
+    /// method so the user can store it somewhere safe.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new() -> Self {
+        let key = RecoveryKey::new().expect("Can't generate a new secret storage key");
+
+        Self { key_id: Self::random_key_id(), key, passphrase: None }
+    }
+
+    /// Create a new secret storage key derived from the given passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new_from_passphrase(passphrase: &str) -> Self {
+        Self::new_from_passphrase_helper(passphrase, PBKDF2_ROUNDS)
+    }
+
+    fn new_from_passphrase_helper(passphrase: &str, rounds: u32) -> Self {
+        let mut salt = Zeroizing::new([0u8; SALT_SIZE]);
+        getrandom(salt.as_mut()).expect("Can't generate randomness");
+
+        let passphrase_info = PassphraseInfo {
+            algorithm: PBKDF2_ALGORITHM.to_owned(),
+            salt: encode(salt.as_ref()),
+            iterations: rounds,
+            bits: None,
+        };
+
+        let key = Self::derive_from_passphrase(passphrase, &passphrase_info);
+
+        Self { key_id: Self::random_key_id(), key, passphrase: Some(passphrase_info) }
+    }
+
+    /// Restore a secret storage key from the given passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The ID of the secret storage key.
+    ///
+    /// * `description` - The content of the `m.secret_storage.key.[key_id]`
+    /// account data event.
+    ///
+    /// * `passphrase` - The passphrase the secret storage key was created
+    /// from.
+    pub fn from_passphrase(
+        key_id: &str,
+        description: &SecretStorageKeyDescription,
+        passphrase: &str,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(description)?;
+
+        let passphrase_info =
+            description.passphrase.as_ref().ok_or(SecretStorageError::MissingPassphraseInfo)?;
+
+        if passphrase_info.algorithm != PBKDF2_ALGORITHM {
+            return Err(SecretStorageError::UnsupportedAlgorithm(
+                passphrase_info.algorithm.to_owned(),
+            ));
+        }
+
+        let key = Self::derive_from_passphrase(passphrase, passphrase_info);
+        let key =
+            Self { key_id: key_id.to_owned(), key, passphrase: Some(passphrase_info.to_owned()) };
+
+        key.check_description(description)?;
+
+        Ok(key)
+    }
+
+    /// Restore a secret storage key from a base58 encoded recovery key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The ID of the secret storage key.
+    ///
+    /// * `description` - The content of the `m.secret_storage.key.[key_id]`
+    /// account data event.
+    ///
+    /// * `recovery_key` - The base58 encoded recovery key, as returned by
+    /// [`SecretStorageKey::to_base58()`].
+    pub fn from_recovery_key(
+        key_id: &str,
+        description: &SecretStorageKeyDescription,
+        recovery_key: &str,
+    ) -> Result<Self, SecretStorageError> {
+        Self::check_algorithm(description)?;
+
+        let key = RecoveryKey::from_base58(recovery_key)?;
+        let key =
+            Self { key_id: key_id.to_owned(), key, passphrase: description.passphrase.clone() };
+
+        key.check_description(description)?;
+
+        Ok(key)
+    }
+
+    /// Get the ID of this secret storage key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Get the event type of the account data event that holds the
+    /// description of this key.
+    pub fn event_type(&self) -> String {
+        format!("m.secret_storage.key.{}", self.key_id)
+    }
+
+    /// Export the key as a base58 encoded recovery key.
+    ///
+    /// The recovery key can be used to restore the secret storage key if the
+    /// passphrase is lost.
+    pub fn to_base58(&self) -> String {
+        self.key.to_base58()
+    }
+
+    /// Get the description of this key, the description should be uploaded
+    /// as the content of the account data event with the type returned by
+    /// [`SecretStorageKey::event_type()`].
+    pub fn description(&self) -> SecretStorageKeyDescription {
+        let check = self.encrypt_bytes(&mut [0u8; KEY_SIZE], "");
+
+        SecretStorageKeyDescription {
+            algorithm: SECRET_STORAGE_ALGORITHM.to_owned(),
+            name: None,
+            passphrase: self.passphrase.clone(),
+            iv: Some(check.iv),
+            mac: Some(check.mac),
+        }
+    }
+
+    /// Encrypt the given secret so it can be uploaded to the account data.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, this is the event type of the
+    /// account data event the secret will be stored in.
+    ///
+    /// * `secret` - The secret that should be encrypted.
+    pub fn encrypt(&self, secret_name: &SecretName, secret: &str) -> SecretEventContent {
+        let mut plaintext = Zeroizing::new(secret.as_bytes().to_vec());
+        let encrypted = self.encrypt_bytes(&mut plaintext, secret_name.as_ref());
+
+        let mut content = SecretEventContent::default();
+        content.encrypted.insert(self.key_id.to_owned(), encrypted);
+
+        content
+    }
+
+    /// Decrypt a secret that was stored in the account data.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, this is the event type of the
+    /// account data event the secret was stored in.
+    ///
+    /// * `content` - The content of the account data event.
+    pub fn decrypt(
+        &self,
+        secret_name: &SecretName,
+        content: &SecretEventContent,
+    ) -> Result<String, SecretStorageError> {
+        let encrypted = content
+            .encrypted
+            .get(&self.key_id)
+            .ok_or_else(|| SecretStorageError::MissingSecret(self.key_id.to_owned()))?;
+
+        let plaintext = Zeroizing::new(self.decrypt_bytes(encrypted, secret_name.as_ref())?);
+
+        Ok(String::from_utf8(plaintext.to_vec())?)
+    }
+
+    fn random_key_id() -> String {
+        let mut key_id = [0u8; KEY_ID_SIZE];
+        getrandom(&mut key_id).expect("Can't generate randomness");
+
+        encode(key_id).chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+    }
+
+    fn derive_from_passphrase(passphrase: &str, info: &PassphraseInfo) -> RecoveryKey {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            key.as_mut(),
+        );
+
+        RecoveryKey::from_slice(key.as_ref()).expect("The derived key has the correct length")
+    }
+
+    fn check_algorithm(
+        description: &SecretStorageKeyDescription,
+    ) -> Result<(), SecretStorageError> {
+        if description.algorithm != SECRET_STORAGE_ALGORITHM {
+            Err(SecretStorageError::UnsupportedAlgorithm(description.algorithm.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_description(
+        &self,
+        description: &SecretStorageKeyDescription,
+    ) -> Result<(), SecretStorageError> {
+        // Keys created by older clients might not contain a MAC to check the
+        // key, there's nothing to check in that case.
+        if let (Some(iv), Some(mac)) = (&description.iv, &description.mac) {
+            let iv = decode(iv.trim_end_matches('='))?;
+
+            if iv.len() != IV_SIZE {
+                return Err(SecretStorageError::InvalidIvLength(iv.len()));
+            }
+
+            let mut check_iv = [0u8; IV_SIZE];
+            check_iv.copy_from_slice(&iv);
+
+            let mut check = self.encrypt_bytes_with_iv(&mut [0u8; KEY_SIZE], "", check_iv);
+            check.mac = mac.to_owned();
+
+            self.decrypt_bytes(&check, "").map_err(|e| match e {
+                SecretStorageError::InvalidMac => SecretStorageError::KeyMismatch,
+                e => e,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn derive_keys(&self, secret_name: &str) -> DerivedKeys {
+        let hkdf = Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE][..]), self.key.as_bytes());
+        let mut okm = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+        hkdf.expand(secret_name.as_bytes(), okm.as_mut()).expect("Can't expand the HKDF output");
+
+        let mut keys = DerivedKeys { aes_key: [0u8; KEY_SIZE], mac_key: [0u8; KEY_SIZE] };
+        keys.aes_key.copy_from_slice(&okm[..KEY_SIZE]);
+        keys.mac_key.copy_from_slice(&okm[KEY_SIZE..]);
+
+        keys
+    }
+
+    fn encrypt_bytes(&self, plaintext: &mut [u8], secret_name: &str) -> AesHmacSha2EncryptedData {
+        let mut iv = [0u8; IV_SIZE];
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        // Clear bit 63 of the IV, some AES-CTR implementations don't handle
+        // the counter overflowing into it.
+        iv[8] &= 0x7f;
+
+        self.encrypt_bytes_with_iv(plaintext, secret_name, iv)
+    }
+
+    fn encrypt_bytes_with_iv(
+        &self,
+        plaintext: &mut [u8],
+        secret_name: &str,
+        iv: [u8; IV_SIZE],
+    ) -> AesHmacSha2EncryptedData {
+        let keys = self.derive_keys(secret_name);
+
+        let aes = Aes256::new(GenericArray::from_slice(&keys.aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(plaintext);
+
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(&keys.mac_key).expect("Can't create an HMAC object");
+        hmac.update(plaintext);
+        let mac = hmac.finalize().into_bytes();
+
+        AesHmacSha2EncryptedData {
+            iv: encode(iv),
+            ciphertext: encode(&plaintext),
+            mac: encode(mac),
+        }
+    }
+
+    fn decrypt_bytes(
+        &self,
+        encrypted: &AesHmacSha2EncryptedData,
+        secret_name: &str,
+    ) -> Result<Vec<u8>, SecretStorageError> {
+        let keys = self.derive_keys(secret_name);
+
+        // Other clients might add padding to the base64 strings.
+        let iv = decode(encrypted.iv.trim_end_matches('='))?;
+        let mut ciphertext = decode(encrypted.ciphertext.trim_end_matches('='))?;
+        let mac = decode(encrypted.mac.trim_end_matches('='))?;
+
+        if iv.len() != IV_SIZE {
+            return Err(SecretStorageError::InvalidIvLength(iv.len()));
+        }
+
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(&keys.mac_key).expect("Can't create an HMAC object");
+        hmac.update(&ciphertext);
+        hmac.verify(&mac).map_err(|_| SecretStorageError::InvalidMac)?;
+
+        let aes = Aes256::new(GenericArray::from_slice(&keys.aes_key));
+        let mut aes = Aes256Ctr::from_block_cipher(aes, GenericArray::from_slice(&iv));
+        aes.apply_keystream(&mut ciphertext);
+
+        Ok(ciphertext)
+    }
+}
+
+impl Default for SecretStorageKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::events::secret::request::SecretName;
+
+    use super::{SecretStorageError, SecretStorageKey, SECRET_STORAGE_ALGORITHM};
+    use crate::utilities::encode;
+
+    #[test]
+    fn encrypt_and_decrypt_secret() {
+        let key = SecretStorageKey::new();
+        let secret_name = SecretName::CrossSigningMasterKey;
+
+        let content = key.encrypt(&secret_name, "It's a secret to everybody");
+        assert!(content.encrypted.contains_key(key.key_id()));
+
+        let decrypted = key.decrypt(&secret_name, &content).unwrap();
+        assert_eq!(decrypted, "It's a secret to everybody");
+
+        // The secret name is bound to the ciphertext.
+        assert!(matches!(
+            key.decrypt(&SecretName::CrossSigningSelfSigningKey, &content),
+            Err(SecretStorageError::InvalidMac)
+        ));
+
+        let other_key = SecretStorageKey::new();
+        assert!(matches!(
+            other_key.decrypt(&secret_name, &content),
+            Err(SecretStorageError::MissingSecret(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_iv_length_is_not_a_mac_error() {
+        let key = SecretStorageKey::new();
+        let secret_name = SecretName::CrossSigningMasterKey;
+
+        let mut content = key.encrypt(&secret_name, "It's a secret to everybody");
+        content.encrypted.get_mut(key.key_id()).unwrap().iv = encode([0u8; 8]);
+
+        assert!(matches!(
+            key.decrypt(&secret_name, &content),
+            Err(SecretStorageError::InvalidIvLength(8))
+        ));
+    }
+
+    #[test]
+    fn restore_from_recovery_key() {
+        let key = SecretStorageKey::new();
+        let description = key.description();
+
+        assert_eq!(description.algorithm, SECRET_STORAGE_ALGORITHM);
+        assert!(description.passphrase.is_none());
+
+        let restored =
+            SecretStorageKey::from_recovery_key(key.key_id(), &description, &key.to_base58())
+                .unwrap();
+
+        let content = key.encrypt(&SecretName::RecoveryKey, "secret");
+        assert_eq!(restored.decrypt(&SecretName::RecoveryKey, &content).unwrap(), "secret");
+
+        let other_key = SecretStorageKey::new();
+        assert!(matches!(
+            SecretStorageKey::from_recovery_key(key.key_id(), &description, &other_key.to_base58()),
+            Err(SecretStorageError::KeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn restore_from_passphrase() {
+        // Use a low number of rounds so the test doesn't take too long.
+        let key = SecretStorageKey::new_from_passphrase_helper("It's a secret to everybody", 10);
+        let description = key.description();
+        assert_eq!(description.passphrase.as_ref().unwrap().iterations, 10);
+
+        let restored = SecretStorageKey::from_passphrase(
+            key.key_id(),
+            &description,
+            "It's a secret to everybody",
+        )
+        .unwrap();
+        assert_eq!(restored.to_base58(), key.to_base58());
+
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(key.key_id(), &description, "wrong passphrase"),
+            Err(SecretStorageError::KeyMismatch)
+        ));
+
+        let random_key = SecretStorageKey::new();
+        assert!(matches!(
+            SecretStorageKey::from_passphrase(
+                random_key.key_id(),
+                &random_key.description(),
+                "It's a secret to everybody"
+            ),
+            Err(SecretStorageError::MissingPassphraseInfo)
+        ));
+    }
+}
@@ -14,12 +14,12 @@
 
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
 
 use dashmap::DashMap;
 use futures::future::join_all;
-use matrix_sdk_common::{executor::spawn, uuid::Uuid};
+use matrix_sdk_common::{executor::spawn, locks::Mutex, uuid::Uuid};
 use ruma::{
     events::{
         room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
@@ -34,8 +34,11 @@ use tracing::{debug, info, trace};
 
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
-    olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareInfo, ShareState},
-    store::{Changes, Result as StoreResult, Store},
+    olm::{
+        Account, InboundGroupSession, OutboundGroupSession, Session, ShareInfo, ShareState,
+        SharingPolicy, WithheldCode, WITHHELD_EVENT_TYPE,
+    },
+    store::{Changes, Result as StoreResult, SharingPolicies, Store},
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
 
@@ -113,13 +116,84 @@ pub struct GroupSessionManager {
     store: Store,
     /// The currently active outbound group sessions.
     sessions: GroupSessionCache,
+    /// The sharing policies, the global one and the ones that were set for
+    /// specific rooms.
+    sharing_policies: Arc<RwLock<SharingPolicies>>,
+    /// Held while the sharing policies are changed and saved, so concurrent
+    /// changes reach the store in the same order as the in-memory copy.
+    sharing_policies_lock: Arc<Mutex<()>>,
 }
 
 impl GroupSessionManager {
     const MAX_TO_DEVICE_MESSAGES: usize = 250;
 
-    pub(crate) fn new(account: Account, store: Store) -> Self {
-        Self { account, store: store.clone(), sessions: GroupSessionCache::new(store) }
+    pub(crate) fn new(account: Account, store: Store, sharing_policies: SharingPolicies) -> Self {
+        Self {
+            account,
+            store: store.clone(),
+            sessions: GroupSessionCache::new(store),
+            sharing_policies: Arc::new(RwLock::new(sharing_policies)),
+            sharing_policies_lock: Default::default(),
+        }
+    }
+
+    /// Get the sharing policy that should be used for the given room.
+    pub fn sharing_policy(&self, room_id: &RoomId) -> SharingPolicy {
+        let policies = self.sharing_policies.read().unwrap();
+        policies.rooms.get(room_id).copied().unwrap_or(policies.global)
+    }
+
+    /// Set the sharing policy that is used for rooms without a policy of their
+    /// own and save it in the store.
+    pub async fn set_sharing_policy(&self, policy: SharingPolicy) -> StoreResult<()> {
+        self.update_sharing_policies(|p| p.global = policy).await
+    }
+
+    /// Set the sharing policy of a single room and save it in the store,
+    /// `None` removes the policy of the room so the global one is used again.
+    pub async fn set_room_sharing_policy(
+        &self,
+        room_id: &RoomId,
+        policy: Option<SharingPolicy>,
+    ) -> StoreResult<()> {
+        self.update_sharing_policies(|p| {
+            if let Some(policy) = policy {
+                p.rooms.insert(room_id.to_owned(), policy);
+            } else {
+                p.rooms.remove(room_id);
+            }
+        })
+        .await
+    }
+
+    async fn update_sharing_policies(
+        &self,
+        update: impl FnOnce(&mut SharingPolicies),
+    ) -> StoreResult<()> {
+        let _guard = self.sharing_policies_lock.lock().await;
+
+        let policies = {
+            let mut policies = self.sharing_policies.write().unwrap();
+            update(&mut policies);
+            policies.clone()
+        };
+
+        let changes = Changes { sharing_policies: Some(policies), ..Default::default() };
+        self.store.save_changes(changes).await
+    }
+
+    /// Get the devices the currently active group session of the given room
+    /// was withheld from, together with the reason why.
+    pub async fn withheld_devices(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<BTreeMap<UserId, BTreeMap<DeviceIdBox, WithheldCode>>> {
+        Ok(self
+            .sessions
+            .get_or_load(room_id)
+            .await?
+            .map(|s| s.withheld_devices())
+            .unwrap_or_default())
     }
 
     pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
@@ -219,6 +293,9 @@ impl GroupSessionManager {
 
     /// Encrypt the given content for the given devices and create a to-device
     /// requests that sends the encrypted content to them.
+    ///
+    /// Devices that we don't have an Olm session with are returned as well,
+    /// they should receive a `m.no_olm` withheld notice.
     async fn encrypt_session_for(
         content: AnyToDeviceEventContent,
         devices: Vec<Device>,
@@ -228,14 +305,17 @@ impl GroupSessionManager {
         ToDeviceRequest,
         BTreeMap<UserId, BTreeMap<DeviceIdBox, ShareInfo>>,
         Vec<Session>,
+        Vec<Device>,
     )> {
         let mut messages = BTreeMap::new();
         let mut changed_sessions = Vec::new();
         let mut share_infos = BTreeMap::new();
+        let mut no_olm_devices = Vec::new();
 
         let encrypt = |device: Device, content: AnyToDeviceEventContent| async move {
             let mut message = BTreeMap::new();
             let mut share_infos = BTreeMap::new();
+            let mut no_olm = None;
 
             let encrypted = device.encrypt(content.clone()).await;
 
@@ -262,13 +342,15 @@ impl GroupSessionManager {
 
                     Some(session)
                 }
-                // TODO we'll want to create m.room_key.withheld here.
                 Err(OlmError::MissingSession)
-                | Err(OlmError::EventError(EventError::MissingSenderKey)) => None,
+                | Err(OlmError::EventError(EventError::MissingSenderKey)) => {
+                    no_olm = Some(device);
+                    None
+                }
                 Err(e) => return Err(e),
             };
 
-            Ok((used_session, share_infos, message))
+            Ok((used_session, share_infos, message, no_olm))
         };
 
         let tasks: Vec<_> =
@@ -277,12 +359,15 @@ impl GroupSessionManager {
         let results = join_all(tasks).await;
 
         for result in results {
-            let (used_session, infos, message) = result.expect("Encryption task panicked")?;
+            let (used_session, infos, message, no_olm) =
+                result.expect("Encryption task panicked")?;
 
             if let Some(session) = used_session {
                 changed_sessions.push(session);
             }
 
+            no_olm_devices.extend(no_olm);
+
             for (user, device_messages) in message.into_iter() {
                 messages.entry(user).or_insert_with(BTreeMap::new).extend(device_messages);
             }
@@ -303,26 +388,80 @@ impl GroupSessionManager {
             "Created a to-device request carrying a room_key"
         );
 
-        Ok((id, request, share_infos, changed_sessions))
+        Ok((id, request, share_infos, changed_sessions, no_olm_devices))
+    }
+
+    /// Create to-device requests carrying `m.room_key.withheld` notices for the
+    /// given devices and queue them up as requests of the outbound session.
+    ///
+    /// Devices that already received a notice with the same code for this
+    /// session are skipped.
+    fn withhold_session(
+        outbound: &OutboundGroupSession,
+        devices: Vec<(Device, WithheldCode)>,
+        being_shared: &DashMap<Uuid, OutboundGroupSession>,
+    ) {
+        let devices: Vec<_> =
+            devices.into_iter().filter(|(d, code)| !outbound.is_withheld_from(d, code)).collect();
+
+        for chunk in devices.chunks(Self::MAX_TO_DEVICE_MESSAGES) {
+            let mut messages = BTreeMap::new();
+
+            for (device, code) in chunk {
+                let content = outbound.withheld_content(code.clone());
+
+                messages.entry(device.user_id().to_owned()).or_insert_with(BTreeMap::new).insert(
+                    DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
+                    content.to_raw(),
+                );
+
+                outbound.mark_withheld_from(device, code.clone());
+            }
+
+            let id = Uuid::new_v4();
+            let request = ToDeviceRequest {
+                event_type: EventType::from(WITHHELD_EVENT_TYPE),
+                txn_id: id,
+                messages,
+            };
+
+            trace!(
+                recipient_count = request.message_count(),
+                transaction_id = ?id,
+                session_id = outbound.session_id(),
+                "Created a to-device request carrying a room key withheld notice"
+            );
+
+            outbound.add_request(id, request.into(), BTreeMap::new());
+            being_shared.insert(id, outbound.clone());
+        }
     }
 
     /// Given a list of user and an outbound session, return the list of users
     /// and their devices that this session should be shared with.
     ///
-    /// Returns a boolean indicating whether the session needs to be rotated and
-    /// the list of users/devices that should receive the session.
+    /// Devices that are blacklisted, that are excluded by the given sharing
+    /// policy or whose owner changed their identity without the change being
+    /// acknowledged won't receive the session.
+    ///
+    /// Returns a boolean indicating whether the session needs to be rotated,
+    /// the list of users/devices that should receive the session and the list
+    /// of devices the session will be withheld from, with the reason why.
     pub async fn collect_session_recipients(
         &self,
         users: impl Iterator<Item = &UserId>,
         history_visibility: HistoryVisibility,
+        sharing_policy: SharingPolicy,
         outbound: &OutboundGroupSession,
-    ) -> OlmResult<(bool, HashMap<UserId, Vec<Device>>)> {
+    ) -> OlmResult<(bool, HashMap<UserId, Vec<Device>>, Vec<(Device, WithheldCode)>)> {
         let users: HashSet<&UserId> = users.collect();
         let mut devices: HashMap<UserId, Vec<Device>> = HashMap::new();
+        let mut withheld_devices = Vec::new();
 
         debug!(
             users = ?users,
             history_visibility = ?history_visibility,
+            sharing_policy = ?sharing_policy,
             session_id = outbound.session_id(),
             "Calculating group session recipients"
         );
@@ -341,7 +480,8 @@ impl GroupSessionManager {
         // To protect the room history we need to rotate the session if either:
         //
         // 1. Any user left the room.
-        // 2. Any of the users' devices got deleted or blacklisted.
+        // 2. Any of the users' devices got deleted, blacklisted or excluded by the
+        //    sharing policy.
         // 3. The history visibility changed.
         //
         // This is calculated in the following code and stored in this variable.
@@ -349,17 +489,33 @@ impl GroupSessionManager {
 
         for user_id in users {
             let user_devices = self.store.get_user_devices(user_id).await?;
-            let non_blacklisted_devices: Vec<Device> =
-                user_devices.devices().filter(|d| !d.is_blacklisted()).collect();
+            let mut recipient_devices = Vec::new();
+
+            for device in user_devices.devices() {
+                let code = if device.is_blacklisted() {
+                    Some(WithheldCode::Blacklisted)
+                } else if device.has_unacknowledged_identity_change() {
+                    Some(WithheldCode::Unverified)
+                } else {
+                    sharing_policy.withheld_code(&device)
+                };
+
+                if let Some(code) = code {
+                    withheld_devices.push((device, code));
+                } else {
+                    recipient_devices.push(device);
+                }
+            }
 
             // If we haven't already concluded that the session should be
             // rotated for other reasons, we also need to check whether any
-            // of the devices in the session got deleted or blacklisted in the
-            // meantime. If so, we should also rotate the session.
+            // of the devices in the session got deleted, blacklisted or
+            // excluded in the meantime. If so, we should also rotate the
+            // session.
             if !should_rotate {
                 // Device IDs that should receive this session
-                let non_blacklisted_device_ids: HashSet<&DeviceId> =
-                    non_blacklisted_devices.iter().map(|d| d.device_id()).collect();
+                let recipient_device_ids: HashSet<&DeviceId> =
+                    recipient_devices.iter().map(|d| d.device_id()).collect();
 
                 if let Some(shared) = outbound.shared_with_set.get(user_id) {
                     // Devices that received this session
@@ -372,18 +528,18 @@ impl GroupSessionManager {
                     // 1. Devices that had previously received the session, and
                     // 2. Devices that would now receive the session
                     //
-                    // represents newly deleted or blacklisted devices. If this
-                    // set is non-empty, we must rotate.
-                    let newly_deleted_or_blacklisted =
-                        shared.difference(&non_blacklisted_device_ids).collect::<HashSet<_>>();
+                    // represents newly deleted, blacklisted or excluded
+                    // devices. If this set is non-empty, we must rotate.
+                    let newly_excluded =
+                        shared.difference(&recipient_device_ids).collect::<HashSet<_>>();
 
-                    if !newly_deleted_or_blacklisted.is_empty() {
+                    if !newly_excluded.is_empty() {
                         should_rotate = true;
                     }
                 };
             }
 
-            devices.entry(user_id.clone()).or_insert_with(Vec::new).extend(non_blacklisted_devices);
+            devices.entry(user_id.clone()).or_insert_with(Vec::new).extend(recipient_devices);
         }
 
         debug!(
@@ -392,7 +548,7 @@ impl GroupSessionManager {
             "Done calculating group session recipients"
         );
 
-        Ok((should_rotate, devices))
+        Ok((should_rotate, devices, withheld_devices))
     }
 
     pub async fn encrypt_request(
@@ -402,7 +558,7 @@ impl GroupSessionManager {
         message_index: u32,
         being_shared: Arc<DashMap<Uuid, OutboundGroupSession>>,
     ) -> OlmResult<Vec<Session>> {
-        let (id, request, share_infos, used_sessions) =
+        let (id, request, share_infos, used_sessions, no_olm_devices) =
             Self::encrypt_session_for(content.clone(), chunk, message_index).await?;
 
         if !request.messages.is_empty() {
@@ -410,6 +566,12 @@ impl GroupSessionManager {
             being_shared.insert(id, outbound.clone());
         }
 
+        Self::withhold_session(
+            &outbound,
+            no_olm_devices.into_iter().map(|d| (d, WithheldCode::NoOlm)).collect(),
+            &being_shared,
+        );
+
         Ok(used_sessions)
     }
 
@@ -438,6 +600,7 @@ impl GroupSessionManager {
 
         let encryption_settings = encryption_settings.into();
         let history_visibility = encryption_settings.history_visibility.clone();
+        let sharing_policy = encryption_settings.sharing_policy;
         let mut changes = Changes::default();
 
         let (outbound, inbound) =
@@ -448,8 +611,9 @@ impl GroupSessionManager {
             changes.inbound_group_sessions.push(inbound);
         }
 
-        let (should_rotate, devices) =
-            self.collect_session_recipients(users, history_visibility, &outbound).await?;
+        let (should_rotate, devices, withheld_devices) = self
+            .collect_session_recipients(users, history_visibility, sharing_policy, &outbound)
+            .await?;
 
         let outbound = if should_rotate {
             let old_session_id = outbound.session_id();
@@ -517,6 +681,8 @@ impl GroupSessionManager {
             changes.sessions.extend(used_sessions?);
         }
 
+        Self::withhold_session(&outbound, withheld_devices, &self.sessions.sessions_being_shared);
+
         let requests = outbound.pending_requests();
 
         debug!(
@@ -21,18 +21,21 @@ use ruma::{
         Request as KeysClaimRequest, Response as KeysClaimResponse,
     },
     assign,
-    events::{dummy::ToDeviceDummyEventContent, AnyToDeviceEventContent},
+    events::{dummy::ToDeviceDummyEventContent, AnyToDeviceEventContent, EventType},
+    serde::Raw,
+    to_device::DeviceIdOrAllDevices,
     DeviceId, DeviceIdBox, DeviceKeyAlgorithm, UserId,
 };
+use serde_json::Value;
 use tracing::{error, info, warn};
 
 use crate::{
-    error::OlmResult,
+    error::{EventError, OlmError, OlmResult},
     gossiping::GossipMachine,
     olm::Account,
     requests::{OutgoingRequest, ToDeviceRequest},
     store::{Changes, Result as StoreResult, Store},
-    ReadOnlyDevice,
+    Device, ReadOnlyDevice,
 };
 
 #[derive(Debug, Clone)]
@@ -112,7 +115,8 @@ impl SessionManager {
 
     /// Check if the session was created to unwedge a Device.
     ///
-    /// If the device was wedged this will queue up a dummy to-device message.
+    /// If the device was wedged this will queue up a dummy to-device message
+    /// and re-request the room keys we're missing from the device.
     async fn check_if_unwedged(&self, user_id: &UserId, device_id: &DeviceId) -> OlmResult<()> {
         if self.wedged_devices.get(user_id).map(|d| d.remove(device_id)).flatten().is_some() {
             if let Some(device) = self.store.get_device(user_id, device_id).await? {
@@ -131,12 +135,81 @@ impl SessionManager {
                 };
 
                 self.outgoing_to_device_requests.insert(request.request_id, request);
+
+                if let Some(sender_key) = device.get_key(DeviceKeyAlgorithm::Curve25519) {
+                    self.key_request_machine.rerequest_undecryptable_sessions(sender_key).await?;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Encrypt a custom to-device event for the given devices.
+    ///
+    /// Olm sessions with the devices need to be established beforehand, see
+    /// [`SessionManager::get_missing_sessions()`]. Devices that we don't have
+    /// an Olm session with are skipped.
+    ///
+    /// Returns a to-device request containing the encrypted event, or `None`
+    /// if the event couldn't be encrypted for any of the devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The devices that should receive the event.
+    ///
+    /// * `event_type` - The type of the event, e.g. `org.example.call`.
+    ///
+    /// * `content` - The JSON content of the event.
+    pub async fn encrypt_to_device_event(
+        &self,
+        devices: &[Device],
+        event_type: &str,
+        content: Value,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        let mut messages = BTreeMap::new();
+        let mut changes = Changes::default();
+
+        for device in devices {
+            match device.encrypt_raw(event_type, content.clone()).await {
+                Ok((session, encrypted)) => {
+                    messages
+                        .entry(device.user_id().to_owned())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(
+                            DeviceIdOrAllDevices::DeviceId(device.device_id().into()),
+                            Raw::new(&AnyToDeviceEventContent::RoomEncrypted(encrypted))
+                                .expect("Failed to serialize encrypted event"),
+                        );
+
+                    changes.sessions.push(session);
+                }
+                Err(OlmError::MissingSession)
+                | Err(OlmError::EventError(EventError::MissingSenderKey)) => {
+                    warn!(
+                        user_id = device.user_id().as_str(),
+                        device_id = device.device_id().as_str(),
+                        event_type,
+                        "Can't encrypt a to-device event for a device, no Olm session found",
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        self.store.save_changes(changes).await?;
+
+        Ok(Some(ToDeviceRequest {
+            event_type: EventType::RoomEncrypted,
+            txn_id: Uuid::new_v4(),
+            messages,
+        }))
+    }
+
     /// Get the a key claiming request for the user/device pairs that we are
     /// missing Olm sessions for.
     ///
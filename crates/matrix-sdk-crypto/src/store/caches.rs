@@ -111,6 +111,14 @@ impl GroupSessionStore {
             .collect()
     }
 
+    /// Get all the group sessions the store knows about for the given room.
+    pub fn get_room_sessions(&self, room_id: &RoomId) -> Vec<InboundGroupSession> {
+        self.entries
+            .get(room_id)
+            .map(|m| m.values().flat_map(|t| t.values().cloned()).collect())
+            .unwrap_or_default()
+    }
+
     /// Get a inbound group session from our store.
     ///
     /// # Arguments
@@ -0,0 +1,756 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A test-suite that every persistent `CryptoStore` implementation should
+//! pass.
+//!
+//! The store type given to the macro needs to provide an
+//! `open_with_passphrase(path, passphrase)` constructor.
+
+macro_rules! cryptostore_integration_tests {
+    ($store:ident) => {
+        use std::collections::BTreeMap;
+
+        use matrix_sdk_common::uuid::Uuid;
+        use matrix_sdk_test::async_test;
+        use olm_rs::outbound_group_session::OlmOutboundGroupSession;
+        use ruma::{
+            encryption::SignedKey, events::room_key_request::RequestedKeyInfo, room_id, user_id,
+            DeviceId, EventEncryptionAlgorithm, UserId,
+        };
+        use tempfile::tempdir;
+        use $crate::{
+            backups::RecoveryKey,
+            gossiping::{GossipRequest, SecretInfo},
+            identities::{
+                device::test::get_device,
+                user::test::{get_other_identity, get_own_identity},
+            },
+            olm::{
+                GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
+                ReadOnlyAccount, RoomKeyWithheldContent, Session, SharingPolicy, WithheldCode,
+            },
+            store::{Changes, CryptoStore, DeviceChanges, IdentityChanges, SharingPolicies},
+        };
+
+        fn alice_id() -> UserId {
+            user_id!("@alice:example.org")
+        }
+
+        fn alice_device_id() -> Box<DeviceId> {
+            "ALICEDEVICE".into()
+        }
+
+        fn bob_id() -> UserId {
+            user_id!("@bob:example.org")
+        }
+
+        fn bob_device_id() -> Box<DeviceId> {
+            "BOBDEVICE".into()
+        }
+
+        async fn get_store(passphrase: Option<&str>) -> ($store, tempfile::TempDir) {
+            let tmpdir = tempdir().unwrap();
+            let tmpdir_path = tmpdir.path().to_str().unwrap();
+
+            let store = $store::open_with_passphrase(tmpdir_path, passphrase)
+                .expect("Can't create a passphrase protected store");
+
+            (store, tmpdir)
+        }
+
+        async fn get_loaded_store() -> (ReadOnlyAccount, $store, tempfile::TempDir) {
+            let (store, dir) = get_store(None).await;
+            let account = get_account();
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            (account, store, dir)
+        }
+
+        fn get_account() -> ReadOnlyAccount {
+            ReadOnlyAccount::new(&alice_id(), &alice_device_id())
+        }
+
+        async fn get_account_and_session() -> (ReadOnlyAccount, Session) {
+            let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+            let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+
+            bob.generate_one_time_keys_helper(1).await;
+            let one_time_key =
+                bob.one_time_keys().await.curve25519().iter().next().unwrap().1.to_owned();
+            let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
+            let sender_key = bob.identity_keys().curve25519().to_owned();
+            let session =
+                alice.create_outbound_session_helper(&sender_key, &one_time_key).await.unwrap();
+
+            (alice, session)
+        }
+
+        #[async_test]
+        async fn create_store() {
+            let tmpdir = tempdir().unwrap();
+            let tmpdir_path = tmpdir.path().to_str().unwrap();
+            let _ = $store::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
+        }
+
+        #[async_test]
+        async fn save_account() {
+            let (store, _dir) = get_store(None).await;
+            assert!(store.load_account().await.unwrap().is_none());
+            let account = get_account();
+
+            store.save_account(account).await.expect("Can't save account");
+        }
+
+        #[async_test]
+        async fn load_account() {
+            let (store, _dir) = get_store(None).await;
+            let account = get_account();
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let loaded_account = store.load_account().await.expect("Can't load account");
+            let loaded_account = loaded_account.unwrap();
+
+            assert_eq!(account, loaded_account);
+        }
+
+        #[async_test]
+        async fn load_account_with_passphrase() {
+            let (store, _dir) = get_store(Some("secret_passphrase")).await;
+            let account = get_account();
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let loaded_account = store.load_account().await.expect("Can't load account");
+            let loaded_account = loaded_account.unwrap();
+
+            assert_eq!(account, loaded_account);
+        }
+
+        #[async_test]
+        async fn save_and_share_account() {
+            let (store, _dir) = get_store(None).await;
+            let account = get_account();
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            account.mark_as_shared();
+            account.update_uploaded_key_count(50);
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let loaded_account = store.load_account().await.expect("Can't load account");
+            let loaded_account = loaded_account.unwrap();
+
+            assert_eq!(account, loaded_account);
+            assert_eq!(account.uploaded_key_count(), loaded_account.uploaded_key_count());
+        }
+
+        #[async_test]
+        async fn load_sessions() {
+            let (store, _dir) = get_store(None).await;
+            let (account, session) = get_account_and_session().await;
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+
+            store.save_changes(changes).await.unwrap();
+
+            let sessions = store
+                .get_sessions(&session.sender_key)
+                .await
+                .expect("Can't load sessions")
+                .unwrap();
+            let loaded_session = sessions.lock().await.get(0).cloned().unwrap();
+
+            assert_eq!(&session, &loaded_session);
+        }
+
+        #[async_test]
+        async fn add_and_save_session() {
+            let (store, dir) = get_store(None).await;
+            let (account, session) = get_account_and_session().await;
+            let sender_key = session.sender_key.to_owned();
+            let session_id = session.session_id().to_owned();
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+            store.save_changes(changes).await.unwrap();
+
+            let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+            let sessions_lock = sessions.lock().await;
+            let session = &sessions_lock[0];
+
+            assert_eq!(session_id, session.session_id());
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            let loaded_account = store.load_account().await.unwrap().unwrap();
+            assert_eq!(account, loaded_account);
+
+            let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+            let sessions_lock = sessions.lock().await;
+            let session = &sessions_lock[0];
+
+            assert_eq!(session_id, session.session_id());
+        }
+
+        #[async_test]
+        async fn save_inbound_group_session() {
+            let (account, store, _dir) = get_loaded_store().await;
+
+            let identity_keys = account.identity_keys();
+            let outbound_session = OlmOutboundGroupSession::new();
+            let session = InboundGroupSession::new(
+                identity_keys.curve25519(),
+                identity_keys.ed25519(),
+                &room_id!("!test:localhost"),
+                GroupSessionKey(outbound_session.session_key()),
+                None,
+            )
+            .expect("Can't create session");
+
+            let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
+
+            store.save_changes(changes).await.expect("Can't save group session");
+        }
+
+        #[async_test]
+        async fn load_inbound_group_session() {
+            let (account, store, dir) = get_loaded_store().await;
+
+            let identity_keys = account.identity_keys();
+            let outbound_session = OlmOutboundGroupSession::new();
+            let session = InboundGroupSession::new(
+                identity_keys.curve25519(),
+                identity_keys.ed25519(),
+                &room_id!("!test:localhost"),
+                GroupSessionKey(outbound_session.session_key()),
+                None,
+            )
+            .expect("Can't create session");
+
+            let mut export = session.export().await;
+
+            export.forwarding_curve25519_key_chain = vec!["some_chain".to_owned()];
+
+            let session = InboundGroupSession::from_export(export).unwrap();
+
+            let changes =
+                Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
+
+            store.save_changes(changes).await.expect("Can't save group session");
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            let loaded_session = store
+                .get_inbound_group_session(
+                    &session.room_id,
+                    &session.sender_key,
+                    session.session_id(),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(session, loaded_session);
+            let export = loaded_session.export().await;
+            assert!(!export.forwarding_curve25519_key_chain.is_empty())
+        }
+
+        #[async_test]
+        async fn load_inbound_group_sessions_for_room() {
+            let (account, store, dir) = get_loaded_store().await;
+
+            let identity_keys = account.identity_keys();
+            let room_id = room_id!("!test:localhost");
+            let other_room_id = room_id!("!test2:localhost");
+
+            let sessions: Vec<InboundGroupSession> = [&room_id, &other_room_id]
+                .iter()
+                .map(|room_id| {
+                    InboundGroupSession::new(
+                        identity_keys.curve25519(),
+                        identity_keys.ed25519(),
+                        room_id,
+                        GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+                        None,
+                    )
+                    .expect("Can't create session")
+                })
+                .collect();
+
+            let changes =
+                Changes { inbound_group_sessions: sessions.clone(), ..Default::default() };
+            store.save_changes(changes).await.expect("Can't save group sessions");
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+            store.load_account().await.unwrap();
+
+            let loaded = store.get_inbound_group_sessions_for_room(&room_id).await.unwrap();
+            assert_eq!(loaded, vec![sessions[0].clone()]);
+            assert_eq!(loaded[0].creation_time(), sessions[0].creation_time());
+            assert!(loaded[0].creation_time().is_some());
+
+            let loaded = store.get_inbound_group_sessions_for_room(&other_room_id).await.unwrap();
+            assert_eq!(loaded, vec![sessions[1].clone()]);
+
+            let unknown_room = room_id!("!unknown:localhost");
+            assert!(store
+                .get_inbound_group_sessions_for_room(&unknown_room)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+
+        #[async_test]
+        async fn load_inbound_group_sessions_in_batches() {
+            let (account, store, _dir) = get_loaded_store().await;
+
+            let identity_keys = account.identity_keys();
+            let room_id = room_id!("!test:localhost");
+
+            let sessions: Vec<InboundGroupSession> = (0..5)
+                .map(|_| {
+                    InboundGroupSession::new(
+                        identity_keys.curve25519(),
+                        identity_keys.ed25519(),
+                        &room_id,
+                        GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+                        None,
+                    )
+                    .expect("Can't create session")
+                })
+                .collect();
+
+            let changes =
+                Changes { inbound_group_sessions: sessions.clone(), ..Default::default() };
+            store.save_changes(changes).await.expect("Can't save group sessions");
+
+            let mut loaded = Vec::new();
+            let mut after: Option<InboundGroupSession> = None;
+
+            loop {
+                let batch =
+                    store.get_inbound_group_sessions_batch(after.as_ref(), 2).await.unwrap();
+                assert!(batch.len() <= 2);
+
+                if let Some(last) = batch.last() {
+                    after = Some(last.clone());
+                    loaded.extend(batch);
+                } else {
+                    break;
+                }
+            }
+
+            assert_eq!(loaded.len(), sessions.len());
+
+            for session in &sessions {
+                assert!(loaded.contains(session));
+            }
+        }
+
+        #[async_test]
+        async fn withheld_info_saving() {
+            let (account, store, dir) = get_loaded_store().await;
+
+            let room_id = room_id!("!test:localhost");
+            let info = RoomKeyWithheldContent::new(
+                &room_id,
+                "session_id",
+                account.identity_keys().curve25519(),
+                WithheldCode::Unverified,
+            );
+
+            assert!(store.get_withheld_info(&room_id, "session_id").await.unwrap().is_none());
+
+            let changes =
+                Changes { withheld_session_info: vec![info.clone()], ..Default::default() };
+            store.save_changes(changes).await.unwrap();
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+            store.load_account().await.unwrap();
+
+            let loaded = store.get_withheld_info(&room_id, "session_id").await.unwrap();
+            assert_eq!(loaded, Some(info));
+            assert!(store.get_withheld_info(&room_id, "other_session").await.unwrap().is_none());
+        }
+
+        #[async_test]
+        async fn save_sharing_policies() {
+            let (_, store, dir) = get_loaded_store().await;
+
+            assert_eq!(store.load_sharing_policies().await.unwrap(), SharingPolicies::default());
+
+            let mut policies =
+                SharingPolicies { global: SharingPolicy::TrustOnFirstUse, ..Default::default() };
+            policies.rooms.insert(room_id!("!test:localhost"), SharingPolicy::CrossSignedOnly);
+
+            let changes =
+                Changes { sharing_policies: Some(policies.clone()), ..Default::default() };
+            store.save_changes(changes).await.unwrap();
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+            store.load_account().await.unwrap();
+
+            assert_eq!(store.load_sharing_policies().await.unwrap(), policies);
+        }
+
+        #[async_test]
+        async fn inbound_group_session_backup_state() {
+            let (account, store, dir) = get_loaded_store().await;
+
+            let identity_keys = account.identity_keys();
+            let outbound_session = OlmOutboundGroupSession::new();
+            let session = InboundGroupSession::new(
+                identity_keys.curve25519(),
+                identity_keys.ed25519(),
+                &room_id!("!test:localhost"),
+                GroupSessionKey(outbound_session.session_key()),
+                None,
+            )
+            .expect("Can't create session");
+
+            let changes =
+                Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
+            store.save_changes(changes).await.expect("Can't save group session");
+
+            assert_eq!(store.inbound_group_session_counts().await.unwrap().backed_up, 0);
+            assert_eq!(store.inbound_group_sessions_for_backup(10).await.unwrap().len(), 1);
+
+            session.mark_as_backed_up();
+            let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
+            store.save_changes(changes).await.expect("Can't save group session");
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+            store.load_account().await.unwrap();
+
+            let counts = store.inbound_group_session_counts().await.unwrap();
+            assert_eq!(counts.total, 1);
+            assert_eq!(counts.backed_up, 1);
+            assert!(store.inbound_group_sessions_for_backup(10).await.unwrap().is_empty());
+
+            store.reset_backup_state().await.unwrap();
+            assert_eq!(store.inbound_group_session_counts().await.unwrap().backed_up, 0);
+            assert_eq!(store.inbound_group_sessions_for_backup(10).await.unwrap().len(), 1);
+        }
+
+        #[async_test]
+        async fn backup_keys_saving() {
+            let (store, dir) = get_store(Some("secret_passphrase")).await;
+
+            let backup_keys = store.load_backup_keys().await.unwrap();
+            assert!(backup_keys.recovery_key.is_none());
+            assert!(backup_keys.backup_version.is_none());
+
+            let recovery_key = RecoveryKey::new().unwrap();
+            let encoded_key = recovery_key.to_base64();
+
+            let changes = Changes {
+                recovery_key: Some(recovery_key),
+                backup_version: Some("1".to_owned()),
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), Some("secret_passphrase"))
+                .expect("Can't create store");
+
+            let backup_keys = store.load_backup_keys().await.unwrap();
+            assert_eq!(backup_keys.recovery_key.unwrap().to_base64(), encoded_key);
+            assert_eq!(backup_keys.backup_version.as_deref(), Some("1"));
+
+            store.reset_backup_state().await.unwrap();
+            assert!(store.load_backup_keys().await.unwrap().backup_version.is_none());
+        }
+
+        #[async_test]
+        async fn test_tracked_users() {
+            let (_account, store, dir) = get_loaded_store().await;
+            let device = get_device();
+
+            assert!(store.update_tracked_user(device.user_id(), false).await.unwrap());
+            assert!(!store.update_tracked_user(device.user_id(), false).await.unwrap());
+
+            assert!(store.is_user_tracked(device.user_id()));
+            assert!(!store.users_for_key_query().contains(device.user_id()));
+            assert!(!store.update_tracked_user(device.user_id(), true).await.unwrap());
+            assert!(store.users_for_key_query().contains(device.user_id()));
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            assert!(store.is_user_tracked(device.user_id()));
+            assert!(store.users_for_key_query().contains(device.user_id()));
+
+            store.update_tracked_user(device.user_id(), false).await.unwrap();
+            assert!(!store.users_for_key_query().contains(device.user_id()));
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            assert!(!store.users_for_key_query().contains(device.user_id()));
+        }
+
+        #[async_test]
+        async fn device_saving() {
+            let (_account, store, dir) = get_loaded_store().await;
+            let device = get_device();
+
+            let changes = Changes {
+                devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            let loaded_device =
+                store.get_device(device.user_id(), device.device_id()).await.unwrap().unwrap();
+
+            assert_eq!(device, loaded_device);
+
+            for algorithm in loaded_device.algorithms() {
+                assert!(device.algorithms().contains(algorithm));
+            }
+            assert_eq!(device.algorithms().len(), loaded_device.algorithms().len());
+            assert_eq!(device.keys(), loaded_device.keys());
+
+            let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
+            assert_eq!(&**user_devices.keys().next().unwrap(), device.device_id());
+            assert_eq!(user_devices.values().next().unwrap(), &device);
+        }
+
+        #[async_test]
+        async fn device_deleting() {
+            let (_account, store, dir) = get_loaded_store().await;
+            let device = get_device();
+
+            let changes = Changes {
+                devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+
+            let changes = Changes {
+                devices: DeviceChanges { deleted: vec![device.clone()], ..Default::default() },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            let loaded_device =
+                store.get_device(device.user_id(), device.device_id()).await.unwrap();
+
+            assert!(loaded_device.is_none());
+        }
+
+        #[async_test]
+        async fn user_saving() {
+            let dir = tempdir().unwrap();
+            let tmpdir_path = dir.path().to_str().unwrap();
+
+            let user_id = user_id!("@example:localhost");
+            let device_id: &DeviceId = "WSKKLTJZCL".into();
+
+            let store =
+                $store::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
+
+            let account = ReadOnlyAccount::new(&user_id, device_id);
+
+            store.save_account(account.clone()).await.expect("Can't save account");
+
+            let own_identity = get_own_identity();
+
+            let changes = Changes {
+                identities: IdentityChanges {
+                    changed: vec![own_identity.clone().into()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.expect("Can't save identity");
+
+            drop(store);
+
+            let store = $store::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+            store.load_account().await.unwrap();
+
+            let loaded_user =
+                store.get_user_identity(own_identity.user_id()).await.unwrap().unwrap();
+
+            assert_eq!(loaded_user.master_key(), own_identity.master_key());
+            assert_eq!(loaded_user.self_signing_key(), own_identity.self_signing_key());
+            assert_eq!(loaded_user, own_identity.clone().into());
+
+            let other_identity = get_other_identity();
+
+            let changes = Changes {
+                identities: IdentityChanges {
+                    changed: vec![other_identity.clone().into()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+
+            let loaded_user =
+                store.get_user_identity(other_identity.user_id()).await.unwrap().unwrap();
+
+            assert_eq!(loaded_user.master_key(), other_identity.master_key());
+            assert_eq!(loaded_user.self_signing_key(), other_identity.self_signing_key());
+            assert_eq!(loaded_user, other_identity.into());
+
+            own_identity.mark_as_verified();
+
+            let changes = Changes {
+                identities: IdentityChanges {
+                    changed: vec![own_identity.into()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            store.save_changes(changes).await.unwrap();
+            let loaded_user = store.get_user_identity(&user_id).await.unwrap().unwrap();
+            assert!(loaded_user.own().unwrap().is_verified())
+        }
+
+        #[async_test]
+        async fn private_identity_saving() {
+            let (_, store, _dir) = get_loaded_store().await;
+            assert!(store.load_identity().await.unwrap().is_none());
+            let identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+
+            let changes =
+                Changes { private_identity: Some(identity.clone()), ..Default::default() };
+
+            store.save_changes(changes).await.unwrap();
+            let loaded_identity = store.load_identity().await.unwrap().unwrap();
+            assert_eq!(identity.user_id(), loaded_identity.user_id());
+        }
+
+        #[async_test]
+        async fn olm_hash_saving() {
+            let (_, store, _dir) = get_loaded_store().await;
+
+            let hash = OlmMessageHash {
+                sender_key: "test_sender".to_owned(),
+                hash: "test_hash".to_owned(),
+            };
+
+            let mut changes = Changes::default();
+            changes.message_hashes.push(hash.clone());
+
+            assert!(!store.is_message_known(&hash).await.unwrap());
+            store.save_changes(changes).await.unwrap();
+            assert!(store.is_message_known(&hash).await.unwrap());
+        }
+
+        #[async_test]
+        async fn key_request_saving() {
+            let (account, store, _dir) = get_loaded_store().await;
+
+            let id = Uuid::new_v4();
+            let info: SecretInfo = RequestedKeyInfo::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                room_id!("!test:localhost"),
+                "test_sender_key".to_string(),
+                "test_session_id".to_string(),
+            )
+            .into();
+
+            let request = GossipRequest {
+                request_recipient: account.user_id().to_owned(),
+                request_id: id,
+                info: info.clone(),
+                sent_out: false,
+            };
+
+            assert!(store.get_outgoing_secret_requests(id).await.unwrap().is_none());
+
+            let mut changes = Changes::default();
+            changes.key_requests.push(request.clone());
+            store.save_changes(changes).await.unwrap();
+
+            let request = Some(request);
+
+            let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
+            assert_eq!(request, stored_request);
+
+            let stored_request = store.get_secret_request_by_info(&info).await.unwrap();
+            assert_eq!(request, stored_request);
+            assert!(!store.get_unsent_secret_requests().await.unwrap().is_empty());
+
+            let request = GossipRequest {
+                request_recipient: account.user_id().to_owned(),
+                request_id: id,
+                info: info.clone(),
+                sent_out: true,
+            };
+
+            let mut changes = Changes::default();
+            changes.key_requests.push(request.clone());
+            store.save_changes(changes).await.unwrap();
+
+            assert!(store.get_unsent_secret_requests().await.unwrap().is_empty());
+            let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
+            assert_eq!(Some(request), stored_request);
+
+            store.delete_outgoing_secret_requests(id).await.unwrap();
+
+            let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
+            assert_eq!(None, stored_request);
+
+            let stored_request = store.get_secret_request_by_info(&info).await.unwrap();
+            assert_eq!(None, stored_request);
+            assert!(store.get_unsent_secret_requests().await.unwrap().is_empty());
+        }
+    };
+}
@@ -13,22 +13,28 @@
 // limitations under the License.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Bound,
     sync::Arc,
 };
 
 use dashmap::{DashMap, DashSet};
-use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid};
+use matrix_sdk_common::{
+    async_trait,
+    locks::{Mutex, RwLock},
+    uuid::Uuid,
+};
 use ruma::{DeviceId, DeviceIdBox, RoomId, UserId};
 
 use super::{
     caches::{DeviceStore, GroupSessionStore, SessionStore},
-    Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    BackupKeys, Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, RoomKeyCounts,
+    Session, SharingPolicies,
 };
 use crate::{
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{OutboundGroupSession, PrivateCrossSigningIdentity, RoomKeyWithheldContent},
 };
 
 fn encode_key_info(info: &SecretInfo) -> String {
@@ -45,6 +51,9 @@ fn encode_key_info(info: &SecretInfo) -> String {
 pub struct MemoryStore {
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
+    /// The keys of the inbound group sessions in the order they are returned
+    /// in batches.
+    inbound_group_session_keys: Arc<RwLock<BTreeSet<(RoomId, String, String)>>>,
     tracked_users: Arc<DashSet<UserId>>,
     users_for_key_query: Arc<DashSet<UserId>>,
     olm_hashes: Arc<DashMap<String, DashSet<String>>>,
@@ -52,6 +61,9 @@ pub struct MemoryStore {
     identities: Arc<DashMap<UserId, ReadOnlyUserIdentities>>,
     outgoing_key_requests: Arc<DashMap<Uuid, GossipRequest>>,
     key_requests_by_info: Arc<DashMap<String, Uuid>>,
+    backup_keys: Arc<RwLock<BackupKeys>>,
+    withheld_info: Arc<DashMap<RoomId, DashMap<String, RoomKeyWithheldContent>>>,
+    sharing_policies: Arc<RwLock<SharingPolicies>>,
 }
 
 impl Default for MemoryStore {
@@ -59,6 +71,7 @@ impl Default for MemoryStore {
         MemoryStore {
             sessions: SessionStore::new(),
             inbound_group_sessions: GroupSessionStore::new(),
+            inbound_group_session_keys: Default::default(),
             tracked_users: Default::default(),
             users_for_key_query: Default::default(),
             olm_hashes: Default::default(),
@@ -66,6 +79,9 @@ impl Default for MemoryStore {
             identities: Default::default(),
             outgoing_key_requests: Default::default(),
             key_requests_by_info: Default::default(),
+            backup_keys: RwLock::new(BackupKeys::default()).into(),
+            withheld_info: Default::default(),
+            sharing_policies: Default::default(),
         }
     }
 }
@@ -95,7 +111,14 @@ impl MemoryStore {
     }
 
     async fn save_inbound_group_sessions(&self, mut sessions: Vec<InboundGroupSession>) {
+        let mut keys = self.inbound_group_session_keys.write().await;
+
         for session in sessions.drain(..) {
+            keys.insert((
+                session.room_id().to_owned(),
+                session.sender_key().to_owned(),
+                session.session_id().to_owned(),
+            ));
             self.inbound_group_sessions.add(session);
         }
     }
@@ -143,6 +166,33 @@ impl CryptoStore for MemoryStore {
             self.key_requests_by_info.insert(info_string, id);
         }
 
+        for info in changes.withheld_session_info {
+            if let (Some(room_id), Some(session_id)) =
+                (info.room_id.clone(), info.session_id.clone())
+            {
+                self.withheld_info
+                    .entry(room_id)
+                    .or_insert_with(DashMap::new)
+                    .insert(session_id, info);
+            }
+        }
+
+        if changes.recovery_key.is_some() || changes.backup_version.is_some() {
+            let mut backup_keys = self.backup_keys.write().await;
+
+            if let Some(recovery_key) = changes.recovery_key {
+                backup_keys.recovery_key = Some(recovery_key);
+            }
+
+            if let Some(version) = changes.backup_version {
+                backup_keys.backup_version = Some(version);
+            }
+        }
+
+        if let Some(policies) = changes.sharing_policies {
+            *self.sharing_policies.write().await = policies;
+        }
+
         Ok(())
     }
 
@@ -163,6 +213,86 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<InboundGroupSession>> {
+        Ok(self.inbound_group_sessions.get_room_sessions(room_id))
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let start = if let Some(after) = after {
+            Bound::Excluded((
+                after.room_id().to_owned(),
+                after.sender_key().to_owned(),
+                after.session_id().to_owned(),
+            ))
+        } else {
+            Bound::Unbounded
+        };
+
+        Ok(self
+            .inbound_group_session_keys
+            .read()
+            .await
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .filter_map(|(room_id, sender_key, session_id)| {
+                self.inbound_group_sessions.get(room_id, sender_key, session_id)
+            })
+            .collect())
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let sessions = self.inbound_group_sessions.get_all();
+        let backed_up = sessions.iter().filter(|s| s.backed_up()).count();
+
+        Ok(RoomKeyCounts { total: sessions.len(), backed_up })
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        Ok(self
+            .inbound_group_sessions
+            .get_all()
+            .into_iter()
+            .filter(|s| !s.backed_up())
+            .take(limit)
+            .collect())
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        for session in self.inbound_group_sessions.get_all() {
+            session.reset_backup_state();
+        }
+
+        self.backup_keys.write().await.backup_version = None;
+
+        Ok(())
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        Ok(self.backup_keys.read().await.to_owned())
+    }
+
+    async fn load_sharing_policies(&self) -> Result<SharingPolicies> {
+        Ok(self.sharing_policies.read().await.to_owned())
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldContent>> {
+        Ok(self.withheld_info.get(room_id).and_then(|s| s.get(session_id).map(|i| i.clone())))
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         _: &RoomId,
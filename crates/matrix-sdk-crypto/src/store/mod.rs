@@ -17,9 +17,9 @@
 //! The storage layer for the [`OlmMachine`] can be customized using a trait.
 //! Implementing your own [`CryptoStore`]
 //!
-//! An in-memory only store is provided as well as a Sled based one, depending
-//! on your needs and targets a custom store may be implemented, e.g. for
-//! `wasm-unknown-unknown` an indexeddb store would be needed
+//! An in-memory only store is provided as well as a Sled and a SQLite based
+//! one, depending on your needs and targets a custom store may be implemented,
+//! e.g. for `wasm-unknown-unknown` an indexeddb store would be needed
 //!
 //! ```
 //! # use matrix_sdk_crypto::{
@@ -38,13 +38,18 @@
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 pub mod caches;
+#[cfg(all(test, any(feature = "sled_cryptostore", feature = "sqlite_cryptostore")))]
+#[macro_use]
+mod integration_tests;
 mod memorystore;
 mod pickle_key;
 #[cfg(feature = "sled_cryptostore")]
 pub(crate) mod sled;
+#[cfg(feature = "sqlite_cryptostore")]
+pub(crate) mod sqlite;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
     ops::Deref,
@@ -60,6 +65,7 @@ use ruma::{
     events::secret::request::SecretName, identifiers::Error as IdentifierValidationError, DeviceId,
     DeviceIdBox, DeviceKeyAlgorithm, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 use tracing::{info, warn};
@@ -67,7 +73,10 @@ use zeroize::Zeroize;
 
 #[cfg(feature = "sled_cryptostore")]
 pub use self::sled::SledStore;
+#[cfg(feature = "sqlite_cryptostore")]
+pub use self::sqlite::SqliteCryptoStore;
 use crate::{
+    backups::RecoveryKey,
     error::SessionUnpicklingError,
     gossiping::{GossipRequest, SecretInfo},
     identities::{
@@ -75,13 +84,18 @@ use crate::{
         Device, ReadOnlyDevice, ReadOnlyUserIdentities, UserDevices,
     },
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        ExportedRoomKey, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, RoomKeyWithheldContent, Session,
+        SharingPolicy,
     },
     verification::VerificationMachine,
     CrossSigningStatus,
 };
 
+/// The number of room keys that are saved to the store at once while
+/// importing room keys.
+pub(crate) const IMPORT_BATCH_SIZE: usize = 1000;
+
 /// A `CryptoStore` specific result type.
 pub type Result<T, E = CryptoStoreError> = std::result::Result<T, E>;
 
@@ -111,6 +125,10 @@ pub struct Changes {
     pub identities: IdentityChanges,
     pub key_requests: Vec<GossipRequest>,
     pub devices: DeviceChanges,
+    pub recovery_key: Option<RecoveryKey>,
+    pub backup_version: Option<String>,
+    pub withheld_session_info: Vec<RoomKeyWithheldContent>,
+    pub sharing_policies: Option<SharingPolicies>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -137,6 +155,34 @@ impl DeviceChanges {
     }
 }
 
+/// Struct holding info about how many room keys the store has.
+#[derive(Debug, Clone, Default)]
+pub struct RoomKeyCounts {
+    /// The total number of room keys the store has.
+    pub total: usize,
+    /// The number of backed up room keys the store has.
+    pub backed_up: usize,
+}
+
+/// Stored versions of the backup keys.
+#[derive(Default, Debug, Clone)]
+pub struct BackupKeys {
+    /// The recovery key, the one used to decrypt backed up room keys.
+    pub recovery_key: Option<RecoveryKey>,
+    /// The version that we are using for backups.
+    pub backup_version: Option<String>,
+}
+
+/// The stored sharing policies, deciding which devices receive our room keys.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SharingPolicies {
+    /// The policy that is used for rooms that don't have a policy of their
+    /// own.
+    pub global: SharingPolicy,
+    /// Policies that were set for specific rooms.
+    pub rooms: BTreeMap<RoomId, SharingPolicy>,
+}
+
 /// A struct containing private cross signing keys that can be backed up or
 /// uploaded to the secret store.
 #[derive(Zeroize)]
@@ -206,6 +252,10 @@ impl Store {
         self.identity.clone()
     }
 
+    pub fn account(&self) -> &ReadOnlyAccount {
+        &self.verification_machine.store.account
+    }
+
     pub async fn save_sessions(&self, sessions: &[Session]) -> Result<()> {
         let changes = Changes { sessions: sessions.to_vec(), ..Default::default() };
 
@@ -222,7 +272,6 @@ impl Store {
         self.save_changes(changes).await
     }
 
-    #[cfg(test)]
     pub async fn save_inbound_group_sessions(
         &self,
         sessions: &[InboundGroupSession],
@@ -232,6 +281,111 @@ impl Store {
         self.save_changes(changes).await
     }
 
+    /// Import the given room keys into the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - A list of previously exported keys that should be
+    /// imported into our store. If we already have a better version of a key
+    /// the key will *not* be imported.
+    ///
+    /// * `from_backup` - Were the room keys imported from the backup, if true
+    /// will mark the room keys as already backed up.
+    ///
+    /// * `progress_listener` - Closure that will be called with the index of
+    /// the currently processed room key and the total number of room keys.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// key export.
+    pub async fn import_room_keys(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        from_backup: bool,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<(usize, usize)> {
+        let total_sessions = exported_keys.len();
+
+        self.import_room_keys_stream(exported_keys.into_iter().map(Ok), from_backup, |i| {
+            progress_listener(i, total_sessions)
+        })
+        .await
+    }
+
+    /// Import the given room keys into the store, one room key at a time.
+    ///
+    /// Unlike [`Store::import_room_keys()`] this never requires all the room
+    /// keys to be in memory at once, the room keys are saved to the store in
+    /// batches of [`IMPORT_BATCH_SIZE`] as they are consumed.
+    ///
+    /// # Arguments
+    ///
+    /// * `exported_keys` - An iterator over previously exported keys that
+    /// should be imported into our store. The iteration stops at the first
+    /// error, room keys that were processed before the error stay imported.
+    ///
+    /// * `from_backup` - Were the room keys imported from the backup, if true
+    /// will mark the room keys as already backed up.
+    ///
+    /// * `progress_listener` - Closure that will be called with the index of
+    /// the currently processed room key.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found.
+    pub async fn import_room_keys_stream<E: From<CryptoStoreError>>(
+        &self,
+        exported_keys: impl IntoIterator<Item = Result<ExportedRoomKey, E>>,
+        from_backup: bool,
+        progress_listener: impl Fn(usize),
+    ) -> Result<(usize, usize), E> {
+        let mut sessions = Vec::new();
+        let mut imported_sessions = 0;
+        let mut total_sessions = 0;
+
+        for (i, key) in exported_keys.into_iter().enumerate() {
+            let session = if from_backup {
+                InboundGroupSession::from_backup(key?)
+            } else {
+                InboundGroupSession::from_export(key?)
+            }
+            .map_err(CryptoStoreError::from)?;
+
+            // Only import the session if we didn't have this session or if it's
+            // a better version of the same session, that is the first known
+            // index is lower.
+            if !self.has_better_session(&session).await? {
+                sessions.push(session)
+            }
+
+            total_sessions = i + 1;
+            progress_listener(i);
+
+            if sessions.len() >= IMPORT_BATCH_SIZE {
+                imported_sessions += sessions.len();
+                self.save_inbound_group_sessions(&sessions).await?;
+                sessions.clear();
+            }
+        }
+
+        if !sessions.is_empty() {
+            imported_sessions += sessions.len();
+            self.save_inbound_group_sessions(&sessions).await?;
+        }
+
+        info!("Successfully imported {} inbound group sessions", imported_sessions);
+
+        Ok((imported_sessions, total_sessions))
+    }
+
+    async fn has_better_session(&self, session: &InboundGroupSession) -> Result<bool> {
+        Ok(self
+            .inner
+            .get_inbound_group_session(&session.room_id, &session.sender_key, session.session_id())
+            .await?
+            .map(|existing| existing.first_known_index() <= session.first_known_index())
+            .unwrap_or(false))
+    }
+
     /// Get the display name of our own device.
     pub async fn device_display_name(&self) -> Result<Option<String>, CryptoStoreError> {
         Ok(self
@@ -483,6 +637,11 @@ pub enum CryptoStoreError {
     #[error(transparent)]
     Database(#[from] sled::Error),
 
+    /// Error in the internal SQLite database
+    #[cfg(feature = "sqlite_cryptostore")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
     /// An IO error occurred.
     #[error(transparent)]
     Io(#[from] IoError),
@@ -507,6 +666,15 @@ pub enum CryptoStoreError {
     #[error("An object failed to be decrypted while unpickling")]
     UnpicklingError,
 
+    /// The store isn't encrypted with a passphrase, so the passphrase can't be
+    /// changed.
+    #[error("the store isn't encrypted with a passphrase")]
+    UnencryptedStore,
+
+    /// The store is already encrypted with a passphrase.
+    #[error("the store is already encrypted with a passphrase")]
+    EncryptedStore,
+
     /// A Matrix identifier failed to be validated.
     #[error(transparent)]
     IdentifierValidation(#[from] IdentifierValidationError),
@@ -566,6 +734,74 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
 
+    /// Get all the inbound group sessions we have stored for the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the sessions belong to.
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<InboundGroupSession>>;
+
+    /// Get a batch of the inbound group sessions we have stored.
+    ///
+    /// The sessions are returned in a stable order that is defined by the
+    /// store, this can be used to go through every stored session without
+    /// loading all of them at once. Sessions that can't be unpickled are
+    /// logged and skipped, an empty batch is only returned once we went
+    /// through all the sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `after` - The last session of the previous batch, `None` to get the
+    /// first batch.
+    ///
+    /// * `limit` - The maximum number of sessions that should be returned.
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>>;
+
+    /// Get the number inbound group sessions we have and how many of them are
+    /// backed up.
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts>;
+
+    /// Get all the inbound group sessions we have not backed up yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of sessions that should be returned.
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>>;
+
+    /// Reset the backup state of all the stored inbound group sessions and
+    /// forget the backup version we were using.
+    async fn reset_backup_state(&self) -> Result<()>;
+
+    /// Get the backup keys we have stored.
+    async fn load_backup_keys(&self) -> Result<BackupKeys>;
+
+    /// Get the sharing policies we have stored.
+    async fn load_sharing_policies(&self) -> Result<SharingPolicies>;
+
+    /// Get the `m.room_key.withheld` notice we received for the given room
+    /// key, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the room that the room key belongs to.
+    ///
+    /// * `session_id` - The unique id of the room key.
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldContent>>;
+
     /// Get the outbound group sessions we have stored that is used for the
     /// given room.
     async fn get_outbound_group_sessions(
@@ -15,6 +15,7 @@
 use std::{
     collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
+    ops::Bound,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
@@ -29,25 +30,29 @@ use ruma::{
 pub use sled::Error;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, Transactional, Tree,
+    Config, Db, IVec, Transactional, Tree,
 };
-use tracing::trace;
+use tracing::{trace, warn};
 use uuid::Uuid;
 
 use super::{
-    caches::SessionStore, Changes, CryptoStore, CryptoStoreError, InboundGroupSession, PickleKey,
-    ReadOnlyAccount, Result, Session,
+    caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, InboundGroupSession,
+    PickleKey, ReadOnlyAccount, Result, RoomKeyCounts, Session, SharingPolicies,
 };
 use crate::{
+    backups::{PickledRecoveryKey, RecoveryKey},
     gossiping::{GossipRequest, SecretInfo},
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
-    olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity,
+        RoomKeyWithheldContent,
+    },
 };
 
 /// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
 /// panic once we try to pickle a Signing object.
 const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
-const DATABASE_VERSION: u8 = 1;
+const DATABASE_VERSION: u8 = 2;
 
 trait EncodeKey {
     const SEPARATOR: u8 = 0xff;
@@ -154,6 +159,8 @@ pub struct SledStore {
     olm_hashes: Tree,
     sessions: Tree,
     inbound_group_sessions: Tree,
+    /// The keys of the inbound group sessions that aren't backed up yet.
+    inbound_group_sessions_for_backup: Tree,
     outbound_group_sessions: Tree,
 
     outgoing_secret_requests: Tree,
@@ -164,6 +171,12 @@ pub struct SledStore {
     identities: Tree,
 
     tracked_users: Tree,
+
+    backup_keys: Tree,
+
+    withheld_info: Tree,
+
+    sharing_policies: Tree,
 }
 
 impl std::fmt::Debug for SledStore {
@@ -229,6 +242,22 @@ impl SledStore {
             db.drop_tree("outbound_group_sessions")?;
         }
 
+        if version < 2 {
+            // Remember which inbound group sessions still need to be backed
+            // up, so we don't need to look at every session to find them.
+            let inbound_group_sessions = db.open_tree("inbound_group_sessions")?;
+            let sessions_for_backup = db.open_tree("inbound_group_sessions_for_backup")?;
+
+            for item in inbound_group_sessions.iter() {
+                let (key, pickle) = item?;
+                let pickle: PickledInboundGroupSession = serde_json::from_slice(&pickle)?;
+
+                if !pickle.backed_up {
+                    sessions_for_backup.insert(key, IVec::default())?;
+                }
+            }
+        }
+
         db.insert("version", DATABASE_VERSION.to_be_bytes().as_ref())?;
 
         Ok(())
@@ -241,6 +270,8 @@ impl SledStore {
 
         let sessions = db.open_tree("session")?;
         let inbound_group_sessions = db.open_tree("inbound_group_sessions")?;
+        let inbound_group_sessions_for_backup =
+            db.open_tree("inbound_group_sessions_for_backup")?;
 
         let outbound_group_sessions = db.open_tree("outbound_group_sessions")?;
 
@@ -254,6 +285,12 @@ impl SledStore {
         let unsent_secret_requests = db.open_tree("unsent_secret_requests")?;
         let secret_requests_by_info = db.open_tree("secret_requests_by_info")?;
 
+        let backup_keys = db.open_tree("backup_keys")?;
+
+        let withheld_info = db.open_tree("withheld_info")?;
+
+        let sharing_policies = db.open_tree("sharing_policies")?;
+
         let session_cache = SessionStore::new();
 
         let pickle_key = if let Some(passphrase) = passphrase {
@@ -275,6 +312,7 @@ impl SledStore {
             tracked_users_cache: DashSet::new().into(),
             users_for_key_query_cache: DashSet::new().into(),
             inbound_group_sessions,
+            inbound_group_sessions_for_backup,
             outbound_group_sessions,
             outgoing_secret_requests,
             unsent_secret_requests,
@@ -283,6 +321,9 @@ impl SledStore {
             tracked_users,
             olm_hashes,
             identities,
+            backup_keys,
+            withheld_info,
+            sharing_policies,
         })
     }
 
@@ -302,6 +343,182 @@ impl SledStore {
         Ok(key)
     }
 
+    /// Change the passphrase that protects the private data of this store.
+    ///
+    /// Only the pickle key is re-encrypted with the new passphrase, the pickled
+    /// objects themselves are left untouched, so this is a single atomic write.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase the store is currently encrypted
+    /// with.
+    ///
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        let encrypted =
+            self.inner.get("pickle_key".encode())?.ok_or(CryptoStoreError::UnencryptedStore)?;
+        let pickle_key =
+            PickleKey::from_encrypted(old_passphrase, serde_json::from_slice(&encrypted)?)
+                .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        let encrypted = pickle_key.encrypt(new_passphrase);
+        self.inner.insert("pickle_key".encode(), serde_json::to_vec(&encrypted)?)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Encrypt an existing, unencrypted, sled based cryptostore at the given
+    /// path with the given passphrase.
+    ///
+    /// A new pickle key is created and all the private data is re-pickled
+    /// using it, the pickle key is then encrypted with the passphrase and
+    /// stored alongside the data in a single transaction.
+    ///
+    /// The store must not be opened while this runs, afterwards it needs to be
+    /// opened using [`SledStore::open_with_passphrase()`] with the same
+    /// passphrase.
+    pub async fn encrypt_store(path: impl AsRef<Path>, passphrase: &str) -> Result<()> {
+        let path = path.as_ref().join("matrix-sdk-crypto");
+        let db = Config::new().temporary(false).path(&path).open()?;
+
+        if db.contains_key("pickle_key".encode())? {
+            return Err(CryptoStoreError::EncryptedStore);
+        }
+
+        let store = SledStore::open_helper(db, Some(path), None)?;
+        store.repickle(&PickleKey::new(), passphrase).await
+    }
+
+    async fn repickle(&self, pickle_key: &PickleKey, passphrase: &str) -> Result<()> {
+        let account_pickle = if let Some(a) = self.load_account().await? {
+            Some(serde_json::to_vec(&a.pickle(pickle_key.pickle_mode()).await)?)
+        } else {
+            None
+        };
+
+        let private_identity_pickle = if let Some(i) = self.load_identity().await? {
+            Some(serde_json::to_vec(&i.pickle(pickle_key.key()).await?)?)
+        } else {
+            None
+        };
+
+        let recovery_key_pickle = self
+            .load_backup_keys()
+            .await?
+            .recovery_key
+            .map(|r| serde_json::to_vec(&r.pickle(pickle_key.key())))
+            .transpose()?;
+
+        let mut sessions = Vec::new();
+
+        for value in self.sessions.iter() {
+            let (key, pickle) = value?;
+            let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+            let session = Session::from_pickle(
+                account_info.user_id,
+                account_info.device_id,
+                account_info.identity_keys,
+                serde_json::from_slice(&pickle)?,
+                self.get_pickle_mode(),
+            )?;
+
+            sessions
+                .push((key, serde_json::to_vec(&session.pickle(pickle_key.pickle_mode()).await)?));
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+
+        for value in self.inbound_group_sessions.iter() {
+            let (key, pickle) = value?;
+            let session = InboundGroupSession::from_pickle(
+                serde_json::from_slice(&pickle)?,
+                self.get_pickle_mode(),
+            )?;
+
+            inbound_group_sessions
+                .push((key, serde_json::to_vec(&session.pickle(pickle_key.pickle_mode()).await)?));
+        }
+
+        let mut outbound_group_sessions = Vec::new();
+
+        for value in self.outbound_group_sessions.iter() {
+            let (key, pickle) = value?;
+            let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+            let session = OutboundGroupSession::from_pickle(
+                account_info.device_id,
+                account_info.identity_keys,
+                serde_json::from_slice(&pickle)?,
+                self.get_pickle_mode(),
+            )?;
+
+            outbound_group_sessions
+                .push((key, serde_json::to_vec(&session.pickle(pickle_key.pickle_mode()).await)?));
+        }
+
+        let encrypted_pickle_key = serde_json::to_vec(&pickle_key.encrypt(passphrase))?;
+
+        let ret: Result<(), TransactionError<serde_json::Error>> = (
+            &self.account,
+            &self.private_identity,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.backup_keys,
+            &*self.inner,
+        )
+            .transaction(
+                |(
+                    account,
+                    private_identity,
+                    session_tree,
+                    inbound_tree,
+                    outbound_tree,
+                    backup_keys,
+                    inner,
+                )| {
+                    if let Some(a) = &account_pickle {
+                        account.insert("account".encode(), a.as_slice())?;
+                    }
+
+                    if let Some(i) = &private_identity_pickle {
+                        private_identity.insert("identity".encode(), i.as_slice())?;
+                    }
+
+                    if let Some(r) = &recovery_key_pickle {
+                        backup_keys.insert("recovery_key_v1".encode(), r.as_slice())?;
+                    }
+
+                    for (key, pickle) in &sessions {
+                        session_tree.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &inbound_group_sessions {
+                        inbound_tree.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    for (key, pickle) in &outbound_group_sessions {
+                        outbound_tree.insert(key.clone(), pickle.as_slice())?;
+                    }
+
+                    inner.insert("pickle_key".encode(), encrypted_pickle_key.as_slice())?;
+
+                    Ok(())
+                },
+            );
+
+        ret?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
     fn get_pickle_mode(&self) -> PicklingMode {
         self.pickle_key.pickle_mode()
     }
@@ -310,6 +527,13 @@ impl SledStore {
         self.pickle_key.key()
     }
 
+    fn get_inbound_group_session_pickles(&self) -> Result<Vec<PickledInboundGroupSession>> {
+        self.inbound_group_sessions
+            .iter()
+            .map(|p| serde_json::from_slice(&p?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
+    }
+
     async fn load_tracked_users(&self) -> Result<()> {
         for value in self.tracked_users.iter() {
             let (user, dirty) = value?;
@@ -399,6 +623,10 @@ impl SledStore {
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
         let key_requests = changes.key_requests;
+        let backup_version = changes.backup_version;
+        let recovery_key_pickle = changes.recovery_key.map(|r| r.pickle(self.get_pickle_key()));
+        let withheld_session_info = changes.withheld_session_info;
+        let sharing_policies = changes.sharing_policies;
 
         let ret: Result<(), TransactionError<serde_json::Error>> = (
             &self.account,
@@ -407,11 +635,14 @@ impl SledStore {
             &self.identities,
             &self.sessions,
             &self.inbound_group_sessions,
+            &self.inbound_group_sessions_for_backup,
             &self.outbound_group_sessions,
             &self.olm_hashes,
             &self.outgoing_secret_requests,
             &self.unsent_secret_requests,
             &self.secret_requests_by_info,
+            &self.backup_keys,
+            &self.withheld_info,
         )
             .transaction(
                 |(
@@ -421,11 +652,14 @@ impl SledStore {
                     identities,
                     sessions,
                     inbound_sessions,
+                    sessions_for_backup,
                     outbound_sessions,
                     hashes,
                     outgoing_secret_requests,
                     unsent_secret_requests,
                     secret_requests_by_info,
+                    backup_keys,
+                    withheld_info,
                 )| {
                     if let Some(a) = &account_pickle {
                         account.insert(
@@ -475,6 +709,12 @@ impl SledStore {
                             serde_json::to_vec(&session)
                                 .map_err(ConflictableTransactionError::Abort)?,
                         )?;
+
+                        if session.backed_up {
+                            sessions_for_backup.remove(key.as_slice())?;
+                        } else {
+                            sessions_for_backup.insert(key.as_slice(), IVec::default())?;
+                        }
                     }
 
                     for (key, session) in &outbound_session_changes {
@@ -518,11 +758,44 @@ impl SledStore {
                         }
                     }
 
+                    if let Some(r) = &recovery_key_pickle {
+                        backup_keys.insert(
+                            "recovery_key_v1".encode(),
+                            serde_json::to_vec(r).map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
+                    if let Some(b) = &backup_version {
+                        backup_keys.insert(
+                            "backup_version_v1".encode(),
+                            serde_json::to_vec(b).map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+
+                    for info in &withheld_session_info {
+                        if let (Some(room_id), Some(session_id)) = (&info.room_id, &info.session_id)
+                        {
+                            withheld_info.insert(
+                                (room_id.as_str(), session_id.as_str()).encode(),
+                                serde_json::to_vec(info)
+                                    .map_err(ConflictableTransactionError::Abort)?,
+                            )?;
+                        }
+                    }
+
                     Ok(())
                 },
             );
 
         ret?;
+
+        // The policies are stored as a whole, a single insert is atomic on its
+        // own.
+        if let Some(policies) = &sharing_policies {
+            self.sharing_policies
+                .insert("sharing_policies_v1".encode(), serde_json::to_vec(policies)?)?;
+        }
+
         self.inner.flush_async().await?;
 
         Ok(())
@@ -643,18 +916,177 @@ impl CryptoStore for SledStore {
     }
 
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
-        let pickles: Result<Vec<PickledInboundGroupSession>> = self
+        Ok(self
+            .get_inbound_group_session_pickles()?
+            .into_iter()
+            .filter_map(|p| InboundGroupSession::from_pickle(p, self.get_pickle_mode()).ok())
+            .collect())
+    }
+
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let pickles: Vec<PickledInboundGroupSession> = self
             .inbound_group_sessions
-            .iter()
+            .scan_prefix(room_id.encode())
             .map(|p| serde_json::from_slice(&p?.1).map_err(CryptoStoreError::Serialization))
-            .collect();
+            .collect::<Result<_>>()?;
 
-        Ok(pickles?
+        Ok(pickles
             .into_iter()
             .filter_map(|p| InboundGroupSession::from_pickle(p, self.get_pickle_mode()).ok())
             .collect())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let start = if let Some(after) = after {
+            Bound::Excluded(
+                (after.room_id().as_str(), after.sender_key(), after.session_id()).encode(),
+            )
+        } else {
+            Bound::Unbounded
+        };
+
+        let mut sessions = Vec::new();
+
+        // The range moves along the raw keys, sessions that can't be unpickled
+        // don't count towards the limit, otherwise a batch of them would look
+        // like the end of the sessions.
+        for item in self.inbound_group_sessions.range((start, Bound::Unbounded)) {
+            if sessions.len() >= limit {
+                break;
+            }
+
+            let (_, pickle) = item?;
+            let session = serde_json::from_slice(&pickle)
+                .map_err(CryptoStoreError::from)
+                .and_then(|p| Ok(InboundGroupSession::from_pickle(p, self.get_pickle_mode())?));
+
+            match session {
+                Ok(session) => sessions.push(session),
+                Err(e) => warn!(error =? e, "Skipping an inbound group session we can't unpickle"),
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let total = self.inbound_group_sessions.len();
+        let not_backed_up = self.inbound_group_sessions_for_backup.len();
+
+        Ok(RoomKeyCounts { total, backed_up: total.saturating_sub(not_backed_up) })
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let mut sessions = Vec::new();
+
+        for key in self.inbound_group_sessions_for_backup.iter().keys().take(limit) {
+            if let Some(pickle) = self.inbound_group_sessions.get(key?)? {
+                let pickle = serde_json::from_slice(&pickle)?;
+                sessions.push(InboundGroupSession::from_pickle(pickle, self.get_pickle_mode())?);
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        let mut keys = Vec::new();
+
+        for key in self.inbound_group_sessions.iter().keys() {
+            let key = key?;
+
+            if !self.inbound_group_sessions_for_backup.contains_key(&key)? {
+                keys.push(key.to_vec());
+            }
+        }
+
+        let ret: Result<(), TransactionError<serde_json::Error>> = (
+            &self.inbound_group_sessions,
+            &self.inbound_group_sessions_for_backup,
+            &self.backup_keys,
+        )
+            .transaction(|(inbound_sessions, sessions_for_backup, backup_keys)| {
+                // The pickles are read inside of the transaction, otherwise we
+                // might overwrite sessions that were changed in the meantime.
+                for key in &keys {
+                    if let Some(pickle) = inbound_sessions.get(key)? {
+                        let mut pickle: PickledInboundGroupSession =
+                            serde_json::from_slice(&pickle)
+                                .map_err(ConflictableTransactionError::Abort)?;
+                        pickle.backed_up = false;
+
+                        inbound_sessions.insert(
+                            key.as_slice(),
+                            serde_json::to_vec(&pickle)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                        sessions_for_backup.insert(key.as_slice(), IVec::default())?;
+                    }
+                }
+
+                backup_keys.remove("backup_version_v1".encode())?;
+
+                Ok(())
+            });
+
+        ret?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        let backup_version = self
+            .backup_keys
+            .get("backup_version_v1".encode())?
+            .map(|v| serde_json::from_slice(&v))
+            .transpose()?;
+
+        let recovery_key = self
+            .backup_keys
+            .get("recovery_key_v1".encode())?
+            .map(|p| serde_json::from_slice(&p))
+            .transpose()?
+            .map(|p: PickledRecoveryKey| {
+                RecoveryKey::from_pickle(p, self.get_pickle_key())
+                    .map_err(|_| CryptoStoreError::UnpicklingError)
+            })
+            .transpose()?;
+
+        Ok(BackupKeys { backup_version, recovery_key })
+    }
+
+    async fn load_sharing_policies(&self) -> Result<SharingPolicies> {
+        Ok(self
+            .sharing_policies
+            .get("sharing_policies_v1".encode())?
+            .map(|p| serde_json::from_slice(&p))
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldContent>> {
+        Ok(self
+            .withheld_info
+            .get((room_id.as_str(), session_id).encode())?
+            .map(|i| serde_json::from_slice(&i))
+            .transpose()?)
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         room_id: &RoomId,
@@ -800,507 +1232,103 @@ impl CryptoStore for SledStore {
 
 #[cfg(test)]
 mod test {
-    use std::collections::BTreeMap;
-
-    use matrix_sdk_common::uuid::Uuid;
-    use matrix_sdk_test::async_test;
-    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
-    use ruma::{
-        encryption::SignedKey, events::room_key_request::RequestedKeyInfo, room_id, user_id,
-        DeviceId, EventEncryptionAlgorithm, UserId,
-    };
-    use tempfile::tempdir;
-
-    use super::{CryptoStore, GossipRequest, SledStore};
-    use crate::{
-        gossiping::SecretInfo,
-        identities::{
-            device::test::get_device,
-            user::test::{get_other_identity, get_own_identity},
-        },
-        olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
-            ReadOnlyAccount, Session,
-        },
-        store::{Changes, DeviceChanges, IdentityChanges},
-    };
-
-    fn alice_id() -> UserId {
-        user_id!("@alice:example.org")
-    }
-
-    fn alice_device_id() -> Box<DeviceId> {
-        "ALICEDEVICE".into()
-    }
-
-    fn bob_id() -> UserId {
-        user_id!("@bob:example.org")
-    }
-
-    fn bob_device_id() -> Box<DeviceId> {
-        "BOBDEVICE".into()
-    }
+    use super::{EncodeKey, SledStore};
 
-    async fn get_store(passphrase: Option<&str>) -> (SledStore, tempfile::TempDir) {
-        let tmpdir = tempdir().unwrap();
-        let tmpdir_path = tmpdir.path().to_str().unwrap();
-
-        let store = SledStore::open_with_passphrase(tmpdir_path, passphrase)
-            .expect("Can't create a passphrase protected store");
-
-        (store, tmpdir)
-    }
-
-    async fn get_loaded_store() -> (ReadOnlyAccount, SledStore, tempfile::TempDir) {
-        let (store, dir) = get_store(None).await;
-        let account = get_account();
-        store.save_account(account.clone()).await.expect("Can't save account");
-
-        (account, store, dir)
-    }
-
-    fn get_account() -> ReadOnlyAccount {
-        ReadOnlyAccount::new(&alice_id(), &alice_device_id())
-    }
-
-    async fn get_account_and_session() -> (ReadOnlyAccount, Session) {
-        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
-        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
-
-        bob.generate_one_time_keys_helper(1).await;
-        let one_time_key =
-            bob.one_time_keys().await.curve25519().iter().next().unwrap().1.to_owned();
-        let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
-        let sender_key = bob.identity_keys().curve25519().to_owned();
-        let session =
-            alice.create_outbound_session_helper(&sender_key, &one_time_key).await.unwrap();
-
-        (alice, session)
-    }
-
-    #[async_test]
-    async fn create_store() {
-        let tmpdir = tempdir().unwrap();
-        let tmpdir_path = tmpdir.path().to_str().unwrap();
-        let _ = SledStore::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
-    }
-
-    #[async_test]
-    async fn save_account() {
-        let (store, _dir) = get_store(None).await;
-        assert!(store.load_account().await.unwrap().is_none());
-        let account = get_account();
-
-        store.save_account(account).await.expect("Can't save account");
-    }
-
-    #[async_test]
-    async fn load_account() {
-        let (store, _dir) = get_store(None).await;
-        let account = get_account();
-
-        store.save_account(account.clone()).await.expect("Can't save account");
-
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
-
-        assert_eq!(account, loaded_account);
-    }
+    cryptostore_integration_tests!(SledStore);
 
     #[async_test]
-    async fn load_account_with_passphrase() {
-        let (store, _dir) = get_store(Some("secret_passphrase")).await;
+    async fn change_passphrase() {
+        let (store, dir) = get_store(Some("old_passphrase")).await;
         let account = get_account();
-
         store.save_account(account.clone()).await.expect("Can't save account");
 
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
-
-        assert_eq!(account, loaded_account);
-    }
-
-    #[async_test]
-    async fn save_and_share_account() {
-        let (store, _dir) = get_store(None).await;
-        let account = get_account();
-
-        store.save_account(account.clone()).await.expect("Can't save account");
+        assert!(store.change_passphrase("wrong_passphrase", "new_passphrase").await.is_err());
+        store.change_passphrase("old_passphrase", "new_passphrase").await.unwrap();
 
-        account.mark_as_shared();
-        account.update_uploaded_key_count(50);
+        drop(store);
 
-        store.save_account(account.clone()).await.expect("Can't save account");
+        assert!(SledStore::open_with_passphrase(dir.path(), Some("old_passphrase")).is_err());
 
-        let loaded_account = store.load_account().await.expect("Can't load account");
-        let loaded_account = loaded_account.unwrap();
+        let store = SledStore::open_with_passphrase(dir.path(), Some("new_passphrase"))
+            .expect("Can't open the store with the new passphrase");
+        let loaded_account = store.load_account().await.unwrap().unwrap();
 
         assert_eq!(account, loaded_account);
-        assert_eq!(account.uploaded_key_count(), loaded_account.uploaded_key_count());
     }
 
     #[async_test]
-    async fn load_sessions() {
-        let (store, _dir) = get_store(None).await;
-        let (account, session) = get_account_and_session().await;
-        store.save_account(account.clone()).await.expect("Can't save account");
-
-        let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
-
-        store.save_changes(changes).await.unwrap();
-
-        let sessions =
-            store.get_sessions(&session.sender_key).await.expect("Can't load sessions").unwrap();
-        let loaded_session = sessions.lock().await.get(0).cloned().unwrap();
-
-        assert_eq!(&session, &loaded_session);
-    }
-
-    #[async_test]
-    async fn add_and_save_session() {
+    async fn encrypt_store() {
         let (store, dir) = get_store(None).await;
         let (account, session) = get_account_and_session().await;
         let sender_key = session.sender_key.to_owned();
         let session_id = session.session_id().to_owned();
 
         store.save_account(account.clone()).await.expect("Can't save account");
+        assert!(store.change_passphrase("old_passphrase", "new_passphrase").await.is_err());
 
-        let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+        let changes = Changes { sessions: vec![session], ..Default::default() };
         store.save_changes(changes).await.unwrap();
 
-        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
-        let sessions_lock = sessions.lock().await;
-        let session = &sessions_lock[0];
-
-        assert_eq!(session_id, session.session_id());
-
         drop(store);
 
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+        SledStore::encrypt_store(dir.path(), "secret_passphrase").await.unwrap();
+        assert!(SledStore::encrypt_store(dir.path(), "secret_passphrase").await.is_err());
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("secret_passphrase"))
+            .expect("Can't open the encrypted store");
 
         let loaded_account = store.load_account().await.unwrap().unwrap();
         assert_eq!(account, loaded_account);
 
         let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
         let sessions_lock = sessions.lock().await;
-        let session = &sessions_lock[0];
 
-        assert_eq!(session_id, session.session_id());
+        assert_eq!(session_id, sessions_lock[0].session_id());
     }
 
     #[async_test]
-    async fn save_inbound_group_session() {
+    async fn batches_skip_broken_sessions() {
         let (account, store, _dir) = get_loaded_store().await;
-
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            &room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
-            None,
-        )
-        .expect("Can't create session");
-
-        let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
-
-        store.save_changes(changes).await.expect("Can't save group session");
-    }
-
-    #[async_test]
-    async fn load_inbound_group_session() {
-        let (account, store, dir) = get_loaded_store().await;
-
-        let identity_keys = account.identity_keys();
-        let outbound_session = OlmOutboundGroupSession::new();
-        let session = InboundGroupSession::new(
-            identity_keys.curve25519(),
-            identity_keys.ed25519(),
-            &room_id!("!test:localhost"),
-            GroupSessionKey(outbound_session.session_key()),
-            None,
-        )
-        .expect("Can't create session");
-
-        let mut export = session.export().await;
-
-        export.forwarding_curve25519_key_chain = vec!["some_chain".to_owned()];
-
-        let session = InboundGroupSession::from_export(export).unwrap();
-
-        let changes =
-            Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
-
-        store.save_changes(changes).await.expect("Can't save group session");
-
-        drop(store);
-
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        let loaded_session = store
-            .get_inbound_group_session(&session.room_id, &session.sender_key, session.session_id())
+        let (_, session) = account
+            .create_group_session_pair_with_defaults(&room_id!("!test:localhost"))
             .await
-            .unwrap()
             .unwrap();
-        assert_eq!(session, loaded_session);
-        let export = loaded_session.export().await;
-        assert!(!export.forwarding_curve25519_key_chain.is_empty())
-    }
-
-    #[async_test]
-    async fn test_tracked_users() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
-
-        assert!(store.update_tracked_user(device.user_id(), false).await.unwrap());
-        assert!(!store.update_tracked_user(device.user_id(), false).await.unwrap());
-
-        assert!(store.is_user_tracked(device.user_id()));
-        assert!(!store.users_for_key_query().contains(device.user_id()));
-        assert!(!store.update_tracked_user(device.user_id(), true).await.unwrap());
-        assert!(store.users_for_key_query().contains(device.user_id()));
-        drop(store);
-
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        assert!(store.is_user_tracked(device.user_id()));
-        assert!(store.users_for_key_query().contains(device.user_id()));
-
-        store.update_tracked_user(device.user_id(), false).await.unwrap();
-        assert!(!store.users_for_key_query().contains(device.user_id()));
-        drop(store);
-
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        assert!(!store.users_for_key_query().contains(device.user_id()));
-    }
-
-    #[async_test]
-    async fn device_saving() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
-
-        let changes = Changes {
-            devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
-            ..Default::default()
-        };
 
+        let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
         store.save_changes(changes).await.unwrap();
 
-        drop(store);
-
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        let loaded_device =
-            store.get_device(device.user_id(), device.device_id()).await.unwrap().unwrap();
-
-        assert_eq!(device, loaded_device);
-
-        for algorithm in loaded_device.algorithms() {
-            assert!(device.algorithms().contains(algorithm));
-        }
-        assert_eq!(device.algorithms().len(), loaded_device.algorithms().len());
-        assert_eq!(device.keys(), loaded_device.keys());
+        // A session that sorts before the valid one and can't be unpickled.
+        store
+            .inbound_group_sessions
+            .insert(("!broken:localhost", "sender_key", "session_id").encode(), b"broken".to_vec())
+            .unwrap();
 
-        let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
-        assert_eq!(&**user_devices.keys().next().unwrap(), device.device_id());
-        assert_eq!(user_devices.values().next().unwrap(), &device);
+        let batch = store.get_inbound_group_sessions_batch(None, 1).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].room_id().as_str(), "!test:localhost");
     }
 
     #[async_test]
-    async fn device_deleting() {
-        let (_account, store, dir) = get_loaded_store().await;
-        let device = get_device();
-
-        let changes = Changes {
-            devices: DeviceChanges { changed: vec![device.clone()], ..Default::default() },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-
-        let changes = Changes {
-            devices: DeviceChanges { deleted: vec![device.clone()], ..Default::default() },
-            ..Default::default()
-        };
+    async fn upgrade_sessions_for_backup() {
+        let (account, store, dir) = get_loaded_store().await;
+        let (_, session) = account
+            .create_group_session_pair_with_defaults(&room_id!("!test:localhost"))
+            .await
+            .unwrap();
 
+        let changes = Changes { inbound_group_sessions: vec![session], ..Default::default() };
         store.save_changes(changes).await.unwrap();
-        drop(store);
-
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
-        store.load_account().await.unwrap();
-
-        let loaded_device = store.get_device(device.user_id(), device.device_id()).await.unwrap();
-
-        assert!(loaded_device.is_none());
-    }
-
-    #[async_test]
-    async fn user_saving() {
-        let dir = tempdir().unwrap();
-        let tmpdir_path = dir.path().to_str().unwrap();
-
-        let user_id = user_id!("@example:localhost");
-        let device_id: &DeviceId = "WSKKLTJZCL".into();
-
-        let store = SledStore::open_with_passphrase(tmpdir_path, None).expect("Can't create store");
-
-        let account = ReadOnlyAccount::new(&user_id, device_id);
-
-        store.save_account(account.clone()).await.expect("Can't save account");
-
-        let own_identity = get_own_identity();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![own_identity.clone().into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.expect("Can't save identity");
 
+        // Pretend the store was created before the sessions that need to be
+        // backed up were tracked.
+        store.inbound_group_sessions_for_backup.clear().unwrap();
+        store.inner.insert("version", 1u8.to_be_bytes().as_ref()).unwrap();
         drop(store);
 
-        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
-
+        let store = SledStore::open_with_passphrase(dir.path(), None).unwrap();
         store.load_account().await.unwrap();
 
-        let loaded_user = store.get_user_identity(own_identity.user_id()).await.unwrap().unwrap();
-
-        assert_eq!(loaded_user.master_key(), own_identity.master_key());
-        assert_eq!(loaded_user.self_signing_key(), own_identity.self_signing_key());
-        assert_eq!(loaded_user, own_identity.clone().into());
-
-        let other_identity = get_other_identity();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![other_identity.clone().into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-
-        let loaded_user = store.get_user_identity(other_identity.user_id()).await.unwrap().unwrap();
-
-        assert_eq!(loaded_user.master_key(), other_identity.master_key());
-        assert_eq!(loaded_user.self_signing_key(), other_identity.self_signing_key());
-        assert_eq!(loaded_user, other_identity.into());
-
-        own_identity.mark_as_verified();
-
-        let changes = Changes {
-            identities: IdentityChanges {
-                changed: vec![own_identity.into()],
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
-        store.save_changes(changes).await.unwrap();
-        let loaded_user = store.get_user_identity(&user_id).await.unwrap().unwrap();
-        assert!(loaded_user.own().unwrap().is_verified())
-    }
-
-    #[async_test]
-    async fn private_identity_saving() {
-        let (_, store, _dir) = get_loaded_store().await;
-        assert!(store.load_identity().await.unwrap().is_none());
-        let identity = PrivateCrossSigningIdentity::new(alice_id()).await;
-
-        let changes = Changes { private_identity: Some(identity.clone()), ..Default::default() };
-
-        store.save_changes(changes).await.unwrap();
-        let loaded_identity = store.load_identity().await.unwrap().unwrap();
-        assert_eq!(identity.user_id(), loaded_identity.user_id());
-    }
-
-    #[async_test]
-    async fn olm_hash_saving() {
-        let (_, store, _dir) = get_loaded_store().await;
-
-        let hash =
-            OlmMessageHash { sender_key: "test_sender".to_owned(), hash: "test_hash".to_owned() };
-
-        let mut changes = Changes::default();
-        changes.message_hashes.push(hash.clone());
-
-        assert!(!store.is_message_known(&hash).await.unwrap());
-        store.save_changes(changes).await.unwrap();
-        assert!(store.is_message_known(&hash).await.unwrap());
-    }
-
-    #[async_test]
-    async fn key_request_saving() {
-        let (account, store, _dir) = get_loaded_store().await;
-
-        let id = Uuid::new_v4();
-        let info: SecretInfo = RequestedKeyInfo::new(
-            EventEncryptionAlgorithm::MegolmV1AesSha2,
-            room_id!("!test:localhost"),
-            "test_sender_key".to_string(),
-            "test_session_id".to_string(),
-        )
-        .into();
-
-        let request = GossipRequest {
-            request_recipient: account.user_id().to_owned(),
-            request_id: id,
-            info: info.clone(),
-            sent_out: false,
-        };
-
-        assert!(store.get_outgoing_secret_requests(id).await.unwrap().is_none());
-
-        let mut changes = Changes::default();
-        changes.key_requests.push(request.clone());
-        store.save_changes(changes).await.unwrap();
-
-        let request = Some(request);
-
-        let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
-        assert_eq!(request, stored_request);
-
-        let stored_request = store.get_secret_request_by_info(&info).await.unwrap();
-        assert_eq!(request, stored_request);
-        assert!(!store.get_unsent_secret_requests().await.unwrap().is_empty());
-
-        let request = GossipRequest {
-            request_recipient: account.user_id().to_owned(),
-            request_id: id,
-            info: info.clone(),
-            sent_out: true,
-        };
-
-        let mut changes = Changes::default();
-        changes.key_requests.push(request.clone());
-        store.save_changes(changes).await.unwrap();
-
-        assert!(store.get_unsent_secret_requests().await.unwrap().is_empty());
-        let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
-        assert_eq!(Some(request), stored_request);
-
-        store.delete_outgoing_secret_requests(id).await.unwrap();
-
-        let stored_request = store.get_outgoing_secret_requests(id).await.unwrap();
-        assert_eq!(None, stored_request);
-
-        let stored_request = store.get_secret_request_by_info(&info).await.unwrap();
-        assert_eq!(None, stored_request);
-        assert!(store.get_unsent_secret_requests().await.unwrap().is_empty());
+        assert_eq!(store.inbound_group_session_counts().await.unwrap().backed_up, 0);
+        assert_eq!(store.inbound_group_sessions_for_backup(10).await.unwrap().len(), 1);
     }
 }
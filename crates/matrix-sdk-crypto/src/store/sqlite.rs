@@ -0,0 +1,881 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as SyncMutex, MutexGuard, RwLock},
+};
+
+use dashmap::DashSet;
+use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid};
+use olm_rs::{account::IdentityKeys, PicklingMode};
+use ruma::{DeviceId, DeviceIdBox, RoomId, UserId};
+use rusqlite::{params, Connection, OptionalExtension, Params};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{trace, warn};
+
+use super::{
+    caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, InboundGroupSession,
+    PickleKey, ReadOnlyAccount, Result, RoomKeyCounts, Session, SharingPolicies,
+};
+use crate::{
+    backups::{PickledRecoveryKey, RecoveryKey},
+    gossiping::{GossipRequest, SecretInfo},
+    identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
+    olm::{
+        OlmMessageHash, OutboundGroupSession, PickledInboundGroupSession, PickledSession,
+        PrivateCrossSigningIdentity, RoomKeyWithheldContent,
+    },
+};
+
+/// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
+/// panic once we try to pickle a Signing object.
+const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
+const DATABASE_NAME: &str = "matrix-sdk-crypto.sqlite3";
+
+/// The schema migrations of the store, the migration at index `n` upgrades the
+/// database from version `n` to version `n + 1`.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE kv (
+        key TEXT PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL
+    );
+
+    CREATE TABLE sessions (
+        sender_key TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        pickle BLOB NOT NULL,
+        PRIMARY KEY (sender_key, session_id)
+    );
+
+    CREATE TABLE inbound_group_sessions (
+        room_id TEXT NOT NULL,
+        sender_key TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        backed_up INTEGER NOT NULL,
+        pickle BLOB NOT NULL,
+        PRIMARY KEY (room_id, sender_key, session_id)
+    );
+    CREATE INDEX inbound_group_sessions_backed_up ON inbound_group_sessions (backed_up);
+
+    CREATE TABLE outbound_group_sessions (
+        room_id TEXT PRIMARY KEY NOT NULL,
+        pickle BLOB NOT NULL
+    );
+
+    CREATE TABLE olm_hashes (
+        sender_key TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (sender_key, hash)
+    );
+
+    CREATE TABLE devices (
+        user_id TEXT NOT NULL,
+        device_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (user_id, device_id)
+    );
+
+    CREATE TABLE identities (
+        user_id TEXT PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL
+    );
+
+    CREATE TABLE tracked_users (
+        user_id TEXT PRIMARY KEY NOT NULL,
+        dirty INTEGER NOT NULL
+    );
+
+    CREATE TABLE secret_requests (
+        request_id TEXT PRIMARY KEY NOT NULL,
+        info TEXT NOT NULL,
+        sent_out INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX secret_requests_info ON secret_requests (info);
+"#,
+    r#"
+    CREATE TABLE withheld_info (
+        room_id TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (room_id, session_id)
+    );
+"#,
+];
+
+const DATABASE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Get a single JSON encoded value using the given query.
+fn query_value<T: DeserializeOwned>(
+    connection: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Option<T>> {
+    Ok(connection
+        .query_row(sql, params, |row| row.get::<_, Vec<u8>>(0))
+        .optional()?
+        .map(|v| serde_json::from_slice(&v))
+        .transpose()?)
+}
+
+/// Get all the JSON encoded values the given query returns.
+fn query_values<T: DeserializeOwned>(
+    connection: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Vec<T>> {
+    let mut statement = connection.prepare(sql)?;
+    let values = statement.query_map(params, |row| row.get::<_, Vec<u8>>(0))?;
+
+    values.map(|v| Ok(serde_json::from_slice(&v?)?)).collect()
+}
+
+fn get_value<T: DeserializeOwned>(connection: &Connection, key: &str) -> Result<Option<T>> {
+    query_value(connection, "SELECT value FROM kv WHERE key = ?1", params![key])
+}
+
+fn set_value(connection: &Connection, key: &str, value: &impl Serialize) -> Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)",
+        params![key, serde_json::to_vec(value)?],
+    )?;
+
+    Ok(())
+}
+
+/// Get the key under which a secret request is indexed by its info.
+fn secret_info_key(info: &SecretInfo) -> Result<String> {
+    Ok(serde_json::to_string(info)?)
+}
+
+#[derive(Clone, Debug)]
+struct AccountInfo {
+    user_id: Arc<UserId>,
+    device_id: Arc<DeviceId>,
+    identity_keys: Arc<IdentityKeys>,
+}
+
+/// A SQLite based cryptostore.
+#[derive(Clone)]
+pub struct SqliteCryptoStore {
+    account_info: Arc<RwLock<Option<AccountInfo>>>,
+    path: PathBuf,
+    connection: Arc<SyncMutex<Connection>>,
+    pickle_key: Arc<PickleKey>,
+
+    session_cache: SessionStore,
+    tracked_users_cache: Arc<DashSet<UserId>>,
+    users_for_key_query_cache: Arc<DashSet<UserId>>,
+}
+
+impl std::fmt::Debug for SqliteCryptoStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteCryptoStore").field("path", &self.path).finish()
+    }
+}
+
+impl SqliteCryptoStore {
+    /// Open the SQLite based cryptostore at the given path using the given
+    /// passphrase to encrypt private data.
+    ///
+    /// The database will be created inside of the given directory, the
+    /// directory will be created if it doesn't exist.
+    pub fn open_with_passphrase(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        std::fs::create_dir_all(path.as_ref())?;
+        let path = path.as_ref().join(DATABASE_NAME);
+
+        let mut connection = Connection::open(&path)?;
+        Self::upgrade_database(&mut connection)?;
+
+        let pickle_key = if let Some(passphrase) = passphrase {
+            Self::get_or_create_pickle_key(passphrase, &connection)?
+        } else {
+            PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
+                .expect("Can't create default pickle key")
+        };
+
+        Ok(Self {
+            account_info: RwLock::new(None).into(),
+            path,
+            connection: SyncMutex::new(connection).into(),
+            pickle_key: pickle_key.into(),
+            session_cache: SessionStore::new(),
+            tracked_users_cache: DashSet::new().into(),
+            users_for_key_query_cache: DashSet::new().into(),
+        })
+    }
+
+    fn upgrade_database(connection: &mut Connection) -> Result<()> {
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        if version < DATABASE_VERSION {
+            trace!(
+                version = version,
+                new_version = DATABASE_VERSION,
+                "Upgrading the SQLite crypto store"
+            );
+
+            let transaction = connection.transaction()?;
+
+            for migration in &MIGRATIONS[version as usize..] {
+                transaction.execute_batch(migration)?;
+            }
+
+            transaction.pragma_update(None, "user_version", &DATABASE_VERSION)?;
+            transaction.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn get_or_create_pickle_key(passphrase: &str, connection: &Connection) -> Result<PickleKey> {
+        let key = if let Some(key) = get_value(connection, "pickle_key")? {
+            PickleKey::from_encrypted(passphrase, key)
+                .map_err(|_| CryptoStoreError::UnpicklingError)?
+        } else {
+            let key = PickleKey::new();
+            let encrypted = key.encrypt(passphrase);
+            set_value(connection, "pickle_key", &encrypted)?;
+            key
+        };
+
+        Ok(key)
+    }
+
+    /// Lock the database connection.
+    ///
+    /// The returned guard must not be held across an `.await` point.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+
+    fn get_account_info(&self) -> Option<AccountInfo> {
+        self.account_info.read().unwrap().clone()
+    }
+
+    fn get_pickle_mode(&self) -> PicklingMode {
+        self.pickle_key.pickle_mode()
+    }
+
+    fn get_pickle_key(&self) -> &[u8] {
+        self.pickle_key.key()
+    }
+
+    fn load_tracked_users(&self) -> Result<()> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT user_id, dirty FROM tracked_users")?;
+        let users = statement
+            .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)))?;
+
+        for user in users {
+            let (user, dirty) = user?;
+            let user = UserId::try_from(user)?;
+
+            self.tracked_users_cache.insert(user.clone());
+
+            if dirty {
+                self.users_for_key_query_cache.insert(user);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_inbound_group_sessions(
+        &self,
+        sql: &str,
+        params: impl Params,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let pickles: Vec<PickledInboundGroupSession> =
+            query_values(&self.connection(), sql, params)?;
+
+        Ok(pickles
+            .into_iter()
+            .filter_map(|p| InboundGroupSession::from_pickle(p, self.get_pickle_mode()).ok())
+            .collect())
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let account_pickle = if let Some(a) = changes.account {
+            Some(a.pickle(self.get_pickle_mode()).await)
+        } else {
+            None
+        };
+
+        let private_identity_pickle = if let Some(i) = changes.private_identity {
+            Some(i.pickle(self.get_pickle_key()).await?)
+        } else {
+            None
+        };
+
+        let mut sessions = Vec::new();
+
+        for session in changes.sessions {
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+            sessions.push((
+                session.sender_key().to_owned(),
+                session.session_id().to_owned(),
+                serde_json::to_vec(&pickle)?,
+            ));
+
+            self.session_cache.add(session).await;
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+
+        for session in changes.inbound_group_sessions {
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+            inbound_group_sessions.push((
+                session.room_id().to_string(),
+                session.sender_key().to_owned(),
+                session.session_id().to_owned(),
+                pickle.backed_up,
+                serde_json::to_vec(&pickle)?,
+            ));
+        }
+
+        let mut outbound_group_sessions = Vec::new();
+
+        for session in changes.outbound_group_sessions {
+            let pickle = session.pickle(self.get_pickle_mode()).await;
+            outbound_group_sessions
+                .push((session.room_id().to_string(), serde_json::to_vec(&pickle)?));
+        }
+
+        let recovery_key_pickle = changes.recovery_key.map(|r| r.pickle(self.get_pickle_key()));
+
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        if let Some(a) = &account_pickle {
+            set_value(&transaction, "account", a)?;
+        }
+
+        if let Some(i) = &private_identity_pickle {
+            set_value(&transaction, "identity", i)?;
+        }
+
+        for device in changes.devices.new.iter().chain(&changes.devices.changed) {
+            transaction.execute(
+                "INSERT OR REPLACE INTO devices (user_id, device_id, data) VALUES (?1, ?2, ?3)",
+                params![
+                    device.user_id().as_str(),
+                    device.device_id().as_str(),
+                    serde_json::to_vec(device)?
+                ],
+            )?;
+        }
+
+        for device in &changes.devices.deleted {
+            transaction.execute(
+                "DELETE FROM devices WHERE user_id = ?1 AND device_id = ?2",
+                params![device.user_id().as_str(), device.device_id().as_str()],
+            )?;
+        }
+
+        for identity in changes.identities.changed.iter().chain(&changes.identities.new) {
+            transaction.execute(
+                "INSERT OR REPLACE INTO identities (user_id, data) VALUES (?1, ?2)",
+                params![identity.user_id().as_str(), serde_json::to_vec(identity)?],
+            )?;
+        }
+
+        for (sender_key, session_id, pickle) in &sessions {
+            transaction.execute(
+                "INSERT OR REPLACE INTO sessions (sender_key, session_id, pickle)
+                 VALUES (?1, ?2, ?3)",
+                params![sender_key, session_id, pickle],
+            )?;
+        }
+
+        for (room_id, sender_key, session_id, backed_up, pickle) in &inbound_group_sessions {
+            transaction.execute(
+                "INSERT OR REPLACE INTO inbound_group_sessions
+                 (room_id, sender_key, session_id, backed_up, pickle) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![room_id, sender_key, session_id, backed_up, pickle],
+            )?;
+        }
+
+        for (room_id, pickle) in &outbound_group_sessions {
+            transaction.execute(
+                "INSERT OR REPLACE INTO outbound_group_sessions (room_id, pickle) VALUES (?1, ?2)",
+                params![room_id, pickle],
+            )?;
+        }
+
+        for hash in &changes.message_hashes {
+            transaction.execute(
+                "INSERT OR IGNORE INTO olm_hashes (sender_key, hash) VALUES (?1, ?2)",
+                params![hash.sender_key, hash.hash],
+            )?;
+        }
+
+        for request in &changes.key_requests {
+            transaction.execute(
+                "INSERT OR REPLACE INTO secret_requests (request_id, info, sent_out, data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    request.request_id.to_string(),
+                    secret_info_key(&request.info)?,
+                    request.sent_out,
+                    serde_json::to_vec(request)?
+                ],
+            )?;
+        }
+
+        if let Some(r) = &recovery_key_pickle {
+            set_value(&transaction, "recovery_key_v1", r)?;
+        }
+
+        if let Some(b) = &changes.backup_version {
+            set_value(&transaction, "backup_version_v1", b)?;
+        }
+
+        if let Some(p) = &changes.sharing_policies {
+            set_value(&transaction, "sharing_policies_v1", p)?;
+        }
+
+        for info in &changes.withheld_session_info {
+            if let (Some(room_id), Some(session_id)) = (&info.room_id, &info.session_id) {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO withheld_info (room_id, session_id, data)
+                     VALUES (?1, ?2, ?3)",
+                    params![room_id.as_str(), session_id, serde_json::to_vec(info)?],
+                )?;
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CryptoStore for SqliteCryptoStore {
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        let pickle = get_value(&self.connection(), "account")?;
+
+        if let Some(pickle) = pickle {
+            self.load_tracked_users()?;
+
+            let account = ReadOnlyAccount::from_pickle(pickle, self.get_pickle_mode())?;
+
+            let account_info = AccountInfo {
+                user_id: account.user_id.clone(),
+                device_id: account.device_id.clone(),
+                identity_keys: account.identity_keys.clone(),
+            };
+
+            *self.account_info.write().unwrap() = Some(account_info);
+
+            Ok(Some(account))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        let account_info = AccountInfo {
+            user_id: account.user_id.clone(),
+            device_id: account.device_id.clone(),
+            identity_keys: account.identity_keys.clone(),
+        };
+
+        *self.account_info.write().unwrap() = Some(account_info);
+
+        let changes = Changes { account: Some(account), ..Default::default() };
+
+        self.save_changes(changes).await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        let pickle = get_value(&self.connection(), "identity")?;
+
+        if let Some(pickle) = pickle {
+            Ok(Some(
+                PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key())
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.save_changes(changes).await
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        if self.session_cache.get(sender_key).is_none() {
+            let pickles: Vec<PickledSession> = query_values(
+                &self.connection(),
+                "SELECT pickle FROM sessions WHERE sender_key = ?1",
+                params![sender_key],
+            )?;
+
+            let sessions: Result<Vec<Session>> = pickles
+                .into_iter()
+                .map(|p| {
+                    Session::from_pickle(
+                        account_info.user_id.clone(),
+                        account_info.device_id.clone(),
+                        account_info.identity_keys.clone(),
+                        p,
+                        self.get_pickle_mode(),
+                    )
+                    .map_err(CryptoStoreError::SessionUnpickling)
+                })
+                .collect();
+
+            self.session_cache.set_for_sender(sender_key, sessions?);
+        }
+
+        Ok(self.session_cache.get(sender_key))
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let pickle = query_value(
+            &self.connection(),
+            "SELECT pickle FROM inbound_group_sessions
+             WHERE room_id = ?1 AND sender_key = ?2 AND session_id = ?3",
+            params![room_id.as_str(), sender_key, session_id],
+        )?;
+
+        if let Some(pickle) = pickle {
+            Ok(Some(InboundGroupSession::from_pickle(pickle, self.get_pickle_mode())?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions("SELECT pickle FROM inbound_group_sessions", params![])
+    }
+
+    async fn get_inbound_group_sessions_for_room(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions(
+            "SELECT pickle FROM inbound_group_sessions WHERE room_id = ?1",
+            params![room_id.as_str()],
+        )
+    }
+
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after: Option<&InboundGroupSession>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let mut cursor = after.map(|s| {
+            (s.room_id().as_str().to_owned(), s.sender_key().to_owned(), s.session_id().to_owned())
+        });
+        let mut sessions = Vec::new();
+
+        // The cursor moves along the primary keys, sessions that can't be
+        // unpickled don't count towards the limit, otherwise a batch of them
+        // would look like the end of the sessions.
+        while sessions.len() < limit {
+            let remaining = limit - sessions.len();
+            let (room_id, sender_key, session_id) = match &cursor {
+                Some((r, s, i)) => (Some(r), Some(s), Some(i)),
+                None => (None, None, None),
+            };
+
+            let connection = self.connection();
+            let mut statement = connection.prepare(
+                "SELECT room_id, sender_key, session_id, pickle FROM inbound_group_sessions
+                 WHERE ?1 IS NULL OR (room_id, sender_key, session_id) > (?1, ?2, ?3)
+                 ORDER BY room_id, sender_key, session_id LIMIT ?4",
+            )?;
+            let rows = statement
+                .query_map(params![room_id, sender_key, session_id, remaining as i64], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get::<_, Vec<u8>>(3)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(String, String, String, Vec<u8>)>>>()?;
+
+            let done = rows.len() < remaining;
+
+            for (room_id, sender_key, session_id, pickle) in rows {
+                let session = serde_json::from_slice(&pickle)
+                    .map_err(CryptoStoreError::from)
+                    .and_then(|p| Ok(InboundGroupSession::from_pickle(p, self.get_pickle_mode())?));
+
+                match session {
+                    Ok(session) => sessions.push(session),
+                    Err(e) => warn!(
+                        room_id = room_id.as_str(),
+                        session_id = session_id.as_str(),
+                        error =? e,
+                        "Skipping an inbound group session we can't unpickle"
+                    ),
+                }
+
+                cursor = Some((room_id, sender_key, session_id));
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let (total, backed_up): (i64, i64) = self.connection().query_row(
+            "SELECT COUNT(*), COALESCE(SUM(backed_up), 0) FROM inbound_group_sessions",
+            params![],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        Ok(RoomKeyCounts { total: total as usize, backed_up: backed_up as usize })
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.load_inbound_group_sessions(
+            "SELECT pickle FROM inbound_group_sessions WHERE backed_up = 0 LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        let sessions = {
+            let mut statement = transaction.prepare(
+                "SELECT room_id, sender_key, session_id, pickle FROM inbound_group_sessions
+                 WHERE backed_up = 1",
+            )?;
+            let sessions = statement.query_map(params![], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                ))
+            })?;
+
+            sessions.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+
+        for (room_id, sender_key, session_id, pickle) in sessions {
+            let mut pickle: PickledInboundGroupSession = serde_json::from_slice(&pickle)?;
+            pickle.backed_up = false;
+
+            transaction.execute(
+                "UPDATE inbound_group_sessions SET backed_up = 0, pickle = ?4
+                 WHERE room_id = ?1 AND sender_key = ?2 AND session_id = ?3",
+                params![room_id, sender_key, session_id, serde_json::to_vec(&pickle)?],
+            )?;
+        }
+
+        transaction.execute("DELETE FROM kv WHERE key = ?1", params!["backup_version_v1"])?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        let connection = self.connection();
+
+        let backup_version = get_value(&connection, "backup_version_v1")?;
+        let recovery_key = get_value(&connection, "recovery_key_v1")?
+            .map(|p: PickledRecoveryKey| {
+                RecoveryKey::from_pickle(p, self.get_pickle_key())
+                    .map_err(|_| CryptoStoreError::UnpicklingError)
+            })
+            .transpose()?;
+
+        Ok(BackupKeys { backup_version, recovery_key })
+    }
+
+    async fn load_sharing_policies(&self) -> Result<SharingPolicies> {
+        Ok(get_value(&self.connection(), "sharing_policies_v1")?.unwrap_or_default())
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldContent>> {
+        query_value(
+            &self.connection(),
+            "SELECT data FROM withheld_info WHERE room_id = ?1 AND session_id = ?2",
+            params![room_id.as_str(), session_id],
+        )
+    }
+
+    async fn get_outbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        let pickle = query_value(
+            &self.connection(),
+            "SELECT pickle FROM outbound_group_sessions WHERE room_id = ?1",
+            params![room_id.as_str()],
+        )?;
+
+        pickle
+            .map(|p| {
+                OutboundGroupSession::from_pickle(
+                    account_info.device_id,
+                    account_info.identity_keys,
+                    p,
+                    self.get_pickle_mode(),
+                )
+                .map_err(CryptoStoreError::OlmGroupSession)
+            })
+            .transpose()
+    }
+
+    fn is_user_tracked(&self, user_id: &UserId) -> bool {
+        self.tracked_users_cache.contains(user_id)
+    }
+
+    fn has_users_for_key_query(&self) -> bool {
+        !self.users_for_key_query_cache.is_empty()
+    }
+
+    fn tracked_users(&self) -> HashSet<UserId> {
+        self.tracked_users_cache.iter().map(|u| u.clone()).collect()
+    }
+
+    fn users_for_key_query(&self) -> HashSet<UserId> {
+        self.users_for_key_query_cache.iter().map(|u| u.clone()).collect()
+    }
+
+    async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        let already_added = self.tracked_users_cache.insert(user.clone());
+
+        if dirty {
+            self.users_for_key_query_cache.insert(user.clone());
+        } else {
+            self.users_for_key_query_cache.remove(user);
+        }
+
+        self.connection().execute(
+            "INSERT OR REPLACE INTO tracked_users (user_id, dirty) VALUES (?1, ?2)",
+            params![user.as_str(), dirty],
+        )?;
+
+        Ok(already_added)
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        query_value(
+            &self.connection(),
+            "SELECT data FROM devices WHERE user_id = ?1 AND device_id = ?2",
+            params![user_id.as_str(), device_id.as_str()],
+        )
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<DeviceIdBox, ReadOnlyDevice>> {
+        let devices: Vec<ReadOnlyDevice> = query_values(
+            &self.connection(),
+            "SELECT data FROM devices WHERE user_id = ?1",
+            params![user_id.as_str()],
+        )?;
+
+        Ok(devices.into_iter().map(|d| (d.device_id().to_owned(), d)).collect())
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<ReadOnlyUserIdentities>> {
+        query_value(
+            &self.connection(),
+            "SELECT data FROM identities WHERE user_id = ?1",
+            params![user_id.as_str()],
+        )
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        Ok(self.connection().query_row(
+            "SELECT EXISTS(SELECT 1 FROM olm_hashes WHERE sender_key = ?1 AND hash = ?2)",
+            params![message_hash.sender_key, message_hash.hash],
+            |row| row.get(0),
+        )?)
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: Uuid,
+    ) -> Result<Option<GossipRequest>> {
+        query_value(
+            &self.connection(),
+            "SELECT data FROM secret_requests WHERE request_id = ?1",
+            params![request_id.to_string()],
+        )
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        key_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>> {
+        query_value(
+            &self.connection(),
+            "SELECT data FROM secret_requests WHERE info = ?1 ORDER BY rowid DESC LIMIT 1",
+            params![secret_info_key(key_info)?],
+        )
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        query_values(
+            &self.connection(),
+            "SELECT data FROM secret_requests WHERE sent_out = 0",
+            params![],
+        )
+    }
+
+    async fn delete_outgoing_secret_requests(&self, request_id: Uuid) -> Result<()> {
+        self.connection().execute(
+            "DELETE FROM secret_requests WHERE request_id = ?1",
+            params![request_id.to_string()],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SqliteCryptoStore;
+
+    cryptostore_integration_tests!(SqliteCryptoStore);
+}
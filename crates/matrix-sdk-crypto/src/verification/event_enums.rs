@@ -772,6 +772,7 @@ impl TryFrom<OutgoingRequest> for OutgoingContent {
             crate::OutgoingRequests::SignatureUpload(_) => Err("Invalid request type".to_owned()),
             crate::OutgoingRequests::KeysClaim(_) => Err("Invalid request type".to_owned()),
             crate::OutgoingRequests::RoomMessage(r) => Ok(Self::from(r.clone())),
+            crate::OutgoingRequests::KeysBackup(_) => Err("Invalid request type".to_owned()),
         }
     }
 }
@@ -22,12 +22,14 @@ mod sas;
 
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::Arc,
+    mem::discriminant,
+    sync::{Arc, Mutex as StdMutex},
 };
 
 use event_enums::OutgoingContent;
+use futures::Stream;
 pub use machine::VerificationMachine;
-use matrix_sdk_common::locks::Mutex;
+use matrix_sdk_common::{locks::Mutex, observable::Observers};
 #[cfg(feature = "qrcode")]
 #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
 pub use qrcode::QrVerification;
@@ -276,6 +278,98 @@ impl From<Cancelled> for CancelInfo {
     }
 }
 
+/// The state a verification request or a verification flow transitioned into.
+///
+/// Items of this type are yielded by the `changes()` streams of
+/// [`VerificationRequest`], [`Sas`] and `QrVerification`. Not every
+/// verification object goes through every state, a verification request for
+/// example never exchanges keys, while a verification flow never is in the
+/// requested state.
+#[derive(Clone, Debug)]
+pub enum VerificationState {
+    /// The verification request has been sent out or received, the other side
+    /// did not yet accept it.
+    Requested,
+    /// Both sides agreed to verify, a verification flow can now be started.
+    Ready,
+    /// A verification flow has been started.
+    Started,
+    /// The keys of the verification flow have been exchanged, the short auth
+    /// string can be presented or the QR code has been scanned.
+    KeysExchanged,
+    /// We confirmed that the verification flow was successful and are waiting
+    /// for the other side to do the same.
+    Confirmed,
+    /// The verification successfully finished.
+    Done,
+    /// The verification has been cancelled, either by us or by the other side.
+    Cancelled(CancelInfo),
+}
+
+impl VerificationState {
+    /// Is this a final state, no further transitions will happen after this
+    /// state has been reached.
+    pub fn is_final(&self) -> bool {
+        matches!(self, VerificationState::Done | VerificationState::Cancelled(_))
+    }
+}
+
+/// Listeners that want to be notified about state transitions of a
+/// verification object.
+#[derive(Clone, Debug)]
+pub(crate) struct StateListeners {
+    inner: Arc<StdMutex<InnerStateListeners>>,
+}
+
+#[derive(Debug)]
+struct InnerStateListeners {
+    state: VerificationState,
+    observers: Observers<VerificationState>,
+}
+
+impl StateListeners {
+    pub fn new(state: VerificationState) -> Self {
+        Self {
+            inner: Arc::new(StdMutex::new(InnerStateListeners {
+                state,
+                observers: Observers::new(),
+            })),
+        }
+    }
+
+    /// Get a stream of state transitions, the current state is yielded first.
+    ///
+    /// If we are already in a final state the stream ends after yielding it.
+    pub fn subscribe(&self) -> impl Stream<Item = VerificationState> {
+        let mut inner = self.inner.lock().unwrap();
+        let state = inner.state.clone();
+        let changes = inner.observers.subscribe(Some(state));
+
+        if inner.state.is_final() {
+            inner.observers.clear();
+        }
+
+        changes
+    }
+
+    /// Transition into the given state, listeners are only notified if the
+    /// state differs from the current one, listeners that went away are
+    /// removed.
+    pub fn update(&self, state: VerificationState) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if discriminant(&inner.state) != discriminant(&state) && !inner.state.is_final() {
+            inner.observers.notify(state.clone());
+
+            if state.is_final() {
+                inner.observers.clear();
+            }
+
+            inner.state = state;
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Cancelled {
     cancelled_by_us: bool,
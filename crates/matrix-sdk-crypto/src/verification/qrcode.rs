@@ -14,6 +14,7 @@
 
 use std::sync::{Arc, Mutex};
 
+use futures::Stream;
 use matrix_qrcode::{
     qrcode::QrCode, EncodingError, QrVerificationData, SelfVerificationData,
     SelfVerificationNoMasterKey, VerificationData,
@@ -41,8 +42,8 @@ use tracing::trace;
 use super::{
     event_enums::{CancelContent, DoneContent, OutgoingContent, OwnedStartContent, StartContent},
     requests::RequestHandle,
-    CancelInfo, Cancelled, Done, FlowId, IdentitiesBeingVerified, VerificationResult,
-    VerificationStore,
+    CancelInfo, Cancelled, Done, FlowId, IdentitiesBeingVerified, StateListeners,
+    VerificationResult, VerificationState, VerificationStore,
 };
 use crate::{
     olm::PrivateCrossSigningIdentity, CryptoStoreError, OutgoingVerificationRequest,
@@ -85,6 +86,7 @@ pub struct QrVerification {
     identities: IdentitiesBeingVerified,
     request_handle: Option<RequestHandle>,
     we_started: bool,
+    changes: StateListeners,
 }
 
 impl std::fmt::Debug for QrVerification {
@@ -195,6 +197,17 @@ impl QrVerification {
         self.inner.to_bytes()
     }
 
+    /// Get a stream of state transitions of this QR code verification flow.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.changes.subscribe()
+    }
+
+    /// Replace the inner state and notify the listeners about the transition.
+    fn set_state(&self, state: &mut InnerState, new_state: InnerState) {
+        self.changes.update(new_state.state());
+        *state = new_state;
+    }
+
     /// Cancel the verification flow.
     pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
         self.cancel_with_code(CancelCode::User)
@@ -229,7 +242,7 @@ impl QrVerification {
             | InnerState::Scanned(_)
             | InnerState::Reciprocated(_)
             | InnerState::Done(_) => {
-                *state = InnerState::Cancelled(new_state);
+                self.set_state(&mut state, InnerState::Cancelled(new_state));
                 Some(self.content_to_request(content))
             }
             InnerState::Cancelled(_) => None,
@@ -262,7 +275,7 @@ impl QrVerification {
             InnerState::Scanned(s) => {
                 let new_state = s.clone().confirm_scanning();
                 let content = new_state.as_content(&self.flow_id);
-                *state = InnerState::Confirmed(new_state);
+                self.set_state(&mut state, InnerState::Confirmed(new_state));
 
                 Some(self.content_to_request(content))
             }
@@ -311,7 +324,7 @@ impl QrVerification {
                 VerificationResult::SignatureUpload(s) => (None, Some(s)),
             };
 
-        *self.state.lock().unwrap() = new_state;
+        self.set_state(&mut self.state.lock().unwrap(), new_state);
 
         Ok((content.map(|c| self.content_to_request(c)), request))
     }
@@ -381,12 +394,17 @@ impl QrVerification {
         match &*state {
             InnerState::Created(s) => match s.clone().receive_reciprocate(content) {
                 Ok(s) => {
-                    *state = InnerState::Scanned(s);
+                    self.set_state(&mut state, InnerState::Scanned(s));
+
+                    if let Some(request_handle) = &self.request_handle {
+                        request_handle.mark_as_started();
+                    }
+
                     None
                 }
                 Err(s) => {
                     let content = s.as_content(self.flow_id());
-                    *state = InnerState::Cancelled(s);
+                    self.set_state(&mut state, InnerState::Cancelled(s));
                     Some(self.content_to_request(content))
                 }
             },
@@ -416,7 +434,7 @@ impl QrVerification {
                 "Cancelling a QR verification, other user has cancelled"
             );
 
-            *state = InnerState::Cancelled(new_state);
+            self.set_state(&mut state, InnerState::Cancelled(new_state));
         }
     }
 
@@ -580,14 +598,18 @@ impl QrVerification {
 
         let secret = qr_code.secret().to_owned();
         let own_device_id = store.account.device_id().to_owned();
+        let state =
+            InnerState::Reciprocated(QrState { state: Reciprocated { secret, own_device_id } });
+
+        if let Some(request_handle) = &request_handle {
+            request_handle.mark_as_started();
+        }
 
         Ok(Self {
             flow_id,
             inner: qr_code.into(),
-            state: Mutex::new(InnerState::Reciprocated(QrState {
-                state: Reciprocated { secret, own_device_id },
-            }))
-            .into(),
+            changes: StateListeners::new(state.state()),
+            state: Mutex::new(state).into(),
             identities,
             we_started,
             request_handle,
@@ -602,11 +624,13 @@ impl QrVerification {
         request_handle: Option<RequestHandle>,
     ) -> Self {
         let secret = inner.secret().to_owned();
+        let state = InnerState::Created(QrState { state: Created { secret } });
 
         Self {
             flow_id,
             inner: inner.into(),
-            state: Mutex::new(InnerState::Created(QrState { state: Created { secret } })).into(),
+            changes: StateListeners::new(state.state()),
+            state: Mutex::new(state).into(),
             identities,
             we_started,
             request_handle,
@@ -624,6 +648,20 @@ enum InnerState {
     Cancelled(QrState<Cancelled>),
 }
 
+impl InnerState {
+    fn state(&self) -> VerificationState {
+        match self {
+            InnerState::Created(_) => VerificationState::Started,
+            InnerState::Scanned(_) | InnerState::Reciprocated(_) => {
+                VerificationState::KeysExchanged
+            }
+            InnerState::Confirmed(_) => VerificationState::Confirmed,
+            InnerState::Done(_) => VerificationState::Done,
+            InnerState::Cancelled(c) => VerificationState::Cancelled(c.state.clone().into()),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct QrState<S: Clone> {
     state: S,
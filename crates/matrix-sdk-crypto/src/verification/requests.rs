@@ -17,6 +17,7 @@ use std::{
     time::Duration,
 };
 
+use futures::Stream;
 #[cfg(feature = "qrcode")]
 use matrix_qrcode::QrVerificationData;
 use matrix_sdk_common::{instant::Instant, uuid::Uuid};
@@ -44,7 +45,7 @@ use super::{
     event_enums::{
         CancelContent, DoneContent, OutgoingContent, ReadyContent, RequestContent, StartContent,
     },
-    CancelInfo, Cancelled, FlowId, VerificationStore,
+    CancelInfo, Cancelled, FlowId, StateListeners, VerificationState, VerificationStore,
 };
 #[cfg(feature = "qrcode")]
 use super::{
@@ -83,6 +84,7 @@ pub struct VerificationRequest {
     creation_time: Arc<Instant>,
     we_started: bool,
     recipient_devices: Arc<Vec<DeviceIdBox>>,
+    changes: StateListeners,
 }
 
 /// A handle to a request so child verification flows can cancel the request.
@@ -96,17 +98,22 @@ pub struct VerificationRequest {
 #[derive(Clone, Debug)]
 pub(crate) struct RequestHandle {
     inner: Arc<Mutex<InnerRequest>>,
+    changes: StateListeners,
 }
 
 impl RequestHandle {
     pub fn cancel_with_code(&self, cancel_code: &CancelCode) {
-        self.inner.lock().unwrap().cancel(true, cancel_code)
+        let mut inner = self.inner.lock().unwrap();
+        inner.cancel(true, cancel_code);
+        self.changes.update(inner.state());
     }
-}
 
-impl From<Arc<Mutex<InnerRequest>>> for RequestHandle {
-    fn from(inner: Arc<Mutex<InnerRequest>>) -> Self {
-        Self { inner }
+    /// Notify the listeners of the request that a verification flow has been
+    /// started from it.
+    pub fn mark_as_started(&self) {
+        if matches!(&*self.inner.lock().unwrap(), InnerRequest::Ready(_)) {
+            self.changes.update(VerificationState::Started);
+        }
     }
 }
 
@@ -122,25 +129,26 @@ impl VerificationRequest {
         methods: Option<Vec<VerificationMethod>>,
     ) -> Self {
         let account = store.account.clone();
-        let inner = Mutex::new(InnerRequest::Created(RequestState::new(
+        let inner = InnerRequest::Created(RequestState::new(
             private_cross_signing_identity,
             cache.clone(),
             store,
             other_user,
             &flow_id,
             methods,
-        )))
-        .into();
+        ));
+        let changes = StateListeners::new(inner.state());
 
         Self {
             account,
             verification_cache: cache,
             flow_id: flow_id.into(),
-            inner,
+            inner: Arc::new(Mutex::new(inner)),
             other_user_id: other_user.to_owned().into(),
             creation_time: Instant::now().into(),
             we_started: true,
             recipient_devices: recipient_devices.into(),
+            changes,
         }
     }
 
@@ -236,6 +244,19 @@ impl VerificationRequest {
         }
     }
 
+    /// Get a stream of state transitions of this verification request.
+    ///
+    /// Once a verification flow has been started from this request the state
+    /// of the flow can be followed using the `changes()` stream of the flow
+    /// itself.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.changes.subscribe()
+    }
+
+    fn handle(&self) -> RequestHandle {
+        RequestHandle { inner: self.inner.clone(), changes: self.changes.clone() }
+    }
+
     /// Has the verification request been answered by another device.
     pub fn is_passive(&self) -> bool {
         matches!(&*self.inner.lock().unwrap(), InnerRequest::Passive(_))
@@ -314,7 +335,7 @@ impl VerificationRequest {
     pub async fn generate_qr_code(&self) -> Result<Option<QrVerification>, CryptoStoreError> {
         let inner = self.inner.lock().unwrap().clone();
 
-        inner.generate_qr_code(self.we_started, self.inner.clone().into()).await
+        inner.generate_qr_code(self.we_started, self.handle()).await
     }
     ///
     #[cfg(feature = "qrcode")]
@@ -343,7 +364,7 @@ impl VerificationRequest {
                 r.flow_id.as_ref().to_owned(),
                 data,
                 self.we_started,
-                Some(self.inner.clone().into()),
+                Some(self.handle()),
             );
 
             // Prevent mutex lock being held across `.await` point.
@@ -367,23 +388,26 @@ impl VerificationRequest {
         content: &RequestContent,
     ) -> Self {
         let account = store.account.clone();
+        let inner = InnerRequest::Requested(RequestState::from_request_event(
+            private_cross_signing_identity,
+            cache.clone(),
+            store,
+            sender,
+            &flow_id,
+            content,
+        ));
+        let changes = StateListeners::new(inner.state());
 
         Self {
-            verification_cache: cache.clone(),
-            inner: Arc::new(Mutex::new(InnerRequest::Requested(RequestState::from_request_event(
-                private_cross_signing_identity,
-                cache,
-                store,
-                sender,
-                &flow_id,
-                content,
-            )))),
+            verification_cache: cache,
+            inner: Arc::new(Mutex::new(inner)),
             account,
             other_user_id: sender.to_owned().into(),
             flow_id: flow_id.into(),
             we_started: false,
             creation_time: Instant::now().into(),
             recipient_devices: vec![].into(),
+            changes,
         }
     }
 
@@ -399,7 +423,10 @@ impl VerificationRequest {
     ) -> Option<OutgoingVerificationRequest> {
         let mut inner = self.inner.lock().unwrap();
 
-        inner.accept(methods).map(|c| match c {
+        let content = inner.accept(methods);
+        self.changes.update(inner.state());
+
+        content.map(|c| match c {
             OutgoingContent::ToDevice(content) => {
                 ToDeviceRequest::new(self.other_user(), inner.other_device_id(), content).into()
             }
@@ -437,6 +464,7 @@ impl VerificationRequest {
         let other_device = inner.other_device_id();
 
         inner.cancel(true, &cancel_code);
+        self.changes.update(inner.state());
 
         let content = if let InnerRequest::Cancelled(c) = &*inner {
             Some(c.state.as_content(self.flow_id()))
@@ -560,6 +588,7 @@ impl VerificationRequest {
         match &*inner {
             InnerRequest::Created(s) => {
                 *inner = InnerRequest::Ready(s.clone().into_ready(sender, content));
+                self.changes.update(inner.state());
 
                 if let Some(request) =
                     self.cancel_for_other_devices(CancelCode::Accepted, Some(content.from_device()))
@@ -570,7 +599,8 @@ impl VerificationRequest {
             InnerRequest::Requested(s) => {
                 if sender == self.own_user_id() && content.from_device() != self.account.device_id()
                 {
-                    *inner = InnerRequest::Passive(s.clone().into_passive(content))
+                    *inner = InnerRequest::Passive(s.clone().into_passive(content));
+                    self.changes.update(inner.state());
                 }
             }
             InnerRequest::Ready(_)
@@ -588,7 +618,7 @@ impl VerificationRequest {
         let inner = self.inner.lock().unwrap().clone();
 
         if let InnerRequest::Ready(s) = inner {
-            s.receive_start(sender, content, self.we_started, self.inner.clone().into()).await?;
+            s.receive_start(sender, content, self.we_started, self.handle()).await?;
         } else {
             warn!(
                 sender = sender.as_str(),
@@ -610,6 +640,7 @@ impl VerificationRequest {
 
             let mut inner = self.inner.lock().unwrap();
             inner.receive_done(content);
+            self.changes.update(inner.state());
         }
     }
 
@@ -622,6 +653,7 @@ impl VerificationRequest {
             );
             let mut inner = self.inner.lock().unwrap();
             inner.cancel(false, content.cancel_code());
+            self.changes.update(inner.state());
 
             if self.we_started() {
                 if let Some(request) =
@@ -647,7 +679,7 @@ impl VerificationRequest {
                         s.store.clone(),
                         s.private_cross_signing_identity.clone(),
                         self.we_started,
-                        self.inner.clone().into(),
+                        self.handle(),
                     )
                     .await?
                 {
@@ -686,6 +718,15 @@ enum InnerRequest {
 }
 
 impl InnerRequest {
+    fn state(&self) -> VerificationState {
+        match self {
+            InnerRequest::Created(_) | InnerRequest::Requested(_) => VerificationState::Requested,
+            InnerRequest::Ready(_) | InnerRequest::Passive(_) => VerificationState::Ready,
+            InnerRequest::Done(_) => VerificationState::Done,
+            InnerRequest::Cancelled(c) => VerificationState::Cancelled(c.state.clone().into()),
+        }
+    }
+
     fn other_device_id(&self) -> DeviceIdOrAllDevices {
         match self {
             InnerRequest::Created(_) => DeviceIdOrAllDevices::AllDevices,
@@ -1271,6 +1312,7 @@ struct Done {}
 mod test {
     use std::convert::{TryFrom, TryInto};
 
+    use futures::StreamExt;
     use matrix_sdk_test::async_test;
     use ruma::{event_id, room_id, DeviceIdBox, UserId};
 
@@ -1281,7 +1323,7 @@ mod test {
         verification::{
             cache::VerificationCache,
             event_enums::{OutgoingContent, ReadyContent, RequestContent, StartContent},
-            FlowId, VerificationStore,
+            FlowId, VerificationState, VerificationStore,
         },
         ReadOnlyDevice,
     };
@@ -1352,6 +1394,92 @@ mod test {
         assert!(alice_request.is_ready());
     }
 
+    #[async_test]
+    async fn test_request_changes() {
+        let event_id = event_id!("$1234localhost");
+        let room_id = room_id!("!test:localhost");
+
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let alice_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let alice_store = VerificationStore { account: alice.clone(), inner: alice_store.into() };
+
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        let bob_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let bob_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let bob_store = VerificationStore { account: bob.clone(), inner: bob_store.into() };
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(bob_device.clone());
+        alice_store.save_changes(changes).await.unwrap();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(alice_device.clone());
+        bob_store.save_changes(changes).await.unwrap();
+
+        let content =
+            VerificationRequest::request(bob.user_id(), bob.device_id(), &alice_id(), None);
+        let flow_id = FlowId::from((room_id, event_id));
+
+        let bob_request = VerificationRequest::new(
+            VerificationCache::new(),
+            bob_identity,
+            bob_store,
+            flow_id.clone(),
+            &alice_id(),
+            vec![],
+            None,
+        );
+
+        let alice_request = VerificationRequest::from_request(
+            VerificationCache::new(),
+            alice_identity,
+            alice_store,
+            &bob_id(),
+            flow_id,
+            &(&content).into(),
+        );
+
+        let alice_changes = alice_request.changes();
+        let bob_changes = bob_request.changes();
+
+        let content: OutgoingContent = alice_request.accept().unwrap().try_into().unwrap();
+        let content = ReadyContent::try_from(&content).unwrap();
+
+        bob_request.receive_ready(&alice_id(), &content);
+        bob_request.start_sas().await.unwrap().unwrap();
+
+        bob_request.cancel().unwrap();
+        alice_request.cancel().unwrap();
+
+        let alice_changes: Vec<_> = alice_changes.collect().await;
+        let bob_changes: Vec<_> = bob_changes.collect().await;
+
+        assert!(matches!(
+            alice_changes.as_slice(),
+            [
+                VerificationState::Requested,
+                VerificationState::Ready,
+                VerificationState::Cancelled(_)
+            ]
+        ));
+        assert!(matches!(
+            bob_changes.as_slice(),
+            [
+                VerificationState::Requested,
+                VerificationState::Ready,
+                VerificationState::Started,
+                VerificationState::Cancelled(info)
+            ] if info.cancelled_by_us()
+        ));
+        assert_eq!(bob_request.changes().collect::<Vec<_>>().await.len(), 1);
+    }
+
     #[async_test]
     async fn test_requesting_until_sas() {
         let event_id = event_id!("$1234localhost");
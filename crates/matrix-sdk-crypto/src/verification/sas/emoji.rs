@@ -0,0 +1,117 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Translations of the descriptions of the emoji that are used for the emoji
+//! based short authentication string.
+//!
+//! The translations are generated from the `sas-emoji.json` file that is part
+//! of the [spec] by the build script of this crate, a copy of the file lives
+//! next to this module. To update the translations, replace the copy with the
+//! latest version of the file. The English descriptions live next to the emoji
+//! themselves in the `helpers` module.
+//!
+//! [spec]: https://spec.matrix.org/unstable/client-server-api/#sas-method-emoji
+
+/// The translated descriptions of the 64 emoji, ordered by the emoji index,
+/// keyed by the language tag that is used in `sas-emoji.json`.
+const TRANSLATIONS: &[(&str, [&str; 64])] =
+    include!(concat!(env!("OUT_DIR"), "/sas_emoji_translations.rs"));
+
+fn primary_subtag(language: &str) -> &str {
+    language.split('_').next().unwrap_or(language)
+}
+
+/// Is the language tag the given primary language with a script subtag, e.g.
+/// `zh_Hans`.
+fn is_script_variant(tag: &str, primary_language: &str) -> bool {
+    let mut subtags = tag.split('_');
+
+    subtags.next().map_or(false, |p| p.eq_ignore_ascii_case(primary_language))
+        && subtags.next().map_or(false, |s| s.len() == 4)
+        && subtags.next().is_none()
+}
+
+fn find(predicate: impl Fn(&str) -> bool) -> Option<&'static [&'static str; 64]> {
+    TRANSLATIONS.iter().find(|(tag, _)| predicate(tag)).map(|(_, translations)| translations)
+}
+
+/// Get the translated descriptions of the emoji for the given language.
+///
+/// The language can be given as a BCP 47 language tag, e.g. `pt-BR`, or in the
+/// form that is used in `sas-emoji.json`, e.g. `pt_BR`. If we don't have a
+/// translation for the exact language tag, the translation for the primary
+/// language is used instead, e.g. `de-AT` uses the `de` translation. A
+/// translation for a different region is never used, `pt-PT` doesn't fall back
+/// to `pt_BR`. A primary language on its own may use a translation that only
+/// adds a script, e.g. `zh` uses the `zh_Hans` translation.
+///
+/// Returns `None` if we don't have a translation for the language.
+pub fn translations(language: &str) -> Option<&'static [&'static str; 64]> {
+    let language = language.replace('-', "_");
+    let primary_language = primary_subtag(&language);
+
+    find(|tag| tag.eq_ignore_ascii_case(&language))
+        .or_else(|| find(|tag| tag.eq_ignore_ascii_case(primary_language)))
+        .or_else(|| {
+            if language == primary_language {
+                find(|tag| is_script_variant(tag, primary_language))
+            } else {
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::{translations, TRANSLATIONS};
+    use crate::verification::sas::helpers::emoji_from_index;
+
+    #[derive(Deserialize)]
+    struct SasEmoji {
+        number: u8,
+        emoji: String,
+        description: String,
+    }
+
+    #[test]
+    fn emoji_match_sas_emoji_json() {
+        let emoji: Vec<SasEmoji> = serde_json::from_str(include_str!("sas-emoji.json")).unwrap();
+        assert_eq!(emoji.len(), 64);
+        assert!(!TRANSLATIONS.is_empty());
+
+        for entry in emoji {
+            assert_eq!(
+                emoji_from_index(entry.number),
+                (entry.emoji.as_str(), entry.description.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn translations_fall_back_to_the_primary_language() {
+        assert_eq!(translations("de").unwrap()[0], "Hund");
+        assert_eq!(translations("de-AT").unwrap()[0], "Hund");
+        assert_eq!(translations("pt-BR").unwrap()[0], "Cachorro");
+        assert_eq!(translations("pt_br").unwrap()[0], "Cachorro");
+        assert_eq!(translations("zh").unwrap()[0], "狗");
+
+        // Translations for other regions aren't used.
+        assert_eq!(translations("pt-PT"), translations("pt"));
+        assert_ne!(translations("pt-PT"), translations("pt-BR"));
+
+        assert!(translations("tlh").is_none());
+    }
+}
@@ -29,7 +29,7 @@ use ruma::{
 use sha2::{Digest, Sha256};
 use tracing::{trace, warn};
 
-use super::{FlowId, OutgoingContent};
+use super::{emoji::translations, FlowId, OutgoingContent};
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
     utilities::encode,
@@ -72,7 +72,7 @@ pub fn calculate_commitment(public_key: &str, content: &StartContent) -> String
 /// bigger than 63.
 ///
 /// [spec]: https://matrix.org/docs/spec/client_server/latest#sas-method-emoji
-fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
+pub(super) fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
     match index {
         0 => ("🐶", "Dog"),
         1 => ("🐱", "Cat"),
@@ -142,6 +142,26 @@ fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
     }
 }
 
+/// Get a tuple of an emoji and a description of the emoji, translated into the
+/// given language, using a number.
+///
+/// Falls back to the English description if we don't have a translation for
+/// the language.
+///
+/// # Panics
+///
+/// The spec defines 64 unique emojis, this function panics if the index is
+/// bigger than 63.
+pub fn emoji_from_index_localized(index: u8, language: &str) -> (&'static str, &'static str) {
+    let (emoji, description) = emoji_from_index(index);
+
+    let description = translations(language)
+        .map(|translations| translations[index as usize])
+        .unwrap_or(description);
+
+    (emoji, description)
+}
+
 /// Get the extra info that will be used when we check the MAC of a
 /// m.key.verification.key event.
 ///
@@ -548,7 +568,7 @@ mod test {
 
     use super::{
         bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, calculate_commitment,
-        emoji_from_index,
+        emoji_from_index, emoji_from_index_localized,
     };
     use crate::verification::event_enums::StartContent;
 
@@ -588,6 +608,16 @@ mod test {
         assert_eq!(bytes_to_emoji(bytes), index.as_ref());
     }
 
+    #[test]
+    fn localized_emoji() {
+        assert_eq!(emoji_from_index_localized(0, "de"), ("🐶", "Hund"));
+        assert_eq!(emoji_from_index_localized(63, "fr"), ("📌", "Punaise"));
+
+        // Unknown languages fall back to English.
+        assert_eq!(emoji_from_index_localized(4, "en"), emoji_from_index(4));
+        assert_eq!(emoji_from_index_localized(4, "tlh"), emoji_from_index(4));
+    }
+
     #[test]
     fn decimal_generation() {
         let bytes = vec![0, 0, 0, 0, 0];
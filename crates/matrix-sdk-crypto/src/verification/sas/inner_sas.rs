@@ -32,7 +32,7 @@ use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
     verification::{
         event_enums::{AnyVerificationContent, OutgoingContent, OwnedAcceptContent, StartContent},
-        Cancelled, Done,
+        Cancelled, Done, VerificationState,
     },
     ReadOnlyAccount, ReadOnlyOwnUserIdentity,
 };
@@ -357,6 +357,19 @@ impl InnerSas {
         matches!(self, InnerSas::Cancelled(_))
     }
 
+    pub fn state(&self) -> VerificationState {
+        match self {
+            InnerSas::Created(_)
+            | InnerSas::Started(_)
+            | InnerSas::Accepted(_)
+            | InnerSas::WeAccepted(_) => VerificationState::Started,
+            InnerSas::KeyReceived(_) | InnerSas::MacReceived(_) => VerificationState::KeysExchanged,
+            InnerSas::Confirmed(_) | InnerSas::WaitingForDone(_) => VerificationState::Confirmed,
+            InnerSas::Done(_) => VerificationState::Done,
+            InnerSas::Cancelled(c) => VerificationState::Cancelled(c.state.as_ref().clone().into()),
+        }
+    }
+
     pub fn have_we_confirmed(&self) -> bool {
         matches!(self, InnerSas::Confirmed(_) | InnerSas::WaitingForDone(_) | InnerSas::Done(_))
     }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod emoji;
 mod helpers;
 mod inner_sas;
 mod sas_state;
@@ -20,6 +21,8 @@ use std::sync::{Arc, Mutex};
 #[cfg(test)]
 use std::time::Instant;
 
+use futures::Stream;
+use helpers::emoji_from_index_localized;
 use inner_sas::InnerSas;
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{
@@ -35,7 +38,8 @@ use tracing::trace;
 use super::{
     event_enums::{AnyVerificationContent, OutgoingContent, OwnedAcceptContent, StartContent},
     requests::RequestHandle,
-    CancelInfo, FlowId, IdentitiesBeingVerified, VerificationResult, VerificationStore,
+    CancelInfo, FlowId, IdentitiesBeingVerified, StateListeners, VerificationResult,
+    VerificationState, VerificationStore,
 };
 use crate::{
     identities::{ReadOnlyDevice, ReadOnlyUserIdentities},
@@ -54,6 +58,7 @@ pub struct Sas {
     flow_id: Arc<FlowId>,
     we_started: bool,
     request_handle: Option<RequestHandle>,
+    changes: StateListeners,
 }
 
 impl Sas {
@@ -137,6 +142,18 @@ impl Sas {
         self.we_started
     }
 
+    /// Get a stream of state transitions of this SAS verification flow.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.changes.subscribe()
+    }
+
+    /// Replace the inner SAS state and notify the listeners about the
+    /// transition.
+    fn set_inner(&self, guard: &mut InnerSas, sas: InnerSas) {
+        self.changes.update(sas.state());
+        *guard = sas;
+    }
+
     #[cfg(test)]
     #[allow(dead_code)]
     pub(crate) fn set_creation_time(&self, time: Instant) {
@@ -153,6 +170,11 @@ impl Sas {
         request_handle: Option<RequestHandle>,
     ) -> Sas {
         let flow_id = inner_sas.verification_flow_id();
+        let changes = StateListeners::new(inner_sas.state());
+
+        if let Some(request_handle) = &request_handle {
+            request_handle.mark_as_started();
+        }
 
         let account = store.account.clone();
 
@@ -170,6 +192,7 @@ impl Sas {
             flow_id,
             we_started,
             request_handle,
+            changes,
         }
     }
 
@@ -327,7 +350,7 @@ impl Sas {
         let methods = settings.allowed_methods;
 
         if let Some((sas, content)) = sas.accept(methods) {
-            *guard = sas;
+            self.set_inner(&mut guard, sas);
 
             Some(match content {
                 OwnedAcceptContent::ToDevice(c) => {
@@ -364,7 +387,7 @@ impl Sas {
             let sas: InnerSas = (*guard).clone();
             let (sas, content) = sas.confirm();
 
-            *guard = sas;
+            self.set_inner(&mut guard, sas);
             (content, guard.is_done())
         };
 
@@ -432,7 +455,7 @@ impl Sas {
 
         let sas: InnerSas = (*guard).clone();
         let (sas, content) = sas.cancel(true, code);
-        *guard = sas;
+        self.set_inner(&mut guard, sas);
         content.map(|c| match c {
             OutgoingContent::Room(room_id, content) => {
                 RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
@@ -479,6 +502,33 @@ impl Sas {
         self.inner.lock().unwrap().emoji()
     }
 
+    /// Get the emoji version of the short auth string with the descriptions of
+    /// the emoji translated into the given language.
+    ///
+    /// The translations are the ones from the `sas-emoji.json` file of the
+    /// [spec]. The language is a language tag like `de`, `pt-BR` or `zh_Hans`,
+    /// if no translation exists for the exact language tag, the translation of
+    /// the primary language is used, e.g. `de` for `de-AT`. The English
+    /// descriptions are used if no translation for the language exists.
+    ///
+    /// Returns None if we can't yet present the short auth string, otherwise
+    /// seven tuples containing the emoji and the translated description.
+    ///
+    /// [spec]: https://spec.matrix.org/unstable/client-server-api/#sas-method-emoji
+    pub fn emoji_localized(&self, language: &str) -> Option<[(&'static str, &'static str); 7]> {
+        let index = self.emoji_index()?;
+
+        Some([
+            emoji_from_index_localized(index[0], language),
+            emoji_from_index_localized(index[1], language),
+            emoji_from_index_localized(index[2], language),
+            emoji_from_index_localized(index[3], language),
+            emoji_from_index_localized(index[4], language),
+            emoji_from_index_localized(index[5], language),
+            emoji_from_index_localized(index[6], language),
+        ])
+    }
+
     /// Get the index of the emoji representing the short auth string
     ///
     /// Returns None if we can't yet present the short auth string, otherwise
@@ -506,7 +556,7 @@ impl Sas {
         let mut guard = self.inner.lock().unwrap();
         let sas: InnerSas = (*guard).clone();
         let (sas, content) = sas.receive_any_event(sender, content);
-        *guard = sas;
+        self.set_inner(&mut guard, sas);
 
         content
     }
@@ -557,6 +607,7 @@ impl AcceptSettings {
 mod test {
     use std::{convert::TryFrom, sync::Arc};
 
+    use futures::StreamExt;
     use ruma::{DeviceId, UserId};
 
     use super::Sas;
@@ -565,7 +616,7 @@ mod test {
         store::MemoryStore,
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, OutgoingContent, StartContent},
-            VerificationStore,
+            VerificationState, VerificationStore,
         },
         ReadOnlyAccount, ReadOnlyDevice,
     };
@@ -629,6 +680,9 @@ mod test {
         )
         .unwrap();
 
+        let alice_changes = alice.changes();
+        let bob_changes = bob.changes();
+
         let request = bob.accept().unwrap();
         let content = OutgoingContent::try_from(request).unwrap();
         let content = AcceptContent::try_from(&content).unwrap();
@@ -662,5 +716,22 @@ mod test {
 
         assert!(alice.verified_devices().unwrap().contains(alice.other_device()));
         assert!(bob.verified_devices().unwrap().contains(bob.other_device()));
+
+        let alice_changes: Vec<_> = alice_changes.collect().await;
+        let bob_changes: Vec<_> = bob_changes.collect().await;
+
+        assert!(matches!(
+            alice_changes.as_slice(),
+            [
+                VerificationState::Started,
+                VerificationState::KeysExchanged,
+                VerificationState::Confirmed,
+                VerificationState::Done
+            ]
+        ));
+        assert!(matches!(
+            bob_changes.as_slice(),
+            [VerificationState::Started, VerificationState::KeysExchanged, VerificationState::Done]
+        ));
     }
 }
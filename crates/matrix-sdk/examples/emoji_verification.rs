@@ -7,10 +7,11 @@ use std::{
     },
 };
 
+use futures::StreamExt;
 use matrix_sdk::{
     self,
     config::SyncSettings,
-    encryption::verification::{SasVerification, Verification},
+    encryption::verification::{SasVerification, Verification, VerificationState},
     ruma::{
         events::{
             room::message::MessageType, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
@@ -21,22 +22,43 @@ use matrix_sdk::{
 };
 use url::Url;
 
-async fn wait_for_confirmation(client: Client, sas: SasVerification) {
+async fn wait_for_confirmation(sas: SasVerification) {
     println!("Does the emoji match: {:?}", sas.emoji());
 
     let mut input = String::new();
     io::stdin().read_line(&mut input).expect("error: unable to read user input");
 
     match input.trim().to_lowercase().as_ref() {
-        "yes" | "true" | "ok" => {
-            sas.confirm().await.unwrap();
+        "yes" | "true" | "ok" => sas.confirm().await.unwrap(),
+        _ => sas.cancel().await.unwrap(),
+    }
+}
+
+async fn sas_verification_handler(client: Client, sas: SasVerification) {
+    println!(
+        "Starting verification with {} {}",
+        &sas.other_device().user_id(),
+        &sas.other_device().device_id()
+    );
+    print_devices(sas.other_device().user_id(), &client).await;
+    sas.accept().await.unwrap();
 
-            if sas.is_done() {
+    let mut changes = sas.changes();
+
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationState::KeysExchanged => {
+                tokio::spawn(wait_for_confirmation(sas.clone()));
+            }
+            VerificationState::Done => {
                 print_result(&sas);
                 print_devices(sas.other_device().user_id(), &client).await;
             }
+            VerificationState::Cancelled(info) => {
+                println!("The verification has been cancelled: {}", info.reason());
+            }
+            _ => (),
         }
-        _ => sas.cancel().await.unwrap(),
     }
 }
 
@@ -89,32 +111,7 @@ async fn login(
                         if let Some(Verification::SasV1(sas)) =
                             client.get_verification(&e.sender, &e.content.transaction_id).await
                         {
-                            println!(
-                                "Starting verification with {} {}",
-                                &sas.other_device().user_id(),
-                                &sas.other_device().device_id()
-                            );
-                            print_devices(&e.sender, client).await;
-                            sas.accept().await.unwrap();
-                        }
-                    }
-
-                    AnyToDeviceEvent::KeyVerificationKey(e) => {
-                        if let Some(Verification::SasV1(sas)) =
-                            client.get_verification(&e.sender, &e.content.transaction_id).await
-                        {
-                            tokio::spawn(wait_for_confirmation((*client).clone(), sas));
-                        }
-                    }
-
-                    AnyToDeviceEvent::KeyVerificationMac(e) => {
-                        if let Some(Verification::SasV1(sas)) =
-                            client.get_verification(&e.sender, &e.content.transaction_id).await
-                        {
-                            if sas.is_done() {
-                                print_result(&sas);
-                                print_devices(&e.sender, client).await;
-                            }
+                            tokio::spawn(sas_verification_handler((*client).clone(), sas));
                         }
                     }
 
@@ -143,18 +140,7 @@ async fn login(
                                             .expect("Can't accept verification request");
                                     }
                                 }
-                                AnySyncMessageEvent::KeyVerificationKey(e) => {
-                                    if let Some(Verification::SasV1(sas)) = client
-                                        .get_verification(
-                                            &e.sender,
-                                            e.content.relates_to.event_id.as_str(),
-                                        )
-                                        .await
-                                    {
-                                        tokio::spawn(wait_for_confirmation((*client).clone(), sas));
-                                    }
-                                }
-                                AnySyncMessageEvent::KeyVerificationMac(e) => {
+                                AnySyncMessageEvent::KeyVerificationStart(e) => {
                                     if let Some(Verification::SasV1(sas)) = client
                                         .get_verification(
                                             &e.sender,
@@ -162,10 +148,10 @@ async fn login(
                                         )
                                         .await
                                     {
-                                        if sas.is_done() {
-                                            print_result(&sas);
-                                            print_devices(&e.sender, client).await;
-                                        }
+                                        tokio::spawn(sas_verification_handler(
+                                            (*client).clone(),
+                                            sas,
+                                        ));
                                     }
                                 }
                                 _ => (),
@@ -0,0 +1,218 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying failed requests, used by the sync loop and the send queue.
+
+use http::StatusCode;
+use matrix_sdk_common::instant::Duration;
+use rand::{thread_rng, Rng};
+use ruma::api::{
+    client::{error::ErrorKind, Error as RumaClientApiError},
+    error::{FromHttpResponseError, ServerError},
+};
+
+use crate::{Error, HttpError};
+
+/// The delay before the first retry of a failed request.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The maximal delay between two retries of a failed request.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The classification of an error that happened while sending a request to
+/// the homeserver, e.g. while syncing or sending a queued event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestErrorKind {
+    /// The access token isn't valid anymore, the user needs to log in again.
+    ///
+    /// If `soft_logout` is true, the user may log in again to the same device
+    /// and keep their local state.
+    UnknownToken {
+        /// Was the client soft logged out.
+        soft_logout: bool,
+    },
+    /// The client tried to send a request before logging in.
+    AuthenticationRequired,
+    /// The server is rate limiting us.
+    RateLimited {
+        /// The time the server asked us to wait before retrying, if any.
+        retry_after: Option<Duration>,
+    },
+    /// The server couldn't be reached or returned a server error.
+    Network,
+    /// The state store or the crypto store failed.
+    Store,
+    /// Any other error, e.g. a response that couldn't be deserialized or an IO
+    /// error.
+    Other,
+}
+
+impl RequestErrorKind {
+    /// Is this error fatal, i.e. will retrying the request always fail.
+    ///
+    /// The sync loop stops on fatal errors while other errors are retried after
+    /// a backoff.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            RequestErrorKind::UnknownToken { .. }
+                | RequestErrorKind::AuthenticationRequired
+                | RequestErrorKind::Store
+        )
+    }
+
+    fn from_client_api_error(error: &RumaClientApiError) -> Self {
+        match &error.kind {
+            ErrorKind::UnknownToken { soft_logout } => {
+                RequestErrorKind::UnknownToken { soft_logout: *soft_logout }
+            }
+            ErrorKind::LimitExceeded { retry_after_ms } => {
+                RequestErrorKind::RateLimited { retry_after: *retry_after_ms }
+            }
+            _ => Self::from_status_code(error.status_code),
+        }
+    }
+
+    fn from_status_code(status_code: StatusCode) -> Self {
+        if status_code == StatusCode::TOO_MANY_REQUESTS {
+            RequestErrorKind::RateLimited { retry_after: None }
+        } else if status_code.is_server_error() {
+            RequestErrorKind::Network
+        } else {
+            RequestErrorKind::Other
+        }
+    }
+}
+
+impl From<&HttpError> for RequestErrorKind {
+    fn from(error: &HttpError) -> Self {
+        match error {
+            HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))) => {
+                Self::from_client_api_error(e)
+            }
+            HttpError::Reqwest(_) => RequestErrorKind::Network,
+            HttpError::Server(status_code) => Self::from_status_code(*status_code),
+            HttpError::AuthenticationRequired
+            | HttpError::ForcedAuthenticationWithoutAccessToken
+            | HttpError::UserIdRequired => RequestErrorKind::AuthenticationRequired,
+            _ => RequestErrorKind::Other,
+        }
+    }
+}
+
+impl From<&Error> for RequestErrorKind {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Http(e) => e.into(),
+            Error::AuthenticationRequired => RequestErrorKind::AuthenticationRequired,
+            Error::StateStore(_) => RequestErrorKind::Store,
+            #[cfg(feature = "encryption")]
+            Error::CryptoStoreError(_) => RequestErrorKind::Store,
+            _ => RequestErrorKind::Other,
+        }
+    }
+}
+
+/// Exponential backoff with jitter for failed requests.
+#[derive(Debug, Default)]
+pub(crate) struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    /// The number of consecutive failed requests.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// A request succeeded, the next failure starts backing off from scratch.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// A request failed with the given error, get the time we should wait
+    /// before retrying.
+    ///
+    /// If the server told us how long we should wait, that time is used,
+    /// otherwise the delay doubles with every consecutive failure, up to
+    /// `MAX_BACKOFF`. A random jitter of up to half the delay is subtracted so
+    /// many clients don't retry in lockstep after a server outage.
+    pub fn next_delay(&mut self, error: &RequestErrorKind) -> Duration {
+        self.attempt = self.attempt.saturating_add(1);
+
+        if let RequestErrorKind::RateLimited { retry_after: Some(retry_after) } = error {
+            return *retry_after;
+        }
+
+        let exponent = (self.attempt - 1).min(16);
+        let delay = INITIAL_BACKOFF.saturating_mul(1 << exponent).min(MAX_BACKOFF);
+
+        let max_jitter = delay.as_millis() as u64 / 2;
+        let jitter = thread_rng().gen_range(0..=max_jitter);
+
+        delay - Duration::from_millis(jitter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+    use matrix_sdk_common::instant::Duration;
+
+    use super::{Backoff, RequestErrorKind, INITIAL_BACKOFF, MAX_BACKOFF};
+    use crate::Error;
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let mut backoff = Backoff::default();
+
+        for attempt in 0..20 {
+            let expected = INITIAL_BACKOFF.saturating_mul(1 << attempt.min(16)).min(MAX_BACKOFF);
+            let delay = backoff.next_delay(&RequestErrorKind::Network);
+
+            assert!(delay <= expected);
+            assert!(delay >= expected / 2);
+        }
+
+        assert_eq!(backoff.attempt(), 20);
+
+        backoff.reset();
+        assert!(backoff.next_delay(&RequestErrorKind::Network) <= INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_respects_retry_after() {
+        let mut backoff = Backoff::default();
+        let retry_after = Duration::from_millis(1234);
+
+        let delay =
+            backoff.next_delay(&RequestErrorKind::RateLimited { retry_after: Some(retry_after) });
+
+        assert_eq!(delay, retry_after);
+    }
+
+    #[test]
+    fn fatal_errors() {
+        assert!(RequestErrorKind::UnknownToken { soft_logout: true }.is_fatal());
+        assert!(RequestErrorKind::Store.is_fatal());
+        assert!(!RequestErrorKind::Network.is_fatal());
+        assert!(!RequestErrorKind::RateLimited { retry_after: None }.is_fatal());
+    }
+
+    #[test]
+    fn io_errors_are_retried() {
+        let error = Error::Io(IoError::new(IoErrorKind::Interrupted, "interrupted"));
+        assert!(!RequestErrorKind::from(&error).is_fatal());
+    }
+}
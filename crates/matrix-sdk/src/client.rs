@@ -20,11 +20,14 @@ use std::{
     io::Read,
     pin::Pin,
     result::Result as StdResult,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use dashmap::DashMap;
-use futures::FutureExt;
+use futures::{FutureExt, Stream};
 use futures_timer::Delay as sleep;
 use matrix_sdk_base::{
     deserialized_responses::{JoinedRoom, LeftRoom, SyncResponse},
@@ -67,11 +70,14 @@ use tracing::{error, info, instrument, warn};
 use url::Url;
 
 use crate::{
+    backoff::{Backoff, RequestErrorKind},
     config::{ClientConfig, RequestConfig},
     error::{HttpError, HttpResult},
     event_handler::{EventHandler, EventHandlerData, EventHandlerResult, EventKind, SyncEvent},
-    http_client::{client_with_config, HttpClient},
-    room, Error, Result,
+    http_client::{self, client_with_config, HttpClient},
+    room::{self, send_queue::SendQueueInner, timeline::WeakTimeline},
+    sync::{StopOnDrop, SyncState, SyncStateObserver, SyncStopReason},
+    Error, Result,
 };
 
 /// A conservative upload speed of 1Mbps
@@ -135,6 +141,14 @@ pub struct Client {
     /// wait for the sync to get the data to fetch a room object from the state
     /// store.
     pub(crate) sync_beat: Arc<event_listener::Event>,
+    /// The state of the sync loop.
+    sync_state: SyncStateObserver,
+    /// The live timelines of rooms. See `room::Joined::live_timeline`.
+    pub(crate) timelines: Arc<DashMap<RoomId, Vec<WeakTimeline>>>,
+    /// The send queues of rooms. See `room::Joined::send_queue`.
+    pub(crate) send_queues: Arc<DashMap<RoomId, Arc<SendQueueInner>>>,
+    /// Were the send queues that contain events from a previous run resumed.
+    send_queues_resumed: Arc<AtomicBool>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -192,6 +206,10 @@ impl Client {
             notification_handlers: Default::default(),
             appservice_mode: config.appservice_mode,
             sync_beat: event_listener::Event::new().into(),
+            sync_state: SyncStateObserver::new(),
+            timelines: Default::default(),
+            send_queues: Default::default(),
+            send_queues_resumed: Default::default(),
         })
     }
 
@@ -819,15 +837,7 @@ impl Client {
             password,
         ));
 
-        let request = assign!(login::Request::new(login_info), {
-            device_id: device_id.map(|d| d.into()),
-            initial_device_display_name,
-        });
-
-        let response = self.send(request, None).await?;
-        self.base_client.receive_login_response(&response).await?;
-
-        Ok(response)
+        self.send_login_request(login_info, device_id, initial_device_display_name).await
     }
 
     /// Login to the server via Single Sign-On.
@@ -1095,19 +1105,39 @@ impl Client {
     ) -> Result<login::Response> {
         info!("Logging in to {}", self.homeserver().await);
 
-        let request = assign!(
-            login::Request::new(
-                login::LoginInfo::Token(login::Token::new(token)),
-            ), {
-                device_id: device_id.map(|d| d.into()),
-                initial_device_display_name,
-            }
-        );
+        let login_info = login::LoginInfo::Token(login::Token::new(token));
 
-        let response = self.send(request, None).await?;
-        self.base_client.receive_login_response(&response).await?;
+        self.send_login_request(login_info, device_id, initial_device_display_name).await
+    }
 
-        Ok(response)
+    /// Send a login request asking for a refresh token, see [MSC2918], and
+    /// update the session of the client with the response.
+    ///
+    /// [MSC2918]: https://github.com/matrix-org/matrix-doc/pull/2918
+    async fn send_login_request(
+        &self,
+        login_info: login::LoginInfo<'_>,
+        device_id: Option<&str>,
+        initial_device_display_name: Option<&str>,
+    ) -> Result<login::Response> {
+        let request = assign!(login::Request::new(login_info), {
+            device_id: device_id.map(|d| d.into()),
+            initial_device_display_name,
+        });
+
+        let response = self.send(http_client::api::login::Request(request), None).await?;
+
+        let session = Session {
+            access_token: response.login.access_token.clone(),
+            device_id: response.login.device_id.clone(),
+            user_id: response.login.user_id.clone(),
+            refresh_token: response.refresh_token,
+            expires_at: response.expires_in_ms.and_then(http_client::expires_at),
+        };
+
+        self.base_client.restore_login(session).await?;
+
+        Ok(response.login)
     }
 
     /// Restore a previously logged in session.
@@ -1128,6 +1158,56 @@ impl Client {
         Ok(self.base_client.restore_login(session).await?)
     }
 
+    /// Get a new access token using the refresh token of the current session,
+    /// see [MSC2918].
+    ///
+    /// This usually doesn't need to be called manually, expired access tokens
+    /// are refreshed transparently when a request fails because of them.
+    ///
+    /// Returns `false` if the current session doesn't have a refresh token.
+    ///
+    /// [MSC2918]: https://github.com/matrix-org/matrix-doc/pull/2918
+    pub async fn refresh_access_token(&self) -> HttpResult<bool> {
+        self.http_client.refresh_access_token(None).await
+    }
+
+    /// Register a handler that is called every time the session of the client
+    /// changes because the access token got refreshed.
+    ///
+    /// The new tokens should be persisted so the session can be restored using
+    /// [`Client::restore_login`] later on.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// client
+    ///     .register_session_change_handler(|session| async move {
+    ///         // Persist the session somewhere safe.
+    ///         println!("Got a new access token for {}", session.user_id);
+    ///     })
+    ///     .await;
+    /// # });
+    /// ```
+    pub async fn register_session_change_handler<H, Fut>(&self, handler: H) -> &Self
+    where
+        H: Fn(Session) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.http_client
+            .session_change_handlers
+            .write()
+            .await
+            .push(Box::new(move |session| (handler)(session).boxed()));
+
+        self
+    }
+
     /// Register a user to the server.
     ///
     /// # Arguments
@@ -1609,15 +1689,17 @@ impl Client {
             rooms,
             presence,
             account_data,
-            to_device: _,
+            to_device,
             device_lists: _,
             device_one_time_keys_count: _,
             ambiguity_changes: _,
             notifications,
+            late_decrypted_events,
         } = &response;
 
         self.handle_sync_events(EventKind::GlobalAccountData, &None, &account_data.events).await?;
         self.handle_sync_events(EventKind::Presence, &None, &presence.events).await?;
+        self.handle_sync_events(EventKind::ToDevice, &None, &to_device.events).await?;
 
         for (room_id, room_info) in &rooms.join {
             let room = self.get_room(room_id);
@@ -1634,6 +1716,7 @@ impl Client {
                 .await?;
             self.handle_sync_state_events(&room, &state.events).await?;
             self.handle_sync_timeline_events(&room, &timeline.events).await?;
+            self.update_timelines(room_id, timeline);
         }
 
         for (room_id, room_info) in &rooms.leave {
@@ -1667,6 +1750,9 @@ impl Client {
             .await?;
         }
 
+        self.handle_late_decrypted_events(late_decrypted_events).await?;
+        self.update_timelines_decrypted(late_decrypted_events);
+
         // Construct notification event handler futures
         let mut futures = Vec::new();
         for handler in &*self.notification_handlers.read().await {
@@ -1691,28 +1777,97 @@ impl Client {
             fut.await;
         }
 
+        // Events that were queued before a restart are only sent once the
+        // first sync told us which rooms we're still in.
+        if !self.send_queues_resumed.swap(true, Ordering::SeqCst) {
+            self.resume_send_queues().await;
+        }
+
         Ok(response)
     }
 
     async fn sync_loop_helper(
         &self,
         sync_settings: &mut crate::config::SyncSettings<'_>,
+        backoff: &mut Backoff,
     ) -> Result<SyncResponse> {
         let response = self.sync_once(sync_settings.clone()).await;
 
         match response {
             Ok(r) => {
                 sync_settings.token = Some(r.next_batch.clone());
+                backoff.reset();
+                self.sync_state.set(SyncState::Running);
+
                 Ok(r)
             }
             Err(e) => {
-                error!("Received an invalid response: {}", e);
-                sleep::new(Duration::from_secs(1)).await;
+                let kind = RequestErrorKind::from(&e);
+
+                if kind.is_fatal() {
+                    error!(error =? e, "Stopping the sync loop, received a fatal error");
+                    self.sync_state.set(SyncState::Stopped(SyncStopReason::Error(kind)));
+                } else {
+                    let delay = backoff.next_delay(&kind);
+
+                    warn!(
+                        error =? e,
+                        attempt = backoff.attempt(),
+                        delay =? delay,
+                        "Sync failed, backing off before retrying"
+                    );
+
+                    self.sync_state.set(SyncState::BackingOff {
+                        error: kind,
+                        attempt: backoff.attempt(),
+                        delay,
+                    });
+
+                    sleep::new(delay).await;
+                }
+
                 Err(e)
             }
         }
     }
 
+    /// Get the current state of the sync loop.
+    pub fn sync_state(&self) -> SyncState {
+        self.sync_state.get()
+    }
+
+    /// Get a stream of sync loop state changes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// use matrix_sdk::{RequestErrorKind, SyncState, SyncStopReason};
+    ///
+    /// let mut changes = client.sync_state_changes();
+    ///
+    /// while let Some(state) = changes.next().await {
+    ///     match state {
+    ///         SyncState::BackingOff { delay, .. } => {
+    ///             println!("Sync failed, retrying in {:?}", delay)
+    ///         }
+    ///         SyncState::Stopped(SyncStopReason::Error(RequestErrorKind::UnknownToken {
+    ///             ..
+    ///         })) => println!("The access token is invalid, please log in again"),
+    ///         _ => (),
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub fn sync_state_changes(&self) -> impl Stream<Item = SyncState> {
+        self.sync_state.subscribe()
+    }
+
     async fn delay_sync(last_sync_time: &mut Option<Instant>) {
         let now = Instant::now();
 
@@ -1852,8 +2007,11 @@ impl Client {
 
     /// Repeatedly synchronize the client state with the server.
     ///
-    /// This method will never return, if cancellation is needed the method
-    /// should be wrapped in a cancelable task or the
+    /// This method will only return if a fatal error happens, e.g. if the
+    /// access token has become invalid. Failed syncs are otherwise retried
+    /// with an exponential backoff. The state of the sync loop can be observed
+    /// using the [`Client::sync_state_changes`] method. If cancellation is
+    /// needed the method should be wrapped in a cancelable task or the
     /// [`Client::sync_with_callback`] method can be used.
     ///
     /// This method will internally call [`Client::sync_once`] in a loop.
@@ -1919,6 +2077,11 @@ impl Client {
     ///   callback returns `LoopCtrl::Continue` the sync will continue, if the
     ///   callback returns `LoopCtrl::Break` the sync will be stopped.
     ///
+    /// Failed syncs are retried with an exponential backoff, the sync loop is
+    /// stopped if a fatal error, like an invalid access token or a failing
+    /// store, happens. See [`Client::sync_state_changes`] to find out why the
+    /// sync loop stopped.
+    ///
     /// # Examples
     ///
     /// The following example demonstrates how to sync forever while sending all
@@ -1970,20 +2133,24 @@ impl Client {
         C: Future<Output = LoopCtrl>,
     {
         let mut last_sync_time: Option<Instant> = None;
+        let mut backoff = Backoff::default();
 
         if sync_settings.token.is_none() {
             sync_settings.token = self.sync_token().await;
         }
 
+        self.sync_state.set(SyncState::Running);
+
         loop {
-            // TODO we should abort the sync loop if the error is a storage error or
-            // the access token got invalid.
-            if let Ok(r) = self.sync_loop_helper(&mut sync_settings).await {
-                if callback(r).await == LoopCtrl::Break {
-                    return;
+            match self.sync_loop_helper(&mut sync_settings, &mut backoff).await {
+                Ok(r) => {
+                    if callback(r).await == LoopCtrl::Break {
+                        self.sync_state.set(SyncState::Stopped(SyncStopReason::Requested));
+                        return;
+                    }
                 }
-            } else {
-                continue;
+                Err(e) if RequestErrorKind::from(&e).is_fatal() => return,
+                Err(_) => continue,
             }
 
             Client::delay_sync(&mut last_sync_time).await
@@ -1996,6 +2163,12 @@ impl Client {
     /// equivalent to the [`Client::sync`] method but the responses are provided
     /// as an async stream.
     ///
+    /// Errors are yielded as well, the stream ends after a fatal error has
+    /// been yielded.
+    ///
+    /// The sync loop is only considered to be running once the stream is
+    /// polled, dropping the stream stops it.
+    ///
     /// # Arguments
     ///
     /// * `sync_settings` - Settings for the sync call. *Note* that those
@@ -2038,16 +2211,29 @@ impl Client {
     pub async fn sync_stream<'a>(
         &'a self,
         mut sync_settings: crate::config::SyncSettings<'a>,
-    ) -> impl futures::stream::Stream<Item = Result<SyncResponse>> + 'a {
+    ) -> impl Stream<Item = Result<SyncResponse>> + 'a {
         let mut last_sync_time: Option<Instant> = None;
+        let mut backoff = Backoff::default();
 
         if sync_settings.token.is_none() {
             sync_settings.token = self.sync_token().await;
         }
 
         async_stream::stream! {
+            // The stream may never be polled, the sync loop only runs once it
+            // is and stops when the stream is dropped.
+            let _stop_on_drop = StopOnDrop(self.sync_state.clone());
+            self.sync_state.set(SyncState::Running);
+
             loop {
-                yield self.sync_loop_helper(&mut sync_settings).await;
+                let response = self.sync_loop_helper(&mut sync_settings, &mut backoff).await;
+                let fatal = matches!(&response, Err(e) if RequestErrorKind::from(e).is_fatal());
+
+                yield response;
+
+                if fatal {
+                    break;
+                }
 
                 Client::delay_sync(&mut last_sync_time).await
             }
@@ -2277,6 +2463,7 @@ pub(crate) mod test {
         time::Duration,
     };
 
+    use futures::StreamExt;
     use matrix_sdk_base::media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType};
     use matrix_sdk_test::{test_json, EventBuilder, EventsJson};
     use mockito::{mock, Matcher};
@@ -2315,7 +2502,7 @@ pub(crate) mod test {
     use super::{Client, Session, Url};
     use crate::{
         config::{ClientConfig, RequestConfig, SyncSettings},
-        HttpError, RoomMember,
+        HttpError, LoopCtrl, RequestErrorKind, RoomMember, SyncState, SyncStopReason,
     };
 
     pub(crate) async fn logged_in_client() -> Client {
@@ -2323,6 +2510,8 @@ pub(crate) mod test {
             access_token: "1234".to_owned(),
             user_id: user_id!("@example:localhost"),
             device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
         };
         let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
         let config = ClientConfig::new().request_config(RequestConfig::new().disable_retry());
@@ -3457,6 +3646,8 @@ pub(crate) mod test {
             access_token: "1234".to_owned(),
             user_id: user_id!("@example:localhost"),
             device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
         };
 
         let sync = json!({
@@ -3546,4 +3737,335 @@ pub(crate) mod test {
 
         matches::assert_matches!(encryption_event, AnySyncStateEvent::RoomEncryption(_));
     }
+
+    #[tokio::test]
+    async fn room_timeline_backfill() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let messages = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/messages\?.*from=t392.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body(test_json::ROOM_MESSAGES.to_string())
+        .expect(1)
+        .create();
+
+        let room_start = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/messages\?.*from=t47409.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "chunk": [], "start": "t47409-4357353_219380_26003_2265" }).to_string())
+        .expect(1)
+        .create();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+        let expected = [
+            "$152037280074GZeOm:localhost",
+            "$1444812213350496Caaaa:example.com",
+            "$1444812213350496Cbbbb:example.com",
+            "$1444812213350496Ccccc:example.com",
+        ];
+
+        // The second time around all the events come from the store.
+        for _ in 0..2 {
+            let events: Vec<_> = room.timeline().collect().await;
+            let event_ids: Vec<String> = events
+                .into_iter()
+                .map(|e| {
+                    let event = e.unwrap().event.deserialize_as::<serde_json::Value>().unwrap();
+                    event["event_id"].as_str().unwrap().to_owned()
+                })
+                .collect();
+
+            assert_eq!(event_ids, expected);
+        }
+
+        messages.assert();
+        room_start.assert();
+    }
+
+    #[tokio::test]
+    async fn refresh_expired_access_token() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new().request_config(RequestConfig::new().disable_retry());
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+            refresh_token: Some("refresh_1234".to_owned()),
+            expires_at: None,
+        };
+        client.restore_login(session).await.unwrap();
+
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        client
+            .register_session_change_handler(move |session| {
+                let sender = sender.clone();
+                async move {
+                    sender.unbounded_send(session).unwrap();
+                }
+            })
+            .await;
+
+        let _expired = mock("GET", "/_matrix/client/r0/account/whoami")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token has expired",
+                    "soft_logout": true,
+                })
+                .to_string(),
+            )
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let refresh =
+            mock("POST", "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh")
+                .with_status(200)
+                .with_body(
+                    json!({
+                        "access_token": "5678",
+                        "refresh_token": "refresh_5678",
+                        "expires_in_ms": 60000,
+                    })
+                    .to_string(),
+                )
+                .match_body(Matcher::Json(json!({ "refresh_token": "refresh_1234" })))
+                .create();
+
+        let _whoami = mock("GET", "/_matrix/client/r0/account/whoami")
+            .with_status(200)
+            .with_body(test_json::WHOAMI.to_string())
+            .match_header("authorization", "Bearer 5678")
+            .create();
+
+        assert_eq!(client.whoami().await.unwrap().user_id, user_id!("@joe:example.org"));
+        refresh.assert();
+
+        let session = client.session().await.unwrap();
+        assert_eq!(session.access_token, "5678");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh_5678"));
+        assert!(session.expires_at.is_some());
+        assert!(!session.is_expired());
+
+        assert_eq!(receiver.next().await.unwrap(), session);
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_returns_the_original_error() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new().request_config(RequestConfig::new().disable_retry());
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+            refresh_token: Some("refresh_1234".to_owned()),
+            expires_at: None,
+        };
+        client.restore_login(session).await.unwrap();
+
+        let _expired = mock("GET", "/_matrix/client/r0/account/whoami")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token has expired",
+                    "soft_logout": true,
+                })
+                .to_string(),
+            )
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let refresh =
+            mock("POST", "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh")
+                .with_status(401)
+                .with_body(
+                    json!({
+                        "errcode": "M_UNKNOWN_TOKEN",
+                        "error": "Refresh token is invalid",
+                        "soft_logout": false,
+                    })
+                    .to_string(),
+                )
+                .create();
+
+        let error = client.whoami().await.unwrap_err();
+        refresh.assert();
+
+        assert_eq!(
+            RequestErrorKind::from(&error),
+            RequestErrorKind::UnknownToken { soft_logout: true }
+        );
+        assert_eq!(client.session().await.unwrap().access_token, "1234");
+    }
+
+    #[tokio::test]
+    async fn login_and_refresh_access_token() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new().request_config(RequestConfig::new().disable_retry());
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        let login = mock("POST", "/_matrix/client/r0/login")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "user_id": "@example:localhost",
+                    "access_token": "1234",
+                    "device_id": "DEVICEID",
+                    "refresh_token": "refresh_1234",
+                    "expires_in_ms": 60000,
+                })
+                .to_string(),
+            )
+            .match_body(Matcher::PartialJson(json!({
+                "refresh_token": true,
+                "org.matrix.msc2918.refresh_token": true,
+            })))
+            .create();
+
+        client.login("example", "wordpass", None, None).await.unwrap();
+        login.assert();
+
+        let session = client.session().await.unwrap();
+        assert_eq!(session.access_token, "1234");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh_1234"));
+        assert!(session.expires_at.is_some());
+        assert!(!session.is_expired());
+
+        let refresh =
+            mock("POST", "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh")
+                .with_status(200)
+                .with_body(
+                    json!({
+                        "access_token": "5678",
+                        "refresh_token": "refresh_5678",
+                        "expires_in_ms": 60000,
+                    })
+                    .to_string(),
+                )
+                .match_body(Matcher::Json(json!({ "refresh_token": "refresh_1234" })))
+                .create();
+
+        assert!(client.refresh_access_token().await.unwrap());
+        refresh.assert();
+
+        let session = client.session().await.unwrap();
+        assert_eq!(session.access_token, "5678");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh_5678"));
+    }
+
+    #[tokio::test]
+    async fn sync_stops_on_unknown_token() {
+        let client = logged_in_client().await;
+        assert_eq!(client.sync_state(), SyncState::Idle);
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Invalid macaroon passed.",
+                    "soft_logout": true,
+                })
+                .to_string(),
+            )
+            .create();
+
+        client.sync(SyncSettings::default()).await;
+
+        assert_eq!(
+            client.sync_state(),
+            SyncState::Stopped(SyncStopReason::Error(RequestErrorKind::UnknownToken {
+                soft_logout: true
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_state_changes() {
+        let client = logged_in_client().await;
+        let mut changes = client.sync_state_changes();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        client.sync_with_callback(SyncSettings::default(), |_| async { LoopCtrl::Break }).await;
+
+        assert_eq!(changes.next().await, Some(SyncState::Idle));
+        assert_eq!(changes.next().await, Some(SyncState::Running));
+        assert_eq!(changes.next().await, Some(SyncState::Stopped(SyncStopReason::Requested)));
+    }
+
+    #[tokio::test]
+    async fn sync_stream_state_changes() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let mut sync_stream = Box::pin(client.sync_stream(SyncSettings::default()).await);
+        assert_eq!(client.sync_state(), SyncState::Idle);
+
+        sync_stream.next().await.unwrap().unwrap();
+        assert_eq!(client.sync_state(), SyncState::Running);
+
+        drop(sync_stream);
+        assert_eq!(client.sync_state(), SyncState::Stopped(SyncStopReason::Requested));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn restore_backup_rejects_a_mismatched_key() {
+        use matrix_sdk_base::crypto::backups::RecoveryKey;
+
+        let client = logged_in_client().await;
+        let other_key = RecoveryKey::new().unwrap().megolm_v1_public_key();
+
+        let _info =
+            mock("GET", Matcher::Regex(r"^/_matrix/client/.*/room_keys/version/1$".to_owned()))
+                .with_status(200)
+                .with_body(
+                    json!({
+                        "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                        "auth_data": {
+                            "public_key": other_key.to_base64(),
+                            "signatures": {},
+                        },
+                        "count": 0,
+                        "etag": "0",
+                        "version": "1",
+                    })
+                    .to_string(),
+                )
+                .create();
+
+        let keys = mock("GET", Matcher::Regex(r"^/_matrix/client/.*/room_keys/keys.*$".to_owned()))
+            .expect(0)
+            .create();
+
+        let result = client.restore_backup(RecoveryKey::new().unwrap(), "1").await;
+
+        assert!(matches!(result, Err(crate::Error::BackupKeyMismatch)));
+        assert!(!client.base_client.olm_machine().await.unwrap().backup_machine().enabled().await);
+        keys.assert();
+    }
 }
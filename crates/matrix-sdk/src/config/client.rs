@@ -19,7 +19,7 @@ use std::{
 };
 
 use http::{header::InvalidHeaderValue, HeaderValue};
-use matrix_sdk_base::BaseClientConfig;
+use matrix_sdk_base::{BaseClientConfig, StateStore};
 
 use crate::{config::RequestConfig, HttpSend, Result};
 
@@ -108,13 +108,15 @@ impl ClientConfig {
         Ok(self)
     }
 
-    ///// Set a custom implementation of a `StateStore`.
-    /////
-    ///// The state store should be opened before being set.
-    //pub fn state_store(mut self, store: Box<dyn StateStore>) -> Self {
-    //    self.base_config = self.base_config.state_store(store);
-    //    self
-    //}
+    /// Set a custom implementation of a `StateStore`.
+    ///
+    /// The state store should be opened before being set. A custom state store
+    /// takes precedence over the default state stores that would otherwise be
+    /// opened in the configured store path.
+    pub fn state_store(mut self, store: Box<dyn StateStore>) -> Self {
+        self.base_config = self.base_config.state_store(store);
+        self
+    }
 
     /// Set the path for storage.
     ///
@@ -132,6 +134,24 @@ impl ClientConfig {
         self
     }
 
+    /// Use the SQLite based state store and save it in the given path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory the SQLite database should be saved in, the
+    /// directory will be created if it doesn't exist.
+    ///
+    /// This takes precedence over the default sled based state store. If a
+    /// passphrase is set it will be used to encrypt the state store. If the
+    /// `sqlite_cryptostore` feature is enabled and no custom crypto store is
+    /// set, a SQLite based crypto store will be opened in the same directory.
+    #[cfg(feature = "sqlite_state_store")]
+    #[cfg_attr(feature = "docs", doc(cfg(sqlite_state_store)))]
+    pub fn sqlite_store_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.base_config = self.base_config.sqlite_store_path(path);
+        self
+    }
+
     /// Set the passphrase to encrypt the crypto store.
     ///
     /// # Argument
@@ -0,0 +1,231 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dehydrated devices, as defined in [MSC2697].
+//!
+//! A dehydrated device receives room keys while all of our devices are
+//! offline, the room keys can be imported once we log in again.
+//!
+//! [MSC2697]: https://github.com/matrix-org/matrix-doc/pull/2697
+
+use matrix_sdk_base::crypto::dehydrated_devices::DehydratedDeviceRequest;
+use ruma::DeviceIdBox;
+use tracing::{debug, info};
+
+use crate::{Client, Error, Result};
+
+/// The endpoints of the unstable dehydrated device API.
+mod api {
+    pub mod create_dehydrated_device {
+        use std::collections::BTreeMap;
+
+        use matrix_sdk_base::crypto::dehydrated_devices::DehydratedDeviceData;
+        use ruma::{
+            api::ruma_api,
+            encryption::{DeviceKeys, OneTimeKey},
+            DeviceIdBox, DeviceKeyId,
+        };
+
+        ruma_api! {
+            metadata: {
+                description: "Upload a new dehydrated device.",
+                method: PUT,
+                name: "create_dehydrated_device",
+                path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device",
+                rate_limited: false,
+                authentication: AccessToken,
+            }
+
+            request: {
+                pub device_id: DeviceIdBox,
+                pub initial_device_display_name: String,
+                pub device_data: DehydratedDeviceData,
+                pub device_keys: DeviceKeys,
+                pub one_time_keys: BTreeMap<DeviceKeyId, OneTimeKey>,
+            }
+
+            response: {
+                pub device_id: DeviceIdBox,
+            }
+
+            error: ruma::api::client::Error
+        }
+    }
+
+    pub mod get_dehydrated_device {
+        use matrix_sdk_base::crypto::dehydrated_devices::DehydratedDeviceData;
+        use ruma::{api::ruma_api, DeviceIdBox};
+
+        ruma_api! {
+            metadata: {
+                description: "Get the dehydrated device of the user.",
+                method: GET,
+                name: "get_dehydrated_device",
+                path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device",
+                rate_limited: false,
+                authentication: AccessToken,
+            }
+
+            request: {}
+
+            response: {
+                pub device_id: DeviceIdBox,
+                pub device_data: DehydratedDeviceData,
+            }
+
+            error: ruma::api::client::Error
+        }
+    }
+
+    pub mod get_dehydrated_device_events {
+        use ruma::{api::ruma_api, events::AnyToDeviceEvent, serde::Raw, DeviceIdBox};
+
+        ruma_api! {
+            metadata: {
+                description: "Get the to-device events that were sent to a dehydrated device.",
+                method: POST,
+                name: "get_dehydrated_device_events",
+                path: "/_matrix/client/unstable/org.matrix.msc2697.v2/dehydrated_device/:device_id/events",
+                rate_limited: false,
+                authentication: AccessToken,
+            }
+
+            request: {
+                #[ruma_api(path)]
+                pub device_id: DeviceIdBox,
+
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub next_batch: Option<String>,
+            }
+
+            response: {
+                pub events: Vec<Raw<AnyToDeviceEvent>>,
+
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub next_batch: Option<String>,
+            }
+
+            error: ruma::api::client::Error
+        }
+    }
+}
+
+impl Client {
+    /// Create a new dehydrated device and upload it to the server.
+    ///
+    /// The dehydrated device will receive room keys while all of our devices
+    /// are offline, the room keys can be imported on the next login using the
+    /// [`Client::rehydrate_device()`] method. Uploading a new dehydrated
+    /// device replaces the existing one.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that will be used to encrypt the dehydrated
+    /// device, the same key needs to be used to rehydrate the device.
+    ///
+    /// * `display_name` - The display name the dehydrated device should have.
+    ///
+    /// Returns the device ID of the dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn create_dehydrated_device(
+        &self,
+        pickle_key: &[u8],
+        display_name: &str,
+    ) -> Result<DeviceIdBox> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let device = olm.dehydrated_devices().create();
+        let DehydratedDeviceRequest {
+            device_id,
+            initial_device_display_name,
+            device_data,
+            device_keys,
+            one_time_keys,
+        } = device.keys_for_upload(display_name.to_owned(), pickle_key).await;
+
+        let request = api::create_dehydrated_device::Request {
+            device_id,
+            initial_device_display_name,
+            device_data,
+            device_keys,
+            one_time_keys,
+        };
+
+        let response = self.send(request, None).await?;
+
+        info!(device_id = response.device_id.as_str(), "Uploaded a new dehydrated device");
+
+        Ok(response.device_id)
+    }
+
+    /// Rehydrate our dehydrated device and import the room keys it received
+    /// while all of our devices were offline.
+    ///
+    /// This should be called after logging in, a new dehydrated device should
+    /// be created afterwards using the [`Client::create_dehydrated_device()`]
+    /// method.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_key` - The key that was used to encrypt the dehydrated device.
+    ///
+    /// Returns the number of room keys that were imported.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn rehydrate_device(&self, pickle_key: &[u8]) -> Result<usize> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let response = self.send(api::get_dehydrated_device::Request {}, None).await?;
+        let device_id = response.device_id;
+
+        let rehydrated = olm
+            .dehydrated_devices()
+            .rehydrate(pickle_key, &device_id, response.device_data)
+            .await?;
+
+        let mut next_batch = None;
+        let mut imported = 0;
+
+        loop {
+            let request = api::get_dehydrated_device_events::Request {
+                device_id: device_id.clone(),
+                next_batch: next_batch.take(),
+            };
+            let response = self.send(request, None).await?;
+
+            debug!(
+                device_id = device_id.as_str(),
+                count = response.events.len(),
+                "Received to-device events for the dehydrated device"
+            );
+
+            if response.events.is_empty() {
+                break;
+            }
+
+            imported += rehydrated.receive_events(response.events).await?;
+
+            if response.next_batch.is_none() {
+                break;
+            }
+
+            next_batch = response.next_batch;
+        }
+
+        info!(device_id = device_id.as_str(), imported, "Rehydrated our dehydrated device");
+
+        Ok(imported)
+    }
+}
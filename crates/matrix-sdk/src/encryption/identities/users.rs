@@ -16,7 +16,8 @@ use std::{result::Result, sync::Arc};
 
 use matrix_sdk_base::{
     crypto::{
-        MasterPubkey, OwnUserIdentity as InnerOwnUserIdentity, UserIdentity as InnerUserIdentity,
+        store::CryptoStoreError, MasterPubkey, OwnUserIdentity as InnerOwnUserIdentity,
+        UserIdentity as InnerUserIdentity,
     },
     locks::RwLock,
 };
@@ -259,6 +260,39 @@ impl UserIdentity {
         }
     }
 
+    /// Has the master key of this user identity changed since we first saw
+    /// it.
+    ///
+    /// The first master key we see for a user is pinned, a different master
+    /// key means that the user reset their cross signing keys or that someone
+    /// is trying to impersonate the user. Room keys won't be shared with the
+    /// devices of the user until the change is acknowledged using the
+    /// [`acknowledge_identity_change()`] method or the new identity is
+    /// verified.
+    ///
+    /// This is always false for our own user identity.
+    ///
+    /// [`acknowledge_identity_change()`]: #method.acknowledge_identity_change
+    pub fn has_changed(&self) -> bool {
+        match &self.inner {
+            UserIdentities::Own(_) => false,
+            UserIdentities::Other(i) => i.inner.has_changed(),
+        }
+    }
+
+    /// Acknowledge that the master key of this user identity has changed.
+    ///
+    /// This pins the current master key of the identity, room keys will be
+    /// shared with the devices of the user again.
+    ///
+    /// This doesn't do anything for our own user identity.
+    pub async fn acknowledge_identity_change(&self) -> Result<(), CryptoStoreError> {
+        match &self.inner {
+            UserIdentities::Own(_) => Ok(()),
+            UserIdentities::Other(i) => i.inner.acknowledge_identity_change().await,
+        }
+    }
+
     /// Get the public part of the master key of this user identity.
     ///
     /// # Examples
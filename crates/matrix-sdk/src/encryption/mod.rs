@@ -14,27 +14,38 @@
 
 //! End to end encryption related types
 
+mod dehydrated_devices;
 pub mod identities;
 pub mod verification;
 use std::{
     collections::{BTreeMap, HashSet},
-    io::Write,
+    io::BufWriter,
     path::PathBuf,
     result::Result as StdResult,
 };
 
-use futures::StreamExt;
-pub use matrix_sdk_base::crypto::{EncryptionInfo, LocalTrust};
+use futures::{Stream, StreamExt};
+pub use matrix_sdk_base::crypto::{
+    EncryptionInfo, KeyExportFilter, LocalTrust, SharingPolicy, WithheldCode,
+};
 use matrix_sdk_base::{
     crypto::{
-        store::CryptoStoreError, CrossSigningStatus, OutgoingRequest, RoomMessageRequest,
-        ToDeviceRequest,
+        backups::RecoveryKey,
+        secret_storage::{
+            DefaultKeyContent, SecretStorageError, SecretStorageKey, SecretStorageKeyDescription,
+            DEFAULT_KEY_EVENT_TYPE,
+        },
+        store::CryptoStoreError,
+        CrossSigningKeyExport, CrossSigningStatus, KeyExportError, KeyExportReader,
+        KeyExportWriter, OutgoingRequest, RoomMessageRequest, ToDeviceRequest,
     },
     deserialized_responses::RoomEvent,
 };
 use matrix_sdk_common::{instant::Duration, uuid::Uuid};
 use ruma::{
     api::client::r0::{
+        backup::{add_backup_keys, create_backup, get_backup, get_backup_keys, BackupAlgorithm},
+        config::set_global_account_data,
         keys::{get_keys, upload_keys, upload_signing_keys::Request as UploadSigningKeysRequest},
         message::send_message_event,
         to_device::send_event_to_device::{
@@ -43,15 +54,18 @@ use ruma::{
         uiaa::AuthData,
     },
     assign,
-    events::{AnyMessageEvent, AnyRoomEvent, AnySyncMessageEvent, EventType},
+    events::{
+        secret::request::SecretName, AnyMessageEvent, AnyRoomEvent, AnySyncMessageEvent, EventType,
+    },
     serde::Raw,
-    DeviceId, DeviceIdBox, UserId,
+    DeviceId, DeviceIdBox, RoomId, UserId,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::{debug, instrument, trace, warn};
 
 use crate::{
     encryption::{
-        identities::{Device, UserDevices},
+        identities::{Device, IdentityChange, UserDevices},
         verification::{SasVerification, Verification, VerificationRequest},
     },
     error::{HttpError, HttpResult, RoomKeyImportError},
@@ -91,6 +105,68 @@ impl Client {
         self.base_client.olm_machine().await.map(|o| o.tracked_users()).unwrap_or_default()
     }
 
+    /// Set the global sharing policy, deciding which devices receive the room
+    /// keys of our encrypted rooms.
+    ///
+    /// The global policy is used for all rooms that don't have a policy of
+    /// their own, see [`Client::set_room_sharing_policy()`]. The policy is
+    /// saved in the crypto store.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, encryption::SharingPolicy};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// client.set_sharing_policy(SharingPolicy::CrossSignedOnly).await.unwrap();
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn set_sharing_policy(&self, policy: SharingPolicy) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.set_sharing_policy(policy).await?)
+    }
+
+    /// Set the sharing policy of a single room, overriding the global one.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the policy should be used for.
+    ///
+    /// * `policy` - The sharing policy of the room, `None` if the global
+    /// policy should be used again.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn set_room_sharing_policy(
+        &self,
+        room_id: &RoomId,
+        policy: Option<SharingPolicy>,
+    ) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.set_room_sharing_policy(room_id, policy).await?)
+    }
+
+    /// Get the devices that didn't receive the current room key of the given
+    /// room, together with the reason why the room key was withheld from
+    /// them.
+    ///
+    /// Devices can be excluded because they are blacklisted, because the
+    /// sharing policy of the room doesn't allow sharing with them or because
+    /// we couldn't establish an encrypted channel with them.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn get_withheld_devices(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<UserId, BTreeMap<DeviceIdBox, WithheldCode>>> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.get_withheld_devices(room_id).await?)
+    }
+
     /// Get a verification object with the given flow id.
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
@@ -261,6 +337,102 @@ impl Client {
         }
     }
 
+    /// Get a stream of identity changes of other users.
+    ///
+    /// The first master key we see for a user is pinned, an item is yielded
+    /// every time the master key of a user changes to a different one. Room
+    /// keys won't be shared with the devices of such a user until the change
+    /// is acknowledged using [`UserIdentity::acknowledge_identity_change()`]
+    /// or the new identity is verified.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let mut changes = client.identity_changes().await?;
+    ///
+    /// while let Some(change) = changes.next().await {
+    ///     println!("The identity of {} has changed", change.user_id);
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    ///
+    /// [`UserIdentity::acknowledge_identity_change()`]: crate::encryption::identities::UserIdentity::acknowledge_identity_change
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn identity_changes(&self) -> Result<impl Stream<Item = IdentityChange>> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        Ok(olm.identity_changes())
+    }
+
+    /// Send a custom to-device event to the given devices, encrypted using
+    /// Olm.
+    ///
+    /// One-time keys are claimed for devices that we don't yet have an Olm
+    /// session with. Devices for which no Olm session could be established
+    /// are skipped.
+    ///
+    /// On the receiving side the event is decrypted and passed to the event
+    /// handlers registered for its type, see
+    /// [`Client::register_event_handler()`].
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The devices that should receive the event.
+    ///
+    /// * `event_type` - The type of the event, e.g. `org.example.call`.
+    ///
+    /// * `content` - The content of the event.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::{Client, ruma::UserId};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use serde_json::json;
+    /// # let alice = UserId::try_from("@alice:example.org").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// // Send the event to all the verified devices of Alice.
+    /// let devices: Vec<_> =
+    ///     client.get_user_devices(&alice).await?.devices().filter(|d| d.verified()).collect();
+    ///
+    /// client
+    ///     .send_encrypted_to_device(&devices, "org.example.call", json!({ "call_id": "1234" }))
+    ///     .await?;
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn send_encrypted_to_device(
+        &self,
+        devices: &[Device],
+        event_type: &str,
+        content: impl Serialize,
+    ) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let content = serde_json::to_value(content)?;
+
+        let users: HashSet<&UserId> = devices.iter().map(|d| d.user_id()).collect();
+        self.claim_one_time_keys(users.into_iter()).await?;
+
+        let devices: Vec<_> = devices.iter().map(|d| d.inner.clone()).collect();
+
+        if let Some(request) = olm.encrypt_to_device_event(&devices, event_type, content).await? {
+            self.send_to_device(&request).await?;
+        }
+
+        Ok(())
+    }
+
     /// Create and upload a new cross signing identity.
     ///
     /// # Arguments
@@ -369,7 +541,7 @@ impl Client {
     /// let room_id = room_id!("!test:localhost");
     ///
     /// client
-    ///     .export_keys(path, "secret-passphrase", |s| s.room_id() == &room_id)
+    ///     .export_keys(path, "secret-passphrase", move |s| s.room_id() == &room_id)
     ///     .await
     ///     .expect("Can't export keys.");
     /// # });
@@ -380,23 +552,106 @@ impl Client {
         &self,
         path: PathBuf,
         passphrase: &str,
-        predicate: impl FnMut(&matrix_sdk_base::crypto::olm::InboundGroupSession) -> bool,
+        predicate: impl FnMut(&matrix_sdk_base::crypto::olm::InboundGroupSession) -> bool
+            + Send
+            + 'static,
     ) -> Result<()> {
         let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let handle = tokio::runtime::Handle::current();
 
-        let keys = olm.export_keys(predicate).await?;
+        Self::write_key_export(path, passphrase, move |writer| {
+            handle.block_on(olm.export_keys_stream(predicate, writer, |_, _| {}))
+        })
+        .await
+    }
+
+    /// Export E2EE keys that match the given filter encrypting them with the
+    /// given passphrase.
+    ///
+    /// This works like [`Client::export_keys()`], but if the filter is
+    /// restricted to some rooms only the room keys of those rooms will be
+    /// loaded from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file path where the exported key file will be saved.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// exported room keys.
+    ///
+    /// * `filter` - The filter deciding which room keys should be exported.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it isn't run on a Tokio runtime.
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::path::PathBuf;
+    /// # use matrix_sdk::{Client, encryption::KeyExportFilter, ruma::room_id};
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let path = PathBuf::from("/home/example/e2e-room-keys.txt");
+    /// let filter = KeyExportFilter::new().room(room_id!("!test:localhost"));
+    ///
+    /// client
+    ///     .export_keys_with_filter(path, "secret-passphrase", &filter)
+    ///     .await
+    ///     .expect("Can't export keys.");
+    /// # });
+    /// ```
+    #[cfg(all(feature = "encryption", not(target_arch = "wasm32")))]
+    #[cfg_attr(feature = "docs", doc(cfg(all(encryption, not(target_arch = "wasm32")))))]
+    pub async fn export_keys_with_filter(
+        &self,
+        path: PathBuf,
+        passphrase: &str,
+        filter: &KeyExportFilter,
+    ) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let handle = tokio::runtime::Handle::current();
+        let filter = filter.clone();
+
+        Self::write_key_export(path, passphrase, move |writer| {
+            handle.block_on(olm.export_keys_stream_with_filter(&filter, writer, |_, _| {}))
+        })
+        .await
+    }
+
+    /// Create a key export at the given path and let `export` write the room
+    /// keys into it.
+    #[cfg(all(feature = "encryption", not(target_arch = "wasm32")))]
+    async fn write_key_export(
+        path: PathBuf,
+        passphrase: &str,
+        export: impl FnOnce(
+                &mut KeyExportWriter<BufWriter<std::fs::File>>,
+            ) -> StdResult<usize, KeyExportError>
+            + Send
+            + 'static,
+    ) -> Result<()> {
         let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
 
-        let encrypt = move || -> Result<()> {
-            let export: String =
-                matrix_sdk_base::crypto::encrypt_key_export(&keys, &passphrase, 500_000)?;
-            let mut file = std::fs::File::create(path)?;
-            file.write_all(&export.into_bytes())?;
+        // Deriving the encryption key from the passphrase, encrypting the room
+        // keys and writing them out all block, do it on a thread where
+        // blocking is fine.
+        let write = move || -> Result<()> {
+            let file = BufWriter::new(std::fs::File::create(path)?);
+            let mut writer = KeyExportWriter::new(file, &passphrase, 500_000)?;
+            export(&mut writer)?;
+            writer.finish()?;
+
             Ok(())
         };
 
-        let task = tokio::task::spawn_blocking(encrypt);
-        task.await.expect("Task join error")
+        tokio::task::spawn_blocking(write).await.expect("Task join error")
     }
 
     /// Import E2EE keys from the given file path.
@@ -412,6 +667,12 @@ impl Client {
     /// were imported and the total number of sessions that were found in the
     /// key export.
     ///
+    /// Events that we previously failed to decrypt and that can be decrypted
+    /// using the imported room keys are passed to the registered event
+    /// handlers, see [`LateDecryption`].
+    ///
+    /// [`LateDecryption`]: crate::event_handler::LateDecryption
+    ///
     /// # Panics
     ///
     /// This method will panic if it isn't run on a Tokio runtime.
@@ -443,16 +704,387 @@ impl Client {
     ) -> StdResult<(usize, usize), RoomKeyImportError> {
         let olm = self.base_client.olm_machine().await.ok_or(RoomKeyImportError::StoreClosed)?;
         let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+        let handle = tokio::runtime::Handle::current();
 
-        let decrypt = move || {
+        // The reader checks the MAC of the whole key export before returning
+        // any room key and decrypts the room keys while reading the file, do
+        // this on a thread where blocking is fine.
+        let import = move || -> StdResult<_, KeyExportError> {
             let file = std::fs::File::open(path)?;
-            matrix_sdk_base::crypto::decrypt_key_export(file, &passphrase)
+            let reader = KeyExportReader::new(file, &passphrase)?;
+
+            handle.block_on(olm.import_keys_stream(reader, |_| {}))
+        };
+
+        let task = tokio::task::spawn_blocking(import);
+        let result = task.await.expect("Task join error")?;
+
+        // The imported room keys might let us decrypt events that we failed to
+        // decrypt before, pass them to the event handlers if so.
+        match self.base_client.retry_decryption().await {
+            Ok(events) => {
+                if let Err(e) = self.handle_late_decrypted_events(&events).await {
+                    warn!(error =? e, "Error while handling late decrypted events");
+                }
+            }
+            Err(e) => warn!(error =? e, "Error while retrying to decrypt undecryptable events"),
+        }
+
+        Ok(result)
+    }
+
+    /// Create a new server-side backup for our room keys and start backing up
+    /// room keys to it.
+    ///
+    /// The room keys will be uploaded to the server as part of the normal sync
+    /// loop. The returned recovery key is the only way to decrypt the backed up
+    /// room keys, it should be stored somewhere safe, e.g. shown to the user
+    /// as a base58 encoded string.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create a new recovery key.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let recovery_key = client.create_backup().await.expect("Can't create a backup");
+    ///
+    /// println!("Your recovery key is: {}", recovery_key.to_base58());
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn create_backup(&self) -> Result<RecoveryKey> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let recovery_key = RecoveryKey::new().expect("Can't generate a new recovery key");
+        let backup_key = recovery_key.megolm_v1_public_key();
+
+        let backup_machine = olm.backup_machine();
+
+        let algorithm = Raw::new(&backup_machine.backup_algorithm(&backup_key).await)?;
+        let response = self.send(create_backup::Request::new(algorithm), None).await?;
+
+        backup_key.set_version(response.version.clone());
+
+        // Enable the backup first, this resets the backup state of our room
+        // keys if they were backed up to a previous backup version.
+        backup_machine.enable_backup(backup_key).await?;
+        backup_machine.save_recovery_key(Some(recovery_key.clone()), None).await?;
+
+        Ok(recovery_key)
+    }
+
+    /// Download and import the room keys of a server-side backup.
+    ///
+    /// After the room keys are imported the backup will be activated, new room
+    /// keys will be uploaded to the same backup version.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key that was used to create the backup.
+    ///
+    /// * `version` - The version of the backup that should be restored.
+    ///
+    /// Returns a tuple of numbers that represent the number of sessions that
+    /// were imported and the total number of sessions that were found in the
+    /// backup.
+    ///
+    /// Returns [`Error::BackupKeyMismatch`] if the recovery key doesn't belong
+    /// to the backup version.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn restore_backup(
+        &self,
+        recovery_key: RecoveryKey,
+        version: &str,
+    ) -> Result<(usize, usize)> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let backup_key = recovery_key.megolm_v1_public_key();
+
+        // Don't enable a backup that we can't decrypt or import room keys that
+        // were encrypted for a different key.
+        let info = self.send(get_backup::Request::new(version), None).await?;
+
+        match info.algorithm.deserialize()? {
+            BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, .. }
+                if public_key == backup_key.to_base64() => {}
+            _ => return Err(Error::BackupKeyMismatch),
+        }
+
+        let response = self.send(get_backup_keys::Request::new(version), None).await?;
+        backup_key.set_version(version.to_owned());
+
+        // The backup needs to be enabled before the import, otherwise the
+        // imported room keys would lose their backed up marker.
+        let backup_machine = olm.backup_machine();
+        backup_machine.enable_backup(backup_key).await?;
+
+        let result = backup_machine
+            .import_backed_up_room_keys(&recovery_key, response.rooms, |_, _| {})
+            .await?;
+        backup_machine.save_recovery_key(Some(recovery_key), None).await?;
+
+        Ok(result)
+    }
+
+    /// Create a new secret storage key and store our private cross signing keys
+    /// and the backup recovery key, if we have them, in the secret storage.
+    ///
+    /// The secret storage key will be set as the default key of the secret
+    /// storage. If no passphrase is given a random key will be created, the
+    /// key needs to be presented to the user as a recovery key using the
+    /// [`SecretStorageKey::to_base58()`] method, otherwise the secrets can't
+    /// be restored on a new login.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase the secret storage key should be
+    /// derived from.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// create a new secret storage key. Deriving the key from a passphrase is
+    /// done on a thread where blocking is fine, outside of WASM this method
+    /// will panic if it isn't run on a Tokio runtime.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let key = client.create_secret_storage(None).await.expect("Can't create the secret storage");
+    ///
+    /// println!("Your recovery key is: {}", key.to_base58());
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn create_secret_storage(
+        &self,
+        passphrase: Option<&str>,
+    ) -> Result<SecretStorageKey> {
+        let key = if let Some(passphrase) = passphrase {
+            let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+            Self::run_blocking(move || SecretStorageKey::new_from_passphrase(&passphrase)).await
+        } else {
+            SecretStorageKey::new()
         };
 
-        let task = tokio::task::spawn_blocking(decrypt);
-        let import = task.await.expect("Task join error")?;
+        self.set_raw_account_data(&key.event_type(), &key.description()).await?;
+        self.set_raw_account_data(
+            DEFAULT_KEY_EVENT_TYPE,
+            &DefaultKeyContent { key: key.key_id().to_owned() },
+        )
+        .await?;
+
+        self.export_secrets_to_secret_storage(&key).await?;
+
+        Ok(key)
+    }
 
-        Ok(olm.import_keys(import, |_, _| {}).await?)
+    /// Run the given blocking closure, e.g. a key derivation, on a thread where
+    /// blocking is fine.
+    #[cfg(feature = "encryption")]
+    async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            tokio::task::spawn_blocking(f).await.expect("Task join error")
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            f()
+        }
+    }
+
+    /// Encrypt our private cross signing keys and the backup recovery key, if
+    /// we have them, with the given secret storage key and upload them to the
+    /// global account data.
+    ///
+    /// This should be used to update the secret storage after new secrets have
+    /// been created, e.g. after cross signing has been bootstrapped.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn export_secrets_to_secret_storage(&self, key: &SecretStorageKey) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let mut secrets = Vec::new();
+
+        if let Some(export) = olm.export_cross_signing_keys().await {
+            let keys = [
+                (SecretName::CrossSigningMasterKey, &export.master_key),
+                (SecretName::CrossSigningSelfSigningKey, &export.self_signing_key),
+                (SecretName::CrossSigningUserSigningKey, &export.user_signing_key),
+            ];
+
+            for (name, secret) in keys.iter() {
+                if let Some(secret) = secret {
+                    secrets.push((name.clone(), key.encrypt(name, secret)));
+                }
+            }
+        }
+
+        if let Some(recovery_key) = olm.backup_machine().get_backup_keys().await?.recovery_key {
+            let secret = zeroize::Zeroizing::new(recovery_key.to_base64());
+            secrets.push((SecretName::RecoveryKey, key.encrypt(&SecretName::RecoveryKey, &secret)));
+        }
+
+        for (name, content) in secrets {
+            debug!(secret_name = name.as_ref(), "Uploading a secret to the secret storage");
+            self.set_raw_account_data(name.as_ref(), &content).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Restore our private cross signing keys and the backup recovery key from
+    /// the secret storage.
+    ///
+    /// This allows a new login to recover our identity without another
+    /// verified device being online. The secrets are read from the global
+    /// account data that was received in a sync, so this needs to be called
+    /// after the first sync of a new login.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase_or_recovery_key` - The passphrase or base58 encoded
+    /// recovery key of the default secret storage key.
+    ///
+    /// Secrets that aren't encrypted with the default secret storage key are
+    /// skipped. Returns the status of our private cross signing keys after the
+    /// import.
+    ///
+    /// # Panics
+    ///
+    /// Deriving the key from a passphrase is done on a thread where blocking
+    /// is fine, outside of WASM this method will panic if it isn't run on a
+    /// Tokio runtime.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::{Client, config::SyncSettings};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// client.sync_once(SyncSettings::default()).await.unwrap();
+    ///
+    /// let status = client
+    ///     .restore_from_secret_storage("It's a secret to everybody")
+    ///     .await
+    ///     .expect("Can't restore our secrets");
+    ///
+    /// assert!(status.has_master);
+    /// # });
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn restore_from_secret_storage(
+        &self,
+        passphrase_or_recovery_key: &str,
+    ) -> Result<CrossSigningStatus> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+
+        let default_key: DefaultKeyContent = self
+            .get_raw_account_data(DEFAULT_KEY_EVENT_TYPE)
+            .await?
+            .ok_or(SecretStorageError::MissingDefaultKey)?;
+        let description: SecretStorageKeyDescription = self
+            .get_raw_account_data(&format!("m.secret_storage.key.{}", default_key.key))
+            .await?
+            .ok_or(SecretStorageError::MissingDefaultKey)?;
+
+        let key = match SecretStorageKey::from_recovery_key(
+            &default_key.key,
+            &description,
+            passphrase_or_recovery_key,
+        ) {
+            Ok(key) => key,
+            Err(SecretStorageError::RecoveryKey(_)) => {
+                let passphrase = zeroize::Zeroizing::new(passphrase_or_recovery_key.to_owned());
+
+                Self::run_blocking(move || {
+                    SecretStorageKey::from_passphrase(&default_key.key, &description, &passphrase)
+                })
+                .await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut secrets = BTreeMap::new();
+
+        for name in &[
+            SecretName::CrossSigningMasterKey,
+            SecretName::CrossSigningSelfSigningKey,
+            SecretName::CrossSigningUserSigningKey,
+            SecretName::RecoveryKey,
+        ] {
+            if let Some(content) = self.get_raw_account_data(name.as_ref()).await? {
+                let secret = match key.decrypt(name, &content) {
+                    Ok(secret) => zeroize::Zeroizing::new(secret),
+                    // Other clients might have stored the secret using a
+                    // different key, the remaining secrets can still be
+                    // restored.
+                    Err(SecretStorageError::MissingSecret(_)) => {
+                        warn!(
+                            secret_name = name.as_ref(),
+                            key_id = key.key_id(),
+                            "The secret isn't encrypted with the default secret storage key"
+                        );
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                secrets.insert(name.as_ref().to_owned(), secret);
+            }
+        }
+
+        if let Some(recovery_key) = secrets.remove(SecretName::RecoveryKey.as_ref()) {
+            let recovery_key =
+                RecoveryKey::from_base64(&recovery_key).map_err(SecretStorageError::from)?;
+            olm.backup_machine().save_recovery_key(Some(recovery_key), None).await?;
+        }
+
+        // Importing the private cross signing keys requires our public identity
+        // to be known.
+        let own_user = olm.user_id().to_owned();
+
+        if olm.get_identity(&own_user).await?.is_none() {
+            let device_keys = std::iter::once((own_user, Vec::new())).collect();
+            self.keys_query(&Uuid::new_v4(), device_keys).await?;
+        }
+
+        let export = CrossSigningKeyExport {
+            master_key: secrets
+                .remove(SecretName::CrossSigningMasterKey.as_ref())
+                .map(|s| s.to_string()),
+            self_signing_key: secrets
+                .remove(SecretName::CrossSigningSelfSigningKey.as_ref())
+                .map(|s| s.to_string()),
+            user_signing_key: secrets
+                .remove(SecretName::CrossSigningUserSigningKey.as_ref())
+                .map(|s| s.to_string()),
+        };
+
+        Ok(olm.import_cross_signing_keys(export).await?)
     }
 
     /// Tries to decrypt a `AnyRoomEvent`. Returns unencrypted room event when
@@ -533,6 +1165,42 @@ impl Client {
         Ok(self.send(request, None).await?)
     }
 
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn set_raw_account_data(
+        &self,
+        event_type: &str,
+        content: &impl Serialize,
+    ) -> Result<set_global_account_data::Response> {
+        let own_user =
+            self.user_id().await.ok_or_else(|| Error::from(HttpError::AuthenticationRequired))?;
+        let data = serde_json::value::to_raw_value(content)?;
+
+        let request = set_global_account_data::Request::new(&data, event_type, &own_user);
+
+        Ok(self.send(request, None).await?)
+    }
+
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    async fn get_raw_account_data<T: DeserializeOwned>(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<T>> {
+        #[derive(Deserialize)]
+        struct AccountDataEvent<C> {
+            content: C,
+        }
+
+        Ok(self
+            .store()
+            .get_account_data_event(event_type.into())
+            .await?
+            .map(|e| e.deserialize_as::<AccountDataEvent<T>>())
+            .transpose()?
+            .map(|e| e.content))
+    }
+
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
     pub(crate) async fn create_dm_room(&self, user_id: UserId) -> Result<Option<room::Joined>> {
@@ -732,6 +1400,12 @@ impl Client {
                 let response = self.send(request.clone(), None).await?;
                 self.base_client.mark_request_as_sent(r.request_id(), &response).await?;
             }
+            OutgoingRequests::KeysBackup(request) => {
+                let request =
+                    add_backup_keys::Request::new(&request.version, request.rooms.clone());
+                let response = self.send(request, None).await?;
+                self.base_client.mark_request_as_sent(r.request_id(), &response).await?;
+            }
         }
 
         Ok(())
@@ -29,13 +29,60 @@
 //!   authentication
 //! string.
 //! * [`QrVerification`] - Interactive verification using QR codes.
+//!
+//! The progress of a verification request or flow can be followed using their
+//! `changes()` streams, these yield a [`VerificationState`] every time the
+//! request or flow transitions into a new state.
+//!
+//! # In-room verification
+//!
+//! Our own devices are verified using to-device messages, other users on the
+//! other hand are verified inside of a DM. Requesting a verification using
+//! [`UserIdentity::request_verification()`] sends the request as a message
+//! into the DM we share with the user, creating the DM if necessary.
+//!
+//! The events of such a flow are received as part of the room timeline, the
+//! flow ID of an in-room verification request is the event ID of the
+//! `m.key.verification.request` message:
+//!
+//! ```no_run
+//! # use matrix_sdk::{
+//! #     Client,
+//! #     ruma::events::{
+//! #         room::message::{MessageEventContent, MessageType},
+//! #         SyncMessageEvent,
+//! #     },
+//! # };
+//! # use url::Url;
+//! # use futures::executor::block_on;
+//! # let homeserver = Url::parse("http://example.com").unwrap();
+//! # let client = Client::new(homeserver).unwrap();
+//! # block_on(async {
+//! client
+//!     .register_event_handler(
+//!         |ev: SyncMessageEvent<MessageEventContent>, client: Client| async move {
+//!             if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
+//!                 let request = client
+//!                     .get_verification_request(&ev.sender, &ev.event_id)
+//!                     .await
+//!                     .expect("The in-room request should be known");
+//!
+//!                 request.accept().await.expect("Can't accept the verification request");
+//!             }
+//!         },
+//!     )
+//!     .await;
+//! # });
+//! ```
+//!
+//! [`UserIdentity::request_verification()`]: crate::encryption::identities::UserIdentity::request_verification
 
 #[cfg(feature = "qrcode")]
 mod qrcode;
 mod requests;
 mod sas;
 
-pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo};
+pub use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, VerificationState};
 #[cfg(feature = "qrcode")]
 #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
 pub use qrcode::QrVerification;
@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures::Stream;
 use matrix_sdk_base::crypto::{
     matrix_qrcode::{qrcode::QrCode, EncodingError},
-    CancelInfo, QrVerification as BaseQrVerification,
+    CancelInfo, QrVerification as BaseQrVerification, VerificationState,
 };
-use ruma::UserId;
+use ruma::{RoomId, UserId};
 
 use crate::{Client, Result};
 
@@ -60,11 +61,26 @@ impl QrVerification {
         self.inner.other_user_id()
     }
 
+    /// Get the unique ID that identifies this QR code verification flow.
+    pub fn flow_id(&self) -> &str {
+        self.inner.flow_id().as_str()
+    }
+
+    /// Get the room ID if the verification is happening inside a room.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
+
     /// Has the verification been cancelled.
     pub fn is_cancelled(&self) -> bool {
         self.inner.is_cancelled()
     }
 
+    /// Get a stream of state transitions of this QR code verification flow.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.inner.changes()
+    }
+
     /// Generate a QR code object that is representing this verification flow.
     ///
     /// The `QrCode` can then be rendered as an image or as an unicode string.
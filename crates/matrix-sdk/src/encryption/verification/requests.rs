@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::{CancelInfo, VerificationRequest as BaseVerificationRequest};
-use ruma::events::key::verification::VerificationMethod;
+use futures::Stream;
+use matrix_sdk_base::crypto::{
+    CancelInfo, VerificationRequest as BaseVerificationRequest, VerificationState,
+};
+use ruma::{events::key::verification::VerificationMethod, RoomId};
 
 #[cfg(feature = "qrcode")]
 use super::QrVerification;
@@ -43,12 +46,30 @@ impl VerificationRequest {
         self.inner.flow_id().as_str()
     }
 
+    /// Get the room ID if the verification is happening inside a room.
+    ///
+    /// In-room verification requests are used to verify other users, the flow
+    /// ID of such a request is the event ID of the `m.key.verification.request`
+    /// message.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
+
     /// Get info about the cancellation if the verification request has been
     /// cancelled.
     pub fn cancel_info(&self) -> Option<CancelInfo> {
         self.inner.cancel_info()
     }
 
+    /// Get a stream of state transitions of this verification request.
+    ///
+    /// Once the [`VerificationState::Started`] state is reached, the
+    /// verification flow can be retrieved using [`Client::get_verification()`]
+    /// and followed using its own `changes()` stream.
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.inner.changes()
+    }
+
     /// Get our own user id.
     pub fn own_user_id(&self) -> &ruma::UserId {
         self.inner.own_user_id()
@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_base::crypto::{AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas};
-use ruma::UserId;
+use futures::Stream;
+use matrix_sdk_base::crypto::{
+    AcceptSettings, CancelInfo, ReadOnlyDevice, Sas as BaseSas, VerificationState,
+};
+use ruma::{RoomId, UserId};
 
 use crate::{error::Result, Client};
 
@@ -103,6 +106,21 @@ impl SasVerification {
         self.inner.emoji()
     }
 
+    /// Get the emoji version of the short auth string with the descriptions of
+    /// the emoji translated into the given language.
+    ///
+    /// The language is a language tag like `de`, `pt-BR` or `zh_Hans`. The
+    /// translation of the primary language is used if no translation for the
+    /// exact tag exists, the English descriptions are used if the language
+    /// isn't supported at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - The language the emoji descriptions should be in.
+    pub fn emoji_localized(&self, language: &str) -> Option<[(&'static str, &'static str); 7]> {
+        self.inner.emoji_localized(language)
+    }
+
     /// Get the decimal version of the short auth string.
     pub fn decimals(&self) -> Option<(u16, u16, u16)> {
         self.inner.decimals()
@@ -140,6 +158,44 @@ impl SasVerification {
         self.inner.is_cancelled()
     }
 
+    /// Get a stream of state transitions of this SAS verification flow.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # use ruma::user_id;
+    /// use matrix_sdk::encryption::verification::VerificationState;
+    ///
+    /// # let flow_id = "someID";
+    /// # let user_id = user_id!("@alice:example");
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver)?;
+    /// if let Some(sas) = client.get_verification(&user_id, flow_id).await.and_then(|v| v.sas()) {
+    ///     let mut changes = sas.changes();
+    ///
+    ///     while let Some(state) = changes.next().await {
+    ///         match state {
+    ///             VerificationState::KeysExchanged => {
+    ///                 println!("Do the emoji match? {:?}", sas.emoji());
+    ///             }
+    ///             VerificationState::Done => println!("Successfully verified"),
+    ///             VerificationState::Cancelled(info) => {
+    ///                 println!("The verification was cancelled: {}", info.reason())
+    ///             }
+    ///             _ => (),
+    ///         }
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub fn changes(&self) -> impl Stream<Item = VerificationState> {
+        self.inner.changes()
+    }
+
     /// Get the other users device that we're verifying.
     pub fn other_device(&self) -> &ReadOnlyDevice {
         self.inner.other_device()
@@ -165,4 +221,14 @@ impl SasVerification {
     pub fn other_user_id(&self) -> &UserId {
         self.inner.other_user_id()
     }
+
+    /// Get the unique ID that identifies this SAS verification flow.
+    pub fn flow_id(&self) -> &str {
+        self.inner.flow_id().as_str()
+    }
+
+    /// Get the room ID if the verification is happening inside a room.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        self.inner.room_id()
+    }
 }
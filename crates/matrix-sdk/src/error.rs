@@ -19,7 +19,8 @@ use std::io::Error as IoError;
 use http::StatusCode;
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{
-    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError,
+    dehydrated_devices::DehydrationError, secret_storage::SecretStorageError, CryptoStoreError,
+    DecryptorError, KeyExportError, MegolmError, OlmError, SecretImportError,
 };
 use matrix_sdk_base::{Error as SdkBaseError, StoreError};
 use reqwest::Error as ReqwestError;
@@ -143,6 +144,37 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while using the secret storage.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error(transparent)]
+    SecretStorage(#[from] SecretStorageError),
+
+    /// An error occurred while importing a secret.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error(transparent)]
+    SecretImport(#[from] SecretImportError),
+
+    /// An error occurred while creating or rehydrating a dehydrated device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error(transparent)]
+    Dehydration(#[from] DehydrationError),
+
+    /// An error occurred while exporting room keys.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
+    /// The recovery key doesn't match the public key of the server-side
+    /// backup.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[error("the recovery key doesn't match the public key of the backup")]
+    BackupKeyMismatch,
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -32,10 +32,10 @@
 
 #[cfg(any(feature = "anyhow", feature = "eyre"))]
 use std::any::TypeId;
-use std::{borrow::Cow, fmt, future::Future, ops::Deref};
+use std::{borrow::Cow, collections::BTreeMap, fmt, future::Future, ops::Deref};
 
 use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncRoomEvent};
-use ruma::{events::AnySyncStateEvent, serde::Raw};
+use ruma::{events::AnySyncStateEvent, serde::Raw, RoomId};
 use serde::Deserialize;
 use serde_json::value::RawValue as RawJsonValue;
 
@@ -121,6 +121,7 @@ pub struct EventHandlerData<'a> {
     pub room: Option<room::Room>,
     pub raw: &'a RawJsonValue,
     pub encryption_info: Option<&'a EncryptionInfo>,
+    pub late_decryption: bool,
 }
 
 /// Context for an event handler.
@@ -179,6 +180,24 @@ impl EventHandlerContext for Option<EncryptionInfo> {
     }
 }
 
+/// Whether the event was only decrypted after it was first received.
+///
+/// Encrypted events that can't be decrypted when they are received, e.g.
+/// because the room key hasn't arrived yet, are remembered and passed to the
+/// event handlers again once the room key arrives. This context argument is
+/// `LateDecryption(true)` for such events.
+///
+/// Used as a context argument for event handlers (see
+/// [`Client::register_event_handler`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LateDecryption(pub bool);
+
+impl EventHandlerContext for LateDecryption {
+    fn from_data(data: &EventHandlerData<'_>) -> Option<Self> {
+        Some(Self(data.late_decryption))
+    }
+}
+
 /// Return types supported for event handlers implement this trait.
 ///
 /// It is not meant to be implemented outside of matrix-sdk.
@@ -232,6 +251,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             events,
+            false,
             |ev| (ev, None),
             |raw| Ok((kind, raw.deserialize_as::<ExtractType>()?.event_type)),
         )
@@ -253,6 +273,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             state_events,
+            false,
             |ev| (ev, None),
             |raw| {
                 let StateEventDetails { event_type, unsigned } = raw.deserialize_as()?;
@@ -267,6 +288,34 @@ impl Client {
         &self,
         room: &Option<room::Room>,
         timeline_events: &[SyncRoomEvent],
+    ) -> serde_json::Result<()> {
+        self.handle_timeline_events_helper(room, timeline_events, false).await
+    }
+
+    /// Pass events that were decrypted only after they were first received to
+    /// the event handlers.
+    pub(crate) async fn handle_late_decrypted_events(
+        &self,
+        rooms: &BTreeMap<RoomId, Vec<SyncRoomEvent>>,
+    ) -> serde_json::Result<()> {
+        for (room_id, events) in rooms {
+            let room = self.get_room(room_id);
+            if room.is_none() {
+                tracing::error!("Can't call event handler, room {} not found", room_id);
+                continue;
+            }
+
+            self.handle_timeline_events_helper(&room, events, true).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_timeline_events_helper(
+        &self,
+        room: &Option<room::Room>,
+        timeline_events: &[SyncRoomEvent],
+        late_decryption: bool,
     ) -> serde_json::Result<()> {
         #[derive(Deserialize)]
         struct TimelineEventDetails<'a> {
@@ -279,6 +328,7 @@ impl Client {
         self.handle_sync_events_wrapped_with(
             room,
             timeline_events,
+            late_decryption,
             |e| (&e.event, e.encryption_info.as_ref()),
             |raw| {
                 let TimelineEventDetails { event_type, state_key, unsigned } =
@@ -300,6 +350,7 @@ impl Client {
         &self,
         room: &Option<room::Room>,
         list: &'a [U],
+        late_decryption: bool,
         get_event_details: impl Fn(&'a U) -> (&'a Raw<T>, Option<&'a EncryptionInfo>),
         get_id: impl Fn(&Raw<T>) -> serde_json::Result<(EventKind, Cow<'_, str>)>,
     ) -> serde_json::Result<()> {
@@ -322,6 +373,7 @@ impl Client {
                         room: room.clone(),
                         raw: raw_event.json(),
                         encryption_info,
+                        late_decryption,
                     };
                     (handler)(data)
                 })
@@ -454,15 +506,24 @@ mod static_events {
 
 #[cfg(test)]
 mod test {
-    use std::{future, sync::Arc};
+    use std::{collections::BTreeMap, future, sync::Arc};
 
     use matrix_sdk_test::{EventBuilder, EventsJson};
     use ruma::{
-        events::room::member::{StrippedMemberEvent, SyncMemberEvent},
+        events::{
+            dummy::ToDeviceDummyEventContent,
+            room::{
+                member::{StrippedMemberEvent, SyncMemberEvent},
+                message::MessageEventContent,
+            },
+            AnySyncRoomEvent, AnyToDeviceEvent, SyncMessageEvent, ToDeviceEvent,
+        },
         room_id,
+        serde::Raw,
     };
     use serde_json::json;
 
+    use super::{EventKind, LateDecryption};
     use crate::{room, Client};
 
     #[tokio::test]
@@ -561,4 +622,79 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn late_decrypted_events() -> crate::Result<()> {
+        use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+        let client = crate::client::test::logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let response =
+            EventBuilder::default().add_room_event(EventsJson::Member).build_sync_response();
+        client.process_sync(response).await?;
+
+        let late_count = Arc::new(AtomicU8::new(0));
+
+        client
+            .register_event_handler({
+                let late_count = late_count.clone();
+                move |_ev: SyncMessageEvent<MessageEventContent>, late: LateDecryption| {
+                    if late.0 {
+                        late_count.fetch_add(1, SeqCst);
+                    }
+                    future::ready(())
+                }
+            })
+            .await;
+
+        let event: Raw<AnySyncRoomEvent> = serde_json::from_value(json!({
+            "content": {
+                "body": "It's a secret to everybody",
+                "msgtype": "m.text"
+            },
+            "event_id": "$152037280074GZeOm:localhost",
+            "origin_server_ts": 152037280,
+            "sender": "@example:localhost",
+            "type": "m.room.message",
+        }))?;
+
+        let mut events = BTreeMap::new();
+        events.insert(room_id, vec![event.into()]);
+        client.handle_late_decrypted_events(&events).await?;
+
+        assert_eq!(late_count.load(SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn to_device_events() -> crate::Result<()> {
+        use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+        let client = crate::client::test::logged_in_client().await;
+        let dummy_count = Arc::new(AtomicU8::new(0));
+
+        client
+            .register_event_handler({
+                let dummy_count = dummy_count.clone();
+                move |_ev: ToDeviceEvent<ToDeviceDummyEventContent>| {
+                    dummy_count.fetch_add(1, SeqCst);
+                    future::ready(())
+                }
+            })
+            .await;
+
+        let event: Raw<AnyToDeviceEvent> = serde_json::from_value(json!({
+            "content": {},
+            "sender": "@example:localhost",
+            "type": "m.dummy",
+        }))?;
+
+        client.handle_sync_events(EventKind::ToDevice, &None, &[event]).await?;
+
+        assert_eq!(dummy_count.load(SeqCst), 1);
+
+        Ok(())
+    }
 }
@@ -12,17 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{convert::TryFrom, fmt::Debug, sync::Arc};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Debug},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
 
 use bytes::{Bytes, BytesMut};
-use http::Response as HttpResponse;
-use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
+use http::{header::AUTHORIZATION, HeaderValue, Response as HttpResponse, StatusCode};
+use matrix_sdk_common::{
+    async_trait,
+    locks::{Mutex, RwLock},
+    AsyncTraitDeps,
+};
 use reqwest::{Client, Response};
-use ruma::api::{
-    client::r0::media::create_content, error::FromHttpResponseError, AuthScheme, IncomingResponse,
-    OutgoingRequest, OutgoingRequestAppserviceExt, SendAccessToken,
+use ruma::{
+    api::{
+        client::{error::ErrorKind, r0::media::create_content, Error as RumaClientApiError},
+        error::{FromHttpResponseError, IntoHttpError},
+        AuthScheme, EndpointError, IncomingResponse, OutgoingRequest, OutgoingRequestAppserviceExt,
+        SendAccessToken,
+    },
+    MilliSecondsSinceUnixEpoch, UInt,
 };
-use tracing::trace;
+use tracing::{debug, trace, warn};
 use url::Url;
 
 use crate::{
@@ -31,6 +46,127 @@ use crate::{
     Session,
 };
 
+pub(crate) mod api {
+    pub mod login {
+        use bytes::BufMut;
+        use ruma::{
+            api::{
+                client::{r0::session::login, Error},
+                error::{DeserializationError, FromHttpResponseError, IntoHttpError},
+                IncomingResponse, Metadata, OutgoingRequest, SendAccessToken,
+            },
+            UInt,
+        };
+        use serde::Deserialize;
+        use serde_json::{json, Value};
+
+        /// The ruma login request, additionally asking the server for a
+        /// refresh token, see MSC2918.
+        #[derive(Clone, Debug)]
+        pub struct Request<'a>(pub login::Request<'a>);
+
+        impl<'a> OutgoingRequest for Request<'a> {
+            type EndpointError = Error;
+            type IncomingResponse = Response;
+
+            const METADATA: Metadata = login::Request::METADATA;
+
+            fn try_into_http_request<T: Default + BufMut>(
+                self,
+                base_url: &str,
+                access_token: SendAccessToken<'_>,
+            ) -> Result<http::Request<T>, IntoHttpError> {
+                let (parts, body) =
+                    self.0.try_into_http_request::<Vec<u8>>(base_url, access_token)?.into_parts();
+
+                let mut content: Value = serde_json::from_slice(&body)?;
+                content["refresh_token"] = json!(true);
+                // Servers implementing the unstable version of MSC2918.
+                content["org.matrix.msc2918.refresh_token"] = json!(true);
+
+                let mut body = T::default();
+                body.put_slice(&serde_json::to_vec(&content)?);
+
+                Ok(http::Request::from_parts(parts, body))
+            }
+        }
+
+        /// The fields MSC2918 adds to the login response.
+        #[derive(Deserialize)]
+        struct RefreshTokenFields {
+            refresh_token: Option<String>,
+            expires_in_ms: Option<UInt>,
+        }
+
+        /// The ruma login response, together with the refresh token the
+        /// server handed out.
+        #[derive(Clone, Debug)]
+        pub struct Response {
+            pub login: login::Response,
+            pub refresh_token: Option<String>,
+            pub expires_in_ms: Option<UInt>,
+        }
+
+        impl IncomingResponse for Response {
+            type EndpointError = Error;
+
+            fn try_from_http_response<T: AsRef<[u8]>>(
+                response: http::Response<T>,
+            ) -> Result<Self, FromHttpResponseError<Error>> {
+                let fields = serde_json::from_slice::<RefreshTokenFields>(response.body().as_ref());
+                // Error responses are handled by the ruma response.
+                let login = login::Response::try_from_http_response(response)?;
+                let fields = fields.map_err(DeserializationError::from)?;
+
+                Ok(Self {
+                    login,
+                    refresh_token: fields.refresh_token,
+                    expires_in_ms: fields.expires_in_ms,
+                })
+            }
+        }
+    }
+
+    pub mod refresh {
+        use ruma::{api::ruma_api, UInt};
+
+        ruma_api! {
+            metadata: {
+                description: "Refresh an access token using a refresh token.",
+                method: POST,
+                name: "refresh",
+                path: "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh",
+                rate_limited: true,
+                authentication: None,
+            }
+
+            request: {
+                pub refresh_token: String,
+            }
+
+            response: {
+                pub access_token: String,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub refresh_token: Option<String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub expires_in_ms: Option<UInt>,
+            }
+
+            error: ruma::api::client::Error
+        }
+    }
+}
+
+/// Get the point in time at which an access token that is valid for the given
+/// number of milliseconds expires.
+pub(crate) fn expires_at(expires_in_ms: UInt) -> Option<MilliSecondsSinceUnixEpoch> {
+    MilliSecondsSinceUnixEpoch::now().0.checked_add(expires_in_ms).map(MilliSecondsSinceUnixEpoch)
+}
+
+type SessionChangeHandlerFut = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type SessionChangeHandlerFn =
+    Box<dyn Fn(Session) -> SessionChangeHandlerFut + Send + Sync>;
+
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -92,12 +228,29 @@ pub trait HttpSend: AsyncTraitDeps {
     ) -> Result<http::Response<Bytes>, HttpError>;
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub(crate) struct HttpClient {
     pub(crate) inner: Arc<dyn HttpSend>,
     pub(crate) homeserver: Arc<RwLock<Url>>,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
     pub(crate) request_config: RequestConfig,
+    /// Lock making sure we're only refreshing the access token once at a time.
+    refresh_lock: Arc<Mutex<()>>,
+    /// Handlers that get called every time the session changes because the
+    /// access token got refreshed.
+    pub(crate) session_change_handlers: Arc<RwLock<Vec<SessionChangeHandlerFn>>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl Debug for HttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpClient")
+            .field("inner", &self.inner)
+            .field("homeserver", &self.homeserver)
+            .field("session", &self.session)
+            .field("request_config", &self.request_config)
+            .finish()
+    }
 }
 
 impl HttpClient {
@@ -107,7 +260,14 @@ impl HttpClient {
         session: Arc<RwLock<Option<Session>>>,
         request_config: RequestConfig,
     ) -> Self {
-        HttpClient { inner, homeserver, session, request_config }
+        HttpClient {
+            inner,
+            homeserver,
+            session,
+            request_config,
+            refresh_lock: Default::default(),
+            session_change_handlers: Default::default(),
+        }
     }
 
     async fn send_request<Request: OutgoingRequest>(
@@ -121,15 +281,151 @@ impl HttpClient {
             None => self.request_config,
         };
 
+        let authenticated = config.force_auth
+            || matches!(Request::METADATA.authentication, AuthScheme::AccessToken);
+
+        let mut refresh_failed = false;
+
+        if authenticated && !self.request_config.assert_identity {
+            // Don't bother sending out a request with an access token we know
+            // has expired.
+            let expired_token = session
+                .read()
+                .await
+                .as_ref()
+                .filter(|s| s.refresh_token.is_some() && s.is_expired())
+                .map(|s| s.access_token.clone());
+
+            if let Some(token) = expired_token {
+                if let Err(e) = self.refresh_access_token(Some(&token)).await {
+                    // Send the request anyways, the response will tell the
+                    // caller that the access token expired.
+                    warn!("Couldn't refresh the expired access token: {}", e);
+                    refresh_failed = true;
+                }
+            }
+        }
+
         let request = if !self.request_config.assert_identity {
             self.try_into_http_request(request, session, config).await?
         } else {
             self.try_into_http_request_with_identity_assertion(request, session, config).await?
         };
 
+        let access_token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(ToOwned::to_owned);
+
+        let access_token = match access_token {
+            Some(t) if !self.request_config.assert_identity => t,
+            _ => return self.inner.send_request(request, config).await,
+        };
+
+        let response = self.inner.send_request(clone_request(&request), config).await?;
+
+        if refresh_failed || !is_soft_logout(&response) {
+            return Ok(response);
+        }
+
+        match self.refresh_access_token(Some(&access_token)).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(response),
+            Err(e) => {
+                // The caller is better served by the original
+                // `M_UNKNOWN_TOKEN` error than by the reason the refresh
+                // failed.
+                warn!("Couldn't refresh the access token: {}", e);
+                return Ok(response);
+            }
+        }
+
+        // The access token expired while the request was in flight, retry it
+        // once using the new access token.
+        let mut request = request;
+        let access_token = self
+            .session
+            .read()
+            .await
+            .as_ref()
+            .map(|s| s.access_token.clone())
+            .ok_or(HttpError::AuthenticationRequired)?;
+        let header = HeaderValue::from_str(&format!("Bearer {}", access_token))
+            .map_err(IntoHttpError::from)?;
+        request.headers_mut().insert(AUTHORIZATION, header);
+
         self.inner.send_request(request, config).await
     }
 
+    /// Get a new access token using the refresh token of the current session.
+    ///
+    /// Concurrent refreshes are serialized. If `expired_token` is given and
+    /// the session got a different access token while we were waiting for our
+    /// turn, the access token was already refreshed and no request is sent.
+    ///
+    /// Returns `false` if the session has no refresh token, `true` if a new
+    /// access token is available.
+    pub async fn refresh_access_token(
+        &self,
+        expired_token: Option<&str>,
+    ) -> Result<bool, HttpError> {
+        let guard = self.refresh_lock.lock().await;
+
+        let session = self.session.read().await.clone().ok_or(HttpError::AuthenticationRequired)?;
+
+        if expired_token.map_or(false, |t| t != session.access_token) {
+            return Ok(true);
+        }
+
+        let refresh_token = match &session.refresh_token {
+            Some(t) => t.clone(),
+            None => return Ok(false),
+        };
+
+        debug!("Refreshing the access token");
+
+        let request = api::refresh::Request { refresh_token };
+        let request = request
+            .try_into_http_request::<BytesMut>(
+                &self.homeserver.read().await.to_string(),
+                SendAccessToken::None,
+            )?
+            .map(|body| body.freeze());
+
+        let response = self.inner.send_request(request, self.request_config).await?;
+        let response = api::refresh::Response::try_from_http_response(response)?;
+
+        let expires_at = response.expires_in_ms.and_then(expires_at);
+
+        let session = Session {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token.or(session.refresh_token),
+            expires_at,
+            ..session
+        };
+
+        *self.session.write().await = Some(session.clone());
+        drop(guard);
+
+        let futures: Vec<_> = self
+            .session_change_handlers
+            .read()
+            .await
+            .iter()
+            .map(|handler| (handler)(session.clone()))
+            .collect();
+
+        // Run the handlers with the `session_change_handlers` lock no longer
+        // being held, in order.
+        for fut in futures {
+            fut.await;
+        }
+
+        Ok(true)
+    }
+
     async fn try_into_http_request<Request: OutgoingRequest>(
         &self,
         request: Request,
@@ -228,14 +524,44 @@ impl HttpClient {
     }
 }
 
+/// Is the response an `M_UNKNOWN_TOKEN` error telling us that we were soft
+/// logged out, e.g. because our access token expired.
+fn is_soft_logout(response: &http::Response<Bytes>) -> bool {
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return false;
+    }
+
+    let response = HttpResponse::builder()
+        .status(response.status())
+        .body(response.body().clone())
+        .expect("Can't construct a response using the given body");
+
+    matches!(
+        RumaClientApiError::try_from_http_response(response),
+        Ok(RumaClientApiError { kind: ErrorKind::UnknownToken { soft_logout: true }, .. })
+    )
+}
+
+/// Copy a request so it can be sent out again.
+fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
+    let mut builder = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version());
+
+    if let Some(headers) = builder.headers_mut() {
+        *headers = request.headers().clone();
+    }
+
+    builder.body(request.body().clone()).expect("Can't copy a valid request")
+}
+
 /// Build a client with the specified configuration.
 pub(crate) fn client_with_config(config: &ClientConfig) -> Result<Client, HttpError> {
     let http_client = reqwest::Client::builder();
 
     #[cfg(not(target_arch = "wasm32"))]
     let http_client = {
-        use http::HeaderValue;
-
         let http_client = if config.disable_ssl_verification {
             http_client.danger_accept_invalid_certs(true)
         } else {
@@ -307,7 +633,6 @@ async fn send_request(
     use std::sync::atomic::{AtomicU64, Ordering};
 
     use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
-    use http::StatusCode;
 
     let mut backoff = ExponentialBackoff::default();
     let mut request = reqwest::Request::try_from(request)?;
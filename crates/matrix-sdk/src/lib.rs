@@ -39,13 +39,14 @@ compile_error!("'sso_login' cannot be enabled on 'wasm32' arch");
 pub use bytes;
 pub use matrix_sdk_base::{
     media, Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomType, Session,
-    StateChanges, StoreError,
+    StateChanges, StateStore, StoreError,
 };
 pub use matrix_sdk_common::*;
 pub use reqwest;
 #[doc(no_inline)]
 pub use ruma;
 
+mod backoff;
 mod client;
 pub mod config;
 mod error;
@@ -55,14 +56,17 @@ mod http_client;
 pub mod room;
 /// High-level room API
 mod room_member;
+mod sync;
 
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub mod encryption;
 
+pub use backoff::RequestErrorKind;
 pub use client::{Client, LoopCtrl};
 pub use error::{Error, HttpError, HttpResult, Result};
 pub use http_client::HttpSend;
 pub use room_member::RoomMember;
+pub use sync::{SyncState, SyncStopReason};
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
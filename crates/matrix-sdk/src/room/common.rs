@@ -1,7 +1,13 @@
 use std::{ops::Deref, sync::Arc};
 
-use matrix_sdk_base::deserialized_responses::{MembersResponse, RoomEvent};
+use futures::Stream;
+use matrix_sdk_base::{
+    deserialized_responses::{MembersResponse, RoomEvent, SyncRoomEvent},
+    TimelineChunk,
+};
 use matrix_sdk_common::locks::Mutex;
+#[cfg(feature = "encryption")]
+use ruma::events::{AnySyncMessageEvent, AnySyncRoomEvent};
 use ruma::{
     api::client::r0::{
         membership::{get_member_events, join_room_by_id, leave_room},
@@ -20,6 +26,30 @@ use crate::{
     BaseRoom, Client, Result, RoomMember,
 };
 
+/// The number of events that are requested from the server at once when a gap
+/// in the stored timeline is filled.
+const BACKFILL_LIMIT: u32 = 50;
+/// The number of events that are read from the store at once.
+const TIMELINE_BATCH_SIZE: usize = 50;
+
+/// A position in the stored timeline of a room, events are loaded backwards
+/// from it in batches.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TimelineCursor {
+    /// The chunks that weren't fully read yet, the current chunk comes last.
+    /// `None` if nothing was read yet.
+    chunks: Option<Vec<TimelineChunk>>,
+    /// The index of the oldest event that was read from the current chunk.
+    before: Option<i64>,
+}
+
+impl TimelineCursor {
+    /// Has nothing been read using the cursor yet.
+    pub(crate) fn is_fresh(&self) -> bool {
+        self.chunks.is_none()
+    }
+}
+
 /// A struct containing methods that are common for Joined, Invited and Left
 /// Rooms
 #[derive(Debug, Clone)]
@@ -149,6 +179,149 @@ impl Common {
         self.client.send(request, None).await
     }
 
+    /// Get a stream of the events of this room, starting with the newest event
+    /// and going back in time.
+    ///
+    /// Events are read from the state store, the server is only asked for
+    /// events if there's a gap in the stored timeline, e.g. because a sync
+    /// response contained a limited timeline. Events that are fetched from the
+    /// server are stored so they don't need to be fetched again.
+    ///
+    /// The stream ends once the beginning of the room is reached.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, pin_mut, StreamExt};
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::ruma::room_id;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// # let client = Client::new(homeserver)?;
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    ///
+    /// let timeline = room.timeline();
+    /// pin_mut!(timeline);
+    ///
+    /// // Print the last 20 events of the room.
+    /// for event in timeline.take(20).collect::<Vec<_>>().await {
+    ///     println!("{:?}", event?.event);
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub fn timeline(&self) -> impl Stream<Item = Result<SyncRoomEvent>> {
+        let room = self.clone();
+
+        async_stream::try_stream! {
+            let mut cursor = TimelineCursor::default();
+
+            loop {
+                let events = room.load_timeline_batch(&mut cursor).await?;
+
+                if events.is_empty() {
+                    break;
+                }
+
+                for event in events {
+                    yield event;
+                }
+            }
+        }
+    }
+
+    /// Load the next batch of events before the given cursor, newest first,
+    /// and move the cursor past them.
+    ///
+    /// Gaps in the stored timeline are filled from the server. Returns an
+    /// empty list once the beginning of the room is reached.
+    pub(crate) async fn load_timeline_batch(
+        &self,
+        cursor: &mut TimelineCursor,
+    ) -> Result<Vec<SyncRoomEvent>> {
+        let store = self.client.store();
+        let room_id = self.room_id();
+
+        if cursor.chunks.is_none() {
+            cursor.chunks = Some(store.get_timeline_chunks(room_id).await?);
+        }
+
+        let TimelineCursor { chunks, before } = cursor;
+        let chunks = chunks.get_or_insert_with(Vec::new);
+
+        while let Some(chunk) = chunks.last_mut() {
+            let events =
+                store.get_timeline_events(room_id, chunk.id, *before, TIMELINE_BATCH_SIZE).await?;
+
+            if let Some((index, _)) = events.last() {
+                *before = Some(*index);
+                return Ok(events.into_iter().map(|(_, event)| event).collect());
+            }
+
+            // We reached the start of the chunk, fill the gap before it if
+            // there is one, otherwise continue with the previous chunk.
+            match chunk.prev_batch.take() {
+                Some(prev_batch) => {
+                    self.backfill_timeline(chunk.id, &prev_batch).await?;
+
+                    chunk.prev_batch = store
+                        .get_timeline_chunks(room_id)
+                        .await?
+                        .into_iter()
+                        .find(|c| c.id == chunk.id)
+                        .and_then(|c| c.prev_batch);
+                }
+                None => {
+                    chunks.pop();
+                    *before = None;
+                }
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    /// Fetch the events before the given chunk of the stored timeline from the
+    /// server and store them.
+    async fn backfill_timeline(&self, chunk_id: u64, prev_batch: &str) -> Result<()> {
+        let mut request = get_message_events::Request::backward(self.room_id(), prev_batch);
+        request.limit = BACKFILL_LIMIT.into();
+
+        let response = self.messages(request).await?;
+        let mut events = Vec::with_capacity(response.chunk.len());
+
+        for event in response.chunk {
+            #[allow(unused_mut)]
+            let mut event: SyncRoomEvent = Raw::from_json(event.into_json()).into();
+
+            #[cfg(feature = "encryption")]
+            if let Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(encrypted))) =
+                event.event.deserialize()
+            {
+                if let Some(machine) = self.client.base_client.olm_machine().await {
+                    if let Ok(decrypted) =
+                        machine.decrypt_room_event(&encrypted, self.room_id()).await
+                    {
+                        event = decrypted;
+                    }
+                }
+            }
+
+            events.push(event);
+        }
+
+        // An empty chunk means that we reached the beginning of the room.
+        let prev_batch = if events.is_empty() { None } else { response.end.as_deref() };
+
+        self.client
+            .store()
+            .add_timeline_backfill(self.room_id(), chunk_id, &events, prev_batch)
+            .await?;
+
+        Ok(())
+    }
+
     /// Sends a request to `/_matrix/client/r0/rooms/{roomId}/event/{eventId}`
     /// and returns a `get_room_event::Response` that contains a event
     /// (`AnyRoomEvent`).
@@ -46,7 +46,11 @@ use tracing::debug;
 #[cfg(feature = "encryption")]
 use tracing::instrument;
 
-use crate::{error::HttpResult, room::Common, BaseRoom, Client, HttpError, Result, RoomType};
+use crate::{
+    error::HttpResult,
+    room::{send_queue::SendQueue, timeline::Timeline, Common},
+    BaseRoom, Client, HttpError, Result, RoomType,
+};
 
 const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
 const TYPING_NOTICE_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
@@ -91,6 +95,31 @@ impl Joined {
         self.inner.leave().await
     }
 
+    /// Get a live view of the timeline of this room.
+    ///
+    /// Unlike [`Common::timeline()`], the returned [`Timeline`] keeps a list of
+    /// items that is ready to be displayed: it includes local echoes for the
+    /// events sent using [`Timeline::send()`], applies edits and redactions to
+    /// the events they target and aggregates reactions.
+    ///
+    /// The timeline is kept up to date by the sync loop.
+    pub fn live_timeline(&self) -> Timeline {
+        Timeline::new(self.clone())
+    }
+
+    /// Get the send queue of this room.
+    ///
+    /// Events that are added to the queue are persisted in the state store
+    /// and sent in the background, sending is retried if the server can't be
+    /// reached. Unlike [`send()`](#method.send), this works while the client
+    /// is offline.
+    ///
+    /// Events that were queued before the application was restarted are sent
+    /// once the queue of the room is requested again.
+    pub async fn send_queue(&self) -> Result<SendQueue> {
+        SendQueue::new(self.clone()).await
+    }
+
     /// Ban the user with `UserId` from this room.
     ///
     /// # Arguments
@@ -489,7 +518,18 @@ impl Joined {
         txn_id: Option<Uuid>,
     ) -> Result<send_message_event::Response> {
         let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
+        self.send_raw_with_txn_id(content, event_type, &txn_id).await
+    }
 
+    /// Send a room message to this room using the given transaction id.
+    ///
+    /// The message is encrypted at this point if the room is encrypted.
+    pub(crate) async fn send_raw_with_txn_id(
+        &self,
+        content: Value,
+        event_type: &str,
+        txn_id: &str,
+    ) -> Result<send_message_event::Response> {
         #[cfg(not(feature = "encryption"))]
         let content = {
             debug!(
@@ -513,11 +553,11 @@ impl Joined {
 
             self.preshare_group_session().await?;
 
-            let olm =
-                self.client.base_client.olm_machine().await.expect("Olm machine wasn't started");
-
-            let encrypted_content =
-                olm.encrypt_raw(self.inner.room_id(), content, event_type).await?;
+            let encrypted_content = self
+                .client
+                .base_client
+                .encrypt_raw(self.inner.room_id(), content, event_type)
+                .await?;
             let raw_content = serde_json::value::to_raw_value(&encrypted_content)
                 .expect("Failed to serialize encrypted event");
 
@@ -533,7 +573,7 @@ impl Joined {
 
         let request = send_message_event::Request::new_raw(
             self.inner.room_id(),
-            &txn_id,
+            txn_id,
             event_type,
             Raw::from_json(content),
         );
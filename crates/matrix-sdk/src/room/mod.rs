@@ -6,6 +6,8 @@ mod common;
 mod invited;
 mod joined;
 mod left;
+pub mod send_queue;
+pub mod timeline;
 
 pub use self::{common::Common, invited::Invited, joined::Joined, left::Left};
 
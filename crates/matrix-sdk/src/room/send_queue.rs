@@ -0,0 +1,672 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A persistent queue of message events that should be sent to a room.
+//!
+//! Events that are added to a [`SendQueue`] are stored in the state store
+//! before they are sent, so they survive restarts of the application. Events
+//! are sent one after the other in the order they were added, sending is
+//! retried with a backoff if the server can't be reached. Events that are still
+//! queued when the application starts are sent after the first sync.
+//!
+//! If an event fails to be sent because of an error that retrying won't fix,
+//! the queue stops at that event until it's retried or cancelled, the events
+//! after it are never sent before it.
+//!
+//! Only events with a JSON content can be queued. Attachments need to be
+//! uploaded before the event that references them is sent, so
+//! [`Joined::send_attachment()`] doesn't go through the queue.
+
+use std::sync::{Arc, Mutex as StdMutex};
+
+use futures::Stream;
+use futures_timer::Delay as sleep;
+use matrix_sdk_base::QueuedEvent;
+use matrix_sdk_common::{executor::spawn, locks::Mutex, observable::Observers, uuid::Uuid};
+use ruma::{events::MessageEventContent, EventId};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    backoff::{Backoff, RequestErrorKind},
+    room::{timeline::WeakTimeline, Joined},
+    Client, Result,
+};
+
+/// The state of an event in a [`SendQueue`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueuedEventState {
+    /// The event is waiting to be sent.
+    Queued,
+    /// The event is being sent to the server.
+    Sending,
+    /// The event was sent, it is removed from the queue.
+    Sent(EventId),
+    /// Sending the event failed with an error that retrying won't fix, the
+    /// event stays in the queue until it's retried or cancelled. The events
+    /// after it aren't sent until then.
+    Failed,
+    /// The event was cancelled, it is removed from the queue.
+    Cancelled,
+}
+
+/// An event in a [`SendQueue`].
+#[derive(Clone, Debug)]
+pub struct SendQueueItem {
+    /// The transaction id that is used to send the event.
+    pub transaction_id: String,
+    /// The type of the event.
+    pub event_type: String,
+    /// The plaintext content of the event.
+    pub content: Value,
+    /// The state of the event.
+    pub state: QueuedEventState,
+}
+
+impl SendQueueItem {
+    fn from_event(event: QueuedEvent) -> Self {
+        Self {
+            transaction_id: event.transaction_id,
+            event_type: event.event_type,
+            content: event.content,
+            state: if event.failed { QueuedEventState::Failed } else { QueuedEventState::Queued },
+        }
+    }
+
+    fn to_event(&self) -> QueuedEvent {
+        QueuedEvent {
+            transaction_id: self.transaction_id.clone(),
+            event_type: self.event_type.clone(),
+            content: self.content.clone(),
+            failed: self.state == QueuedEventState::Failed,
+        }
+    }
+}
+
+/// A persistent queue of message events that should be sent to a joined room.
+///
+/// Events keep their transaction id while they are in the queue, so the server
+/// won't create duplicate events if a request is retried after the server
+/// already received it. Events are encrypted right before they are sent if the
+/// room is encrypted.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    room: Joined,
+    inner: Arc<SendQueueInner>,
+}
+
+impl SendQueue {
+    pub(crate) async fn new(room: Joined) -> Result<Self> {
+        let inner = room.client.send_queues.entry(room.room_id().clone()).or_default().clone();
+        let queue = Self { room, inner };
+
+        let mut loaded = queue.inner.loaded.lock().await;
+
+        if !*loaded {
+            let events = queue.room.client.store().get_queued_events(queue.room.room_id()).await?;
+            let mut state = queue.inner.state.lock().unwrap();
+
+            for event in events {
+                state.push(SendQueueItem::from_event(event));
+            }
+
+            drop(state);
+            *loaded = true;
+        }
+
+        drop(loaded);
+        queue.start();
+
+        Ok(queue)
+    }
+
+    /// Add a message event to the end of the queue.
+    ///
+    /// Returns the transaction id of the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::ruma::room_id;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// # let client = Client::new(homeserver)?;
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// use matrix_sdk::{
+    ///     room::send_queue::QueuedEventState,
+    ///     ruma::events::room::message::MessageEventContent,
+    /// };
+    ///
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    /// let queue = room.send_queue().await?;
+    /// let mut updates = queue.subscribe();
+    ///
+    /// let txn_id = queue.enqueue(MessageEventContent::text_plain("Hello world")).await?;
+    ///
+    /// while let Some(item) = updates.next().await {
+    ///     if item.transaction_id == txn_id {
+    ///         if let QueuedEventState::Sent(event_id) = item.state {
+    ///             println!("Our message was sent with the event id {}", event_id);
+    ///             break;
+    ///         }
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub async fn enqueue(&self, content: impl MessageEventContent) -> Result<String> {
+        let event_type = content.event_type().to_owned();
+        let content = serde_json::to_value(content)?;
+
+        self.enqueue_raw(content, &event_type).await
+    }
+
+    /// Add a message event with a json `Value` content to the end of the
+    /// queue.
+    ///
+    /// Returns the transaction id of the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event as a json `Value`.
+    ///
+    /// * `event_type` - The type of the message event.
+    pub async fn enqueue_raw(&self, content: Value, event_type: &str) -> Result<String> {
+        let event = QueuedEvent {
+            transaction_id: Uuid::new_v4().to_string(),
+            event_type: event_type.to_owned(),
+            content,
+            failed: false,
+        };
+
+        self.room.client.store().add_queued_event(self.room.room_id(), &event).await?;
+
+        let transaction_id = event.transaction_id.clone();
+        self.inner.state.lock().unwrap().push(SendQueueItem::from_event(event));
+        self.start();
+
+        Ok(transaction_id)
+    }
+
+    /// Get the events that are currently in the queue, in the order they will
+    /// be sent.
+    pub fn items(&self) -> Vec<SendQueueItem> {
+        self.inner.state.lock().unwrap().items.clone()
+    }
+
+    /// Get a stream of updates to the events in the queue.
+    pub fn subscribe(&self) -> impl Stream<Item = SendQueueItem> + Unpin {
+        let mut guard = self.inner.state.lock().unwrap();
+        let state = &mut *guard;
+
+        state.observers.subscribe(state.items.iter().cloned())
+    }
+
+    /// Cancel sending an event.
+    ///
+    /// Returns true if the event was removed from the queue, false if the
+    /// event isn't in the queue or is being sent right now.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_id` - The transaction id of the event.
+    pub async fn cancel(&self, transaction_id: &str) -> Result<bool> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+
+            match state.find(transaction_id) {
+                Some(index) if state.items[index].state != QueuedEventState::Sending => {
+                    state.remove(index, QueuedEventState::Cancelled);
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        self.room.client.store().remove_queued_event(self.room.room_id(), transaction_id).await?;
+
+        // The cancelled event might have held back the rest of the queue.
+        self.start();
+
+        Ok(true)
+    }
+
+    /// Try to send an event that failed to be sent again.
+    ///
+    /// Returns true if the event was queued again, false if the event isn't
+    /// in the queue or didn't fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_id` - The transaction id of the event.
+    pub async fn retry(&self, transaction_id: &str) -> Result<bool> {
+        let event = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            match state.find(transaction_id) {
+                Some(index) if state.items[index].state == QueuedEventState::Failed => {
+                    state.set_state(index, QueuedEventState::Queued);
+                    state.items[index].to_event()
+                }
+                _ => return Ok(false),
+            }
+        };
+
+        self.start();
+
+        // If the event was sent in the meantime this doesn't do anything.
+        self.room.client.store().update_queued_event(self.room.room_id(), &event).await?;
+
+        Ok(true)
+    }
+
+    /// Start sending the queued events if we aren't doing so already.
+    fn start(&self) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if state.sending || !state.is_next_queued() {
+                return;
+            }
+
+            state.sending = true;
+        }
+
+        let queue = self.clone();
+        spawn(async move { queue.send_queued_events().await });
+    }
+
+    async fn send_queued_events(&self) {
+        let mut backoff = Backoff::default();
+
+        loop {
+            let event = {
+                let mut state = self.inner.state.lock().unwrap();
+
+                // Events are sent strictly in order, a failed event holds
+                // back the rest of the queue.
+                if state.is_next_queued() {
+                    state.set_state(0, QueuedEventState::Sending);
+                    state.items[0].clone()
+                } else {
+                    state.sending = false;
+                    return;
+                }
+            };
+
+            let response = self
+                .room
+                .send_raw_with_txn_id(event.content, &event.event_type, &event.transaction_id)
+                .await;
+
+            match response {
+                Ok(response) => {
+                    backoff.reset();
+
+                    if let Err(e) = self
+                        .room
+                        .client
+                        .store()
+                        .remove_queued_event(self.room.room_id(), &event.transaction_id)
+                        .await
+                    {
+                        warn!(error =? e, "Couldn't remove a sent event from the send queue");
+                    }
+
+                    let mut state = self.inner.state.lock().unwrap();
+
+                    if let Some(index) = state.find(&event.transaction_id) {
+                        state.remove(index, QueuedEventState::Sent(response.event_id));
+                    }
+                }
+                Err(e) => {
+                    let kind = RequestErrorKind::from(&e);
+                    let retry = matches!(
+                        kind,
+                        RequestErrorKind::Network | RequestErrorKind::RateLimited { .. }
+                    );
+
+                    // Remember that the event failed, otherwise it would be
+                    // sent again once the application restarts.
+                    if !retry {
+                        let failed = QueuedEvent { failed: true, ..event.to_event() };
+
+                        if let Err(e) = self
+                            .room
+                            .client
+                            .store()
+                            .update_queued_event(self.room.room_id(), &failed)
+                            .await
+                        {
+                            warn!(error =? e, "Couldn't mark a queued event as failed");
+                        }
+                    }
+
+                    {
+                        let mut state = self.inner.state.lock().unwrap();
+
+                        if let Some(index) = state.find(&event.transaction_id) {
+                            let new_state = if retry {
+                                QueuedEventState::Queued
+                            } else {
+                                QueuedEventState::Failed
+                            };
+                            state.set_state(index, new_state);
+                        }
+                    }
+
+                    if retry {
+                        let delay = backoff.next_delay(&kind);
+
+                        warn!(
+                            error =? e,
+                            room_id = self.room.room_id().as_str(),
+                            delay =? delay,
+                            "Couldn't send a queued event, backing off before retrying"
+                        );
+
+                        sleep::new(delay).await;
+                    } else {
+                        warn!(
+                            error =? e,
+                            room_id = self.room.room_id().as_str(),
+                            "Couldn't send a queued event"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Start sending the events of all the send queues that contain events,
+    /// e.g. because they were queued before the application was restarted.
+    pub(crate) async fn resume_send_queues(&self) {
+        let room_ids = match self.store().get_rooms_with_queued_events().await {
+            Ok(room_ids) => room_ids,
+            Err(e) => {
+                warn!(error =? e, "Couldn't load the rooms with queued events");
+                return;
+            }
+        };
+
+        for room_id in room_ids {
+            if let Some(room) = self.get_joined_room(&room_id) {
+                if let Err(e) = room.send_queue().await {
+                    warn!(
+                        error =? e,
+                        room_id = room_id.as_str(),
+                        "Couldn't resume sending the queued events of a room"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The state of the send queue of a room, shared between all the `SendQueue`s
+/// of the room.
+#[derive(Debug, Default)]
+pub(crate) struct SendQueueInner {
+    /// Has the queue been loaded from the state store.
+    loaded: Mutex<bool>,
+    state: StdMutex<SendQueueState>,
+}
+
+impl SendQueueInner {
+    /// Show the queued events as local echoes in the given timeline and keep
+    /// them up to date.
+    pub(crate) fn add_timeline(&self, timeline: WeakTimeline) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(inner) = timeline.upgrade() {
+            let mut inner = inner.lock().unwrap();
+
+            for item in &state.items {
+                inner.handle_send_queue_item(item);
+            }
+        }
+
+        state.timelines.push(timeline);
+    }
+}
+
+#[derive(Debug, Default)]
+struct SendQueueState {
+    items: Vec<SendQueueItem>,
+    /// Are the queued events being sent right now.
+    sending: bool,
+    observers: Observers<SendQueueItem>,
+    /// The timelines of the room, they show the queued events as local
+    /// echoes.
+    timelines: Vec<WeakTimeline>,
+}
+
+impl SendQueueState {
+    fn notify(&mut self, item: SendQueueItem) {
+        // Timelines that were dropped are removed here as well.
+        self.timelines.retain(|timeline| match timeline.upgrade() {
+            Some(timeline) => {
+                timeline.lock().unwrap().handle_send_queue_item(&item);
+                true
+            }
+            None => false,
+        });
+
+        self.observers.notify(item);
+    }
+
+    /// Is the first event of the queue waiting to be sent.
+    fn is_next_queued(&self) -> bool {
+        self.items.first().map_or(false, |i| i.state == QueuedEventState::Queued)
+    }
+
+    fn find(&self, transaction_id: &str) -> Option<usize> {
+        self.items.iter().position(|i| i.transaction_id == transaction_id)
+    }
+
+    fn push(&mut self, item: SendQueueItem) {
+        self.items.push(item.clone());
+        self.notify(item);
+    }
+
+    fn set_state(&mut self, index: usize, state: QueuedEventState) {
+        self.items[index].state = state;
+        let item = self.items[index].clone();
+        self.notify(item);
+    }
+
+    fn remove(&mut self, index: usize, state: QueuedEventState) {
+        let mut item = self.items.remove(index);
+        item.state = state;
+        self.notify(item);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use futures_timer::Delay as sleep;
+    use matrix_sdk_base::QueuedEvent;
+    use matrix_sdk_test::test_json;
+    use mockito::{mock, Matcher};
+    use ruma::{event_id, events::room::message::MessageEventContent, room_id};
+    use serde_json::json;
+
+    use super::QueuedEventState;
+    use crate::{client::test::logged_in_client, config::SyncSettings};
+
+    #[tokio::test]
+    async fn queued_event_is_sent() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let _m = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .with_body(test_json::EVENT_ID.to_string())
+            .create();
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let room = client.get_joined_room(&room_id).unwrap();
+        let queue = room.send_queue().await.unwrap();
+        let mut updates = queue.subscribe();
+
+        let txn_id = queue.enqueue(MessageEventContent::text_plain("Hello world")).await.unwrap();
+
+        let states: Vec<_> =
+            updates.by_ref().take(3).map(|i| (i.transaction_id, i.state)).collect().await;
+
+        assert_eq!(
+            states,
+            vec![
+                (txn_id.clone(), QueuedEventState::Queued),
+                (txn_id.clone(), QueuedEventState::Sending),
+                (txn_id, QueuedEventState::Sent(event_id!("$h29iv0s8:example.com"))),
+            ]
+        );
+        assert!(queue.items().is_empty());
+        assert!(client.store().get_queued_events(&room_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_resumed_after_the_first_sync() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        // An event that was queued before the application restarted.
+        let event = QueuedEvent {
+            transaction_id: "txn1".to_owned(),
+            event_type: "m.room.message".to_owned(),
+            content: json!({ "msgtype": "m.text", "body": "Hello world" }),
+            failed: false,
+        };
+        client.store().add_queued_event(&room_id, &event).await.unwrap();
+
+        let send = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.message/txn1".to_string()),
+        )
+        .with_status(200)
+        .with_body(test_json::EVENT_ID.to_string())
+        .expect(1)
+        .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        // The event is sent in the background.
+        while !client.store().get_queued_events(&room_id).await.unwrap().is_empty() {
+            sleep::new(Duration::from_millis(10)).await;
+        }
+
+        send.assert();
+    }
+
+    #[tokio::test]
+    async fn failed_event_holds_back_the_queue() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.message/".to_string()),
+        )
+        .with_status(403)
+        .with_body(json!({ "errcode": "M_FORBIDDEN", "error": "Not allowed" }).to_string())
+        .create();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/org.example.custom/".to_string()),
+        )
+        .with_status(200)
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let room = client.get_joined_room(&room_id).unwrap();
+        let queue = room.send_queue().await.unwrap();
+        let mut updates = queue.subscribe();
+
+        let first = queue.enqueue(MessageEventContent::text_plain("Hello world")).await.unwrap();
+        let second =
+            queue.enqueue_raw(json!({ "body": "Hello" }), "org.example.custom").await.unwrap();
+
+        while let Some(item) = updates.next().await {
+            if item.transaction_id == first && item.state == QueuedEventState::Failed {
+                break;
+            }
+        }
+
+        // The failed event stays in the queue and the events after it aren't
+        // sent, so events are never sent out of order.
+        let states: Vec<_> =
+            queue.items().into_iter().map(|i| (i.transaction_id, i.state)).collect();
+        assert_eq!(
+            states,
+            vec![
+                (first.clone(), QueuedEventState::Failed),
+                (second.clone(), QueuedEventState::Queued)
+            ]
+        );
+        // The failed event isn't sent again after a restart.
+        let stored: Vec<_> = client
+            .store()
+            .get_queued_events(&room_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.transaction_id, e.failed))
+            .collect();
+        assert_eq!(stored, vec![(first.clone(), true), (second.clone(), false)]);
+
+        // Once the failed event is cancelled the queue continues.
+        assert!(queue.cancel(&first).await.unwrap());
+
+        while let Some(item) = updates.next().await {
+            if item.transaction_id == second {
+                if let QueuedEventState::Sent(_) = item.state {
+                    break;
+                }
+            }
+        }
+
+        assert!(queue.items().is_empty());
+        assert!(client.store().get_queued_events(&room_id).await.unwrap().is_empty());
+    }
+}
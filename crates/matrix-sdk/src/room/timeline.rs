@@ -0,0 +1,1165 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A live view of the timeline of a joined room.
+//!
+//! A [`Timeline`] keeps a list of [`TimelineItem`]s that is ready to be
+//! displayed: local echoes are shown for events that are still being sent,
+//! edits and redactions are applied to the events they target and reactions
+//! are aggregated on the event they react to. Every change to the list is
+//! reported as a [`TimelineDiff`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Mutex as StdMutex, Weak},
+};
+
+use futures::Stream;
+use matrix_sdk_base::deserialized_responses::{
+    EncryptionInfo, SyncRoomEvent, Timeline as SyncTimeline,
+};
+use matrix_sdk_common::{locks::Mutex, observable::Observers};
+use ruma::{events::MessageEventContent, EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::Value as JsonValue;
+use tracing::warn;
+
+use super::common::TimelineCursor;
+use crate::{
+    room::{
+        send_queue::{QueuedEventState, SendQueueItem},
+        Joined,
+    },
+    Client, Result,
+};
+
+/// The key that identifies an item of a [`Timeline`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TimelineKey {
+    /// The item is a local echo, identified by the transaction ID that is used
+    /// to send the event.
+    TransactionId(String),
+    /// The item is an event that was received from the server.
+    EventId(EventId),
+}
+
+/// The state of a local echo.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalEchoState {
+    /// The event is waiting in the send queue of the room.
+    Queued,
+    /// The event is being sent to the server.
+    Sending,
+    /// The server accepted the event, it will be replaced by the remote echo
+    /// once it comes down the sync.
+    Sent(EventId),
+    /// Sending the event failed, it can be retried or cancelled using the
+    /// send queue of the room.
+    Failed,
+}
+
+/// An item of a [`Timeline`].
+#[derive(Clone, Debug)]
+pub struct TimelineItem {
+    /// The key that identifies the item.
+    pub key: TimelineKey,
+    /// The sender of the event.
+    pub sender: UserId,
+    /// The type of the event.
+    pub event_type: String,
+    /// The content of the event.
+    ///
+    /// If the event was edited this is the content of the latest edit, if the
+    /// event was redacted this is empty.
+    pub content: JsonValue,
+    /// The time the event was sent, `None` for local echoes.
+    pub origin_server_ts: Option<MilliSecondsSinceUnixEpoch>,
+    /// Was the event edited.
+    pub edited: bool,
+    /// Was the event redacted.
+    pub redacted: bool,
+    /// The reactions to the event, the reaction key mapped to the users that
+    /// reacted with it.
+    pub reactions: BTreeMap<String, BTreeSet<UserId>>,
+    /// The state of the local echo, `None` if the item isn't a local echo.
+    pub send_state: Option<LocalEchoState>,
+    /// The encryption info of the event, `None` if the event wasn't
+    /// encrypted.
+    pub encryption_info: Option<EncryptionInfo>,
+}
+
+impl TimelineItem {
+    /// Get the event ID of the item.
+    ///
+    /// Local echoes only have an event ID once the server accepted the event.
+    pub fn event_id(&self) -> Option<&EventId> {
+        match (&self.key, &self.send_state) {
+            (TimelineKey::EventId(event_id), _) => Some(event_id),
+            (TimelineKey::TransactionId(_), Some(LocalEchoState::Sent(event_id))) => Some(event_id),
+            _ => None,
+        }
+    }
+
+    /// Is the item a local echo of an event that we're sending.
+    pub fn is_local_echo(&self) -> bool {
+        matches!(self.key, TimelineKey::TransactionId(_))
+    }
+
+    fn local_echo(txn_id: String, sender: UserId, event_type: String, content: JsonValue) -> Self {
+        Self {
+            key: TimelineKey::TransactionId(txn_id),
+            sender,
+            event_type,
+            content,
+            origin_server_ts: None,
+            edited: false,
+            redacted: false,
+            reactions: BTreeMap::new(),
+            send_state: Some(LocalEchoState::Queued),
+            encryption_info: None,
+        }
+    }
+
+    fn redact(&mut self) {
+        self.content = JsonValue::Object(Default::default());
+        self.edited = false;
+        self.redacted = true;
+        self.reactions.clear();
+    }
+}
+
+/// A change of the items of a [`Timeline`].
+///
+/// Diffs need to be applied in the order they are received, the indices refer
+/// to the list of items after all the previous diffs were applied.
+#[derive(Clone, Debug)]
+pub enum TimelineDiff {
+    /// An item was inserted at the given index.
+    Insert {
+        /// The index of the new item.
+        index: usize,
+        /// The new item.
+        item: TimelineItem,
+    },
+    /// The item at the given index changed.
+    Update {
+        /// The index of the item.
+        index: usize,
+        /// The new version of the item.
+        item: TimelineItem,
+    },
+    /// The item at the given index was removed.
+    Remove {
+        /// The index of the removed item.
+        index: usize,
+    },
+}
+
+/// A live view of the timeline of a joined room.
+///
+/// The timeline starts out empty, events that are received in a sync are
+/// added to the end of it while older events are loaded using
+/// [`paginate_backwards()`](Self::paginate_backwards).
+///
+/// The timeline stays up to date as long as the sync loop is running and as
+/// long as the `Timeline` or one of its clones is alive.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    room: Joined,
+    inner: Arc<StdMutex<TimelineInner>>,
+    pagination: Arc<Mutex<Pagination>>,
+}
+
+/// The state of the back-pagination of a [`Timeline`], kept between calls so
+/// every call continues where the previous one stopped.
+#[derive(Debug, Default)]
+struct Pagination {
+    cursor: TimelineCursor,
+    /// Events that were loaded but not added to the timeline yet.
+    events: VecDeque<SyncRoomEvent>,
+    /// Events that are newer than this event are skipped, they were or will
+    /// be added by the sync.
+    skip_until: Option<EventId>,
+}
+
+impl Timeline {
+    pub(crate) fn new(room: Joined) -> Self {
+        let inner = Arc::new(StdMutex::new(TimelineInner {
+            own_user_id: Some(room.own_user_id().clone()),
+            ..Default::default()
+        }));
+
+        room.client
+            .timelines
+            .entry(room.room_id().clone())
+            .or_default()
+            .push(Arc::downgrade(&inner));
+
+        let send_queue = room.client.send_queues.entry(room.room_id().clone()).or_default().clone();
+        send_queue.add_timeline(Arc::downgrade(&inner));
+
+        Self { room, inner, pagination: Default::default() }
+    }
+
+    /// Get the current items of the timeline, the oldest item comes first.
+    pub fn items(&self) -> Vec<TimelineItem> {
+        self.inner.lock().unwrap().items.clone()
+    }
+
+    /// Get a stream of changes to the items of the timeline.
+    ///
+    /// The current items are yielded as insertions first, after that a diff
+    /// is yielded every time the timeline changes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, pin_mut, StreamExt};
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::ruma::room_id;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// # let client = Client::new(homeserver)?;
+    /// # let room_id = room_id!("!roomid:example.com");
+    /// use matrix_sdk::room::timeline::TimelineDiff;
+    ///
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    /// let timeline = room.live_timeline();
+    /// let diffs = timeline.subscribe();
+    /// pin_mut!(diffs);
+    ///
+    /// timeline.paginate_backwards(20).await?;
+    ///
+    /// let mut items = Vec::new();
+    ///
+    /// while let Some(diff) = diffs.next().await {
+    ///     match diff {
+    ///         TimelineDiff::Insert { index, item } => items.insert(index, item),
+    ///         TimelineDiff::Update { index, item } => items[index] = item,
+    ///         TimelineDiff::Remove { index } => {
+    ///             items.remove(index);
+    ///         }
+    ///     }
+    /// }
+    /// # anyhow::Result::<()>::Ok(()) });
+    /// ```
+    pub fn subscribe(&self) -> impl Stream<Item = TimelineDiff> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        let items = inner.items.iter().enumerate();
+        inner.observers.subscribe(
+            items.map(|(index, item)| TimelineDiff::Insert { index, item: item.clone() }),
+        )
+    }
+
+    /// Load older events into the timeline.
+    ///
+    /// Events are loaded from the store like [`Common::timeline()`] does, so
+    /// events that are stored locally don't need to be fetched from the server
+    /// again. Every call continues where the previous one stopped.
+    ///
+    /// Returns the number of items that were added to the timeline, this is
+    /// smaller than `limit` if the beginning of the room was reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximal number of items that should be added.
+    ///
+    /// [`Common::timeline()`]: crate::room::Common::timeline
+    pub async fn paginate_backwards(&self, limit: usize) -> Result<usize> {
+        let mut pagination = self.pagination.lock().await;
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+
+            if inner.reset_pagination {
+                *pagination = Pagination::default();
+                inner.reset_pagination = false;
+            }
+
+            // The sync might have stored events that it didn't add to the
+            // timeline yet. Start adding events once we reach the events the
+            // sync already added, the newer ones will be added at the end of
+            // the timeline by the sync.
+            if pagination.cursor.is_fresh() {
+                pagination.skip_until = inner.sync_start.clone();
+            }
+        }
+
+        let mut added = 0;
+
+        while added < limit {
+            let event = match pagination.events.pop_front() {
+                Some(event) => event,
+                None => {
+                    let Pagination { cursor, events, .. } = &mut *pagination;
+                    events.extend(self.room.load_timeline_batch(cursor).await?);
+
+                    if events.is_empty() {
+                        break;
+                    }
+
+                    continue;
+                }
+            };
+
+            if let Some(skip_until) = &pagination.skip_until {
+                if event_id(&event).as_ref() == Some(skip_until) {
+                    pagination.skip_until = None;
+                }
+
+                continue;
+            }
+
+            let mut inner = self.inner.lock().unwrap();
+
+            // A limited sync threw away the timeline while we were loading
+            // events, the loaded events don't belong to it anymore.
+            if inner.reset_pagination {
+                break;
+            }
+
+            if inner.handle_event(event, Position::Start) {
+                added += 1;
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Send a message event to the room.
+    ///
+    /// The event is added to the [`SendQueue`] of the room. Every event in the
+    /// queue shows up as a local echo at the end of the timeline, it is
+    /// replaced by the event once the server sends it back to us. Edits and
+    /// reactions don't get a local echo, they are applied once the server
+    /// sends them back to us.
+    ///
+    /// Returns the transaction ID of the event, which is also the key of its
+    /// local echo.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// [`SendQueue`]: crate::room::send_queue::SendQueue
+    pub async fn send(&self, content: impl MessageEventContent) -> Result<String> {
+        self.room.send_queue().await?.enqueue(content).await
+    }
+
+    /// Remove the local echo of an event that failed to be sent, the event is
+    /// removed from the send queue as well.
+    ///
+    /// Returns true if the local echo was removed, false if there's no failed
+    /// local echo with the given transaction ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction ID of the local echo.
+    pub async fn discard_local_echo(&self, txn_id: &str) -> Result<bool> {
+        let failed = {
+            let inner = self.inner.lock().unwrap();
+            inner.find_local_echo(txn_id).map_or(false, |index| {
+                inner.items[index].send_state == Some(LocalEchoState::Failed)
+            })
+        };
+
+        if failed {
+            self.room.send_queue().await?.cancel(txn_id).await
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+impl Client {
+    /// Add the events of a sync response to the live timelines of the room.
+    pub(crate) fn update_timelines(&self, room_id: &RoomId, timeline: &SyncTimeline) {
+        self.for_each_timeline(room_id, |t| t.handle_sync_timeline(timeline));
+    }
+
+    /// Replace events in the live timelines with their late decrypted
+    /// versions.
+    pub(crate) fn update_timelines_decrypted(&self, rooms: &BTreeMap<RoomId, Vec<SyncRoomEvent>>) {
+        for (room_id, events) in rooms {
+            self.for_each_timeline(room_id, |t| {
+                for event in events {
+                    t.handle_decrypted_event(event.clone());
+                }
+            });
+        }
+    }
+
+    fn for_each_timeline(&self, room_id: &RoomId, f: impl Fn(&mut TimelineInner)) {
+        if let Some(mut timelines) = self.timelines.get_mut(room_id) {
+            // Timelines that were dropped are removed here as well.
+            timelines.retain(|timeline| match timeline.upgrade() {
+                Some(timeline) => {
+                    f(&mut timeline.lock().unwrap());
+                    true
+                }
+                None => false,
+            });
+        }
+    }
+}
+
+/// A weak reference to the state of a [`Timeline`], used by the client to
+/// keep the timeline up to date without keeping it alive.
+pub(crate) type WeakTimeline = Weak<StdMutex<TimelineInner>>;
+
+/// Where events are added to the timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Position {
+    /// Older events, loaded using back-pagination.
+    Start,
+    /// Newer events, received in a sync.
+    End,
+}
+
+/// The parts of an event the timeline cares about.
+#[derive(Deserialize)]
+struct EventDetails {
+    event_id: EventId,
+    sender: UserId,
+    #[serde(rename = "type")]
+    event_type: String,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    #[serde(default)]
+    content: JsonValue,
+    redacts: Option<EventId>,
+    #[serde(default)]
+    unsigned: UnsignedDetails,
+}
+
+/// Get the ID of an event, `None` if the event is malformed.
+fn event_id(event: &SyncRoomEvent) -> Option<EventId> {
+    #[derive(Deserialize)]
+    struct EventIdOnly {
+        event_id: EventId,
+    }
+
+    event.event.deserialize_as::<EventIdOnly>().ok().map(|e| e.event_id)
+}
+
+#[derive(Default, Deserialize)]
+struct UnsignedDetails {
+    transaction_id: Option<String>,
+    redacted_because: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct RelatesTo {
+    rel_type: Option<String>,
+    event_id: Option<EventId>,
+    key: Option<String>,
+}
+
+/// A relation that is applied to the event it targets instead of showing up
+/// as an item.
+enum Relation {
+    Replace { target: EventId, new_content: JsonValue },
+    Annotation { target: EventId, key: String },
+}
+
+impl Relation {
+    fn from_content(content: &JsonValue) -> Option<Self> {
+        let relates_to = content.get("m.relates_to")?;
+        let RelatesTo { rel_type, event_id, key } =
+            serde_json::from_value(relates_to.clone()).ok()?;
+
+        match rel_type.as_deref()? {
+            "m.replace" => Some(Relation::Replace {
+                target: event_id?,
+                new_content: content.get("m.new_content")?.clone(),
+            }),
+            "m.annotation" => Some(Relation::Annotation { target: event_id?, key: key? }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TimelineInner {
+    items: Vec<TimelineItem>,
+    /// The IDs of the events that were added to the timeline, including the
+    /// ones that don't show up as items.
+    seen_events: BTreeSet<EventId>,
+    /// The IDs of the events we know are redacted.
+    redacted_events: BTreeSet<EventId>,
+    /// The known reactions, the event ID of the reaction mapped to the target
+    /// event ID, the key and the sender of the reaction.
+    reactions: BTreeMap<EventId, (EventId, String, UserId)>,
+    /// Edits whose target event isn't part of the timeline yet, the target
+    /// event ID mapped to the newest edit of every sender. Edits are kept per
+    /// sender since we can't tell yet which sender is allowed to edit the
+    /// target.
+    pending_edits: BTreeMap<EventId, BTreeMap<UserId, JsonValue>>,
+    /// The ID of the oldest event that was added by the sync since the
+    /// timeline was last reset.
+    sync_start: Option<EventId>,
+    /// A limited sync reset the timeline, back-pagination needs to start over
+    /// from the newest event.
+    reset_pagination: bool,
+    /// The user ID of the client, the sender of the local echoes.
+    own_user_id: Option<UserId>,
+    observers: Observers<TimelineDiff>,
+}
+
+impl TimelineInner {
+    fn notify(&mut self, diff: TimelineDiff) {
+        self.observers.notify(diff);
+    }
+
+    fn insert(&mut self, index: usize, item: TimelineItem) {
+        self.items.insert(index, item.clone());
+        self.notify(TimelineDiff::Insert { index, item });
+    }
+
+    fn update(&mut self, index: usize) {
+        let item = self.items[index].clone();
+        self.notify(TimelineDiff::Update { index, item });
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.items.remove(index);
+        self.notify(TimelineDiff::Remove { index });
+    }
+
+    /// Find the index of the item with the given event ID.
+    fn find_event(&self, event_id: &EventId) -> Option<usize> {
+        self.items.iter().position(|i| i.event_id() == Some(event_id))
+    }
+
+    fn find_local_echo(&self, txn_id: &str) -> Option<usize> {
+        self.items
+            .iter()
+            .position(|i| matches!(&i.key, TimelineKey::TransactionId(t) if t == txn_id))
+    }
+
+    fn handle_sync_timeline(&mut self, timeline: &SyncTimeline) {
+        if timeline.limited {
+            // There's a gap between the events we have and the new events,
+            // throw away the old events, they can be loaded again using
+            // back-pagination.
+            for index in (0..self.items.len()).rev() {
+                if !self.items[index].is_local_echo() {
+                    self.remove(index);
+                }
+            }
+
+            self.seen_events.clear();
+            self.pending_edits.clear();
+            self.sync_start = None;
+            self.reset_pagination = true;
+        }
+
+        for event in &timeline.events {
+            self.handle_event(event.clone(), Position::End);
+        }
+    }
+
+    /// Add an event to the timeline.
+    ///
+    /// Returns true if a new item was added.
+    fn handle_event(&mut self, event: SyncRoomEvent, position: Position) -> bool {
+        let details = match event.event.deserialize_as::<EventDetails>() {
+            Ok(details) => details,
+            Err(e) => {
+                warn!(error =? e, "Can't add an event to the timeline, the event is malformed");
+                return false;
+            }
+        };
+
+        if position == Position::End && self.sync_start.is_none() {
+            self.sync_start = Some(details.event_id.clone());
+        }
+
+        if !self.seen_events.insert(details.event_id.clone()) {
+            return false;
+        }
+
+        let txn_id = details.unsigned.transaction_id.clone();
+        let mut item = match self.handle_relations(details, event.encryption_info, position) {
+            Some(item) => item,
+            None => return false,
+        };
+
+        self.apply_aggregations(&mut item);
+
+        if position == Position::End {
+            let event_id = item.event_id().cloned();
+            let local_echo = self.items.iter().position(|i| match (&i.key, &i.send_state) {
+                (TimelineKey::TransactionId(t), _) if Some(t) == txn_id.as_ref() => true,
+                (_, Some(LocalEchoState::Sent(e))) => Some(e) == event_id.as_ref(),
+                _ => false,
+            });
+
+            if let Some(index) = local_echo {
+                // This is the remote echo of an event we sent.
+                self.items[index] = item;
+                self.update(index);
+
+                return false;
+            }
+        }
+
+        let index = match position {
+            Position::Start => 0,
+            // Local echoes stay at the end of the timeline until the server
+            // sends them back to us.
+            Position::End => {
+                self.items.iter().position(|i| i.is_local_echo()).unwrap_or(self.items.len())
+            }
+        };
+
+        self.insert(index, item);
+
+        true
+    }
+
+    /// Replace an encrypted event with its late decrypted version.
+    fn handle_decrypted_event(&mut self, event: SyncRoomEvent) {
+        let details = match event.event.deserialize_as::<EventDetails>() {
+            Ok(details) => details,
+            Err(_) => return,
+        };
+
+        let index = match self.find_event(&details.event_id) {
+            Some(index) => index,
+            None => return,
+        };
+
+        match self.handle_relations(details, event.encryption_info, Position::End) {
+            Some(mut item) => {
+                self.apply_aggregations(&mut item);
+                self.items[index] = item;
+                self.update(index);
+            }
+            // The decrypted event is a relation, it doesn't show up as an
+            // item anymore.
+            None => self.remove(index),
+        }
+    }
+
+    /// Apply the event if it's a redaction, an edit or a reaction, otherwise
+    /// turn it into an item.
+    fn handle_relations(
+        &mut self,
+        details: EventDetails,
+        encryption_info: Option<EncryptionInfo>,
+        position: Position,
+    ) -> Option<TimelineItem> {
+        let redacted = details.unsigned.redacted_because.is_some()
+            || self.redacted_events.contains(&details.event_id);
+
+        if details.event_type == "m.room.redaction" {
+            let redacts = details.redacts.or_else(|| {
+                details.content.get("redacts").and_then(|r| serde_json::from_value(r.clone()).ok())
+            });
+
+            if let Some(redacts) = redacts {
+                self.redact(redacts);
+            }
+
+            return None;
+        }
+
+        if !redacted {
+            match Relation::from_content(&details.content) {
+                Some(Relation::Replace { target, new_content }) => {
+                    self.edit(target, details.sender, new_content, position);
+                    return None;
+                }
+                Some(Relation::Annotation { target, key }) => {
+                    self.react(details.event_id, target, key, details.sender);
+                    return None;
+                }
+                None => (),
+            }
+        }
+
+        let mut item = TimelineItem {
+            key: TimelineKey::EventId(details.event_id),
+            sender: details.sender,
+            event_type: details.event_type,
+            content: details.content,
+            origin_server_ts: Some(details.origin_server_ts),
+            edited: false,
+            redacted: false,
+            reactions: BTreeMap::new(),
+            send_state: None,
+            encryption_info,
+        };
+
+        if redacted {
+            item.redact();
+        }
+
+        Some(item)
+    }
+
+    /// Apply the edits and reactions we already know about to a new item.
+    fn apply_aggregations(&mut self, item: &mut TimelineItem) {
+        let event_id = match &item.key {
+            TimelineKey::EventId(event_id) => event_id,
+            TimelineKey::TransactionId(_) => return,
+        };
+
+        if item.redacted {
+            return;
+        }
+
+        if let Some(content) =
+            self.pending_edits.remove(event_id).and_then(|mut edits| edits.remove(&item.sender))
+        {
+            item.content = content;
+            item.edited = true;
+        }
+
+        for (target, key, sender) in self.reactions.values() {
+            if target == event_id {
+                item.reactions.entry(key.clone()).or_default().insert(sender.clone());
+            }
+        }
+    }
+
+    fn edit(
+        &mut self,
+        target: EventId,
+        sender: UserId,
+        new_content: JsonValue,
+        position: Position,
+    ) {
+        match self.find_event(&target) {
+            Some(index) => {
+                let item = &mut self.items[index];
+
+                // Only the sender of an event may edit it. Edits are seen
+                // newest first while paginating backwards, so a later edit may
+                // already be applied.
+                if item.sender != sender
+                    || item.redacted
+                    || (position == Position::Start && item.edited)
+                {
+                    return;
+                }
+
+                item.content = new_content;
+                item.edited = true;
+                self.update(index);
+            }
+            None => {
+                let edits = self.pending_edits.entry(target).or_default();
+
+                match position {
+                    Position::Start => {
+                        edits.entry(sender).or_insert(new_content);
+                    }
+                    Position::End => {
+                        edits.insert(sender, new_content);
+                    }
+                }
+            }
+        }
+    }
+
+    fn react(&mut self, reaction_id: EventId, target: EventId, key: String, sender: UserId) {
+        self.reactions.insert(reaction_id, (target.clone(), key.clone(), sender.clone()));
+
+        if let Some(index) = self.find_event(&target) {
+            let item = &mut self.items[index];
+
+            if !item.redacted && item.reactions.entry(key).or_default().insert(sender) {
+                self.update(index);
+            }
+        }
+    }
+
+    fn redact(&mut self, event_id: EventId) {
+        self.pending_edits.remove(&event_id);
+
+        if let Some((target, key, sender)) = self.reactions.remove(&event_id) {
+            if let Some(index) = self.find_event(&target) {
+                let item = &mut self.items[index];
+
+                if let Some(senders) = item.reactions.get_mut(&key) {
+                    senders.remove(&sender);
+
+                    if senders.is_empty() {
+                        item.reactions.remove(&key);
+                    }
+
+                    self.update(index);
+                }
+            }
+        } else if let Some(index) = self.find_event(&event_id) {
+            if !self.items[index].redacted {
+                self.items[index].redact();
+                self.update(index);
+            }
+        }
+
+        self.redacted_events.insert(event_id);
+    }
+
+    fn add_local_echo(&mut self, item: TimelineItem) {
+        if Relation::from_content(&item.content).is_none() {
+            let index = self.items.len();
+            self.insert(index, item);
+        }
+    }
+
+    fn set_local_echo_state(&mut self, txn_id: &str, state: LocalEchoState) {
+        // The local echo is gone if the remote echo already arrived.
+        if let Some(index) = self.find_local_echo(txn_id) {
+            self.items[index].send_state = Some(state);
+            self.update(index);
+        }
+    }
+
+    /// Add, update or remove the local echo of an event in the send queue of
+    /// the room.
+    pub(crate) fn handle_send_queue_item(&mut self, item: &SendQueueItem) {
+        let state = match &item.state {
+            QueuedEventState::Queued => LocalEchoState::Queued,
+            QueuedEventState::Sending => LocalEchoState::Sending,
+            QueuedEventState::Sent(event_id) => LocalEchoState::Sent(event_id.clone()),
+            QueuedEventState::Failed => LocalEchoState::Failed,
+            QueuedEventState::Cancelled => {
+                if let Some(index) = self.find_local_echo(&item.transaction_id) {
+                    self.remove(index);
+                }
+
+                return;
+            }
+        };
+
+        if self.find_local_echo(&item.transaction_id).is_some() {
+            self.set_local_echo_state(&item.transaction_id, state);
+            return;
+        }
+
+        // A sent event without a local echo was already replaced by its remote
+        // echo.
+        if matches!(state, LocalEchoState::Sent(_)) {
+            return;
+        }
+
+        if let Some(sender) = self.own_user_id.clone() {
+            let mut local_echo = TimelineItem::local_echo(
+                item.transaction_id.clone(),
+                sender,
+                item.event_type.clone(),
+                item.content.clone(),
+            );
+            local_echo.send_state = Some(state);
+            self.add_local_echo(local_echo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{executor::block_on_stream, pin_mut, StreamExt};
+    use matrix_sdk_base::deserialized_responses::{SyncRoomEvent, Timeline as SyncTimeline};
+    use matrix_sdk_test::test_json;
+    use mockito::{mock, Matcher};
+    use ruma::{
+        api::{client::r0::sync::sync_events, IncomingResponse},
+        event_id,
+        events::room::message::MessageEventContent,
+        room_id,
+        serde::Raw,
+        user_id, RoomId,
+    };
+    use serde_json::{json, value::to_raw_value, Value as JsonValue};
+
+    use super::{LocalEchoState, Position, TimelineDiff, TimelineInner, TimelineItem, TimelineKey};
+    use crate::client::test::logged_in_client;
+
+    fn event(json: JsonValue) -> SyncRoomEvent {
+        SyncRoomEvent::from(Raw::from_json(to_raw_value(&json).unwrap()))
+    }
+
+    fn sync_response(room_id: &RoomId, events: &[&str]) -> sync_events::Response {
+        let events: Vec<_> = events
+            .iter()
+            .map(|event_id| {
+                json!({
+                    "event_id": event_id,
+                    "sender": "@alice:example.org",
+                    "type": "m.room.message",
+                    "origin_server_ts": 1,
+                    "content": { "msgtype": "m.text", "body": event_id },
+                })
+            })
+            .collect();
+
+        let body = json!({
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    room_id.as_str(): {
+                        "timeline": { "events": events, "limited": false },
+                    },
+                },
+            },
+        });
+
+        sync_events::Response::try_from_http_response(
+            http::Response::builder().status(200).body(serde_json::to_vec(&body).unwrap()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn item_ids(items: &[TimelineItem]) -> Vec<&str> {
+        items.iter().map(|i| i.event_id().unwrap().as_str()).collect()
+    }
+
+    fn message(event_id: &str, sender: &str, body: &str) -> SyncRoomEvent {
+        event(json!({
+            "event_id": event_id,
+            "sender": sender,
+            "type": "m.room.message",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": body },
+        }))
+    }
+
+    fn edit(event_id: &str, sender: &str, target: &str, body: &str) -> SyncRoomEvent {
+        event(json!({
+            "event_id": event_id,
+            "sender": sender,
+            "type": "m.room.message",
+            "origin_server_ts": 1,
+            "content": {
+                "msgtype": "m.text",
+                "body": format!("* {}", body),
+                "m.new_content": { "msgtype": "m.text", "body": body },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": target },
+            },
+        }))
+    }
+
+    fn reaction(event_id: &str, sender: &str, target: &str, key: &str) -> SyncRoomEvent {
+        event(json!({
+            "event_id": event_id,
+            "sender": sender,
+            "type": "m.reaction",
+            "origin_server_ts": 1,
+            "content": {
+                "m.relates_to": { "rel_type": "m.annotation", "event_id": target, "key": key },
+            },
+        }))
+    }
+
+    fn redaction(event_id: &str, sender: &str, redacts: &str) -> SyncRoomEvent {
+        event(json!({
+            "event_id": event_id,
+            "sender": sender,
+            "type": "m.room.redaction",
+            "origin_server_ts": 1,
+            "redacts": redacts,
+            "content": {},
+        }))
+    }
+
+    fn body(item: &TimelineItem) -> &str {
+        item.content["body"].as_str().unwrap()
+    }
+
+    #[test]
+    fn edits_reactions_and_redactions() {
+        let mut timeline = TimelineInner::default();
+
+        timeline.handle_event(message("$1", "@alice:example.org", "hello"), Position::End);
+        timeline.handle_event(edit("$2", "@alice:example.org", "$1", "hi"), Position::End);
+        timeline.handle_event(edit("$3", "@mallory:example.org", "$1", "bye"), Position::End);
+        timeline.handle_event(reaction("$4", "@bob:example.org", "$1", "👍"), Position::End);
+        timeline.handle_event(reaction("$5", "@carol:example.org", "$1", "👍"), Position::End);
+
+        assert_eq!(timeline.items.len(), 1);
+        let item = &timeline.items[0];
+        assert_eq!(body(item), "hi");
+        assert!(item.edited);
+        assert_eq!(item.reactions["👍"].len(), 2);
+
+        timeline.handle_event(redaction("$6", "@bob:example.org", "$4"), Position::End);
+        let senders = &timeline.items[0].reactions["👍"];
+        assert_eq!(senders.len(), 1);
+        assert!(senders.contains(&user_id!("@carol:example.org")));
+
+        timeline.handle_event(redaction("$7", "@alice:example.org", "$1"), Position::End);
+        let item = &timeline.items[0];
+        assert!(item.redacted);
+        assert!(!item.edited);
+        assert!(item.reactions.is_empty());
+        assert_eq!(item.content, json!({}));
+    }
+
+    #[test]
+    fn back_pagination_applies_newer_relations() {
+        let mut timeline = TimelineInner::default();
+
+        // Events are seen newest first while paginating backwards.
+        for event in vec![
+            // Edits of other users don't replace the pending edit of the
+            // sender of the target event.
+            edit("$6", "@mallory:example.org", "$1", "forged"),
+            reaction("$5", "@bob:example.org", "$1", "🎉"),
+            edit("$4", "@alice:example.org", "$1", "newest"),
+            edit("$3", "@alice:example.org", "$1", "older"),
+            message("$2", "@bob:example.org", "second"),
+            message("$1", "@alice:example.org", "first"),
+        ] {
+            timeline.handle_event(event, Position::Start);
+        }
+
+        assert_eq!(timeline.items.len(), 2);
+        assert_eq!(body(&timeline.items[0]), "newest");
+        assert!(timeline.items[0].edited);
+        assert!(timeline.items[0].reactions.contains_key("🎉"));
+        assert_eq!(body(&timeline.items[1]), "second");
+
+        // Events that are already part of the timeline aren't added again.
+        assert!(
+            !timeline.handle_event(message("$2", "@bob:example.org", "second"), Position::Start)
+        );
+    }
+
+    #[test]
+    fn local_echoes() {
+        let mut timeline = TimelineInner::default();
+        let receiver = timeline.observers.subscribe(None);
+
+        timeline.add_local_echo(TimelineItem::local_echo(
+            "txn1".to_owned(),
+            user_id!("@alice:example.org"),
+            "m.room.message".to_owned(),
+            json!({ "msgtype": "m.text", "body": "hello" }),
+        ));
+        timeline.set_local_echo_state("txn1", LocalEchoState::Sent(event_id!("$1")));
+
+        // New events from the server are added before the local echo.
+        timeline.handle_event(message("$0", "@bob:example.org", "hey"), Position::End);
+        assert_eq!(timeline.items[0].key, TimelineKey::EventId(event_id!("$0")));
+        assert!(timeline.items[1].is_local_echo());
+
+        let remote_echo = event(json!({
+            "event_id": "$1",
+            "sender": "@alice:example.org",
+            "type": "m.room.message",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": "hello" },
+            "unsigned": { "transaction_id": "txn1" },
+        }));
+        timeline.handle_sync_timeline(&SyncTimeline {
+            limited: false,
+            prev_batch: None,
+            events: vec![remote_echo],
+        });
+
+        assert_eq!(timeline.items.len(), 2);
+        assert_eq!(timeline.items[1].key, TimelineKey::EventId(event_id!("$1")));
+        assert_eq!(timeline.items[1].send_state, None);
+
+        drop(timeline);
+        let diffs: Vec<_> = block_on_stream(receiver).collect();
+
+        assert!(matches!(
+            diffs.as_slice(),
+            [
+                TimelineDiff::Insert { index: 0, .. },
+                TimelineDiff::Update { index: 0, .. },
+                TimelineDiff::Insert { index: 0, .. },
+                TimelineDiff::Update { index: 1, .. },
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn back_pagination_skips_events_the_sync_will_add() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!test:localhost");
+
+        client.process_sync(sync_response(&room_id, &["$a", "$b"])).await.unwrap();
+
+        let timeline = client.get_joined_room(&room_id).unwrap().live_timeline();
+        client.process_sync(sync_response(&room_id, &["$c"])).await.unwrap();
+
+        // The next sync response got stored, but the timeline didn't see it
+        // yet when we start paginating.
+        let response = client
+            .base_client
+            .receive_sync_response(sync_response(&room_id, &["$d"]))
+            .await
+            .unwrap();
+
+        assert_eq!(timeline.paginate_backwards(10).await.unwrap(), 2);
+        client.update_timelines(&room_id, &response.rooms.join[&room_id].timeline);
+
+        assert_eq!(item_ids(&timeline.items()), ["$a", "$b", "$c", "$d"]);
+
+        // Pagination continues where it stopped, the beginning of the room
+        // was already reached.
+        assert_eq!(timeline.paginate_backwards(10).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn sent_events_go_through_the_send_queue() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!test:localhost");
+
+        client.process_sync(sync_response(&room_id, &["$a"])).await.unwrap();
+
+        let _m = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .with_body(test_json::EVENT_ID.to_string())
+            .create();
+
+        let timeline = client.get_joined_room(&room_id).unwrap().live_timeline();
+        let diffs = timeline.subscribe();
+        pin_mut!(diffs);
+
+        let txn_id = timeline.send(MessageEventContent::text_plain("Hello world")).await.unwrap();
+
+        let mut states = Vec::new();
+
+        while let Some(diff) = diffs.next().await {
+            if let TimelineDiff::Insert { item, .. } | TimelineDiff::Update { item, .. } = diff {
+                if item.key == TimelineKey::TransactionId(txn_id.clone()) {
+                    let state = item.send_state.unwrap();
+                    states.push(state.clone());
+
+                    if let LocalEchoState::Sent(_) = state {
+                        break;
+                    }
+                }
+            }
+        }
+
+        assert_eq!(
+            states,
+            [
+                LocalEchoState::Queued,
+                LocalEchoState::Sending,
+                LocalEchoState::Sent(event_id!("$h29iv0s8:example.com")),
+            ]
+        );
+        assert_eq!(timeline.items().len(), 2);
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types describing the state of the sync loop.
+
+use std::sync::{Arc, Mutex as StdMutex};
+
+use futures::Stream;
+use matrix_sdk_common::{instant::Duration, observable::Observers};
+
+use crate::RequestErrorKind;
+
+/// The reason why the sync loop stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncStopReason {
+    /// The sync loop was asked to stop, e.g. by returning [`LoopCtrl::Break`]
+    /// from the sync callback.
+    ///
+    /// [`LoopCtrl::Break`]: crate::LoopCtrl::Break
+    Requested,
+    /// The sync loop stopped because of a fatal error.
+    Error(RequestErrorKind),
+}
+
+/// The state of the sync loop of a [`Client`].
+///
+/// [`Client`]: crate::Client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncState {
+    /// No sync loop has been started yet.
+    Idle,
+    /// The sync loop is running.
+    Running,
+    /// The last sync failed, the sync loop is waiting before it retries.
+    BackingOff {
+        /// The error that made the last sync fail.
+        error: RequestErrorKind,
+        /// The number of consecutive failed syncs.
+        attempt: u32,
+        /// The time we're waiting before the next sync.
+        delay: Duration,
+    },
+    /// The sync loop has stopped.
+    Stopped(SyncStopReason),
+}
+
+/// The state of the sync loop and the listeners that want to be notified when
+/// it changes.
+#[derive(Clone, Debug)]
+pub(crate) struct SyncStateObserver {
+    inner: Arc<StdMutex<InnerSyncStateObserver>>,
+}
+
+#[derive(Debug)]
+struct InnerSyncStateObserver {
+    state: SyncState,
+    observers: Observers<SyncState>,
+}
+
+impl SyncStateObserver {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(StdMutex::new(InnerSyncStateObserver {
+                state: SyncState::Idle,
+                observers: Observers::new(),
+            })),
+        }
+    }
+
+    pub fn get(&self) -> SyncState {
+        self.inner.lock().unwrap().state.clone()
+    }
+
+    /// Get a stream of sync state changes, the current state is yielded first.
+    pub fn subscribe(&self) -> impl Stream<Item = SyncState> {
+        let mut inner = self.inner.lock().unwrap();
+        let state = inner.state.clone();
+
+        inner.observers.subscribe(Some(state))
+    }
+
+    /// Set a new sync state, listeners are only notified if the state changed,
+    /// listeners that went away are removed.
+    pub fn set(&self, state: SyncState) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state != state {
+            inner.observers.notify(state.clone());
+            inner.state = state;
+        }
+    }
+
+    /// Mark the sync loop as stopped because it was asked to stop, unless it
+    /// already stopped because of an error.
+    pub fn stop(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !matches!(inner.state, SyncState::Stopped(_)) {
+            let state = SyncState::Stopped(SyncStopReason::Requested);
+            inner.observers.notify(state.clone());
+            inner.state = state;
+        }
+    }
+}
+
+/// Marks the sync loop as stopped once it gets dropped, used to notice that a
+/// sync stream went away.
+#[derive(Debug)]
+pub(crate) struct StopOnDrop(pub SyncStateObserver);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}